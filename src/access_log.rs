@@ -0,0 +1,156 @@
+//! Audit trail of connections through the local proxies, see `innisfree up
+//! --access-log`. Each connection is logged as it closes, as a line of JSON
+//! in `access.log` in the tunnel's config dir: who connected, to which
+//! service, for how long, and how many bytes passed. The log is rotated once
+//! it grows past [MAX_SIZE], keeping one previous file, `access.log.1`.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::config::make_config_dir;
+
+/// Size past which the log is rotated.
+pub const MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A connection through a local proxy, as logged once it closes.
+pub struct AccessEntry {
+    /// When the connection was accepted, as seconds since the unix epoch.
+    pub time: u64,
+    /// Public port and protocol of the service, e.g. `443/TCP`.
+    pub service: String,
+    /// Name of the service, if it has one, e.g. `web`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Client's address, as reported by the PROXY protocol header, if any,
+    /// else the server's end of the tunnel, which nginx connects from.
+    pub client: SocketAddr,
+    /// How long the connection was open, in milliseconds.
+    pub duration_ms: u64,
+    /// Bytes received from the client.
+    pub rx_bytes: u64,
+    /// Bytes sent to the client.
+    pub tx_bytes: u64,
+    /// Why the connection was dropped, if it failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+#[derive(Debug, Clone)]
+/// Access log shared by a tunnel's proxies. Clones append to the same file.
+pub struct AccessLog(Arc<Mutex<LogFile>>);
+
+impl AccessLog {
+    /// Opens the access log of the tunnel `name`, appending to it.
+    pub fn open(name: &str) -> Result<AccessLog> {
+        AccessLog::open_path(&make_config_dir(name)?.join("access.log"), MAX_SIZE)
+    }
+
+    /// Opens the access log at `path`, rotated past `max_size`.
+    pub(crate) fn open_path(path: &Path, max_size: u64) -> Result<AccessLog> {
+        let file = append(path)?;
+        let size = file.metadata()?.len();
+        Ok(AccessLog(Arc::new(Mutex::new(LogFile {
+            path: path.to_owned(),
+            file,
+            size,
+            max_size,
+        }))))
+    }
+
+    /// Appends the entry, rotating the log first if it's grown too large.
+    /// Failures are only reported, so they never drop a connection.
+    pub fn record(&self, entry: &AccessEntry) {
+        let mut log = match self.0.lock() {
+            Ok(l) => l,
+            Err(e) => e.into_inner(),
+        };
+        if let Err(e) = log.write(entry) {
+            tracing::warn!("Failed to write access log {}: {:#}", log.path.display(), e);
+        }
+    }
+}
+
+impl LogFile {
+    fn write(&mut self, entry: &AccessEntry) -> Result<()> {
+        if self.size >= self.max_size {
+            let rotated = self.path.with_extension("log.1");
+            std::fs::rename(&self.path, &rotated).context("Failed to rotate access log")?;
+            self.file = append(&self.path)?;
+            self.size = 0;
+        }
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Opens the file at `path` for appending, creating it, readable only by
+/// the owner, since it records clients' addresses.
+fn append(path: &Path) -> Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to open access log {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_appended_and_rotated() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("innisfree-access-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("access.log");
+        let entry = AccessEntry {
+            time: 1_700_000_000,
+            service: "443/TCP".to_string(),
+            name: None,
+            client: "203.0.113.7:51234".parse()?,
+            duration_ms: 1500,
+            rx_bytes: 512,
+            tx_bytes: 4096,
+            error: None,
+        };
+        let log = AccessLog::open_path(&path, 300)?;
+        log.record(&entry);
+        log.clone().record(&entry);
+        let contents = std::fs::read_to_string(&path)?;
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(serde_json::from_str::<AccessEntry>(lines[0])?, entry);
+        assert!(!lines[0].contains("error"));
+
+        // Past the limit, the next entry starts a new file
+        log.record(&entry);
+        log.record(&entry);
+        assert_eq!(std::fs::read_to_string(&path)?.lines().count(), 1);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("access.log.1"))?
+                .lines()
+                .count(),
+            3
+        );
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,479 @@
+//! Certificates from Let's Encrypt, or another ACME CA, for local TLS
+//! termination, see `innisfree up --tls-acme`. Domains are validated via
+//! DNS-01 challenges, answered with TXT records set via a [DnsProvider],
+//! so wildcard certificates can be issued, e.g. for `*.example.com`, and
+//! port 80 needn't be forwarded for HTTP-01. Certificates are kept across
+//! runs, outside the tunnel's config dir, and renewed by `up` once they're
+//! within [RENEW_BEFORE_DAYS] of expiring.
+//! See <https://www.rfc-editor.org/rfc/rfc8555> for the protocol.
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509ReqBuilder, X509};
+use serde_json::{json, Value};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::make_config_dir;
+use crate::dns::DnsProvider;
+
+/// Directory of Let's Encrypt's production CA.
+pub const LETSENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// Days before expiry from which a certificate is renewed.
+const RENEW_BEFORE_DAYS: i32 = 30;
+/// How long to wait for challenge records to reach the domain's name
+/// servers before asking the CA to check them.
+const PROPAGATION_WAIT: Duration = Duration::from_secs(30);
+/// How often to check on pending authorizations and orders.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Checks on a pending authorization or order before giving up.
+const MAX_POLLS: u32 = 60;
+
+/// Encodes `data` as unpadded URL-safe base64, as JWS expects.
+fn b64(data: &[u8]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(data)
+}
+
+/// Generates a P-256 key, for accounts and certificates alike.
+fn generate_key() -> Result<EcKey<Private>> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    Ok(EcKey::generate(&group)?)
+}
+
+/// Writes `data` to `path`, readable only by the owner.
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    use std::io::Write;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut f| f.write_all(data))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Key identifying the ACME account, which signs each request.
+struct AccountKey(EcKey<Private>);
+
+impl AccountKey {
+    /// Loads the key at `path`, generating one if there's none yet.
+    fn load_or_create(path: &Path) -> Result<AccountKey> {
+        if path.exists() {
+            let pem = std::fs::read(path)?;
+            return Ok(AccountKey(EcKey::private_key_from_pem(&pem)?));
+        }
+        let key = generate_key()?;
+        write_private(path, &key.private_key_to_pem()?)?;
+        Ok(AccountKey(key))
+    }
+
+    /// Returns the public key as a JWK, with members in lexicographic
+    /// order, as its thumbprint requires, see RFC 7638.
+    fn jwk(&self) -> Result<Value> {
+        let mut ctx = BigNumContext::new()?;
+        let (mut x, mut y) = (BigNum::new()?, BigNum::new()?);
+        self.0
+            .public_key()
+            .affine_coordinates(self.0.group(), &mut x, &mut y, &mut ctx)?;
+        Ok(json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": b64(&x.to_vec_padded(32)?),
+            "y": b64(&y.to_vec_padded(32)?),
+        }))
+    }
+
+    /// Returns the JWK thumbprint, which key authorizations end with.
+    fn thumbprint(&self) -> Result<String> {
+        let jwk = serde_json::to_string(&self.jwk()?)?;
+        Ok(b64(&openssl::sha::sha256(jwk.as_bytes())))
+    }
+
+    /// Signs `data` via ES256, as the fixed-size `r || s` JWS expects,
+    /// rather than DER.
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let sig = EcdsaSig::sign(&openssl::sha::sha256(data), &self.0)?;
+        let mut raw = sig.r().to_vec_padded(32)?;
+        raw.extend(sig.s().to_vec_padded(32)?);
+        Ok(raw)
+    }
+}
+
+/// Returns the name of the TXT record answering the DNS-01 challenge for
+/// `domain`, shared by the domain and its wildcard.
+fn challenge_name(domain: &str) -> String {
+    format!("_acme-challenge.{}", domain.trim_start_matches("*."))
+}
+
+/// Returns the value of the TXT record answering the DNS-01 challenge
+/// with `token`, the digest of its key authorization.
+fn challenge_value(token: &str, thumbprint: &str) -> String {
+    let key_authorization = format!("{}.{}", token, thumbprint);
+    b64(&openssl::sha::sha256(key_authorization.as_bytes()))
+}
+
+/// Builds a certificate signing request for `domains`, the first as its
+/// subject, in DER.
+fn csr(domains: &[String], key: &PKey<Private>) -> Result<Vec<u8>> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, &domains[0])?;
+    let mut req = X509ReqBuilder::new()?;
+    req.set_subject_name(&name.build())?;
+    req.set_pubkey(key)?;
+    let mut san = SubjectAlternativeName::new();
+    for d in domains {
+        san.dns(d);
+    }
+    let mut extensions = Stack::new()?;
+    extensions.push(san.build(&req.x509v3_context(None))?)?;
+    req.add_extensions(&extensions)?;
+    req.sign(key, MessageDigest::sha256())?;
+    Ok(req.build().to_der()?)
+}
+
+/// Whether the certificate chain `pem` expires within [RENEW_BEFORE_DAYS].
+fn needs_renewal(pem: &[u8]) -> Result<bool> {
+    let cert = X509::from_pem(pem)?;
+    let left = Asn1Time::days_from_now(0)?.diff(cert.not_after())?;
+    Ok(left.days < RENEW_BEFORE_DAYS)
+}
+
+/// Returns the directory holding the certificate for `domains`, named after
+/// the first. Kept outside the tunnel's config dir, which is removed on
+/// clean, so certificates outlive tunnels, as the CA's rate limits expect.
+fn cert_dir(domains: &[String]) -> Result<PathBuf> {
+    let dir = make_config_dir(".acme")?.join(domains[0].replace('*', "_wildcard"));
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Client for an ACME CA, signing requests with the account key.
+struct AcmeClient {
+    http: reqwest::Client,
+    /// URLs of the CA's endpoints, e.g. `newOrder`.
+    directory: Value,
+    key: AccountKey,
+    /// URL of the account, once registered.
+    kid: Option<String>,
+    /// Nonce for the next request, from the previous response.
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Fetches the CA's directory from `url`.
+    async fn new(url: &str, key: AccountKey) -> Result<AcmeClient> {
+        let http = reqwest::Client::new();
+        let directory = http
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("Failed to fetch ACME directory {}", url))?;
+        Ok(AcmeClient {
+            http,
+            directory,
+            key,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    /// Returns the URL of the CA's endpoint `name`.
+    fn endpoint(&self, name: &str) -> Result<String> {
+        self.directory[name]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| anyhow!("ACME directory lacks {}", name))
+    }
+
+    /// Returns a fresh nonce, from the last response, else the CA.
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(n) = self.nonce.take() {
+            return Ok(n);
+        }
+        let response = self.http.head(self.endpoint("newNonce")?).send().await?;
+        replay_nonce(&response).ok_or_else(|| anyhow!("No nonce from ACME CA"))
+    }
+
+    /// Sends `payload` to `url`, signed, or an empty payload for
+    /// POST-as-GET. Retries once if the CA rejects the nonce.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<reqwest::Response> {
+        let payload = match payload {
+            Some(p) => b64(serde_json::to_string(p)?.as_bytes()),
+            None => String::new(),
+        };
+        let mut retried = false;
+        loop {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.nonce().await?,
+                "url": url,
+            });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.key.jwk()?,
+            }
+            let protected = b64(serde_json::to_string(&protected)?.as_bytes());
+            let signature = self
+                .key
+                .sign(format!("{}.{}", protected, payload).as_bytes())?;
+            let response = self
+                .http
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .json(&json!({
+                    "protected": protected,
+                    "payload": payload,
+                    "signature": b64(&signature),
+                }))
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            let problem: Value = response.json().await.unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(anyhow!(
+                "ACME request to {} failed with {}: {}",
+                url,
+                status,
+                problem["detail"].as_str().unwrap_or("no details")
+            ));
+        }
+    }
+
+    /// Sends `payload` to `url`, returning the response's JSON, and its
+    /// `Location` header, if any.
+    async fn post_json(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<(Value, Option<String>)> {
+        let response = self.post(url, payload).await?;
+        let location = response
+            .headers()
+            .get("Location")
+            .and_then(|l| l.to_str().ok())
+            .map(String::from);
+        Ok((response.json().await?, location))
+    }
+
+    /// Registers the account, or looks it up if the key's registered
+    /// already, agreeing to the CA's terms of service.
+    async fn register(&mut self) -> Result<()> {
+        let url = self.endpoint("newAccount")?;
+        let (_, kid) = self
+            .post_json(&url, Some(&json!({ "termsOfServiceAgreed": true })))
+            .await?;
+        self.kid = Some(kid.ok_or_else(|| anyhow!("No account URL from ACME CA"))?);
+        Ok(())
+    }
+
+    /// Checks on the authorization or order at `url` until it's no longer
+    /// in one of the `pending` states, returning it.
+    async fn poll(&mut self, url: &str, pending: &[&str]) -> Result<Value> {
+        for _ in 0..MAX_POLLS {
+            let (j, _) = self.post_json(url, None).await?;
+            if !pending.iter().any(|p| j["status"] == *p) {
+                return Ok(j);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(anyhow!("Timed out waiting on ACME CA for {}", url))
+    }
+}
+
+/// Returns the `Replay-Nonce` header of `response`, if any.
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("Replay-Nonce")
+        .and_then(|n| n.to_str().ok())
+        .map(String::from)
+}
+
+/// Returns the paths of the certificate chain and private key for
+/// `domains`, e.g. `*.example.com` and `example.com`, issued by the CA
+/// whose directory is at `directory`. Reuses the certificate from an earlier
+/// run, unless it's due for renewal.
+pub async fn certificate(
+    domains: &[String],
+    dns: &dyn DnsProvider,
+    directory: &str,
+) -> Result<(PathBuf, PathBuf)> {
+    if domains.is_empty() {
+        return Err(anyhow!("No domains to request a certificate for"));
+    }
+    let dir = cert_dir(domains)?;
+    let (cert_path, key_path) = (dir.join("fullchain.pem"), dir.join("privkey.pem"));
+    if let Ok(pem) = std::fs::read(&cert_path) {
+        if !needs_renewal(&pem)? {
+            tracing::debug!("Reusing certificate {}", cert_path.display());
+            return Ok((cert_path, key_path));
+        }
+        tracing::info!("Renewing certificate for {}", domains.join(", "));
+    } else {
+        tracing::info!("Requesting certificate for {}", domains.join(", "));
+    }
+    let account = AccountKey::load_or_create(&make_config_dir(".acme")?.join("account.pem"))?;
+    let mut client = AcmeClient::new(directory, account).await?;
+    client.register().await?;
+
+    let identifiers: Vec<Value> = domains
+        .iter()
+        .map(|d| json!({ "type": "dns", "value": d }))
+        .collect();
+    let url = client.endpoint("newOrder")?;
+    let (order, order_url) = client
+        .post_json(&url, Some(&json!({ "identifiers": identifiers })))
+        .await?;
+    let order_url = order_url.ok_or_else(|| anyhow!("No order URL from ACME CA"))?;
+
+    // Challenges for each authorization that isn't valid already.
+    let thumbprint = client.key.thumbprint()?;
+    let mut challenges = vec![];
+    for authz_url in order["authorizations"].as_array().into_iter().flatten() {
+        let authz_url = authz_url.as_str().unwrap_or_default().to_string();
+        let (authz, _) = client.post_json(&authz_url, None).await?;
+        if authz["status"] == "valid" {
+            continue;
+        }
+        let domain = authz["identifier"]["value"].as_str().unwrap_or_default();
+        let challenge = authz["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|c| c["type"] == "dns-01")
+            .ok_or_else(|| anyhow!("ACME CA offered no DNS-01 challenge for {}", domain))?;
+        challenges.push((
+            authz_url,
+            challenge["url"].as_str().unwrap_or_default().to_string(),
+            challenge_name(domain),
+            challenge_value(challenge["token"].as_str().unwrap_or_default(), &thumbprint),
+        ));
+    }
+
+    let mut added = vec![];
+    let validated = async {
+        for (_, _, name, value) in &challenges {
+            tracing::debug!("Adding TXT record {}", name);
+            dns.add_txt(name, value).await?;
+            added.push((name, value));
+        }
+        if !challenges.is_empty() {
+            tracing::debug!("Waiting for challenge records to propagate");
+            tokio::time::sleep(PROPAGATION_WAIT).await;
+        }
+        for (authz_url, challenge_url, _, _) in &challenges {
+            client.post_json(challenge_url, Some(&json!({}))).await?;
+            let authz = client.poll(authz_url, &["pending"]).await?;
+            if authz["status"] != "valid" {
+                return Err(anyhow!(
+                    "ACME CA failed to validate {}: {}",
+                    authz["identifier"]["value"],
+                    authz["challenges"]
+                ));
+            }
+        }
+        Ok(())
+    }
+    .await;
+    for (name, value) in added {
+        if let Err(e) = dns.remove_txt(name, value).await {
+            tracing::warn!("Failed to remove TXT record {}: {:#}", name, e);
+        }
+    }
+    validated?;
+
+    let key = PKey::from_ec_key(generate_key()?)?;
+    let finalize = order["finalize"]
+        .as_str()
+        .ok_or_else(|| anyhow!("No finalize URL from ACME CA"))?;
+    client
+        .post_json(finalize, Some(&json!({ "csr": b64(&csr(domains, &key)?) })))
+        .await?;
+    let order = client
+        .poll(&order_url, &["pending", "ready", "processing"])
+        .await?;
+    let cert_url = match order["certificate"].as_str() {
+        Some(url) if order["status"] == "valid" => url.to_string(),
+        _ => return Err(anyhow!("ACME order failed: {}", order)),
+    };
+    let chain = client.post(&cert_url, None).await?.bytes().await?;
+    write_private(&key_path, &key.private_key_to_pem_pkcs8()?)?;
+    std::fs::write(&cert_path, &chain)?;
+    tracing::info!("Certificate saved to {}", cert_path.display());
+    Ok((cert_path, key_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::x509::X509Req;
+
+    #[test]
+    fn requests_signed_and_challenges_answered() -> Result<()> {
+        let key = AccountKey(generate_key()?);
+        let jwk = serde_json::to_string(&key.jwk()?)?;
+        assert!(jwk.starts_with(r#"{"crv":"P-256","kty":"EC","x":""#));
+        assert_eq!(key.thumbprint()?.len(), 43);
+
+        // The raw signature verifies once converted back to DER
+        let sig = key.sign(b"protected.payload")?;
+        assert_eq!(sig.len(), 64);
+        let der = EcdsaSig::from_private_components(
+            BigNum::from_slice(&sig[..32])?,
+            BigNum::from_slice(&sig[32..])?,
+        )?;
+        assert!(der.verify(&openssl::sha::sha256(b"protected.payload"), &key.0)?);
+
+        assert_eq!(
+            challenge_name("*.example.com"),
+            "_acme-challenge.example.com"
+        );
+        let value = challenge_value("evaGxfADs6pSRb2LAv9IZf17Dt3juxGJ-PCt92wr-oA", "thumb");
+        assert_eq!(value.len(), 43);
+        assert!(!value.contains(['+', '/', '=']));
+
+        let domains = vec!["*.example.com".to_string(), "example.com".to_string()];
+        let req = X509Req::from_der(&csr(&domains, &PKey::from_ec_key(generate_key()?)?)?)?;
+        let text = String::from_utf8(req.to_text()?)?;
+        assert!(text.contains("DNS:*.example.com, DNS:example.com"));
+        Ok(())
+    }
+
+    #[test]
+    fn certificates_renewed_near_expiry() -> Result<()> {
+        let key = PKey::from_ec_key(generate_key()?)?;
+        let cert = |days: u32| -> Result<Vec<u8>> {
+            let (now, expiry) = (Asn1Time::days_from_now(0)?, Asn1Time::days_from_now(days)?);
+            let mut b = X509::builder()?;
+            b.set_pubkey(&key)?;
+            b.set_not_before(&now)?;
+            b.set_not_after(&expiry)?;
+            b.sign(&key, MessageDigest::sha256())?;
+            Ok(b.build().to_pem()?)
+        };
+        assert!(!needs_renewal(&cert(90)?)?);
+        assert!(needs_renewal(&cert(10)?)?);
+        Ok(())
+    }
+}
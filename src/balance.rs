@@ -0,0 +1,210 @@
+//! Load balancing across several local destinations for one service, see
+//! `innisfree up --backend`, so a small local cluster can sit behind one
+//! public port. The service's local proxy picks a backend per connection,
+//! round-robin or by fewest open connections, skipping those failing their
+//! health checks, see [crate::service_health]. If connecting to a backend
+//! fails, the next is tried, and the failed one skipped for a while.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::HealthCheck;
+use crate::proxy::SocketOptions;
+use crate::service_health::Health;
+
+/// How long a backend is skipped after connecting to it fails.
+const FAILED_HOLD: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// How a service's local proxy picks a backend for each connection.
+pub enum Strategy {
+    /// Each backend in turn.
+    #[default]
+    RoundRobin,
+    /// The backend with the fewest connections open.
+    LeastConns,
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-conns" => Ok(Strategy::LeastConns),
+            _ => Err(anyhow!(
+                "Unknown balancing strategy '{}', expected one of: round-robin, least-conns",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+/// One of a service's destinations.
+struct Backend {
+    addr: SocketAddr,
+    /// Connections open to the backend, see [BackendConn].
+    open: AtomicUsize,
+    health: Health,
+    /// Set when connecting fails, to skip the backend until then.
+    failed_until: Mutex<Option<Instant>>,
+}
+
+impl Backend {
+    /// Whether to pick the backend: it passed its latest health check, if
+    /// any, and connecting to it hasn't failed lately.
+    fn available(&self) -> bool {
+        let failed_until = match self.failed_until.lock() {
+            Ok(f) => *f,
+            Err(e) => *e.into_inner(),
+        };
+        let failing = matches!(failed_until, Some(t) if t > Instant::now());
+        self.health.is_healthy() && !failing
+    }
+
+    fn failed(&self) {
+        let mut failed_until = match self.failed_until.lock() {
+            Ok(f) => f,
+            Err(e) => e.into_inner(),
+        };
+        *failed_until = Some(Instant::now() + FAILED_HOLD);
+    }
+}
+
+/// A connection open to a backend, counted until dropped.
+pub struct BackendConn(Arc<Backend>);
+
+impl Drop for BackendConn {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+/// Picks among a service's backends for each connection.
+pub struct Balancer {
+    backends: Vec<Arc<Backend>>,
+    strategy: Strategy,
+    /// Where round-robin picks next.
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    /// Returns a balancer across `addrs`, all assumed healthy until
+    /// checked, see [Balancer::watch].
+    pub fn new(addrs: &[SocketAddr], strategy: Strategy) -> Balancer {
+        Balancer {
+            backends: addrs
+                .iter()
+                .map(|&addr| {
+                    Arc::new(Backend {
+                        addr,
+                        open: AtomicUsize::new(0),
+                        health: Health::default(),
+                        failed_until: Mutex::new(None),
+                    })
+                })
+                .collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Checks each backend via `check` until `cancel` fires.
+    pub fn watch(&self, check: &HealthCheck, cancel: &CancellationToken) {
+        for b in &self.backends {
+            b.health.watch(check.clone(), b.addr, cancel.clone());
+        }
+    }
+
+    /// Whether any backend passed its latest health check.
+    pub fn is_healthy(&self) -> bool {
+        self.backends.iter().any(|b| b.health.is_healthy())
+    }
+
+    /// Returns the backends in the order to try them: those available first,
+    /// as the strategy picks, then the rest, as a last resort.
+    fn candidates(&self) -> Vec<Arc<Backend>> {
+        let n = self.backends.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut order: Vec<Arc<Backend>> = (0..n)
+            .map(|i| self.backends[(start + i) % n].clone())
+            .collect();
+        if self.strategy == Strategy::LeastConns {
+            // Stable, so ties go round-robin.
+            order.sort_by_key(|b| b.open.load(Ordering::Relaxed));
+        }
+        let (mut available, rest): (Vec<_>, Vec<_>) =
+            order.into_iter().partition(|b| b.available());
+        available.extend(rest);
+        available
+    }
+
+    /// Connects to a backend, trying the next if that fails. The backend
+    /// counts the connection as open until the returned [BackendConn] drops.
+    pub async fn connect(&self, sockets: &SocketOptions) -> Result<(TcpStream, BackendConn)> {
+        let mut last_err = anyhow!("no backends");
+        for b in self.candidates() {
+            b.open.fetch_add(1, Ordering::Relaxed);
+            let open = BackendConn(b.clone());
+            match sockets.connect(b.addr).await {
+                Ok(stream) => return Ok((stream, open)),
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to connect to backend {}, trying the next: {}",
+                        b.addr,
+                        e
+                    );
+                    b.failed();
+                    last_err = anyhow!("backend {}: {}", b.addr, e);
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn backends_balanced_with_failover() -> Result<()> {
+        let a = TcpListener::bind("127.0.0.1:0").await?;
+        let b = TcpListener::bind("127.0.0.1:0").await?;
+        let down = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let addrs = [a.local_addr()?, b.local_addr()?, down];
+        let sockets = SocketOptions::default();
+
+        let balancer = Balancer::new(&addrs, Strategy::RoundRobin);
+        let mut picked = vec![];
+        for _ in 0..4 {
+            let (stream, _open) = balancer.connect(&sockets).await?;
+            picked.push(stream.peer_addr()?);
+        }
+        // The failed backend is skipped, in favour of the next, then for a while.
+        assert_eq!(picked, [addrs[0], addrs[1], addrs[0], addrs[0]]);
+
+        let balancer = Balancer::new(&addrs[..2], "least-conns".parse()?);
+        let (first, held) = balancer.connect(&sockets).await?;
+        let (second, _) = balancer.connect(&sockets).await?;
+        let (third, _) = balancer.connect(&sockets).await?;
+        assert_eq!(first.peer_addr()?, addrs[0]);
+        // The first is still open
+        assert_eq!(second.peer_addr()?, addrs[1]);
+        assert_eq!(third.peer_addr()?, addrs[1]);
+        drop(held);
+        assert!("random".parse::<Strategy>().is_err());
+        Ok(())
+    }
+}
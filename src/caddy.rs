@@ -0,0 +1,71 @@
+//! Caddy as the server's proxy, in place of nginx, see `innisfree up
+//! --remote-proxy caddy`. Distros package Caddy without the layer4 app, which
+//! forwards plain TCP and UDP streams, so cloud-init downloads a build
+//! including it, then runs it as a service. Caddy obtains and renews
+//! certificates itself, so HTTPS mode needs no certbot.
+
+use anyhow::{Context, Result};
+
+/// Where the server downloads Caddy from, built with the layer4 app.
+pub const CADDY_DOWNLOAD_URL: &str =
+    "https://caddyserver.com/api/download?os=linux&arch=amd64&p=github.com%2Fmholt%2Fcaddy-l4";
+/// Path to `caddy` on the server.
+const SERVER_BINARY: &str = "/usr/local/bin/caddy";
+/// Path to the Caddyfile on the server.
+pub const CADDYFILE_PATH: &str = "/etc/caddy/Caddyfile";
+/// Directory on the server holding Caddy's logs.
+const LOG_DIR: &str = "/var/log/caddy";
+/// Name of the Caddy service on the server.
+pub const SERVICE_NAME: &str = "innisfree-caddy";
+/// Path to the Caddy service's unit on the server.
+pub const SERVER_UNIT_PATH: &str = "/etc/systemd/system/innisfree-caddy.service";
+
+/// Renders the systemd unit running Caddy with the Caddyfile. Reloading the
+/// unit applies an updated Caddyfile without dropping connections.
+pub fn server_unit() -> Result<String> {
+    let mut context = tera::Context::new();
+    context.insert("binary", SERVER_BINARY);
+    context.insert("caddyfile", CADDYFILE_PATH);
+    tera::Tera::one_off(include_str!("../files/caddy.service.j2"), &context, false)
+        .context("Failed to render Caddy unit")
+}
+
+/// Commands for cloud-init, installing Caddy on the server, then starting it.
+/// Nginx is stopped first, in case the server booted from a prebuilt image
+/// with it installed, since both listen on the same ports.
+pub fn server_runcmd() -> Vec<Vec<String>> {
+    let install = format!(
+        "curl -fsSL '{}' -o {} && chmod 755 {}",
+        CADDY_DOWNLOAD_URL, SERVER_BINARY, SERVER_BINARY
+    );
+    vec![
+        vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            "systemctl disable --now nginx || true".to_string(),
+        ],
+        vec!["sh".to_string(), "-c".to_string(), install],
+        vec!["mkdir".to_string(), "-p".to_string(), LOG_DIR.to_string()],
+        vec![
+            "systemctl".to_string(),
+            "enable".to_string(),
+            "--now".to_string(),
+            SERVICE_NAME.to_string(),
+        ],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_runs_caddyfile() -> Result<()> {
+        let unit = server_unit()?;
+        assert!(unit.contains("ExecStart=/usr/local/bin/caddy run --config /etc/caddy/Caddyfile"));
+        assert!(unit.contains("ExecReload=/usr/local/bin/caddy reload"));
+        let runcmd = server_runcmd();
+        assert_eq!(runcmd.last().unwrap()[3], SERVICE_NAME);
+        Ok(())
+    }
+}
@@ -1,11 +1,15 @@
 //! Storage logic, to persist configuration of remote tunnels locally.
 //! Includes methods for creating and destroying configuration directories.
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 // Define public exports
 const DEFAULT_PORT: i32 = 80;
@@ -15,37 +19,479 @@ const DEFAULT_LOCAL_PORT: i32 = 80;
 /// The port will be reused to listen locally and forward remotely.
 // Will be passed around to nginx and wireguard configuration logic
 // to build out the tunnel.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ServicePort {
+    /// Name of the service, e.g. `web`, shown in logs and `status`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     /// Port number for the public service.
     pub port: i32,
     /// Port number for the local service, to which traffic is forwarded.
     pub local_port: i32,
     /// Protocol, one of TCP or UDP.
-    pub protocol: String,
+    pub protocol: Protocol,
+    /// Whether to prepend a PROXY protocol header to connections,
+    /// so the local service can see the client's address.
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Source ranges allowed to reach the public port. If any are set,
+    /// connections from elsewhere are dropped on the server.
+    #[serde(default)]
+    pub allow: Vec<ipnet::IpNet>,
+    /// Source ranges dropped on the server, even if also allowed.
+    #[serde(default)]
+    pub deny: Vec<ipnet::IpNet>,
+    /// Most connections the local proxy passes to the service at once.
+    /// Others wait to be accepted until one closes.
+    #[serde(default)]
+    pub max_conns: Option<u32>,
+    /// How the local proxy checks that the service is up, before passing
+    /// connections to it, see [crate::service_health].
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    /// Destinations the local proxy balances connections across, in place
+    /// of the local port on the dest IP, see [crate::balance].
+    #[serde(default)]
+    pub backends: Vec<SocketAddr>,
+}
+
+/// Transport protocol of a service.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(try_from = "String")]
+pub enum Protocol {
+    /// TCP, the default.
+    #[default]
+    #[serde(rename = "TCP")]
+    Tcp,
+    /// UDP.
+    #[serde(rename = "UDP")]
+    Udp,
+}
+
+impl Protocol {
+    /// Returns the protocol's name in lowercase, as used by nftables and
+    /// cloud firewalls, e.g. `tcp`.
+    pub fn lowercase(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    /// Formats the protocol in uppercase, e.g. `TCP`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.lowercase().to_uppercase())
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    /// Parses `TCP` or `UDP`, in any case.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "TCP" => Ok(Protocol::Tcp),
+            "UDP" => Ok(Protocol::Udp),
+            _ => Err(anyhow::anyhow!(
+                "Unknown protocol '{}', expected one of: TCP, UDP",
+                s
+            )),
+        }
+    }
+}
+
+/// Saved states may spell the protocol in any case, e.g. `tcp`.
+impl TryFrom<String> for Protocol {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Version of the PROXY protocol header to send to a local service.
+/// The server's nginx always sends v1, so other modes are
+/// translated by [crate::proxy::proxy_protocol_handler].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    /// Text header, passed through from nginx untouched.
+    V1,
+    /// Binary header, translated from nginx's v1 header.
+    V2,
+    /// No header: it's removed before reaching the service,
+    /// and the client address is only logged.
+    Strip,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "v1" => Ok(ProxyProtocol::V1),
+            "v2" => Ok(ProxyProtocol::V2),
+            "strip" => Ok(ProxyProtocol::Strip),
+            _ => Err(anyhow::anyhow!(
+                "Unknown PROXY protocol mode '{}', expected one of: v1, v2, strip",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ProxyProtocol {
+    /// Formats the mode as parsed, e.g. `v2`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            ProxyProtocol::V1 => "v1",
+            ProxyProtocol::V2 => "v2",
+            ProxyProtocol::Strip => "strip",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+/// How to check that a local service is up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheck {
+    /// The service accepts TCP connections.
+    Tcp,
+    /// The service answers an HTTP GET for the path with a 2xx or 3xx status.
+    Http {
+        /// Path requested, e.g. `/healthz`.
+        path: String,
+    },
+}
+
+impl FromStr for HealthCheck {
+    type Err = anyhow::Error;
+
+    /// Parses `tcp`, `http`, or `http:<PATH>`, e.g. `http:/healthz`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s.eq_ignore_ascii_case("tcp") => Ok(HealthCheck::Tcp),
+            None if s.eq_ignore_ascii_case("http") => Ok(HealthCheck::Http {
+                path: "/".to_string(),
+            }),
+            Some((kind, path)) if kind.eq_ignore_ascii_case("http") && path.starts_with('/') => {
+                // Sent as-is in the request line.
+                if path.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                    return Err(anyhow::anyhow!("Invalid health check path '{}'", path));
+                }
+                Ok(HealthCheck::Http {
+                    path: path.to_string(),
+                })
+            }
+            _ => Err(anyhow::anyhow!(
+                "Unknown health check '{}', expected one of: tcp, http, http:<PATH>",
+                s
+            )),
+        }
+    }
+}
+
+/// Enables the PROXY protocol on services, given a comma-separated spec
+/// of public ports, each with an optional mode: `<PORT>[=<MODE>]`,
+/// e.g. `443,22=strip`. The mode defaults to [ProxyProtocol::V2].
+pub fn apply_proxy_protocol(services: &mut [ServicePort], spec: &str) -> Result<()> {
+    for entry in spec.split(',').filter(|e| !e.is_empty()) {
+        let (port, mode) = match entry.split_once('=') {
+            Some((port, mode)) => (port, mode.parse()?),
+            None => (entry, ProxyProtocol::V2),
+        };
+        let port: i32 = port.parse()?;
+        let service = services
+            .iter_mut()
+            .find(|s| s.port == port)
+            .ok_or_else(|| anyhow::anyhow!("No service on port {} for PROXY protocol", port))?;
+        if service.protocol != Protocol::Tcp {
+            return Err(anyhow::anyhow!(
+                "PROXY protocol is only supported for TCP services, not {}/{}",
+                service.port,
+                service.protocol
+            ));
+        }
+        service.proxy_protocol = Some(mode);
+    }
+    Ok(())
+}
+
+/// Restricts the sources allowed to reach services, given a comma-separated
+/// spec of public ports, each with a source range: `<PORT>=<CIDR>`, e.g.
+/// `8443=203.0.113.0/24,8443=2001:db8::/32`. Ranges add up per port.
+pub fn apply_allow_cidrs(services: &mut [ServicePort], spec: &str) -> Result<()> {
+    apply_cidrs(services, spec, |s| &mut s.allow)
+}
+
+/// Blocks sources from reaching services, given a spec as for [apply_allow_cidrs].
+pub fn apply_deny_cidrs(services: &mut [ServicePort], spec: &str) -> Result<()> {
+    apply_cidrs(services, spec, |s| &mut s.deny)
+}
+
+/// Adds the source ranges in `spec` to the list selected by `list` on each service.
+fn apply_cidrs(
+    services: &mut [ServicePort],
+    spec: &str,
+    list: fn(&mut ServicePort) -> &mut Vec<ipnet::IpNet>,
+) -> Result<()> {
+    for entry in spec.split(',').filter(|e| !e.is_empty()) {
+        let (port, cidr) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <PORT>=<CIDR>, got '{}'", entry))?;
+        let port: i32 = port.parse()?;
+        // A bare address is a range of one.
+        let cidr = match cidr.parse::<ipnet::IpNet>() {
+            Ok(net) => net.trunc(),
+            Err(_) => ipnet::IpNet::from(
+                cidr.parse::<IpAddr>()
+                    .with_context(|| format!("Invalid source range '{}'", cidr))?,
+            ),
+        };
+        let mut found = false;
+        for service in services.iter_mut().filter(|s| s.port == port) {
+            list(service).push(cidr);
+            found = true;
+        }
+        if !found {
+            return Err(anyhow::anyhow!(
+                "No service on port {} for source range",
+                port
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Caps the connections open at once to services, given a comma-separated
+/// spec of public ports, each with a count: `<PORT>=<N>`, e.g. `8443=20`.
+pub fn apply_max_conns(services: &mut [ServicePort], spec: &str) -> Result<()> {
+    for entry in spec.split(',').filter(|e| !e.is_empty()) {
+        let (port, max) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <PORT>=<N>, got '{}'", entry))?;
+        let port: i32 = port.parse()?;
+        let max: u32 = max
+            .parse()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| anyhow::anyhow!("Invalid connection limit '{}'", max))?;
+        let service = services
+            .iter_mut()
+            .find(|s| s.port == port)
+            .ok_or_else(|| anyhow::anyhow!("No service on port {} for connection limit", port))?;
+        service.max_conns = Some(max);
+    }
+    Ok(())
+}
+
+/// Checks that services are up, given a comma-separated spec of public
+/// ports, each with a check: `<PORT>=<CHECK>`, e.g. `8443=tcp,80=http:/healthz`.
+pub fn apply_health_checks(services: &mut [ServicePort], spec: &str) -> Result<()> {
+    for entry in spec.split(',').filter(|e| !e.is_empty()) {
+        let (port, check) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <PORT>=<CHECK>, got '{}'", entry))?;
+        let port: i32 = port.parse()?;
+        let check: HealthCheck = check.parse()?;
+        let service = services
+            .iter_mut()
+            .find(|s| s.port == port && s.protocol == Protocol::Tcp)
+            .ok_or_else(|| anyhow::anyhow!("No TCP service on port {} for health check", port))?;
+        service.health_check = Some(check);
+    }
+    Ok(())
+}
+
+/// Balances services across several destinations, given a comma-separated
+/// spec of public ports, each with a destination: `<PORT>=<DEST>`, where
+/// the destination is a port on `dest_ip`, or an address, e.g.
+/// `80=8001,80=8002,80=192.168.1.20:8000`. Destinations add up per port.
+pub fn apply_backends(services: &mut [ServicePort], spec: &str, dest_ip: IpAddr) -> Result<()> {
+    for entry in spec.split(',').filter(|e| !e.is_empty()) {
+        let (port, dest) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <PORT>=<DEST>, got '{}'", entry))?;
+        let port: i32 = port.parse()?;
+        let dest = match dest.parse::<u16>() {
+            Ok(p) => SocketAddr::new(dest_ip, p),
+            Err(_) => dest
+                .parse()
+                .with_context(|| format!("Invalid backend '{}'", dest))?,
+        };
+        let service = services
+            .iter_mut()
+            .find(|s| s.port == port && s.protocol == Protocol::Tcp)
+            .ok_or_else(|| anyhow::anyhow!("No TCP service on port {} for backend", port))?;
+        service.backends.push(dest);
+    }
+    Ok(())
+}
+
+/// Returns the services to forward, given the settings for `up` as env vars,
+/// e.g. from [TunnelFile::env_vars], with their options applied, balancing
+/// backends given as ports on `dest_ip`. Fails if there are none.
+pub fn services_from_vars(vars: &[(&str, String)], dest_ip: IpAddr) -> Result<Vec<ServicePort>> {
+    let get = |k: &str| vars.iter().find(|(n, _)| *n == k).map(|(_, v)| v.as_str());
+    let mut services = ServicePort::from_str_multi(get("INNISFREE_PORTS").unwrap_or(""))?;
+    if services.is_empty() {
+        return Err(anyhow::anyhow!("No ports or services given"));
+    }
+    if let Some(spec) = get("INNISFREE_PROXY_PROTOCOL") {
+        apply_proxy_protocol(&mut services, spec)?;
+    }
+    if let Some(spec) = get("INNISFREE_ALLOW_CIDR") {
+        apply_allow_cidrs(&mut services, spec)?;
+    }
+    if let Some(spec) = get("INNISFREE_DENY_CIDR") {
+        apply_deny_cidrs(&mut services, spec)?;
+    }
+    if let Some(spec) = get("INNISFREE_MAX_CONNS") {
+        apply_max_conns(&mut services, spec)?;
+    }
+    if let Some(spec) = get("INNISFREE_HEALTH_CHECK") {
+        apply_health_checks(&mut services, spec)?;
+    }
+    if let Some(spec) = get("INNISFREE_BACKEND") {
+        apply_backends(&mut services, spec, dest_ip)?;
+    }
+    Ok(services)
+}
+
+/// Returns `base` with services found at runtime added, e.g. for labelled
+/// Docker containers, see [crate::docker], given per source, e.g.
+/// `container 'web'`. These replace any in `base` on the same public port.
+/// Sources that can't be forwarded, e.g. with a port an earlier one already
+/// uses, are skipped, with a warning.
+pub fn with_discovered(
+    base: &[ServicePort],
+    found: Vec<(String, Result<Vec<ServicePort>>)>,
+) -> Vec<ServicePort> {
+    let mut discovered: Vec<ServicePort> = vec![];
+    for (source, services) in found {
+        let services = match services {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::warn!("Not forwarding {}: {:#}", source, e);
+                continue;
+            }
+        };
+        if let Some(s) = services
+            .iter()
+            .find(|s| discovered.iter().any(|d| d.same_port(s)))
+        {
+            tracing::warn!(
+                "Not forwarding {}: port {} is already forwarded",
+                source,
+                s.port
+            );
+            continue;
+        }
+        discovered.extend(services);
+    }
+    let mut services: Vec<ServicePort> = base
+        .iter()
+        .filter(|s| !discovered.iter().any(|d| d.same_port(s)))
+        .cloned()
+        .collect();
+    services.extend(discovered);
+    services
 }
 
 impl ServicePort {
     /// Parse a comma-separated string of ServicePort specs,
-    /// e.g. `8080/TCP,4444/UDP`.
+    /// e.g. `8080/TCP,4444/UDP`. Fails on the first malformed spec,
+    /// rather than forwarding the rest without it.
     pub fn from_str_multi(port_spec: &str) -> Result<Vec<ServicePort>> {
-        Ok(port_spec
+        let services = port_spec
             .split(',')
-            .flat_map(ServicePort::try_from)
-            .collect())
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| ServicePort::try_from(s).with_context(|| format!("Invalid port spec '{}'", s)))
+            .collect::<Result<Vec<ServicePort>>>()?;
+        for (i, s) in services.iter().enumerate() {
+            if let Some(name) = &s.name {
+                if services[..i].iter().any(|o| o.name.as_ref() == Some(name)) {
+                    return Err(anyhow::anyhow!("Service name '{}' is used twice", name));
+                }
+            }
+        }
+        Ok(services)
+    }
+
+    /// Whether both services share a public port and protocol, i.e. would clash.
+    pub fn same_port(&self, other: &ServicePort) -> bool {
+        self.port == other.port && self.protocol == other.protocol
+    }
+
+    /// Whether this is the standard HTTPS service, i.e. 443/TCP,
+    /// for which TLS can be terminated rather than passed through.
+    pub fn is_https(&self) -> bool {
+        self.port == 443 && self.protocol == Protocol::Tcp
+    }
+}
+
+/// Formats the service by its public port and protocol, and its name,
+/// if any, e.g. `443/TCP (web)`.
+impl fmt::Display for ServicePort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.port, self.protocol)?;
+        match &self.name {
+            Some(name) => write!(f, " ({})", name),
+            None => Ok(()),
+        }
     }
 }
 
 impl Default for ServicePort {
     fn default() -> Self {
         ServicePort {
+            name: None,
             port: DEFAULT_PORT,
             local_port: DEFAULT_LOCAL_PORT,
-            protocol: "TCP".to_string(),
+            protocol: Protocol::Tcp,
+            proxy_protocol: None,
+            allow: vec![],
+            deny: vec![],
+            max_conns: None,
+            health_check: None,
+            backends: vec![],
         }
     }
 }
 
+/// Parses a port number, from 1 to 65535, e.g. from a spec, or, as a JSON
+/// number, from a container's or Service's description.
+pub(crate) fn parse_port(s: &str) -> Result<i32> {
+    match s.parse::<u16>() {
+        Ok(0) | Err(_) => Err(anyhow::anyhow!(
+            "Invalid port '{}', expected a number from 1 to 65535",
+            s
+        )),
+        Ok(p) => Ok(p.into()),
+    }
+}
+
+/// Checks a service's name, which shows up in the server's config files,
+/// so is limited to letters, digits, `-`, and `_`.
+fn parse_service_name(s: &str) -> Result<String> {
+    if s.is_empty()
+        || !s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow::anyhow!(
+            "Invalid service name '{}', expected letters, digits, '-', or '_'",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
 /// We implement `TryFrom<&str>` so we can parse CLI args.
 impl TryFrom<&str> for ServicePort {
     type Error = anyhow::Error;
@@ -55,44 +501,139 @@ impl TryFrom<&str> for ServicePort {
     ///   * `80/TCP`
     ///   * `80`
     ///   * `80:80`
-    ///   * `88888:9999`
+    ///   * `8888:9999`
+    ///   * `web=443:8443/TCP`
     ///
     /// In the format `8888:9999`, `8888` remote port on the public ingress,
     /// and `9999` is the local port of the service to forward traffic to.
+    /// An optional `<NAME>=` prefix names the service.
     fn try_from(port_spec: &str) -> Result<Self> {
         let mut sp = ServicePort::default();
+        // Handle optional name
+        let port_spec = match port_spec.split_once('=') {
+            Some((name, spec)) => {
+                sp.name = Some(parse_service_name(name)?);
+                spec
+            }
+            None => port_spec,
+        };
         // Handle optional protocol spec
-        let port_and_proto_spec: Vec<String> =
-            port_spec.split('/').map(|x| x.to_string()).collect();
-        sp.protocol = match port_and_proto_spec.get(1) {
-            Some(p) => p.to_string(),
-            None => String::from("TCP"),
+        let (port_spec, protocol) = match port_spec.split_once('/') {
+            Some((ports, protocol)) => (ports, protocol.parse()?),
+            None => (port_spec, Protocol::Tcp),
         };
+        sp.protocol = protocol;
 
         // Handle port spec, with optional local/remote distinction
-        let port_spec = &port_and_proto_spec[0];
-        let port_spec_parts: Vec<String> = port_spec.split(':').map(|x| x.to_string()).collect();
-        sp.port = port_spec_parts[0].parse()?;
-        sp.local_port = match port_spec_parts.get(1) {
-            Some(p) => p.parse()?,
-            None => sp.port,
+        let (port, local_port) = match port_spec.split_once(':') {
+            Some((port, local_port)) => (port, local_port),
+            None => (port_spec, port_spec),
         };
+        sp.port = parse_port(port)?;
+        sp.local_port = parse_port(local_port)?;
         Ok(sp)
     }
 }
 
-/// Create local config dir, e.g. ~/.config/innisfree/,
-/// for storing state of active tunnels.
-pub fn make_config_dir(service_name: &str) -> Result<PathBuf> {
-    let config_dir = home::home_dir()
+/// Routes connections to a local port based on the hostname requested,
+/// via SNI for TLS or the Host header for HTTP. Lets several services
+/// share a single public port.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostRoute {
+    /// Hostname requested by the client, e.g. `app.example.com`.
+    /// Wildcards such as `*.example.com` are supported.
+    pub hostname: String,
+    /// Port number for the local service, to which traffic is forwarded.
+    pub local_port: i32,
+}
+
+impl HostRoute {
+    /// Returns a [ServicePort] for the route's local port, so it can be
+    /// permitted through the local firewall and proxied like a service.
+    pub fn service(&self) -> ServicePort {
+        ServicePort {
+            name: None,
+            port: self.local_port,
+            local_port: self.local_port,
+            protocol: Protocol::Tcp,
+            proxy_protocol: None,
+            allow: vec![],
+            deny: vec![],
+            max_conns: None,
+            health_check: None,
+            backends: vec![],
+        }
+    }
+}
+
+/// We implement `TryFrom<&str>` so we can parse CLI args,
+/// in the format `<HOSTNAME>=<LOCAL_PORT>`, e.g. `app.example.com=8443`.
+impl TryFrom<&str> for HostRoute {
+    type Error = anyhow::Error;
+
+    fn try_from(route_spec: &str) -> Result<Self> {
+        let (hostname, port) = route_spec
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <HOSTNAME>=<PORT>, got '{}'", route_spec))?;
+        // The hostname is interpolated into the nginx config, so be strict.
+        if hostname.is_empty()
+            || !hostname
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-.*".contains(c))
+        {
+            return Err(anyhow::anyhow!("Invalid hostname '{}'", hostname));
+        }
+        let local_port: u16 = port.parse()?;
+        Ok(HostRoute {
+            hostname: hostname.to_lowercase(),
+            local_port: local_port.into(),
+        })
+    }
+}
+
+/// Path to local config dir, e.g. ~/.config/innisfree/,
+/// for storing state of active tunnels. Does not create it.
+fn config_dir_path(service_name: &str) -> Result<PathBuf> {
+    Ok(home::home_dir()
         .ok_or(anyhow::anyhow!("could not find home directory"))?
         .join(".config")
         .join("innisfree")
-        .join(service_name);
+        .join(service_name))
+}
+
+/// Create local config dir, e.g. ~/.config/innisfree/,
+/// for storing state of active tunnels.
+pub fn make_config_dir(service_name: &str) -> Result<PathBuf> {
+    let config_dir = config_dir_path(service_name)?;
     std::fs::create_dir_all(&config_dir)?;
     Ok(config_dir)
 }
 
+/// Checks whether a local config dir exists for the service,
+/// i.e. whether a tunnel by that name is known on this machine.
+pub fn config_dir_exists(service_name: &str) -> Result<bool> {
+    Ok(config_dir_path(service_name)?.is_dir())
+}
+
+/// Lists the names of all tunnels with a local config dir, sorted.
+/// Hidden dirs, e.g. for reserved IPs, hold shared records rather than tunnels.
+pub fn list_config_dirs() -> Result<Vec<String>> {
+    let parent = config_dir_path("")?;
+    if !parent.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut names = vec![];
+    for entry in std::fs::read_dir(parent)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_dir() && !name.starts_with('.') {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
 /// Remove config dir and all contents.
 /// Will render active tunnels unconfigurable,
 /// and subject to manual cleanup.
@@ -117,15 +658,316 @@ pub fn clean_name(name: &str) -> String {
     result
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+/// A service in a [TunnelFile], either as a spec string, e.g. `443:8443/TCP`,
+/// as for `up --ports`, or as a table.
+pub enum PortEntry {
+    /// Spec for the service, see [ServicePort].
+    Spec(String),
+    /// Service given field by field.
+    Table {
+        /// Port number for the public service.
+        port: i32,
+        /// Port number for the local service. Defaults to the public port.
+        local_port: Option<i32>,
+        /// Protocol, one of TCP or UDP. Defaults to TCP.
+        protocol: Option<Protocol>,
+    },
+}
+
+impl PortEntry {
+    /// Returns the entry as a spec string, as accepted by [ServicePort::try_from].
+    fn spec(&self) -> String {
+        match self {
+            PortEntry::Spec(s) => s.clone(),
+            PortEntry::Table {
+                port,
+                local_port,
+                protocol,
+            } => format!(
+                "{}:{}/{}",
+                port,
+                local_port.unwrap_or(*port),
+                protocol.unwrap_or_default()
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+/// Whether a [ServiceEntry] gets PROXY protocol headers: `true` for the
+/// default mode, or the mode, as for `up --proxy-protocol`.
+pub enum ProxyProtocolSetting {
+    /// Enabled with [ProxyProtocol::V2], or disabled.
+    Enabled(bool),
+    /// Enabled with the given mode, e.g. `strip`.
+    Mode(ProxyProtocol),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A named service in a [TunnelFile], with its options, e.g.
+/// `web = { port = 443, local = 8443, proxy_protocol = true }`.
+/// Options mirror the `up` options of the same name, for this service.
+pub struct ServiceEntry {
+    /// Port number for the public service.
+    pub port: i32,
+    /// Port number for the local service. Defaults to the public port.
+    pub local: Option<i32>,
+    /// Protocol, one of TCP or UDP. Defaults to TCP.
+    pub proto: Option<Protocol>,
+    /// As for `up --proxy-protocol`.
+    pub proxy_protocol: Option<ProxyProtocolSetting>,
+    /// As for `up --allow-cidr`, one range per entry.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// As for `up --deny-cidr`, one range per entry.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// As for `up --max-conns`.
+    pub max_conns: Option<u32>,
+    /// As for `up --health-check`, e.g. `http:/healthz`.
+    pub health_check: Option<String>,
+    /// As for `up --backend`, one destination per entry.
+    #[serde(default)]
+    pub backends: Vec<String>,
+}
+
+impl ServiceEntry {
+    /// Returns the entry as a spec string named `name`, as accepted by
+    /// [ServicePort::try_from], e.g. `web=443:8443/TCP`.
+    fn spec(&self, name: &str) -> String {
+        format!(
+            "{}={}:{}/{}",
+            name,
+            self.port,
+            self.local.unwrap_or(self.port),
+            self.proto.unwrap_or_default()
+        )
+    }
+
+    /// Returns the options as entries of the `up` options' specs, keyed by
+    /// public port, e.g. `("INNISFREE_MAX_CONNS", "443=20")`.
+    fn options(&self) -> Vec<(&'static str, String)> {
+        let mut entries = vec![];
+        let mut add = |k: &'static str, v: &dyn fmt::Display| {
+            entries.push((k, format!("{}={}", self.port, v)));
+        };
+        match &self.proxy_protocol {
+            Some(ProxyProtocolSetting::Enabled(true)) => add("INNISFREE_PROXY_PROTOCOL", &"v2"),
+            Some(ProxyProtocolSetting::Mode(mode)) => add("INNISFREE_PROXY_PROTOCOL", mode),
+            Some(ProxyProtocolSetting::Enabled(false)) | None => {}
+        }
+        for cidr in &self.allow {
+            add("INNISFREE_ALLOW_CIDR", cidr);
+        }
+        for cidr in &self.deny {
+            add("INNISFREE_DENY_CIDR", cidr);
+        }
+        if let Some(n) = self.max_conns {
+            add("INNISFREE_MAX_CONNS", &n);
+        }
+        if let Some(check) = &self.health_check {
+            add("INNISFREE_HEALTH_CHECK", check);
+        }
+        for dest in &self.backends {
+            add("INNISFREE_BACKEND", dest);
+        }
+        entries
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+/// Setting given either as a number, or a keyword such as `auto`.
+pub enum NumberOrKeyword {
+    /// Numeric value, e.g. an MTU of `1380`.
+    Number(u32),
+    /// Keyword, e.g. `auto` or `random`.
+    Keyword(String),
+}
+
+impl std::fmt::Display for NumberOrKeyword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NumberOrKeyword::Number(n) => write!(f, "{}", n),
+            NumberOrKeyword::Keyword(k) => write!(f, "{}", k),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The `[wireguard]` table of a [TunnelFile].
+pub struct WireguardSection {
+    /// As for `up --wg-mtu`, e.g. `1380` or `auto`.
+    pub mtu: Option<NumberOrKeyword>,
+    /// As for `up --wg-subnet`, e.g. `10.60.0.0/24`.
+    pub subnet: Option<String>,
+    /// As for `up --wg-port`, e.g. `51821` or `random`.
+    pub port: Option<NumberOrKeyword>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// Declarative description of a tunnel, read via `innisfree up --file`,
+/// so complex setups can be kept under version control. Each setting
+/// mirrors the `up` option of the same name. Options given on the
+/// command line, or via env vars, take precedence.
+pub struct TunnelFile {
+    /// As for `up --name`.
+    pub name: Option<String>,
+    /// As for `up --provider`.
+    pub provider: Option<String>,
+    /// As for `up --region`.
+    pub region: Option<String>,
+    /// As for `up --size`.
+    pub size: Option<String>,
+    /// As for `up --image`.
+    pub image: Option<String>,
+    /// As for `up --vpc-uuid`.
+    pub vpc_uuid: Option<String>,
+    /// As for `up --do-project`.
+    pub do_project: Option<String>,
+    /// As for `--profile`.
+    pub profile: Option<String>,
+    /// As for `--token-file`. Relative paths are resolved from the file's dir.
+    pub token_file: Option<PathBuf>,
+    /// As for `--token-cmd`.
+    pub token_cmd: Option<String>,
+    /// Services to forward, as for `up --ports`, one entry per service.
+    #[serde(default)]
+    pub ports: Vec<PortEntry>,
+    /// Named services to forward, with their options, alongside `ports`.
+    #[serde(default)]
+    pub services: BTreeMap<String, ServiceEntry>,
+    /// As for `up --dest-ip`.
+    pub dest_ip: Option<IpAddr>,
+    /// As for `up --floating-ip`.
+    pub floating_ip: Option<IpAddr>,
+    /// As for `up --reserve-ip`.
+    pub reserve_ip: Option<bool>,
+    /// As for `up --self-heal`.
+    pub self_heal: Option<bool>,
+    /// As for `up --idle-timeout`, e.g. `2h`.
+    pub idle_timeout: Option<String>,
+    /// As for `up --webhook`, one endpoint per entry, e.g. `slack=https://hooks.slack.com/...`.
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+    /// As for `up --https`.
+    pub https: Option<String>,
+    /// As for `up --sni`, one route per entry, e.g. `app.example.com=8443`.
+    #[serde(default)]
+    pub sni: Vec<String>,
+    /// As for `up --http-vhost`, one route per entry, e.g. `app.example.com=8001`.
+    #[serde(default)]
+    pub http_vhost: Vec<String>,
+    /// As for `up --proxy-protocol`.
+    pub proxy_protocol: Option<String>,
+    /// As for `up --dnat`.
+    pub dnat: Option<bool>,
+    /// As for `up --tls-cert`. Relative paths are resolved from the file's dir.
+    pub tls_cert: Option<PathBuf>,
+    /// As for `up --tls-key`. Relative paths are resolved from the file's dir.
+    pub tls_key: Option<PathBuf>,
+    /// Wireguard settings.
+    #[serde(default)]
+    pub wireguard: WireguardSection,
+}
+
+impl TunnelFile {
+    /// Reads a tunnel description from a TOML file.
+    pub fn load(path: &Path) -> Result<TunnelFile> {
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&s).with_context(|| format!("Invalid tunnel file {}", path.display()))
+    }
+
+    /// Returns the settings as the env vars read by `up`, e.g. `INNISFREE_PORTS`,
+    /// resolving relative paths from `base_dir`.
+    pub fn env_vars(&self, base_dir: &Path) -> Vec<(&'static str, String)> {
+        let mut vars = vec![];
+        let mut add = |k: &'static str, v: Option<String>| {
+            if let Some(v) = v {
+                vars.push((k, v));
+            }
+        };
+        let join = |entries: &[String]| (!entries.is_empty()).then(|| entries.join(","));
+        let path = |p: &Option<PathBuf>| p.as_ref().map(|p| base_dir.join(p).display().to_string());
+        let mut ports: Vec<String> = self.ports.iter().map(|p| p.spec()).collect();
+        ports.extend(self.services.iter().map(|(name, s)| s.spec(name)));
+        // Each service's options, merged into those for all of them.
+        let mut options: BTreeMap<&'static str, Vec<String>> = BTreeMap::new();
+        if let Some(spec) = &self.proxy_protocol {
+            options
+                .entry("INNISFREE_PROXY_PROTOCOL")
+                .or_default()
+                .push(spec.clone());
+        }
+        for (k, v) in self.services.values().flat_map(|s| s.options()) {
+            options.entry(k).or_default().push(v);
+        }
+
+        add("INNISFREE_NAME", self.name.clone());
+        add("INNISFREE_PROVIDER", self.provider.clone());
+        add("INNISFREE_REGION", self.region.clone());
+        add("INNISFREE_SIZE", self.size.clone());
+        add("INNISFREE_IMAGE", self.image.clone());
+        add("INNISFREE_VPC_UUID", self.vpc_uuid.clone());
+        add("INNISFREE_DO_PROJECT", self.do_project.clone());
+        add("INNISFREE_PROFILE", self.profile.clone());
+        add("INNISFREE_TOKEN_FILE", path(&self.token_file));
+        add("INNISFREE_TOKEN_CMD", self.token_cmd.clone());
+        add("INNISFREE_PORTS", join(&ports));
+        add("INNISFREE_DEST_IP", self.dest_ip.map(|ip| ip.to_string()));
+        add(
+            "INNISFREE_FLOATING_IP",
+            self.floating_ip.map(|ip| ip.to_string()),
+        );
+        add(
+            "INNISFREE_RESERVE_IP",
+            self.reserve_ip.map(|b| b.to_string()),
+        );
+        add("INNISFREE_SELF_HEAL", self.self_heal.map(|b| b.to_string()));
+        add("INNISFREE_IDLE_TIMEOUT", self.idle_timeout.clone());
+        add("INNISFREE_WEBHOOKS", join(&self.webhooks));
+        add("INNISFREE_HTTPS", self.https.clone());
+        add("INNISFREE_SNI", join(&self.sni));
+        add("INNISFREE_HTTP_VHOST", join(&self.http_vhost));
+        add("INNISFREE_DNAT", self.dnat.map(|b| b.to_string()));
+        for (k, v) in &options {
+            add(k, join(v));
+        }
+        add("INNISFREE_TLS_CERT", path(&self.tls_cert));
+        add("INNISFREE_TLS_KEY", path(&self.tls_key));
+        let wg = &self.wireguard;
+        add("INNISFREE_WG_MTU", wg.mtu.as_ref().map(|m| m.to_string()));
+        add("INNISFREE_WG_SUBNET", wg.subnet.clone());
+        add("INNISFREE_WG_PORT", wg.port.as_ref().map(|p| p.to_string()));
+        vars
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn services_clash_by_port_and_protocol() -> Result<()> {
+        let tcp = ServicePort::try_from("8080:8000/TCP")?;
+        assert!(tcp.same_port(&ServicePort::try_from("8080/tcp")?));
+        assert!(!tcp.same_port(&ServicePort::try_from("8080/UDP")?));
+        assert!(!tcp.same_port(&ServicePort::try_from("8000/TCP")?));
+        Ok(())
+    }
+
     #[test]
     fn service_port_manual_creation() {
         let s = ServicePort::default();
         assert!(s.port == 80);
-        assert!(s.protocol == "TCP");
+        assert!(s.protocol == Protocol::Tcp);
     }
 
     #[test]
@@ -135,11 +977,11 @@ mod tests {
         assert!(services.len() == 2);
         let s1 = &services[0];
         assert!(s1.port == 80);
-        assert!(s1.protocol == "TCP");
+        assert!(s1.protocol == Protocol::Tcp);
 
         let s2 = &services[1];
         assert!(s2.port == 443);
-        assert!(s2.protocol == "TCP");
+        assert!(s2.protocol == Protocol::Tcp);
         Ok(())
     }
 
@@ -149,7 +991,7 @@ mod tests {
         let s = ServicePort::try_from(port_spec)?;
         assert!(s.port == 80);
         assert!(s.local_port == 30080);
-        assert!(s.protocol == "TCP");
+        assert!(s.protocol == Protocol::Tcp);
         Ok(())
     }
     #[test]
@@ -160,14 +1002,141 @@ mod tests {
         let s1 = &services[0];
         assert!(s1.port == 80);
         assert!(s1.local_port == 30080);
-        assert!(s1.protocol == "TCP");
+        assert!(s1.protocol == Protocol::Tcp);
 
         let s2 = &services[1];
         assert!(s2.port == 443);
         assert!(s2.local_port == 30443);
-        assert!(s2.protocol == "TCP");
+        assert!(s2.protocol == Protocol::Tcp);
         Ok(())
     }
+    #[test]
+    fn malformed_port_specs_rejected() -> Result<()> {
+        let e = ServicePort::from_str_multi("80/TCP,443/TPC").unwrap_err();
+        assert_eq!(e.to_string(), "Invalid port spec '443/TPC'");
+        for spec in [
+            "0",
+            "65536",
+            "80:0",
+            "80:70000",
+            "-1",
+            "80:",
+            "http",
+            "80:90:100",
+        ] {
+            assert!(ServicePort::try_from(spec).is_err(), "{}", spec);
+        }
+        let services = ServicePort::from_str_multi("65535:1/udp, 22,")?;
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[0].protocol, Protocol::Udp);
+        assert_eq!(services[0].local_port, 1);
+        assert_eq!(services[1], ServicePort::try_from("22/TCP")?);
+
+        // Saved in uppercase, but read in any case
+        assert_eq!(serde_json::to_string(&Protocol::Udp)?, "\"UDP\"");
+        assert_eq!(serde_json::from_str::<Protocol>("\"tcp\"")?, Protocol::Tcp);
+        assert!(serde_json::from_str::<Protocol>("\"SCTP\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_named_ports() -> Result<()> {
+        let services = ServicePort::from_str_multi("web=443:8443/TCP,dns=53/UDP,22")?;
+        assert_eq!(services[0].name.as_deref(), Some("web"));
+        assert_eq!(services[0].local_port, 8443);
+        assert_eq!(services[0].to_string(), "443/TCP (web)");
+        assert_eq!(services[1].protocol, Protocol::Udp);
+        assert_eq!(services[2].to_string(), "22/TCP");
+        assert!(ServicePort::from_str_multi("web=443,web=80").is_err());
+        assert!(ServicePort::try_from("=443").is_err());
+        assert!(ServicePort::try_from("my web=443").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn enable_proxy_protocol() -> Result<()> {
+        let mut services = ServicePort::from_str_multi("443/TCP,22/TCP,53/UDP,8080/TCP")?;
+        apply_proxy_protocol(&mut services, "443,22=strip,8080=V1")?;
+        assert_eq!(services[0].proxy_protocol, Some(ProxyProtocol::V2));
+        assert_eq!(services[1].proxy_protocol, Some(ProxyProtocol::Strip));
+        assert_eq!(services[2].proxy_protocol, None);
+        assert_eq!(services[3].proxy_protocol, Some(ProxyProtocol::V1));
+        assert!(apply_proxy_protocol(&mut services, "53").is_err());
+        assert!(apply_proxy_protocol(&mut services, "9999").is_err());
+        assert!(apply_proxy_protocol(&mut services, "443=v3").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn restrict_sources() -> Result<()> {
+        let mut services = ServicePort::from_str_multi("443/TCP,8443/TCP,53/UDP,53/TCP")?;
+        apply_allow_cidrs(&mut services, "8443=203.0.113.7/24,8443=2001:db8::1")?;
+        apply_deny_cidrs(&mut services, "53=198.51.100.0/24")?;
+        assert!(services[0].allow.is_empty() && services[0].deny.is_empty());
+        assert_eq!(
+            services[1].allow,
+            vec!["203.0.113.0/24".parse()?, "2001:db8::1/128".parse()?]
+        );
+        // Both protocols on the port
+        assert_eq!(services[2].deny, vec!["198.51.100.0/24".parse()?]);
+        assert_eq!(services[3].deny, services[2].deny);
+        assert!(apply_allow_cidrs(&mut services, "9999=10.0.0.0/8").is_err());
+        assert!(apply_allow_cidrs(&mut services, "443=office").is_err());
+        assert!(apply_deny_cidrs(&mut services, "443").is_err());
+
+        apply_max_conns(&mut services, "8443=20")?;
+        assert_eq!(services[1].max_conns, Some(20));
+        assert_eq!(services[0].max_conns, None);
+        assert!(apply_max_conns(&mut services, "8443=0").is_err());
+        assert!(apply_max_conns(&mut services, "9999=20").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_service_options() -> Result<()> {
+        let mut services = ServicePort::from_str_multi("443/TCP,80/TCP,53/UDP")?;
+        apply_health_checks(&mut services, "443=tcp,80=http:/healthz")?;
+        assert_eq!(services[0].health_check, Some(HealthCheck::Tcp));
+        assert_eq!(
+            services[1].health_check,
+            Some(HealthCheck::Http {
+                path: "/healthz".to_string()
+            })
+        );
+        assert_eq!(
+            "HTTP".parse::<HealthCheck>()?,
+            HealthCheck::Http {
+                path: "/".to_string()
+            }
+        );
+        assert!(apply_health_checks(&mut services, "53=tcp").is_err());
+        assert!(apply_health_checks(&mut services, "80=http:healthz").is_err());
+        assert!(apply_health_checks(&mut services, "80=http:/a b").is_err());
+        assert!(apply_health_checks(&mut services, "80=ping").is_err());
+
+        let dest_ip = "10.0.0.2".parse()?;
+        apply_backends(&mut services, "80=8001,80=192.168.1.20:8000", dest_ip)?;
+        assert_eq!(
+            services[1].backends,
+            vec!["10.0.0.2:8001".parse()?, "192.168.1.20:8000".parse()?]
+        );
+        assert!(apply_backends(&mut services, "80=cluster", dest_ip).is_err());
+        assert!(apply_backends(&mut services, "53=8053", dest_ip).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_host_routes() -> Result<()> {
+        let r = HostRoute::try_from("App.example.com=8443")?;
+        assert_eq!(r.hostname, "app.example.com");
+        assert_eq!(r.local_port, 8443);
+        assert!(HostRoute::try_from("*.example.com=9443").is_ok());
+        assert!(HostRoute::try_from("example.com").is_err());
+        assert!(HostRoute::try_from("example.com=99999").is_err());
+        assert!(HostRoute::try_from("evil;com=8443").is_err());
+        Ok(())
+    }
+
     #[test]
     fn clean_service_name() {
         let s_simple = "foo";
@@ -182,4 +1151,94 @@ mod tests {
         let r_default = clean_name(s_default);
         assert!(r_default == *"innisfree");
     }
+
+    #[test]
+    fn tunnel_file_maps_to_env_vars() -> Result<()> {
+        let file: TunnelFile = toml::from_str(
+            r#"
+            name = "demo"
+            provider = "linode"
+            ports = ["80:8000/TCP", { port = 443, local_port = 8443 }, { port = 53, protocol = "UDP" }]
+            dest_ip = "192.168.1.10"
+            reserve_ip = true
+            sni = ["app.example.com=8444", "api.example.com=8445"]
+            tls_cert = "certs/fullchain.pem"
+
+            [wireguard]
+            mtu = 1380
+            port = "random"
+            "#,
+        )?;
+        let vars = file.env_vars(Path::new("/srv/demo"));
+        let get = |k: &str| vars.iter().find(|(n, _)| *n == k).map(|(_, v)| v.as_str());
+        assert_eq!(get("INNISFREE_NAME"), Some("demo"));
+        assert_eq!(
+            get("INNISFREE_PORTS"),
+            Some("80:8000/TCP,443:8443/TCP,53:53/UDP")
+        );
+        assert_eq!(get("INNISFREE_DEST_IP"), Some("192.168.1.10"));
+        assert_eq!(get("INNISFREE_RESERVE_IP"), Some("true"));
+        assert_eq!(
+            get("INNISFREE_SNI"),
+            Some("app.example.com=8444,api.example.com=8445")
+        );
+        assert_eq!(
+            get("INNISFREE_TLS_CERT"),
+            Some("/srv/demo/certs/fullchain.pem")
+        );
+        assert_eq!(get("INNISFREE_WG_MTU"), Some("1380"));
+        assert_eq!(get("INNISFREE_WG_PORT"), Some("random"));
+        assert_eq!(get("INNISFREE_HTTP_VHOST"), None);
+        for (_, spec) in vars.iter().filter(|(k, _)| *k == "INNISFREE_PORTS") {
+            assert_eq!(ServicePort::from_str_multi(spec)?.len(), 3);
+        }
+        assert!(toml::from_str::<TunnelFile>("prots = [\"80\"]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tunnel_file_named_services() -> Result<()> {
+        let file: TunnelFile = toml::from_str(
+            r#"
+            ports = ["22/TCP"]
+            proxy_protocol = "22=strip"
+
+            [services]
+            web = { port = 443, local = 8443, proto = "tcp", proxy_protocol = true, max_conns = 20 }
+            api = { port = 8080, health_check = "http:/healthz", allow = ["203.0.113.0/24"] }
+            dns = { port = 53, proto = "udp", proxy_protocol = false }
+            "#,
+        )?;
+        let vars = file.env_vars(Path::new("/srv/demo"));
+        let get = |k: &str| vars.iter().find(|(n, _)| *n == k).map(|(_, v)| v.as_str());
+        assert_eq!(
+            get("INNISFREE_PORTS"),
+            Some("22/TCP,api=8080:8080/TCP,dns=53:53/UDP,web=443:8443/TCP")
+        );
+        assert_eq!(get("INNISFREE_PROXY_PROTOCOL"), Some("22=strip,443=v2"));
+        assert_eq!(get("INNISFREE_MAX_CONNS"), Some("443=20"));
+        assert_eq!(get("INNISFREE_HEALTH_CHECK"), Some("8080=http:/healthz"));
+        assert_eq!(get("INNISFREE_ALLOW_CIDR"), Some("8080=203.0.113.0/24"));
+        assert_eq!(get("INNISFREE_BACKEND"), None);
+
+        let mut services = ServicePort::from_str_multi(get("INNISFREE_PORTS").unwrap())?;
+        apply_proxy_protocol(&mut services, get("INNISFREE_PROXY_PROTOCOL").unwrap())?;
+        assert_eq!(services[3].name.as_deref(), Some("web"));
+        assert_eq!(services[3].proxy_protocol, Some(ProxyProtocol::V2));
+        let loaded = services_from_vars(&vars, "127.0.0.1".parse()?)?;
+        assert_eq!(loaded[3].proxy_protocol, services[3].proxy_protocol);
+        assert_eq!(loaded[3].max_conns, Some(20));
+        assert_eq!(
+            loaded[1].health_check,
+            Some(HealthCheck::Http {
+                path: "/healthz".to_string()
+            })
+        );
+        assert!(services_from_vars(&[], "127.0.0.1".parse()?).is_err());
+        assert!(
+            toml::from_str::<TunnelFile>("[services]\nweb = { port = 443, prot = \"tcp\" }")
+                .is_err()
+        );
+        Ok(())
+    }
 }
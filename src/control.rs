@@ -0,0 +1,539 @@
+//! Control socket for a running tunnel, so services can be added or
+//! removed without tearing it down, via `innisfree add-port` and
+//! `innisfree remove-port`, its status inspected via `innisfree status`,
+//! and the tunnel torn down via `innisfree down`.
+//! The `up` process listens on a Unix socket in the tunnel's config dir,
+//! and accepts one JSON request per line.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::ProxyProtocol;
+use crate::config::{make_config_dir, ServicePort};
+use crate::event::TunnelEvent;
+use crate::manager::{run_proxy, run_proxy_protocol, run_tls_proxy, TunnelManager};
+use crate::proxy::ByteCounts;
+use crate::state::TunnelState;
+use crate::wg::PeerStats;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+/// Request sent to a running tunnel over its control socket.
+pub enum ControlRequest {
+    /// Start forwarding a service, given as a [ServicePort] spec, e.g. `8080:8000/TCP`.
+    AddPort {
+        /// Spec for the service to add.
+        spec: String,
+    },
+    /// Stop forwarding a service, given as `<PORT>[/PROTOCOL]`, e.g. `8080/TCP`.
+    RemovePort {
+        /// Spec for the service to remove. Only the public port and protocol are used.
+        spec: String,
+    },
+    /// Converge on the given services, e.g. when `innisfree up` is re-run
+    /// with an edited port list.
+    SetPorts {
+        /// Every service to forward.
+        services: Vec<ServicePort>,
+    },
+    /// Forward to another dest IP, e.g. once a container restarts with a
+    /// new address, see [crate::docker].
+    SetDest {
+        /// IP of the local services from now on.
+        dest_ip: IpAddr,
+    },
+    /// Report the tunnel's status, see [TunnelStatus].
+    Status,
+    /// Tear down the tunnel and destroy the server, then exit, as on ctrl+c.
+    Down,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+/// Reply to a [ControlRequest], describing the outcome.
+pub struct ControlResponse {
+    /// Whether the request succeeded.
+    pub ok: bool,
+    /// Summary of the change, or the reason it failed.
+    pub message: String,
+    /// The tunnel's status, in reply to [ControlRequest::Status].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<TunnelStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+/// Snapshot of a running tunnel, for `innisfree status`.
+pub struct TunnelStatus {
+    /// Public IP on which the services are published.
+    pub public_ip: IpAddr,
+    /// Public ports currently forwarded, e.g. `443/TCP`.
+    pub ports: Vec<String>,
+    /// Names of the named services, keyed as in `ports`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub names: BTreeMap<String, String>,
+    /// Stats for the local Wireguard interface's peers, see [TunnelManager::stats].
+    pub peers: Vec<PeerStats>,
+    /// Bytes passed per service, keyed as in `ports`.
+    #[serde(default)]
+    pub traffic: BTreeMap<String, ServiceTraffic>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+/// Bytes passed through a service, as counted at either end of the tunnel.
+/// Counts are missing where the service isn't proxied, e.g. on the local
+/// end if it listens on the Wireguard interface directly.
+pub struct ServiceTraffic {
+    /// Counted by the local proxy, since `up` started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local: Option<ByteCounts>,
+    /// Counted by the server's nginx, since the server booted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<ByteCounts>,
+}
+
+/// Returns the path to the control socket for the tunnel `name`.
+pub fn socket_path(name: &str) -> Result<PathBuf> {
+    Ok(make_config_dir(name)?.join("control.sock"))
+}
+
+/// Whether the service needs a local proxy, listening on its local port on
+/// the Wireguard interface: to terminate TLS, if `tls`, or to rewrite PROXY
+/// protocol headers, since nginx only sends v1. Otherwise only if `dest_ip`
+/// isn't loopback, i.e. if local services don't listen on the Wireguard
+/// interface directly, or if it balances across backends.
+pub fn needs_proxy(service: &ServicePort, dest_ip: IpAddr, tls: bool) -> bool {
+    tls || matches!(
+        service.proxy_protocol,
+        Some(ProxyProtocol::V2) | Some(ProxyProtocol::Strip)
+    ) || !dest_ip.is_loopback()
+        || !service.backends.is_empty()
+}
+
+/// Whether an `up` process for the tunnel `name` is listening on its control socket.
+pub async fn is_running(name: &str) -> bool {
+    match socket_path(name) {
+        Ok(path) => UnixStream::connect(path).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Sends a request to the running tunnel `name`, and returns its reply
+/// if the request succeeded.
+async fn request(name: &str, request: &ControlRequest) -> Result<ControlResponse> {
+    let path = socket_path(name)?;
+    let stream = UnixStream::connect(&path).await.with_context(|| {
+        format!(
+            "No running tunnel found at {}. Try running 'innisfree up' first, or pass --name=<service>",
+            path.display()
+        )
+    })?;
+    let (reader, mut writer) = stream.into_split();
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    let mut reply = String::new();
+    BufReader::new(reader).read_line(&mut reply).await?;
+    let response: ControlResponse =
+        serde_json::from_str(&reply).context("Invalid reply from control socket")?;
+    if response.ok {
+        Ok(response)
+    } else {
+        Err(anyhow!(response.message))
+    }
+}
+
+/// Sends a request to the running tunnel `name`, and returns its reply.
+pub async fn send(name: &str, req: &ControlRequest) -> Result<String> {
+    Ok(request(name, req).await?.message)
+}
+
+/// Asks the running tunnel `name` for its status.
+pub async fn status(name: &str) -> Result<TunnelStatus> {
+    request(name, &ControlRequest::Status)
+        .await?
+        .status
+        .ok_or_else(|| anyhow!("No status in reply from control socket"))
+}
+
+/// Serves a running tunnel's control socket, tracking its services and
+/// the local proxy tasks for each, so both can change at runtime.
+pub struct ControlServer {
+    mgr: Arc<TunnelManager>,
+    services: Vec<ServicePort>,
+    proxies: Vec<(ServicePort, JoinHandle<Result<()>>)>,
+    local_ip: IpAddr,
+    dest_ip: IpAddr,
+    /// Service on which TLS is terminated locally, and the acceptor doing so.
+    tls: Option<(ServicePort, TlsAcceptor)>,
+}
+
+impl ControlServer {
+    /// Creates a controller for the tunnel's current services. Proxies
+    /// listen on `local_ip` and forward to `dest_ip`, as in [run_proxy].
+    pub fn new(mgr: Arc<TunnelManager>, local_ip: IpAddr, dest_ip: IpAddr) -> ControlServer {
+        ControlServer {
+            services: mgr.services.clone(),
+            mgr,
+            proxies: vec![],
+            local_ip,
+            dest_ip,
+            tls: None,
+        }
+    }
+
+    /// Terminates TLS for the service locally via `acceptor`, once its
+    /// proxy is started, see [ControlServer::spawn_proxy].
+    pub fn terminate_tls(&mut self, service: ServicePort, acceptor: TlsAcceptor) {
+        self.tls = Some((service, acceptor));
+    }
+
+    /// Starts a local proxy for the service, if it needs one, see [needs_proxy].
+    pub fn spawn_proxy(&mut self, service: ServicePort) {
+        let (local_ip, dest_ip) = (self.local_ip, self.dest_ip);
+        let (proxy, cancel) = (self.mgr.proxy_config(), self.mgr.cancellation_token());
+        let tls = match &self.tls {
+            Some((s, acceptor)) if s.same_port(&service) => Some(acceptor.clone()),
+            _ => None,
+        };
+        if !needs_proxy(&service, dest_ip, tls.is_some()) {
+            return;
+        }
+        let h = if let Some(acceptor) = tls {
+            tracing::info!(
+                "Terminating TLS locally for {}/TCP, forwarding plaintext to {}:{}",
+                service.port,
+                dest_ip,
+                service.local_port
+            );
+            tokio::spawn(run_tls_proxy(
+                local_ip,
+                dest_ip,
+                service.clone(),
+                acceptor,
+                proxy,
+                cancel,
+            ))
+        } else if matches!(
+            service.proxy_protocol,
+            Some(ProxyProtocol::V2) | Some(ProxyProtocol::Strip)
+        ) {
+            tokio::spawn(run_proxy_protocol(
+                local_ip,
+                dest_ip,
+                service.clone(),
+                proxy,
+                cancel,
+            ))
+        } else {
+            tokio::spawn(run_proxy(
+                local_ip,
+                dest_ip,
+                vec![service.clone()],
+                proxy,
+                cancel,
+            ))
+        };
+        self.track(service, h);
+    }
+
+    /// Records a proxy task started elsewhere, e.g. for TLS termination,
+    /// so that it's stopped if the service is removed.
+    pub fn track(&mut self, service: ServicePort, handle: JoinHandle<Result<()>>) {
+        self.mgr
+            .emit(TunnelEvent::ProxyStarted { port: service.port });
+        self.proxies.push((service, handle));
+    }
+
+    /// Listens on the control socket, handling requests one at a time,
+    /// so changes to the server's config never interleave.
+    pub async fn serve(mut self) -> Result<()> {
+        let path = socket_path(&self.mgr.name)?;
+        // Left behind if a previous run was killed
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+        tracing::debug!("Listening for control requests on {}", path.display());
+        while let Ok((stream, _)) = listener.accept().await {
+            if let Err(e) = self.handle(stream).await {
+                tracing::warn!("Control request failed: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a single request from the stream, and writes the reply.
+    async fn handle(&mut self, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await?;
+        let mut exit = false;
+        let result = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(ControlRequest::AddPort { spec }) => self.add_port(&spec).await.map(|m| (m, None)),
+            Ok(ControlRequest::RemovePort { spec }) => {
+                self.remove_port(&spec).await.map(|m| (m, None))
+            }
+            Ok(ControlRequest::SetPorts { services }) => {
+                self.set_ports(services).await.map(|m| (m, None))
+            }
+            Ok(ControlRequest::SetDest { dest_ip }) => Ok((self.set_dest(dest_ip), None)),
+            Ok(ControlRequest::Status) => self.status(),
+            Ok(ControlRequest::Down) => {
+                exit = true;
+                tracing::warn!("Received down request, exiting gracefully");
+                self.mgr
+                    .clean()
+                    .await
+                    .map(|_| (format!("Tunnel '{}' torn down", self.mgr.name), None))
+                    .map_err(Into::into)
+            }
+            Err(e) => Err(anyhow!("Invalid control request: {}", e)),
+        };
+        let response = match result {
+            Ok((message, status)) => {
+                if status.is_none() {
+                    tracing::info!("{}", message);
+                }
+                ControlResponse {
+                    ok: true,
+                    message,
+                    status,
+                }
+            }
+            Err(e) => ControlResponse {
+                ok: false,
+                message: format!("{:#}", e),
+                status: None,
+            },
+        };
+        let mut reply = serde_json::to_string(&response)?;
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+        if exit {
+            std::process::exit(0);
+        }
+        Ok(())
+    }
+
+    /// Reports the tunnel's public IP, current services, and Wireguard stats.
+    fn status(&self) -> Result<(String, Option<TunnelStatus>)> {
+        let ports: Vec<String> = self
+            .services
+            .iter()
+            .map(|s| format!("{}/{}", s.port, s.protocol))
+            .collect();
+        let local = self.mgr.traffic().totals();
+        // Missing counts shouldn't fail the rest of the status.
+        let server = self.mgr.server_traffic().unwrap_or_else(|e| {
+            tracing::warn!("Failed to read traffic from server: {:#}", e);
+            None
+        });
+        let traffic = ports
+            .iter()
+            .map(|p| {
+                let key = p.to_uppercase();
+                let t = ServiceTraffic {
+                    local: local.get(&key).copied(),
+                    server: server
+                        .as_ref()
+                        .map(|s| s.get(&key).copied().unwrap_or_default()),
+                };
+                (p.clone(), t)
+            })
+            .collect();
+        let names = self
+            .services
+            .iter()
+            .filter_map(|s| Some((format!("{}/{}", s.port, s.protocol), s.name.clone()?)))
+            .collect();
+        let status = TunnelStatus {
+            public_ip: self.mgr.public_ip()?,
+            ports,
+            names,
+            peers: self.mgr.stats()?,
+            traffic,
+        };
+        Ok((String::from("ok"), Some(status)))
+    }
+
+    /// Records the current services in the tunnel's saved state.
+    fn save_services(&self) {
+        let services = self.services.clone();
+        if let Err(e) = TunnelState::update(&self.mgr.name, |s| s.services = services) {
+            tracing::warn!("Failed to record services: {:#}", e);
+        }
+    }
+
+    /// Opens the service's port on the server, reloads its forwarding
+    /// config, and starts forwarding locally.
+    async fn add_port(&mut self, spec: &str) -> Result<String> {
+        let service = ServicePort::try_from(spec)?;
+        if self.services.iter().any(|s| s.same_port(&service)) {
+            return Err(anyhow!("{} is already forwarded", service));
+        }
+        let mut services = self.services.clone();
+        services.push(service.clone());
+        self.mgr.options.validate(&services)?;
+
+        self.mgr.server().open_port(&service).await?;
+        if let Err(e) = self.mgr.reload_services(&services) {
+            let _ = self.mgr.server().close_port(&service).await;
+            return Err(e.into());
+        }
+        self.mgr.set_local_port_open(&service, true)?;
+        self.spawn_proxy(service.clone());
+        self.services = services;
+        self.save_services();
+        Ok(format!(
+            "Forwarding {} to local port {}",
+            service, service.local_port
+        ))
+    }
+
+    /// Stops forwarding the service, the reverse of [ControlServer::add_port].
+    async fn remove_port(&mut self, spec: &str) -> Result<String> {
+        let target = ServicePort::try_from(spec)?;
+        let service = self
+            .services
+            .iter()
+            .find(|s| s.same_port(&target))
+            .cloned()
+            .ok_or_else(|| anyhow!("{}/{} isn't forwarded", target.port, target.protocol))?;
+        if self.services.len() == 1 {
+            return Err(anyhow!(
+                "Can't remove the last service, run 'innisfree clean' to tear down the tunnel"
+            ));
+        }
+        let mut services = self.services.clone();
+        services.retain(|s| !s.same_port(&service));
+        self.mgr.options.validate(&services)?;
+
+        self.mgr.reload_services(&services)?;
+        self.services = services;
+        self.save_services();
+        if let Err(e) = self.mgr.set_local_port_open(&service, false) {
+            tracing::warn!("Failed to close local port {}: {}", service.local_port, e);
+        }
+        self.mgr.server().close_port(&service).await?;
+        self.proxies.retain(|(s, h)| {
+            let stop = s.same_port(&service);
+            if stop {
+                h.abort();
+            }
+            !stop
+        });
+        Ok(format!("Stopped forwarding {}", service))
+    }
+
+    /// Replaces the forwarded services in one step, so that changing a
+    /// service's local port, or swapping the only service, needs no
+    /// intermediate config that would fail validation.
+    async fn set_ports(&mut self, services: Vec<ServicePort>) -> Result<String> {
+        if services.is_empty() {
+            return Err(anyhow!("No services given"));
+        }
+        if services == self.services {
+            return Ok(String::from("Services unchanged"));
+        }
+        self.mgr.converge(&self.services, &services).await?;
+        for service in self.services.clone() {
+            if services.contains(&service) {
+                continue;
+            }
+            if let Err(e) = self.mgr.set_local_port_open(&service, false) {
+                tracing::warn!("Failed to close local port {}: {}", service.local_port, e);
+            }
+            self.proxies.retain(|(s, h)| {
+                let stop = *s == service;
+                if stop {
+                    h.abort();
+                }
+                !stop
+            });
+        }
+        let added: Vec<ServicePort> = services
+            .iter()
+            .filter(|s| !self.services.contains(s))
+            .cloned()
+            .collect();
+        for service in added {
+            self.mgr.set_local_port_open(&service, true)?;
+            self.spawn_proxy(service);
+        }
+        self.services = services;
+        let ports: Vec<String> = self
+            .services
+            .iter()
+            .map(|s| format!("{}/{}", s.port, s.protocol))
+            .collect();
+        Ok(format!("Now forwarding {}", ports.join(", ")))
+    }
+
+    /// Restarts the local proxies, forwarding to `dest_ip` from now on.
+    /// Open connections to the old address are dropped.
+    fn set_dest(&mut self, dest_ip: IpAddr) -> String {
+        if dest_ip == self.dest_ip {
+            return String::from("Dest IP unchanged");
+        }
+        for (_, h) in self.proxies.drain(..) {
+            h.abort();
+        }
+        self.dest_ip = dest_ip;
+        for service in self.mgr.options.local_services(&self.services) {
+            self.spawn_proxy(service);
+        }
+        format!("Now forwarding to {}", dest_ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_tagged_by_command() -> Result<()> {
+        let request = ControlRequest::AddPort {
+            spec: "8080:8000/TCP".to_string(),
+        };
+        let line = serde_json::to_string(&request)?;
+        assert_eq!(line, r#"{"cmd":"add-port","spec":"8080:8000/TCP"}"#);
+        assert_eq!(serde_json::from_str::<ControlRequest>(&line)?, request);
+
+        let line = r#"{"cmd":"remove-port","spec":"53/UDP"}"#;
+        assert_eq!(
+            serde_json::from_str::<ControlRequest>(line)?,
+            ControlRequest::RemovePort {
+                spec: "53/UDP".to_string()
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<ControlRequest>(r#"{"cmd":"status"}"#)?,
+            ControlRequest::Status
+        );
+        assert_eq!(
+            serde_json::from_str::<ControlRequest>(r#"{"cmd":"down"}"#)?,
+            ControlRequest::Down
+        );
+        let request = ControlRequest::SetPorts {
+            services: ServicePort::from_str_multi("80,443")?,
+        };
+        let line = serde_json::to_string(&request)?;
+        assert!(line.starts_with(r#"{"cmd":"set-ports","services":[{"#));
+        assert_eq!(serde_json::from_str::<ControlRequest>(&line)?, request);
+        assert_eq!(
+            serde_json::from_str::<ControlRequest>(r#"{"cmd":"set-dest","dest_ip":"172.17.0.3"}"#)?,
+            ControlRequest::SetDest {
+                dest_ip: "172.17.0.3".parse()?
+            }
+        );
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"cmd":"reboot"}"#).is_err());
+        Ok(())
+    }
+}
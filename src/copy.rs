@@ -0,0 +1,74 @@
+//! File transfers to and from a tunnel's server, via `innisfree cp`, e.g. to
+//! drop in an updated nginx snippet, or retrieve logs, without crafting
+//! `scp` commands by hand. Files are copied via SFTP, with the tunnel's keys.
+
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::ssh::client::SshClient;
+
+/// Prefix marking a path as being on the server, as with `host:` for `scp`.
+const REMOTE_PREFIX: &str = "server:";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Source or destination of a copy.
+pub enum CopyPath {
+    /// Path on the local machine.
+    Local(PathBuf),
+    /// Path on the server, accessed as the `innisfree` user.
+    Remote(String),
+}
+
+impl FromStr for CopyPath {
+    type Err = anyhow::Error;
+
+    /// Parses `server:<PATH>` as a path on the server, and anything else as a local one.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix(REMOTE_PREFIX) {
+            Some("") => Err(anyhow!("Missing path after '{}'", REMOTE_PREFIX)),
+            Some(path) => Ok(CopyPath::Remote(path.to_string())),
+            None => Ok(CopyPath::Local(PathBuf::from(s))),
+        }
+    }
+}
+
+/// Copies `src` to `dest` for the tunnel `service_name`, running in a separate
+/// process. Exactly one of them must be on the server. Returns the number of
+/// bytes copied.
+pub fn copy(service_name: &str, src: &CopyPath, dest: &CopyPath) -> Result<u64> {
+    match (src, dest) {
+        (CopyPath::Local(local), CopyPath::Remote(remote)) => {
+            SshClient::for_tunnel(service_name)?.push_file(local, remote)
+        }
+        (CopyPath::Remote(remote), CopyPath::Local(local)) => {
+            // As with `cp`, copying into a directory keeps the file's name.
+            let local = match Path::new(remote).file_name() {
+                Some(name) if local.is_dir() => local.join(name),
+                _ => local.to_path_buf(),
+            };
+            SshClient::for_tunnel(service_name)?.pull_file(remote, &local)
+        }
+        _ => Err(anyhow!(
+            "Exactly one path must be on the server, as '{}<PATH>'",
+            REMOTE_PREFIX
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_paths_prefixed() -> Result<()> {
+        let p: CopyPath = "server:/var/log/nginx/access.log".parse()?;
+        assert_eq!(p, CopyPath::Remote("/var/log/nginx/access.log".to_string()));
+        let p: CopyPath = "./snippet.conf".parse()?;
+        assert_eq!(p, CopyPath::Local(PathBuf::from("./snippet.conf")));
+        assert!("server:".parse::<CopyPath>().is_err());
+        let local = CopyPath::Local(PathBuf::from("a"));
+        assert!(copy("innisfree-test-copy", &local, &local).is_err());
+        Ok(())
+    }
+}
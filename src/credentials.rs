@@ -0,0 +1,143 @@
+//! Named credential profiles, selected via `--profile`, so that tunnels
+//! can use different cloud accounts, e.g. personal and work DigitalOcean
+//! accounts, without juggling env vars. Profiles are tables in
+//! `~/.config/innisfree/credentials.toml`:
+//!
+//! ```toml
+//! [personal]
+//! token_keyring = true
+//!
+//! [work]
+//! token_cmd = "pass show work/digitalocean"
+//! env = { LINODE_API_TOKEN = "..." }
+//! ```
+//!
+//! Each gives the DigitalOcean API token, or where to read it from, as
+//! for [crate::token], and env vars for other providers' credentials.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::config::make_config_dir;
+use crate::token::{TokenSource, KEYRING_ACCOUNT};
+
+/// Name of the file holding the profiles, in the config dir.
+const CREDENTIALS_FILE: &str = "credentials.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// Credentials for one cloud account, or set of them.
+pub struct Profile {
+    /// The DigitalOcean API token itself.
+    pub digitalocean_token: Option<String>,
+    /// As for `--token-file`.
+    pub token_file: Option<PathBuf>,
+    /// As for `--token-cmd`.
+    pub token_cmd: Option<String>,
+    /// As for `--token-keyring`, though under the keyring account
+    /// `digitalocean-<PROFILE>`, so that each profile has its own.
+    pub token_keyring: Option<bool>,
+    /// Env vars to set, e.g. `LINODE_API_TOKEN`, overriding those inherited.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+/// Returns the path of the credentials file, e.g. `~/.config/innisfree/credentials.toml`.
+pub fn path() -> Result<PathBuf> {
+    Ok(make_config_dir("")?.join(CREDENTIALS_FILE))
+}
+
+/// Parses the profile `name` from the contents of a credentials file.
+fn parse(s: &str, name: &str) -> Result<Profile> {
+    let mut profiles: BTreeMap<String, Profile> = toml::from_str(s)?;
+    let names = profiles.keys().cloned().collect::<Vec<_>>();
+    profiles.remove(name).ok_or_else(|| {
+        anyhow!(
+            "No profile '{}', only: {}",
+            name,
+            match names.is_empty() {
+                true => "none".to_string(),
+                false => names.join(", "),
+            }
+        )
+    })
+}
+
+impl Profile {
+    /// Reads the profile `name` from the credentials file, see [path].
+    pub fn load(name: &str) -> Result<Profile> {
+        let path = path()?;
+        let s = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        parse(&s, name).with_context(|| format!("Failed to load profile from {}", path.display()))
+    }
+
+    /// Returns where the profile `name` has the DigitalOcean API token read
+    /// from, if anywhere. Fails if it gives more than one source.
+    pub fn token_source(&self, name: &str) -> Result<Option<TokenSource>> {
+        let mut sources = vec![];
+        if let Some(token) = &self.digitalocean_token {
+            sources.push(TokenSource::Value(token.clone()));
+        }
+        if let Some(path) = &self.token_file {
+            sources.push(TokenSource::File(path.clone()));
+        }
+        if let Some(cmd) = &self.token_cmd {
+            sources.push(TokenSource::Command(cmd.clone()));
+        }
+        if self.token_keyring == Some(true) {
+            sources.push(TokenSource::Keyring(format!(
+                "{}-{}",
+                KEYRING_ACCOUNT, name
+            )));
+        }
+        if sources.len() > 1 {
+            return Err(anyhow!(
+                "Profile '{}' sets more than one of digitalocean_token, token_file, token_cmd, and token_keyring",
+                name
+            ));
+        }
+        Ok(sources.pop())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_parsed() -> Result<()> {
+        let s = r#"
+            [personal]
+            token_keyring = true
+
+            [work]
+            digitalocean_token = "dop_v1_abc123"
+            env = { LINODE_API_TOKEN = "def456" }
+
+            [broken]
+            digitalocean_token = "dop_v1_abc123"
+            token_cmd = "pass show do/token"
+        "#;
+        let work = parse(s, "work")?;
+        assert_eq!(
+            work.token_source("work")?,
+            Some(TokenSource::Value("dop_v1_abc123".to_string()))
+        );
+        assert_eq!(work.env["LINODE_API_TOKEN"], "def456");
+        assert_eq!(
+            parse(s, "personal")?.token_source("personal")?,
+            Some(TokenSource::Keyring("digitalocean-personal".to_string()))
+        );
+        assert!(parse(s, "broken")?.token_source("broken").is_err());
+        let missing = parse(s, "home").unwrap_err();
+        assert_eq!(
+            missing.to_string(),
+            "No profile 'home', only: broken, personal, work"
+        );
+        assert!(parse("[work]\ntoken = \"x\"\n", "work").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,449 @@
+//! Discovery of services in a Docker container, see `innisfree up --docker`.
+//! The container is inspected via the Docker Engine API, on its Unix
+//! socket, for its address on the bridge network and the ports it exposes,
+//! which become the tunnel's dest IP and services. The container is then
+//! watched, so if it restarts with a new address, or ports, the running
+//! tunnel follows, via its control socket, see [crate::control].
+//!
+//! Alternatively, with `innisfree up --docker-labels`, every running
+//! container labelled `innisfree.enable=true` is forwarded, alongside
+//! `--ports`, following Docker's events as containers start and stop.
+//! Each of its ports becomes a TCP service balanced to the container, see
+//! [crate::balance], so containers needn't share an address. Ports are
+//! those the container exposes, unless its `innisfree.ports` label lists
+//! them, e.g. `80:8000,443:8443`, as `<PUBLIC_PORT>[:<CONTAINER_PORT>]`.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use crate::config::{self, Protocol, ServicePort};
+use crate::control::{self, ControlRequest};
+
+/// Where the Docker daemon listens, unless `DOCKER_HOST` says otherwise.
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+/// How often a watched container is inspected for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+/// Label marking containers to forward, see `innisfree up --docker-labels`.
+const ENABLE_LABEL: &str = "innisfree.enable";
+/// Label listing a container's ports to forward, in place of those it exposes.
+const PORTS_LABEL: &str = "innisfree.ports";
+/// Running containers labelled `innisfree.enable=true`, URL-encoded from
+/// `{"label":["innisfree.enable=true"]}`.
+const LABELLED_PATH: &str =
+    "/containers/json?filters=%7B%22label%22%3A%5B%22innisfree.enable%3Dtrue%22%5D%7D";
+/// Starts and stops of labelled containers, URL-encoded from `{"type":["container"],
+/// "event":["start","die"],"label":["innisfree.enable=true"]}`.
+const EVENTS_PATH: &str = "/events?filters=%7B%22type%22%3A%5B%22container%22%5D%2C%22event%22%3A%5B%22start%22%2C%22die%22%5D%2C%22label%22%3A%5B%22innisfree.enable%3Dtrue%22%5D%7D";
+
+/// Returns the path of the Docker daemon's socket, from `DOCKER_HOST`,
+/// if it's a `unix://` URL, else the default.
+fn socket_path() -> PathBuf {
+    match std::env::var("DOCKER_HOST") {
+        Ok(host) => match host.strip_prefix("unix://") {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(DOCKER_SOCKET),
+        },
+        Err(_) => PathBuf::from(DOCKER_SOCKET),
+    }
+}
+
+/// Splits a raw HTTP response into its status code and body, parsed as
+/// JSON. Error responses fail with the daemon's message.
+fn parse_response(raw: &[u8]) -> Result<Value> {
+    let text = String::from_utf8_lossy(raw);
+    let (head, body) = text
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow!("Malformed response from Docker daemon"))?;
+    let status: u16 = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| anyhow!("Malformed response from Docker daemon"))?;
+    let body: Value = serde_json::from_str(body).unwrap_or_default();
+    if !(200..300).contains(&status) {
+        return Err(anyhow!(
+            "Docker daemon returned {}: {}",
+            status,
+            body["message"].as_str().unwrap_or("no details")
+        ));
+    }
+    Ok(body)
+}
+
+/// Sends a GET request for `path` to the Docker daemon, returning the
+/// stream to read the response from. HTTP/1.0, so the response is neither
+/// chunked nor kept alive.
+async fn request(path: &str) -> Result<UnixStream> {
+    let socket = socket_path();
+    let mut stream = UnixStream::connect(&socket).await.with_context(|| {
+        format!(
+            "Failed to connect to Docker daemon at {}, is it running, and can you access it?",
+            socket.display()
+        )
+    })?;
+    let request = format!("GET {} HTTP/1.0\r\nHost: docker\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await?;
+    Ok(stream)
+}
+
+/// Sends a GET request for `path` to the Docker daemon, returning the JSON
+/// response.
+async fn get(path: &str) -> Result<Value> {
+    let mut raw = vec![];
+    request(path).await?.read_to_end(&mut raw).await?;
+    parse_response(&raw)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A running container's address and services, as the tunnel forwards them.
+pub struct Container {
+    /// Container's address on its network, e.g. `172.17.0.2`.
+    pub ip: IpAddr,
+    /// A service per port the container exposes, forwarded to that port on
+    /// `ip`. Ports published on the host keep their host port as public port.
+    pub services: Vec<ServicePort>,
+}
+
+impl Container {
+    /// Reads the container's address and services from the daemon's
+    /// description of it, as returned by `docker inspect`.
+    fn from_inspect(j: &Value) -> Result<Container> {
+        let name = j["Name"]
+            .as_str()
+            .unwrap_or_default()
+            .trim_start_matches('/');
+        if j["State"]["Running"] != true {
+            return Err(anyhow!("Container '{}' isn't running", name));
+        }
+        let settings = &j["NetworkSettings"];
+        // Empty for containers on user-defined networks, which list theirs.
+        let ip = std::iter::once(&settings["IPAddress"])
+            .chain(
+                settings["Networks"]
+                    .as_object()
+                    .into_iter()
+                    .flat_map(|n| n.values().map(|n| &n["IPAddress"])),
+            )
+            .filter_map(|ip| ip.as_str())
+            .find(|ip| !ip.is_empty())
+            .ok_or_else(|| anyhow!("Container '{}' has no IP address", name))?
+            .parse()?;
+        let mut services = vec![];
+        for spec in j["Config"]["ExposedPorts"]
+            .as_object()
+            .into_iter()
+            .flatten()
+        {
+            // e.g. `80/tcp`
+            let (port, protocol) = spec.0.split_once('/').unwrap_or((spec.0, "tcp"));
+            let local_port = config::parse_port(port)?;
+            let published = settings["Ports"][spec.0][0]["HostPort"]
+                .as_str()
+                .and_then(|p| config::parse_port(p).ok());
+            services.push(ServicePort {
+                port: published.unwrap_or(local_port),
+                local_port,
+                protocol: protocol.parse()?,
+                ..Default::default()
+            });
+        }
+        if services.is_empty() {
+            return Err(anyhow!(
+                "Container '{}' exposes no ports, EXPOSE some in its image",
+                name
+            ));
+        }
+        services.sort_by_key(|s| (s.port, s.protocol));
+        Ok(Container { ip, services })
+    }
+}
+
+/// Inspects the running container `name`, or ID.
+pub async fn inspect(name: &str) -> Result<Container> {
+    let j = get(&format!("/containers/{}/json", name))
+        .await
+        .with_context(|| format!("Failed to inspect container '{}'", name))?;
+    Container::from_inspect(&j)
+}
+
+/// Inspects the container `container` every [WATCH_INTERVAL], pointing the
+/// running tunnel `name` at its new address, or ports, once it's restarted
+/// with different ones. Starts from `current`.
+pub fn watch(name: &str, container: &str, mut current: Container) {
+    let (name, container) = (name.to_string(), container.to_string());
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(WATCH_INTERVAL).await;
+            let latest = match inspect(&container).await {
+                Ok(c) => c,
+                // Stopped for now, e.g. restarting
+                Err(e) => {
+                    tracing::debug!("{:#}", e);
+                    continue;
+                }
+            };
+            if latest.services != current.services {
+                tracing::info!("Container '{}' changed ports, updating services", container);
+                let request = ControlRequest::SetPorts {
+                    services: latest.services.clone(),
+                };
+                if let Err(e) = control::send(&name, &request).await {
+                    tracing::warn!("Failed to update services: {:#}", e);
+                    continue;
+                }
+            }
+            if latest.ip != current.ip {
+                tracing::info!(
+                    "Container '{}' moved from {} to {}",
+                    container,
+                    current.ip,
+                    latest.ip
+                );
+                let request = ControlRequest::SetDest { dest_ip: latest.ip };
+                if let Err(e) = control::send(&name, &request).await {
+                    tracing::warn!("Failed to update dest IP: {:#}", e);
+                    continue;
+                }
+            }
+            current = latest;
+        }
+    });
+}
+
+/// Returns the services to forward for a labelled container, from its entry
+/// in the daemon's list of containers: one per port, on TCP, balanced to the
+/// container's address, and listening locally on the public port, so that
+/// containers using the same ports don't clash.
+fn labelled_services(c: &Value) -> Result<Vec<ServicePort>> {
+    let ip: IpAddr = c["NetworkSettings"]["Networks"]
+        .as_object()
+        .into_iter()
+        .flat_map(|n| n.values())
+        .filter_map(|n| n["IPAddress"].as_str())
+        .find(|ip| !ip.is_empty())
+        .ok_or_else(|| anyhow!("no IP address"))?
+        .parse()?;
+    let ports: Vec<(i32, i32)> = match c["Labels"][PORTS_LABEL].as_str() {
+        Some(spec) => spec
+            .split(',')
+            .map(|p| {
+                let s = ServicePort::try_from(p)
+                    .with_context(|| format!("invalid port '{}' in {} label", p, PORTS_LABEL))?;
+                if s.protocol != Protocol::Tcp {
+                    return Err(anyhow!("only TCP ports are forwarded, not '{}'", p));
+                }
+                // Without a container port, it's the public port.
+                let port = match p.contains(':') {
+                    true => s.local_port,
+                    false => s.port,
+                };
+                Ok((s.port, port))
+            })
+            .collect::<Result<_>>()?,
+        None => c["Ports"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|p| p["Type"] == "tcp")
+            .map(|p| config::parse_port(&p["PrivatePort"].to_string()).map(|p| (p, p)))
+            .collect::<Result<_>>()?,
+    };
+    let mut services: Vec<ServicePort> = vec![];
+    for (port, container_port) in ports {
+        let backend = SocketAddr::new(ip, u16::try_from(container_port)?);
+        // Listed once per address it's published on
+        if services.iter().any(|s| s.port == port) {
+            continue;
+        }
+        services.push(ServicePort {
+            port,
+            local_port: port,
+            backends: vec![backend],
+            ..Default::default()
+        });
+    }
+    if services.is_empty() {
+        return Err(anyhow!(
+            "no TCP ports, EXPOSE some, or list them in the {} label",
+            PORTS_LABEL
+        ));
+    }
+    Ok(services)
+}
+
+/// Returns `base` with the services of the labelled containers in `list`,
+/// as the daemon lists them, added, see [config::with_discovered].
+fn with_labelled(base: &[ServicePort], list: &Value) -> Vec<ServicePort> {
+    let found = list
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|c| c["Labels"][ENABLE_LABEL] == "true")
+        .map(|c| {
+            let name = c["Names"][0]
+                .as_str()
+                .unwrap_or_default()
+                .trim_start_matches('/');
+            (format!("container '{}'", name), labelled_services(c))
+        })
+        .collect();
+    config::with_discovered(base, found)
+}
+
+/// Returns `base` with the services of every running labelled container
+/// added, see [crate::docker].
+pub async fn labelled(base: &[ServicePort]) -> Result<Vec<ServicePort>> {
+    let list = get(LABELLED_PATH)
+        .await
+        .context("Failed to list labelled containers")?;
+    Ok(with_labelled(base, &list))
+}
+
+/// Follows Docker's events for labelled containers, re-listing them on each,
+/// and forwarding their services through the running tunnel `name`,
+/// alongside `base`. Starts from `current`. If the daemon goes away, it's
+/// reconnected to every [WATCH_INTERVAL].
+pub fn watch_labels(name: &str, base: Vec<ServicePort>, mut current: Vec<ServicePort>) {
+    let name = name.to_string();
+    tokio::spawn(async move {
+        loop {
+            let events = match request(EVENTS_PATH).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!("Not following containers: {:#}", e);
+                    tokio::time::sleep(WATCH_INTERVAL).await;
+                    continue;
+                }
+            };
+            let mut lines = BufReader::new(events).lines();
+            // Catch up on any changes missed while not following.
+            let mut changed = true;
+            loop {
+                if changed {
+                    match labelled(&base).await {
+                        Ok(services) if services != current => {
+                            let request = ControlRequest::SetPorts {
+                                services: services.clone(),
+                            };
+                            match control::send(&name, &request).await {
+                                Ok(_) => current = services,
+                                Err(e) => tracing::warn!("Failed to update services: {:#}", e),
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => tracing::warn!("{:#}", e),
+                    }
+                }
+                // After the headers, one JSON object per line, per event.
+                changed = match lines.next_line().await {
+                    Ok(Some(line)) => line.starts_with('{'),
+                    Ok(None) | Err(_) => break,
+                };
+            }
+            tracing::debug!("Docker events stream ended, reconnecting");
+            tokio::time::sleep(WATCH_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn containers_inspected() -> Result<()> {
+        let inspected = serde_json::json!({
+            "Name": "/web",
+            "State": { "Running": true },
+            "Config": { "ExposedPorts": { "80/tcp": {}, "53/udp": {}, "443/tcp": {} } },
+            "NetworkSettings": {
+                "IPAddress": "",
+                "Networks": { "app": { "IPAddress": "172.18.0.4" } },
+                "Ports": {
+                    "80/tcp": [{ "HostIp": "0.0.0.0", "HostPort": "8080" }],
+                    "443/tcp": null,
+                },
+            },
+        });
+        let c = Container::from_inspect(&inspected)?;
+        assert_eq!(c.ip, "172.18.0.4".parse::<IpAddr>()?);
+        assert_eq!(
+            c.services,
+            ServicePort::from_str_multi("53/UDP,443/TCP,8080:80/TCP")?
+        );
+
+        let mut stopped = inspected.clone();
+        stopped["State"]["Running"] = false.into();
+        assert!(Container::from_inspect(&stopped).is_err());
+        let mut portless = inspected;
+        portless["Config"]["ExposedPorts"] = Value::Null;
+        assert!(Container::from_inspect(&portless).is_err());
+
+        let listed = serde_json::json!([
+            {
+                "Names": ["/web"],
+                "Labels": { "innisfree.enable": "true" },
+                "Ports": [
+                    { "PrivatePort": 80, "PublicPort": 8080, "Type": "tcp", "IP": "0.0.0.0" },
+                    { "PrivatePort": 80, "PublicPort": 8080, "Type": "tcp", "IP": "::" },
+                    { "PrivatePort": 53, "Type": "udp" },
+                ],
+                "NetworkSettings": { "Networks": { "bridge": { "IPAddress": "172.17.0.2" } } },
+            },
+            {
+                "Names": ["/api"],
+                "Labels": { "innisfree.enable": "true", "innisfree.ports": "443:8443,8080" },
+                "NetworkSettings": { "Networks": { "bridge": { "IPAddress": "172.17.0.3" } } },
+            },
+            {
+                "Names": ["/clash"],
+                "Labels": { "innisfree.enable": "true", "innisfree.ports": "80" },
+                "NetworkSettings": { "Networks": { "bridge": { "IPAddress": "172.17.0.4" } } },
+            },
+            {
+                "Names": ["/broken"],
+                "Labels": { "innisfree.enable": "true" },
+                "Ports": [{ "PrivatePort": 0, "Type": "tcp" }],
+                "NetworkSettings": { "Networks": { "bridge": { "IPAddress": "172.17.0.6" } } },
+            },
+            {
+                "Names": ["/udp"],
+                "Labels": { "innisfree.enable": "true", "innisfree.ports": "53/UDP" },
+                "NetworkSettings": { "Networks": { "bridge": { "IPAddress": "172.17.0.5" } } },
+            },
+        ]);
+        let base = ServicePort::from_str_multi("22/TCP,443/TCP")?;
+        let services = with_labelled(&base, &listed);
+        let forwarded: Vec<(i32, i32, Vec<SocketAddr>)> = services
+            .into_iter()
+            .map(|s| (s.port, s.local_port, s.backends))
+            .collect();
+        let to = |addr: &str| -> Result<Vec<SocketAddr>> { Ok(vec![addr.parse()?]) };
+        assert_eq!(
+            forwarded,
+            [
+                (22, 22, vec![]),
+                (80, 80, to("172.17.0.2:80")?),
+                (443, 443, to("172.17.0.3:8443")?),
+                (8080, 8080, to("172.17.0.3:8080")?),
+            ]
+        );
+        assert_eq!(with_labelled(&base, &serde_json::json!([])), base);
+
+        let raw = b"HTTP/1.0 404 Not Found\r\nContent-Type: application/json\r\n\r\n{\"message\":\"No such container: db\"}";
+        let e = parse_response(raw).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Docker daemon returned 404: No such container: db"
+        );
+        let raw = b"HTTP/1.0 200 OK\r\n\r\n{\"Id\":\"abc\"}";
+        assert_eq!(parse_response(raw)?["Id"], "abc");
+        Ok(())
+    }
+}
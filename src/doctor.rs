@@ -1,21 +1,303 @@
 //! Utility functions for detecting dependencies.
-//! Checks whether Wireguard is installed, whether
-//! a cloud provider authorization token is present.
+//! Checks whether Wireguard is installed, and in the kernel or userspace,
+//! whether a cloud provider authorization token is present and valid,
+//! and whether the network lets the tunnel through.
 
-use anyhow::Result;
+use ipnet::IpNet;
+use serde::Serialize;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::path::Path;
 
-/// Checks that `wg-quick` is found on `$PATH`.
-/// Also checks that `DIGITALOCEAN_API_TOKEN` environment
-/// variable is set.
-pub fn platform_is_supported() -> Result<bool> {
-    let mut result: bool = std::env::var("DIGITALOCEAN_API_TOKEN").is_ok();
-    if check_if_command_exists("wg-quick") {
-        tracing::info!("Wireguard appears to be installed!");
-    } else {
-        tracing::warn!("Wireguard does not appear to be installed");
-        result = false;
+use innisfree::net::INNISFREE_SUBNET;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+/// Outcome of a check.
+pub enum Status {
+    /// Good to go.
+    Pass,
+    /// Tunnels should work, but may be slower, or fail in some setups.
+    Warn,
+    /// Tunnels won't work until fixed.
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+/// Outcome of a single check, e.g. whether Wireguard is installed.
+pub struct Check {
+    /// Short identifier for the check, e.g. `wireguard`.
+    pub name: &'static str,
+    /// Whether the check passed, or only warned.
+    pub ok: bool,
+    /// Whether the check passed, warned, or failed.
+    pub status: Status,
+    /// Human-readable summary of the outcome.
+    pub message: String,
+    /// How to fix a warning or failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, message: impl Into<String>) -> Check {
+        Check {
+            name,
+            ok: status != Status::Fail,
+            status,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Check {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+/// Runs all checks: that `wg-quick` is found on `$PATH`, with Wireguard
+/// in the kernel, or a userspace fallback, that the DigitalOcean API token
+/// can be read, see [innisfree::token], and is valid for another droplet, that
+/// Wireguard's packets can leave, that IP forwarding is on, and that the
+/// tunnels' subnet is free.
+pub async fn run_checks() -> Vec<Check> {
+    let wg = check_if_command_exists("wg-quick");
+    let token = innisfree::token::digitalocean();
+    let mut checks = vec![
+        if wg {
+            Check::new(
+                "wireguard",
+                Status::Pass,
+                "Wireguard appears to be installed!",
+            )
+        } else {
+            Check::new(
+                "wireguard",
+                Status::Fail,
+                "Wireguard does not appear to be installed",
+            )
+            .hint("Install wireguard-tools, e.g. `apt install wireguard-tools`")
+        },
+        check_kernel_wireguard(),
+        match &token {
+            Ok(_) => Check::new(
+                "digitalocean_token",
+                Status::Pass,
+                "DigitalOcean API token found",
+            ),
+            Err(e) => Check::new(
+                "digitalocean_token",
+                Status::Fail,
+                format!("DigitalOcean API token not found: {:#}", e),
+            )
+            .hint("Create a token with read and write scopes at https://cloud.digitalocean.com/account/api/tokens"),
+        },
+    ];
+    if token.is_ok() {
+        checks.push(check_digitalocean_account().await);
+    }
+    checks.push(check_outbound_udp());
+    checks.push(check_ip_forwarding());
+    checks.push(check_subnet(
+        &std::fs::read_to_string("/proc/net/route").unwrap_or_default(),
+    ));
+    checks
+}
+
+/// Checks whether Wireguard runs in the kernel, either loaded or available
+/// as a module, or else in userspace, which is slower.
+fn check_kernel_wireguard() -> Check {
+    let name = "wireguard_kernel";
+    if Path::new("/sys/module/wireguard").exists() {
+        return Check::new(name, Status::Pass, "Wireguard kernel module is loaded");
+    }
+    let modinfo = std::process::Command::new("modinfo")
+        .arg("wireguard")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+    if matches!(modinfo, Ok(s) if s.success()) {
+        return Check::new(name, Status::Pass, "Wireguard kernel module is available");
+    }
+    match ["wireguard-go", "boringtun"]
+        .into_iter()
+        .find(|cmd| check_if_command_exists(cmd))
+    {
+        Some(cmd) => Check::new(
+            name,
+            Status::Warn,
+            format!("Wireguard isn't in the kernel, falling back to {}", cmd),
+        )
+        .hint("Userspace Wireguard is slower; use a kernel 5.6 or newer, or install the wireguard-dkms package"),
+        None => Check::new(
+            name,
+            Status::Fail,
+            "Wireguard is neither in the kernel nor in userspace",
+        )
+        .hint("Use a kernel 5.6 or newer, or install wireguard-dkms, or wireguard-go"),
+    }
+}
+
+#[cfg(feature = "digitalocean")]
+/// Checks that the DigitalOcean token is accepted, and that the account
+/// may create another droplet.
+async fn check_digitalocean_account() -> Check {
+    use innisfree::server::digitalocean::client::DoApiClient;
+
+    let name = "digitalocean_account";
+    let result = async {
+        let client = DoApiClient::new()?;
+        let account = client.get("/account").await?;
+        let droplets = client
+            .get_with_query("/droplets", &[("per_page", "1")])
+            .await?;
+        anyhow::Ok((account, droplets))
+    }
+    .await;
+    match result {
+        Ok((account, droplets)) => droplet_headroom(&account, &droplets),
+        Err(e) => Check::new(
+            name,
+            Status::Fail,
+            format!("DigitalOcean rejected the token: {:#}", e),
+        )
+        .hint("Check that DIGITALOCEAN_API_TOKEN is current, with read and write scopes"),
     }
-    Ok(result)
+}
+
+#[cfg(not(feature = "digitalocean"))]
+/// Without DigitalOcean support, there's no account to check.
+async fn check_digitalocean_account() -> Check {
+    Check::new(
+        "digitalocean_account",
+        Status::Warn,
+        "Built without DigitalOcean support, not checking the account",
+    )
+}
+
+#[cfg_attr(not(feature = "digitalocean"), allow(dead_code))]
+/// Checks the account's droplet limit against the droplets it has, from the
+/// API's `/account` and `/droplets` responses.
+fn droplet_headroom(account: &serde_json::Value, droplets: &serde_json::Value) -> Check {
+    let name = "digitalocean_account";
+    let account = &account["account"];
+    if account["status"].as_str().is_some_and(|s| s != "active") {
+        return Check::new(
+            name,
+            Status::Fail,
+            format!(
+                "DigitalOcean account is {}",
+                account["status"].as_str().unwrap_or_default()
+            ),
+        )
+        .hint("See https://cloud.digitalocean.com/account for why");
+    }
+    let limit = account["droplet_limit"].as_u64().unwrap_or_default();
+    let used = droplets["meta"]["total"].as_u64().unwrap_or_default();
+    let message = format!(
+        "DigitalOcean token is valid, with {} of {} droplets in use",
+        used, limit
+    );
+    let hint =
+        "Destroy unused droplets, e.g. via `innisfree gc`, or ask DigitalOcean to raise the limit";
+    match limit.saturating_sub(used) {
+        0 => Check::new(name, Status::Fail, message).hint(hint),
+        1 => Check::new(name, Status::Warn, message).hint(hint),
+        _ => Check::new(name, Status::Pass, message),
+    }
+}
+
+/// Checks that Wireguard's packets to the default port, 51820/UDP, have a
+/// route off this machine. Firewalls elsewhere can only be found by trying.
+fn check_outbound_udp() -> Check {
+    let name = "outbound_udp";
+    // Connecting a UDP socket sends nothing, but picks a route.
+    let routed = UdpSocket::bind("0.0.0.0:0").and_then(|s| s.connect("1.1.1.1:51820"));
+    match routed {
+        Ok(()) => Check::new(
+            name,
+            Status::Pass,
+            "Outbound 51820/UDP has a route, though firewalls further along may still drop it",
+        ),
+        Err(e) => Check::new(
+            name,
+            Status::Fail,
+            format!("Outbound 51820/UDP has no route: {}", e),
+        )
+        .hint("Check the network connection; if UDP is blocked, pass `--transport tcp` or `--wg-port` to `up`"),
+    }
+}
+
+/// Checks that IPv4 forwarding is on, as needed to route tunnel traffic
+/// on to other hosts, rather than via the local proxy.
+fn check_ip_forwarding() -> Check {
+    let name = "ip_forwarding";
+    match std::fs::read_to_string("/proc/sys/net/ipv4/ip_forward") {
+        Ok(v) if v.trim() == "1" => Check::new(name, Status::Pass, "IP forwarding is on"),
+        Ok(_) => Check::new(name, Status::Warn, "IP forwarding is off")
+            .hint("Only needed to route traffic to other hosts, e.g. with `--dnat`; turn it on via `sysctl -w net.ipv4.ip_forward=1`"),
+        Err(e) => Check::new(
+            name,
+            Status::Warn,
+            format!("Failed to read IP forwarding sysctl: {}", e),
+        ),
+    }
+}
+
+/// Parses the kernel's IPv4 routing table, i.e. `/proc/net/route`, into
+/// each route's interface and destination. Addresses are in hex, in host
+/// byte order.
+fn parse_routes(table: &str) -> Vec<(String, IpNet)> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split_whitespace().collect();
+            let dest = u32::from_str_radix(fields.get(1)?, 16).ok()?;
+            let mask = u32::from_str_radix(fields.get(7)?, 16).ok()?;
+            let net = IpNet::new(
+                Ipv4Addr::from(dest.to_le_bytes()).into(),
+                mask.count_ones() as u8,
+            )
+            .ok()?;
+            Some((fields[0].to_string(), net))
+        })
+        .collect()
+}
+
+/// Checks that no routes in `table`, as in `/proc/net/route`, overlap the
+/// range tunnels' subnets are picked from, e.g. for a VPN, other than
+/// tunnels' own, which take a /30 each.
+fn check_subnet(table: &str) -> Check {
+    let name = "wireguard_subnet";
+    let parent: IpNet = match INNISFREE_SUBNET.parse::<IpNet>() {
+        Ok(n) => n.trunc(),
+        Err(e) => return Check::new(name, Status::Fail, e.to_string()),
+    };
+    let clashes: Vec<String> = parse_routes(table)
+        .into_iter()
+        .filter(|(_, net)| net.prefix_len() > 0)
+        .filter(|(_, net)| parent.contains(net) || net.contains(&parent))
+        .filter(|(_, net)| !(parent.contains(net) && net.prefix_len() >= 30))
+        .map(|(iface, net)| format!("{} via {}", net, iface))
+        .collect();
+    if clashes.is_empty() {
+        return Check::new(
+            name,
+            Status::Pass,
+            format!("Tunnel subnet {} is free", parent),
+        );
+    }
+    Check::new(
+        name,
+        Status::Warn,
+        format!(
+            "Tunnel subnet {} overlaps routes: {}",
+            parent,
+            clashes.join(", ")
+        ),
+    )
+    .hint("Pass another private range to `up` via `--wg-subnet`, e.g. `--wg-subnet 10.77.0.0/28`")
 }
 
 /// Search for given program on `$PATH`.
@@ -42,4 +324,42 @@ mod tests {
     fn missing_cmd_does_not_exist() {
         assert!(!check_if_command_exists("wg-quick2"));
     }
+
+    #[test]
+    fn network_and_account_checked() {
+        let table =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                     eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+                     eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n\
+                     innisfree\t0000320A\t00000000\t0001\t0\t0\t0\tFCFFFFFF\t0\t0\t0\n";
+        assert_eq!(
+            parse_routes(table)[1],
+            ("eth0".to_string(), "192.168.1.0/24".parse().unwrap())
+        );
+        // Tunnels' own /30s don't clash
+        assert_eq!(check_subnet(table).status, Status::Pass);
+        let vpn = format!(
+            "{}tun0\t0000000A\t00000000\t0001\t0\t0\t0\t000000FF\t0\t0\t0\n",
+            table
+        );
+        let check = check_subnet(&vpn);
+        assert_eq!(check.status, Status::Warn);
+        assert!(check.message.ends_with("10.0.0.0/8 via tun0"));
+
+        let account = serde_json::json!({ "account": { "droplet_limit": 10, "status": "active" } });
+        let droplets = |n: u64| serde_json::json!({ "droplets": [], "meta": { "total": n } });
+        assert_eq!(
+            droplet_headroom(&account, &droplets(3)).status,
+            Status::Pass
+        );
+        assert_eq!(
+            droplet_headroom(&account, &droplets(9)).status,
+            Status::Warn
+        );
+        let full = droplet_headroom(&account, &droplets(10));
+        assert_eq!(full.status, Status::Fail);
+        assert!(!full.ok);
+        let locked = serde_json::json!({ "account": { "droplet_limit": 10, "status": "locked" } });
+        assert_eq!(droplet_headroom(&locked, &droplets(0)).status, Status::Fail);
+    }
 }
@@ -0,0 +1,295 @@
+//! Typed errors for the library's public API, so embedding applications
+//! can branch on the class of failure, e.g. retrying when a provider's API
+//! is rate limited, but aborting when its credentials are rejected.
+//!
+//! Internally, errors are [anyhow::Error]s, with context added as they
+//! propagate. Where the class of failure is known, the root cause is
+//! created via the helpers here, e.g. [ssh], and [InnisfreeError] finds
+//! it again at the API boundary, keeping the full chain as its source.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How a provider's API rejected a request, see [InnisfreeError::Provider].
+pub enum ProviderFailure {
+    /// Credentials are missing, invalid, or lack the required permissions.
+    Unauthorized,
+    /// Too many requests, so retrying later may succeed.
+    RateLimited,
+    /// The account hit a resource limit, e.g. on servers.
+    QuotaExceeded,
+    /// The requested resource doesn't exist.
+    NotFound,
+    /// Any other failure, e.g. a server error, or a network error.
+    Other,
+}
+
+#[derive(Debug)]
+/// Error returned by the library's public API, classified by cause.
+/// The source holds the full chain of context, as for [anyhow::Error].
+#[non_exhaustive]
+pub enum InnisfreeError {
+    /// A cloud provider's API failed, or rejected a request.
+    Provider {
+        /// How the request failed.
+        failure: ProviderFailure,
+        /// Chain of errors leading to the failure.
+        source: anyhow::Error,
+    },
+    /// Connecting to the server via SSH, or a command run over it, failed.
+    Ssh(anyhow::Error),
+    /// Configuring or bringing up a Wireguard interface failed.
+    Wireguard(anyhow::Error),
+    /// The requested configuration is invalid, e.g. an unparseable port.
+    Config(anyhow::Error),
+    /// Waiting on something, e.g. the server's boot, took too long.
+    Timeout(anyhow::Error),
+    /// The operation was interrupted, see [crate::manager::TunnelManager::shutdown].
+    Cancelled(anyhow::Error),
+    /// Any other failure, e.g. reading a local file.
+    Other(anyhow::Error),
+}
+
+impl InnisfreeError {
+    /// Returns the full chain of errors leading to the failure.
+    pub fn source_chain(&self) -> &anyhow::Error {
+        match self {
+            InnisfreeError::Provider { source, .. } => source,
+            InnisfreeError::Ssh(e)
+            | InnisfreeError::Wireguard(e)
+            | InnisfreeError::Config(e)
+            | InnisfreeError::Timeout(e)
+            | InnisfreeError::Cancelled(e)
+            | InnisfreeError::Other(e) => e,
+        }
+    }
+
+    /// Whether a provider's API rate limited the request, so retrying later may succeed.
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(
+            self,
+            InnisfreeError::Provider {
+                failure: ProviderFailure::RateLimited,
+                ..
+            }
+        )
+    }
+
+    /// Whether a provider's API rejected the credentials, so retrying won't help.
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(
+            self,
+            InnisfreeError::Provider {
+                failure: ProviderFailure::Unauthorized,
+                ..
+            }
+        )
+    }
+}
+
+impl fmt::Display for InnisfreeError {
+    /// Shows the outermost message, as [anyhow::Error] does, with
+    /// the rest of the chain available via `source()`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source_chain())
+    }
+}
+
+impl std::error::Error for InnisfreeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // The outermost message is already shown by Display.
+        self.source_chain().source()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Class of a root cause, see [classified].
+enum Class {
+    Provider(ProviderFailure),
+    Ssh,
+    Wireguard,
+    Config,
+    Timeout,
+    Cancelled,
+}
+
+#[derive(Debug)]
+/// Root cause of a known class, carried inside an [anyhow::Error].
+struct Classified {
+    class: Class,
+    message: String,
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Classified {}
+
+/// Builds an error of the given class, displayed as `message`.
+fn classified(class: Class, message: String) -> anyhow::Error {
+    anyhow::Error::new(Classified { class, message })
+}
+
+/// Builds an error for a provider's API failing as `failure`, e.g. so
+/// a [crate::server::ServerProvider] implemented outside the crate can
+/// report rate limits.
+pub fn provider<M: fmt::Display>(failure: ProviderFailure, message: M) -> anyhow::Error {
+    classified(Class::Provider(failure), message.to_string())
+}
+
+/// Builds an error for a failed SSH connection or remote command.
+pub fn ssh<M: fmt::Display>(message: M) -> anyhow::Error {
+    classified(Class::Ssh, message.to_string())
+}
+
+/// Builds an error for a failure to configure or bring up Wireguard.
+pub fn wireguard<M: fmt::Display>(message: M) -> anyhow::Error {
+    classified(Class::Wireguard, message.to_string())
+}
+
+/// Adds `message` to `e`, as [anyhow::Context] does, classifying the
+/// failure as a Wireguard one, e.g. when bringing up the interface over SSH.
+pub fn wireguard_context<M: fmt::Display>(e: anyhow::Error, message: M) -> anyhow::Error {
+    e.context(Classified {
+        class: Class::Wireguard,
+        message: message.to_string(),
+    })
+}
+
+/// Builds an error for an invalid configuration.
+pub fn config<M: fmt::Display>(message: M) -> anyhow::Error {
+    classified(Class::Config, message.to_string())
+}
+
+/// Builds an error for something that took too long.
+pub fn timeout<M: fmt::Display>(message: M) -> anyhow::Error {
+    classified(Class::Timeout, message.to_string())
+}
+
+/// Builds an error for an operation interrupted by a cancellation token.
+pub fn cancelled<M: fmt::Display>(message: M) -> anyhow::Error {
+    classified(Class::Cancelled, message.to_string())
+}
+
+/// Classifies a provider's HTTP status code.
+#[cfg_attr(
+    not(any(
+        feature = "digitalocean",
+        feature = "linode",
+        feature = "azure",
+        feature = "scaleway",
+        feature = "oci"
+    )),
+    allow(dead_code)
+)]
+fn status_failure(status: u16) -> ProviderFailure {
+    match status {
+        401 | 403 => ProviderFailure::Unauthorized,
+        404 => ProviderFailure::NotFound,
+        429 => ProviderFailure::RateLimited,
+        _ => ProviderFailure::Other,
+    }
+}
+
+/// Finds the class of a root cause within the chain, if known.
+fn classify(e: &anyhow::Error) -> Option<Class> {
+    // Finds the outermost class, whether added as context or the root cause.
+    if let Some(c) = e.downcast_ref::<Classified>() {
+        return Some(c.class);
+    }
+    for cause in e.chain() {
+        if cause.is::<crate::ssh::SshCommandError>() {
+            return Some(Class::Ssh);
+        }
+        if let Some(e) = cause.downcast_ref::<InnisfreeError>() {
+            return classify(e.source_chain());
+        }
+        #[cfg(feature = "digitalocean")]
+        if let Some(e) = cause.downcast_ref::<crate::server::digitalocean::client::DoApiError>() {
+            use crate::server::digitalocean::client::DoApiError;
+            let failure = match e {
+                DoApiError::Unauthorized(_) => ProviderFailure::Unauthorized,
+                DoApiError::RateLimited => ProviderFailure::RateLimited,
+                DoApiError::QuotaExceeded(_) => ProviderFailure::QuotaExceeded,
+                DoApiError::NotFound(_) => ProviderFailure::NotFound,
+                _ => ProviderFailure::Other,
+            };
+            return Some(Class::Provider(failure));
+        }
+        // Providers other than DigitalOcean surface error responses as-is.
+        #[cfg(any(
+            feature = "digitalocean",
+            feature = "linode",
+            feature = "azure",
+            feature = "scaleway",
+            feature = "oci"
+        ))]
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            let failure = match e.status() {
+                Some(s) => status_failure(s.as_u16()),
+                None => ProviderFailure::Other,
+            };
+            return Some(Class::Provider(failure));
+        }
+    }
+    None
+}
+
+impl From<anyhow::Error> for InnisfreeError {
+    fn from(e: anyhow::Error) -> Self {
+        match classify(&e) {
+            Some(Class::Provider(failure)) => InnisfreeError::Provider { failure, source: e },
+            Some(Class::Ssh) => InnisfreeError::Ssh(e),
+            Some(Class::Wireguard) => InnisfreeError::Wireguard(e),
+            Some(Class::Config) => InnisfreeError::Config(e),
+            Some(Class::Timeout) => InnisfreeError::Timeout(e),
+            Some(Class::Cancelled) => InnisfreeError::Cancelled(e),
+            None => InnisfreeError::Other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn errors_classified_through_context() {
+        let e: anyhow::Result<()> = Err(ssh("wg-quick exited with 1"));
+        let e = InnisfreeError::from(e.context("failed to bring up remote wg").unwrap_err());
+        assert!(matches!(e, InnisfreeError::Ssh(_)));
+        assert_eq!(e.to_string(), "failed to bring up remote wg");
+        let source = std::error::Error::source(&e).map(|s| s.to_string());
+        assert_eq!(source.as_deref(), Some("wg-quick exited with 1"));
+
+        // Classes survive a round trip through anyhow, e.g. via `?`.
+        let e = InnisfreeError::from(anyhow::Error::new(InnisfreeError::from(timeout("no ssh"))));
+        assert!(matches!(e, InnisfreeError::Timeout(_)));
+
+        let e = InnisfreeError::from(anyhow::anyhow!("disk full"));
+        assert!(matches!(e, InnisfreeError::Other(_)));
+        assert_eq!(status_failure(429), ProviderFailure::RateLimited);
+        let e = provider(ProviderFailure::RateLimited, "slow down");
+        assert!(InnisfreeError::from(e).is_rate_limited());
+
+        // Context classifies failures of remote commands more precisely.
+        let e = wireguard_context(ssh("wg-quick exited with 1"), "no kernel module");
+        let e = InnisfreeError::from(e.context("failed to bring up remote wg"));
+        assert!(matches!(e, InnisfreeError::Wireguard(_)));
+    }
+
+    #[test]
+    #[cfg(feature = "digitalocean")]
+    fn provider_failures_classified() {
+        use crate::server::digitalocean::client::DoApiError;
+        let e = InnisfreeError::from(anyhow::Error::new(DoApiError::RateLimited));
+        assert!(e.is_rate_limited());
+        let e = anyhow::Error::new(DoApiError::Unauthorized("bad token".to_string()))
+            .context("Failed to create droplet");
+        assert!(InnisfreeError::from(e).is_unauthorized());
+    }
+}
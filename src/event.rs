@@ -0,0 +1,126 @@
+//! Typed events emitted by a [crate::manager::TunnelManager] as the tunnel
+//! changes state, e.g. so library consumers can drive their own UI,
+//! rather than parsing logs. Subscribe via [crate::manager::TunnelManager::subscribe].
+//! Some events are also delivered to [crate::webhook]s.
+
+use serde::Serialize;
+use std::net::IpAddr;
+
+/// How many events a slow subscriber may fall behind by, before missing some.
+pub const EVENT_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+/// Change in a tunnel's lifecycle.
+pub enum TunnelEvent {
+    /// Creating the server via the provider's API has started.
+    ServerCreating,
+    /// The server booted, and has the public IPv4 address `ip`.
+    ServerReady {
+        /// Public IPv4 address of the server itself.
+        ip: IpAddr,
+    },
+    /// Both ends of the Wireguard tunnel are up, and can reach each other.
+    WireguardUp,
+    /// A local proxy started forwarding the service published on `port`.
+    ProxyStarted {
+        /// Public port of the service.
+        port: i32,
+    },
+    /// Tunnel is ready, and serving traffic on `public_ip`.
+    Up {
+        /// Public IP on which services are published.
+        public_ip: IpAddr,
+    },
+    /// Tunnel failed several health checks in a row, and is being re-established.
+    Degraded {
+        /// Consecutive failed health checks.
+        failures: u32,
+    },
+    /// Server was replaced by a new one, e.g. after the provider deleted it.
+    Recreated {
+        /// Public IP on which services are published, which changed,
+        /// unless a Floating IP is attached.
+        public_ip: IpAddr,
+    },
+    /// Tunnel was torn down, and its server destroyed.
+    Destroyed,
+}
+
+impl TunnelEvent {
+    /// Short name for the event, e.g. `server_ready`, as serialized.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TunnelEvent::ServerCreating => "server_creating",
+            TunnelEvent::ServerReady { .. } => "server_ready",
+            TunnelEvent::WireguardUp => "wireguard_up",
+            TunnelEvent::ProxyStarted { .. } => "proxy_started",
+            TunnelEvent::Up { .. } => "up",
+            TunnelEvent::Degraded { .. } => "degraded",
+            TunnelEvent::Recreated { .. } => "recreated",
+            TunnelEvent::Destroyed => "destroyed",
+        }
+    }
+
+    /// Describes the event for a human, e.g. in a chat message.
+    pub fn message(&self, service_name: &str) -> String {
+        match self {
+            TunnelEvent::ServerCreating => {
+                format!("Creating server for tunnel '{}'", service_name)
+            }
+            TunnelEvent::ServerReady { ip } => {
+                format!("Server for tunnel '{}' is ready at {}", service_name, ip)
+            }
+            TunnelEvent::WireguardUp => {
+                format!("Wireguard is up for tunnel '{}'", service_name)
+            }
+            TunnelEvent::ProxyStarted { port } => format!(
+                "Tunnel '{}' is proxying port {} locally",
+                service_name, port
+            ),
+            TunnelEvent::Up { public_ip } => {
+                format!("Tunnel '{}' is up on {}", service_name, public_ip)
+            }
+            TunnelEvent::Degraded { failures } => format!(
+                "Tunnel '{}' is degraded, after {} failed health checks",
+                service_name, failures
+            ),
+            TunnelEvent::Recreated { public_ip } => format!(
+                "Tunnel '{}' moved to a new server, serving on {}",
+                service_name, public_ip
+            ),
+            TunnelEvent::Destroyed => format!("Tunnel '{}' is down", service_name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_serialized_by_name() -> anyhow::Result<()> {
+        let events = vec![
+            TunnelEvent::ServerCreating,
+            TunnelEvent::ServerReady {
+                ip: "203.0.113.5".parse()?,
+            },
+            TunnelEvent::WireguardUp,
+            TunnelEvent::ProxyStarted { port: 443 },
+            TunnelEvent::Up {
+                public_ip: "203.0.113.5".parse()?,
+            },
+            TunnelEvent::Degraded { failures: 3 },
+            TunnelEvent::Recreated {
+                public_ip: "203.0.113.5".parse()?,
+            },
+            TunnelEvent::Destroyed,
+        ];
+        for e in events {
+            assert_eq!(serde_json::to_value(&e)?["event"], e.name());
+        }
+        let j = serde_json::to_value(TunnelEvent::ProxyStarted { port: 443 })?;
+        assert_eq!(j["port"], 443);
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! innisfree itself as the server's proxy, in place of nginx, see `innisfree
+//! up --remote-proxy innisfree`. The server runs `innisfree proxy` as a
+//! service, listening on the public ports and forwarding over the tunnel, so
+//! both ends share the same proxy code. Cloud-init downloads the release
+//! matching the local version, unless a static build is uploaded instead.
+
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv6Addr};
+
+use crate::config::ServicePort;
+
+/// Where the server downloads innisfree from, pinned to the local version.
+/// The release build is linked statically, so runs on any distro.
+pub const RELEASE_URL: &str = concat!(
+    "https://github.com/conorsch/innisfree/releases/download/v",
+    env!("CARGO_PKG_VERSION"),
+    "/innisfree-x86_64-unknown-linux-musl"
+);
+/// Path to `innisfree` on the server.
+pub const SERVER_BINARY: &str = "/usr/local/bin/innisfree";
+/// Path on the server to the environment file configuring the service.
+pub const ENV_PATH: &str = "/etc/innisfree/forwarder.env";
+/// Directory on the server holding the service's logs.
+const LOG_DIR: &str = "/var/log/innisfree";
+/// Name of the service on the server.
+pub const SERVICE_NAME: &str = "innisfree-forwarder";
+/// Path to the service's unit on the server.
+pub const SERVER_UNIT_PATH: &str = "/etc/systemd/system/innisfree-forwarder.service";
+
+/// Address on which the server listens, i.e. all of them. Linux accepts IPv4
+/// connections on IPv6 sockets, too.
+const LISTEN_IP: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+
+/// Renders the environment file for `innisfree proxy`, forwarding each
+/// service's public port to its local port on `dest_ip`. The port pairs
+/// are reversed, since the proxy listens on the "local" port of each.
+pub fn server_env(services: &[ServicePort], dest_ip: IpAddr) -> String {
+    let ports: Vec<String> = services
+        .iter()
+        .map(|s| format!("{}:{}/{}", s.local_port, s.port, s.protocol))
+        .collect();
+    format!(
+        "INNISFREE_PORTS={}\nINNISFREE_DEST_IP={}\nINNISFREE_LISTEN_IP={}\n",
+        ports.join(","),
+        dest_ip,
+        LISTEN_IP
+    )
+}
+
+/// Renders the systemd unit running `innisfree proxy` with [ENV_PATH].
+/// Restarting the unit applies an updated environment file, dropping
+/// open connections.
+pub fn server_unit() -> Result<String> {
+    let mut context = tera::Context::new();
+    context.insert("binary", SERVER_BINARY);
+    context.insert("env_file", ENV_PATH);
+    context.insert("log_file", &format!("{}/forwarder.log", LOG_DIR));
+    tera::Tera::one_off(
+        include_str!("../files/forwarder.service.j2"),
+        &context,
+        false,
+    )
+    .context("Failed to render forwarder unit")
+}
+
+/// Commands for cloud-init, installing innisfree on the server, then
+/// starting it. If `download` isn't set, the binary is uploaded once the
+/// server is up, so the service is only enabled, see [install_cmd].
+/// Nginx is stopped first, in case the server booted from a prebuilt image
+/// with it installed, since both listen on the same ports.
+pub fn server_runcmd(download: bool) -> Vec<Vec<String>> {
+    let mut runcmd = vec![
+        vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            "systemctl disable --now nginx || true".to_string(),
+        ],
+        vec!["mkdir".to_string(), "-p".to_string(), LOG_DIR.to_string()],
+    ];
+    let mut enable = vec!["systemctl".to_string(), "enable".to_string()];
+    if download {
+        let install = format!(
+            "curl -fsSL {} -o {} && chmod 755 {}",
+            RELEASE_URL, SERVER_BINARY, SERVER_BINARY
+        );
+        runcmd.push(vec!["sh".to_string(), "-c".to_string(), install]);
+        enable.push("--now".to_string());
+    }
+    enable.push(SERVICE_NAME.to_string());
+    runcmd.push(enable);
+    runcmd
+}
+
+/// Command installing a binary uploaded to `uploaded` on the server as
+/// [SERVER_BINARY], then restarting the service to run it.
+pub fn install_cmd(uploaded: &str) -> Vec<String> {
+    let install = format!(
+        "install -m 755 {} {} && rm {} && systemctl restart {}",
+        uploaded, SERVER_BINARY, uploaded, SERVICE_NAME
+    );
+    vec![
+        "sudo".to_string(),
+        "sh".to_string(),
+        "-c".to_string(),
+        install,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_reverses_ports() -> Result<()> {
+        let services = ServicePort::from_str_multi("443:8443/TCP,80:8000/TCP")?;
+        let env = server_env(&services, "10.50.0.2".parse()?);
+        assert!(env.contains("INNISFREE_PORTS=8443:443/TCP,8000:80/TCP\n"));
+        assert!(env.contains("INNISFREE_DEST_IP=10.50.0.2\n"));
+        assert!(env.contains("INNISFREE_LISTEN_IP=::\n"));
+        // The proxy listens on the public ports, and forwards to the local ones.
+        let line = env.lines().next().unwrap();
+        let parsed = ServicePort::from_str_multi(line.trim_start_matches("INNISFREE_PORTS="))?;
+        assert_eq!((parsed[0].local_port, parsed[0].port), (443, 8443));
+
+        let unit = server_unit()?;
+        assert!(unit.contains("ExecStart=/usr/local/bin/innisfree proxy"));
+        assert!(unit.contains("EnvironmentFile=/etc/innisfree/forwarder.env"));
+        assert!(RELEASE_URL.contains(env!("CARGO_PKG_VERSION")));
+
+        let runcmd = server_runcmd(true);
+        assert!(runcmd[2][2].contains(RELEASE_URL));
+        assert_eq!(runcmd.last().unwrap()[2], "--now");
+        let runcmd = server_runcmd(false);
+        assert!(!runcmd.iter().any(|c| c.join(" ").contains("curl")));
+        assert_eq!(runcmd.last().unwrap()[2], SERVICE_NAME);
+        // Run as a single script, by sh
+        let cmd = install_cmd("/tmp/innisfree");
+        assert_eq!(cmd[..3], ["sudo", "sh", "-c"]);
+        assert!(cmd[3].starts_with("install -m 755 /tmp/innisfree "));
+        assert!(cmd[3].ends_with("&& systemctl restart innisfree-forwarder"));
+        Ok(())
+    }
+}
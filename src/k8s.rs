@@ -0,0 +1,283 @@
+//! Exposure of Kubernetes Services, see `innisfree up --k8s`, making the
+//! tunnel a load balancer for a home cluster, e.g. k3s or kind. Services
+//! annotated `innisfree.io/expose: "true"` are forwarded, alongside
+//! `--ports`: each TCP port on its own port number, balanced to its
+//! NodePort on a node, see [crate::balance], so the Service must be of
+//! type NodePort or LoadBalancer. The public IP is written back to the
+//! Service's status, as a cloud load balancer would. To expose Ingresses,
+//! annotate the ingress controller's Service.
+//!
+//! The cluster is reached via `kubectl`, so it's configured as usual,
+//! e.g. via `KUBECONFIG`, and polled for changes every few seconds.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::config::{self, ServicePort};
+use crate::control::{self, ControlRequest};
+
+/// Annotation marking Services to forward.
+const EXPOSE_ANNOTATION: &str = "innisfree.io/expose";
+/// How often the cluster's Services are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Runs `kubectl` with `args`, returning its output, or failing with its errors.
+fn kubectl(args: &[&str]) -> Result<String> {
+    let output = Command::new("kubectl")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run kubectl, is it installed?")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "kubectl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Returns the address on which NodePorts are reached: the first node's
+/// internal IP, from the cluster's list of nodes.
+fn node_ip(nodes: &Value) -> Result<IpAddr> {
+    let ip = nodes["items"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|n| n["status"]["addresses"].as_array().into_iter().flatten())
+        .find(|a| a["type"] == "InternalIP")
+        .and_then(|a| a["address"].as_str())
+        .ok_or_else(|| anyhow!("No node in the cluster has an internal IP"))?;
+    Ok(ip.parse()?)
+}
+
+/// Returns a Service's `<namespace>/<name>`.
+fn service_name(svc: &Value) -> String {
+    format!(
+        "{}/{}",
+        svc["metadata"]["namespace"].as_str().unwrap_or("default"),
+        svc["metadata"]["name"].as_str().unwrap_or_default()
+    )
+}
+
+/// Returns the services to forward for an annotated Service: one per TCP
+/// port, balanced to its NodePort on `node_ip`, and listening locally on the
+/// public port.
+fn annotated_services(svc: &Value, node_ip: IpAddr) -> Result<Vec<ServicePort>> {
+    let mut services = vec![];
+    for p in svc["spec"]["ports"].as_array().into_iter().flatten() {
+        let port = config::parse_port(&p["port"].to_string())?;
+        if p["protocol"].as_str().unwrap_or("TCP") != "TCP" {
+            tracing::warn!(
+                "Only TCP ports are forwarded, skipping port {} of Service '{}'",
+                port,
+                service_name(svc)
+            );
+            continue;
+        }
+        let node_port = match p["nodePort"].as_u64() {
+            Some(n) => u16::try_from(n)?,
+            None => continue,
+        };
+        services.push(ServicePort {
+            port,
+            local_port: port,
+            backends: vec![SocketAddr::new(node_ip, node_port)],
+            ..Default::default()
+        });
+    }
+    if services.is_empty() {
+        return Err(anyhow!(
+            "no TCP NodePorts, make it a NodePort or LoadBalancer Service"
+        ));
+    }
+    Ok(services)
+}
+
+/// Returns `base` with the services of the annotated Services in `list`, as
+/// `kubectl get services` lists them, added, see [config::with_discovered],
+/// and the names of the Services forwarded.
+fn with_annotated(
+    base: &[ServicePort],
+    list: &Value,
+    node_ip: IpAddr,
+) -> (Vec<ServicePort>, Vec<String>) {
+    let found: Vec<(String, Result<Vec<ServicePort>>)> = list["items"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|s| s["metadata"]["annotations"][EXPOSE_ANNOTATION] == "true")
+        .map(|s| (service_name(s), annotated_services(s, node_ip)))
+        .collect();
+    // Those skipped, e.g. clashing with another, are missing from the result.
+    let forwarded = found
+        .iter()
+        .filter_map(|(name, services)| Some((name, services.as_ref().ok()?)))
+        .map(|(name, services)| (name.clone(), services.clone()))
+        .collect::<Vec<_>>();
+    let sources = found
+        .into_iter()
+        .map(|(name, services)| (format!("Service '{}'", name), services))
+        .collect();
+    let services = config::with_discovered(base, sources);
+    let exposed = forwarded
+        .into_iter()
+        .filter(|(_, s)| s.iter().all(|s| services.contains(s)))
+        .map(|(name, _)| name)
+        .collect();
+    (services, exposed)
+}
+
+/// Returns `base` with the services of every annotated Service in the
+/// cluster added, and the names of the Services forwarded, see [crate::k8s].
+pub async fn annotated(base: &[ServicePort]) -> Result<(Vec<ServicePort>, Vec<String>)> {
+    let base = base.to_vec();
+    tokio::task::spawn_blocking(move || {
+        let nodes: Value = serde_json::from_str(&kubectl(&["get", "nodes", "-o", "json"])?)?;
+        let list: Value = serde_json::from_str(&kubectl(&[
+            "get",
+            "services",
+            "--all-namespaces",
+            "-o",
+            "json",
+        ])?)?;
+        Ok(with_annotated(&base, &list, node_ip(&nodes)?))
+    })
+    .await?
+}
+
+/// Writes `ip` to the Service's status as its load balancer's address,
+/// or clears it, if `None`.
+async fn set_ingress(service: &str, ip: Option<IpAddr>) -> Result<()> {
+    let (namespace, name) = service.split_once('/').unwrap_or(("default", service));
+    let ingress = match ip {
+        Some(ip) => serde_json::json!([{ "ip": ip }]),
+        None => serde_json::json!([]),
+    };
+    let patch = serde_json::json!({ "status": { "loadBalancer": { "ingress": ingress } } });
+    let args = [
+        "patch",
+        "service",
+        name,
+        "--namespace",
+        namespace,
+        "--subresource=status",
+        "--type=merge",
+        "--patch",
+        &patch.to_string(),
+    ]
+    .map(String::from);
+    tokio::task::spawn_blocking(move || {
+        kubectl(&args.iter().map(String::as_str).collect::<Vec<_>>())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Checks the cluster's Services every [POLL_INTERVAL], forwarding those
+/// annotated through the running tunnel `name`, alongside `base`, and
+/// pointing their status at `public_ip`. Starts from `current`.
+pub fn watch(name: &str, base: Vec<ServicePort>, mut current: Vec<ServicePort>, public_ip: IpAddr) {
+    let name = name.to_string();
+    tokio::spawn(async move {
+        let mut exposed: Vec<String> = vec![];
+        loop {
+            match annotated(&base).await {
+                Ok((services, names)) => {
+                    if services != current {
+                        let request = ControlRequest::SetPorts {
+                            services: services.clone(),
+                        };
+                        match control::send(&name, &request).await {
+                            Ok(_) => current = services,
+                            Err(e) => {
+                                tracing::warn!("Failed to update services: {:#}", e);
+                                tokio::time::sleep(POLL_INTERVAL).await;
+                                continue;
+                            }
+                        }
+                    }
+                    for service in names.iter().filter(|n| !exposed.contains(n)) {
+                        tracing::info!("Exposing Service '{}' on {}", service, public_ip);
+                        if let Err(e) = set_ingress(service, Some(public_ip)).await {
+                            tracing::warn!("Failed to update status of '{}': {:#}", service, e);
+                        }
+                    }
+                    for service in exposed.iter().filter(|n| !names.contains(n)) {
+                        tracing::info!("No longer exposing Service '{}'", service);
+                        // Gone, if deleted, rather than no longer annotated.
+                        if let Err(e) = set_ingress(service, None).await {
+                            tracing::debug!("Failed to update status of '{}': {:#}", service, e);
+                        }
+                    }
+                    exposed = names;
+                }
+                Err(e) => tracing::warn!("Failed to list Services: {:#}", e),
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annotated_services_forwarded() -> Result<()> {
+        let nodes = serde_json::json!({ "items": [{ "status": { "addresses": [
+            { "type": "Hostname", "address": "k3s" },
+            { "type": "InternalIP", "address": "192.168.1.20" },
+        ] } }] });
+        let ip = node_ip(&nodes)?;
+        assert_eq!(ip, "192.168.1.20".parse::<IpAddr>()?);
+        assert!(node_ip(&serde_json::json!({ "items": [] })).is_err());
+
+        let expose = serde_json::json!({ "innisfree.io/expose": "true" });
+        let list = serde_json::json!({ "items": [
+            {
+                "metadata": { "name": "traefik", "namespace": "kube-system", "annotations": expose },
+                "spec": { "type": "LoadBalancer", "ports": [
+                    { "port": 80, "nodePort": 30080, "protocol": "TCP" },
+                    { "port": 443, "nodePort": 30443, "protocol": "TCP" },
+                    { "port": 53, "nodePort": 30053, "protocol": "UDP" },
+                ] },
+            },
+            {
+                "metadata": { "name": "internal", "namespace": "default", "annotations": expose },
+                "spec": { "type": "ClusterIP", "ports": [{ "port": 8080 }] },
+            },
+            {
+                "metadata": { "name": "clash", "namespace": "default", "annotations": expose },
+                "spec": { "type": "NodePort", "ports": [{ "port": 443, "nodePort": 31443 }] },
+            },
+            {
+                "metadata": { "name": "private", "namespace": "default" },
+                "spec": { "type": "NodePort", "ports": [{ "port": 22, "nodePort": 30022 }] },
+            },
+            {
+                "metadata": { "name": "broken", "namespace": "default", "annotations": expose },
+                "spec": { "type": "NodePort", "ports": [{ "port": 65616, "nodePort": 30080 }] },
+            },
+        ] });
+        let base = ServicePort::from_str_multi("80/TCP,8443/TCP")?;
+        let (services, exposed) = with_annotated(&base, &list, ip);
+        let forwarded: Vec<(i32, Vec<SocketAddr>)> =
+            services.into_iter().map(|s| (s.port, s.backends)).collect();
+        assert_eq!(
+            forwarded,
+            [
+                (8443, vec![]),
+                (80, vec!["192.168.1.20:30080".parse()?]),
+                (443, vec!["192.168.1.20:30443".parse()?]),
+            ]
+        );
+        assert_eq!(exposed, ["kube-system/traefik"]);
+        Ok(())
+    }
+}
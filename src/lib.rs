@@ -5,15 +5,55 @@
 //! configured, via [crate::config::ServicePort].
 //!
 //! Right now, only TCP traffic is supported, but UDP support is planned.
-//! As for cloud providers, only DigitalOcean is supported,
-//! but adding others should be fairly straightforward.
+//! Several cloud providers are supported, each behind a cargo feature
+//! of the same name (`digitalocean`, `linode`, `azure`, `scaleway`, `oci`),
+//! all enabled by default. See [crate::server::ProviderRegistry].
+//! Delivering [crate::webhook]s needs the `webhooks` feature, also a default.
+//! Updating dynamic DNS services, see [crate::dns::Dyndns], needs the `dyndns` feature, likewise,
+//! and issuing certificates via ACME the `acme` feature.
+//!
+//! Failures from [crate::manager::TunnelManager] are [crate::error::InnisfreeError]s,
+//! classified by cause, e.g. to retry only when a provider's API is rate limited.
 
 #![warn(missing_docs)]
 
+pub mod access_log;
+#[cfg(feature = "acme")]
+pub mod acme;
+pub mod balance;
+pub mod caddy;
 pub mod config;
+pub mod control;
+pub mod copy;
+pub mod credentials;
+pub mod dns;
+pub mod docker;
+pub mod error;
+pub mod event;
+pub mod forwarder;
+pub mod k8s;
+pub mod list;
+pub mod lock;
+pub mod logs;
 pub mod manager;
 pub mod net;
+pub mod peer;
+pub mod plan;
+pub mod pool;
 pub mod proxy;
+pub mod reload;
+pub mod remote_health;
+pub mod secrets;
 pub mod server;
+pub mod service_health;
+pub mod share;
+#[cfg(target_os = "linux")]
+pub mod splice;
 pub mod ssh;
+pub mod state;
+pub mod systemd;
+pub mod tls;
+pub mod token;
+pub mod udp2raw;
+pub mod webhook;
 pub mod wg;
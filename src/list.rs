@@ -0,0 +1,176 @@
+//! Summaries of every tunnel known on this machine, for `innisfree list`.
+//! Each tunnel's config dir is cross-checked against its running `up`
+//! process, via the control socket, and against the provider's API.
+
+use anyhow::Result;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::config::list_config_dirs;
+use crate::control::{self, TunnelStatus};
+use crate::manager::{get_server_ip, HANDSHAKE_TIMEOUT};
+use crate::server::ProviderRegistry;
+use crate::state::TunnelState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Overall health of a tunnel, as shown by `innisfree list`.
+pub enum Health {
+    /// The `up` process is running, and the tunnel had a recent handshake.
+    Healthy,
+    /// The `up` process is running, but the tunnel had no recent handshake.
+    Degraded,
+    /// No `up` process is running, but the server still exists,
+    /// e.g. because the process was killed. Try `innisfree down`.
+    Stopped,
+    /// The provider reports that the server no longer exists.
+    Gone,
+    /// Neither the `up` process nor the provider could be reached.
+    Unknown,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Health::Healthy => "healthy",
+            Health::Degraded => "degraded",
+            Health::Stopped => "stopped",
+            Health::Gone => "gone",
+            Health::Unknown => "unknown",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Determines a tunnel's health, from the status reported by its `up`
+/// process, if running, and whether the provider still has its server.
+fn health(status: Option<&TunnelStatus>, server_exists: Option<bool>) -> Health {
+    match (status, server_exists) {
+        (_, Some(false)) => Health::Gone,
+        (Some(s), _) => {
+            let recent = s.peers.iter().any(|p| {
+                p.latest_handshake
+                    .is_some_and(|t| t.elapsed().is_ok_and(|e| e < HANDSHAKE_TIMEOUT))
+            });
+            if recent {
+                Health::Healthy
+            } else {
+                Health::Degraded
+            }
+        }
+        (None, Some(true)) => Health::Stopped,
+        (None, None) => Health::Unknown,
+    }
+}
+
+/// Formats a duration coarsely, e.g. `2d 3h` or `5m`.
+pub fn human_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (days, hours, mins) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins)
+    } else {
+        format!("{}m", mins)
+    }
+}
+
+#[derive(Debug)]
+/// Overview of a single tunnel, as a row in `innisfree list`.
+pub struct TunnelSummary {
+    /// Name of the tunnel, i.e. its config dir.
+    pub name: String,
+    /// Public IP of the server, if known.
+    pub public_ip: Option<IpAddr>,
+    /// Public ports forwarded, if known.
+    pub ports: Option<Vec<String>>,
+    /// Time since the server was created, if recorded.
+    pub uptime: Option<Duration>,
+    /// Overall health of the tunnel.
+    pub health: Health,
+}
+
+impl TunnelSummary {
+    /// Gathers the overview for the tunnel `name`. Failures to reach the
+    /// `up` process or the provider are reflected in the summary, not returned.
+    pub async fn new(name: &str, registry: &ProviderRegistry) -> TunnelSummary {
+        let status = control::status(name).await.ok();
+        let state = TunnelState::load(name).ok();
+        let mut server_exists = None;
+        if let Some(s) = &state {
+            match registry.get(&s.provider) {
+                Ok(p) => match p.server_exists(name, &s.server_id).await {
+                    Ok(exists) => server_exists = Some(exists),
+                    Err(e) => tracing::debug!("Failed to look up server for '{}': {:#}", name, e),
+                },
+                Err(e) => tracing::debug!("{}", e),
+            }
+        }
+        TunnelSummary {
+            name: name.to_string(),
+            public_ip: match (&status, &state) {
+                (Some(s), _) => Some(s.public_ip),
+                (None, Some(s)) => Some(s.public_ip),
+                (None, None) => get_server_ip(name).ok(),
+            },
+            health: health(status.as_ref(), server_exists),
+            ports: match status {
+                Some(s) => Some(s.ports),
+                None => state.as_ref().map(|s| s.ports()),
+            },
+            uptime: state.map(|s| s.uptime()),
+        }
+    }
+}
+
+/// Gathers an overview of every tunnel with a local config dir.
+pub async fn list_tunnels(registry: &ProviderRegistry) -> Result<Vec<TunnelSummary>> {
+    let mut summaries = vec![];
+    for name in list_config_dirs()? {
+        summaries.push(TunnelSummary::new(&name, registry).await);
+    }
+    Ok(summaries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wg::PeerStats;
+    use std::time::SystemTime;
+
+    #[test]
+    fn health_prefers_running_process() -> Result<()> {
+        let mut status = TunnelStatus {
+            public_ip: "203.0.113.5".parse()?,
+            ports: vec!["443/TCP".to_string()],
+            names: Default::default(),
+            peers: vec![PeerStats {
+                public_key: "ISRq2SHZQDnSfV0VlmMEP4MbwfExE/iNHzthMQ7eNmY=".to_string(),
+                endpoint: None,
+                latest_handshake: Some(SystemTime::now()),
+                rx_bytes: 0,
+                tx_bytes: 0,
+            }],
+            traffic: Default::default(),
+        };
+        assert_eq!(health(Some(&status), Some(true)), Health::Healthy);
+        assert_eq!(health(Some(&status), None), Health::Healthy);
+        assert_eq!(health(Some(&status), Some(false)), Health::Gone);
+        status.peers[0].latest_handshake = None;
+        assert_eq!(health(Some(&status), Some(true)), Health::Degraded);
+        assert_eq!(health(None, Some(true)), Health::Stopped);
+        assert_eq!(health(None, None), Health::Unknown);
+        Ok(())
+    }
+
+    #[test]
+    fn durations_are_coarse() {
+        assert_eq!(human_duration(Duration::from_secs(59)), "0m");
+        assert_eq!(human_duration(Duration::from_secs(3 * 3600 + 125)), "3h 2m");
+        assert_eq!(
+            human_duration(Duration::from_secs(2 * 86400 + 7200)),
+            "2d 2h"
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! Advisory locks preventing two processes from managing the same tunnel,
+//! e.g. two simultaneous `innisfree up -n foo` runs, which would otherwise
+//! both wipe the config dir and race to create servers. Locks are held via
+//! `flock(2)`, so they're released when the process exits, even if killed.
+
+use anyhow::{anyhow, Context, Result};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::config::make_config_dir;
+
+/// Exclusive lock on a tunnel, held until dropped.
+#[derive(Debug)]
+pub struct TunnelLock {
+    _file: File,
+    /// Path to the lock file, which records the holder's PID.
+    pub path: PathBuf,
+}
+
+impl TunnelLock {
+    /// Locks the tunnel `service_name`, failing immediately, rather than
+    /// waiting, if another process holds the lock. Lock files live outside
+    /// the tunnel's config dir, since that's wiped on creation.
+    pub fn acquire(service_name: &str) -> Result<TunnelLock> {
+        let path = make_config_dir(".locks")?.join(service_name);
+        TunnelLock::acquire_at(&path).with_context(|| format!("Tunnel '{}' is busy", service_name))
+    }
+
+    /// Locks the file at `path`, creating it if necessary.
+    fn acquire_at(path: &Path) -> Result<TunnelLock> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let pid = std::fs::read_to_string(path).unwrap_or_default();
+                return Err(anyhow!(
+                    "Another innisfree process (PID {}) is managing it, stop that one first",
+                    pid.trim()
+                ));
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).context(format!("Failed to lock {}", path.display()));
+            }
+        }
+        // Record the holder, so a refused process can say which one to stop.
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(TunnelLock {
+            _file: file,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_lock_refused_until_released() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("innisfree-lock-test-{}", std::process::id()));
+        let lock = TunnelLock::acquire_at(&path)?;
+        // Each open file holds its own flock, even within one process.
+        let e = TunnelLock::acquire_at(&path).unwrap_err().to_string();
+        assert!(e.contains(&format!("PID {}", std::process::id())));
+        drop(lock);
+        TunnelLock::acquire_at(&path)?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+//! Remote logs for a running tunnel, via `innisfree logs`, so problems
+//! like refused connections can be debugged without hunting for files
+//! on the server. Logs are read over SSH, with the tunnel's own keys.
+
+use anyhow::{anyhow, Context, Result};
+use std::str::FromStr;
+
+use crate::server::cloudinit::RemoteProxy;
+use crate::ssh::client::SshClient;
+use crate::state::TunnelState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Set of log files on the server.
+pub enum LogSource {
+    /// Nginx access and error logs, including per-host access logs.
+    /// Tunnels in DNAT mode don't use nginx, so have none.
+    Nginx,
+    /// Caddy's logs, including per-host access logs, for tunnels using it
+    /// in place of nginx.
+    Caddy,
+    /// Output of `innisfree proxy`, for tunnels using it in place of nginx.
+    Innisfree,
+    /// Output of the server's first boot, e.g. package installation.
+    CloudInit,
+}
+
+impl LogSource {
+    /// Sources shown by default, in order, for tunnels using nginx.
+    pub const ALL: [LogSource; 2] = [LogSource::Nginx, LogSource::CloudInit];
+
+    /// Sources shown by default for the tunnel `service_name`, depending on
+    /// the proxy on its server.
+    pub fn defaults(service_name: &str) -> Vec<LogSource> {
+        match TunnelState::load(service_name).map(|s| s.options.remote_proxy) {
+            Ok(RemoteProxy::Caddy) => vec![LogSource::Caddy, LogSource::CloudInit],
+            Ok(RemoteProxy::Innisfree) => vec![LogSource::Innisfree, LogSource::CloudInit],
+            _ => LogSource::ALL.to_vec(),
+        }
+    }
+
+    /// Paths to the source's files on the server, possibly as globs.
+    fn paths(&self) -> &'static [&'static str] {
+        match self {
+            LogSource::Nginx => &["/var/log/nginx/*.log"],
+            LogSource::Caddy => &["/var/log/caddy/*.log"],
+            LogSource::Innisfree => &["/var/log/innisfree/*.log"],
+            LogSource::CloudInit => &["/var/log/cloud-init.log", "/var/log/cloud-init-output.log"],
+        }
+    }
+}
+
+impl FromStr for LogSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nginx" => Ok(LogSource::Nginx),
+            "caddy" => Ok(LogSource::Caddy),
+            "innisfree" => Ok(LogSource::Innisfree),
+            "cloud-init" => Ok(LogSource::CloudInit),
+            _ => Err(anyhow!(
+                "Invalid log source '{}', expected 'nginx', 'caddy', 'innisfree', or 'cloud-init'",
+                s
+            )),
+        }
+    }
+}
+
+/// Builds the remote command printing the last `lines` lines of each source,
+/// then following them, if `follow` is set. Globs are expanded by `sh`, as root,
+/// since arguments reach the server quoted.
+fn tail_cmd(sources: &[LogSource], lines: u32, follow: bool) -> Vec<String> {
+    let mut tail = vec!["tail".to_string(), "-n".to_string(), lines.to_string()];
+    if follow {
+        // Follow by name, so rotated logs keep streaming.
+        tail.push("-F".to_string());
+    }
+    for source in sources {
+        tail.extend(source.paths().iter().map(|p| p.to_string()));
+    }
+    vec![
+        "sudo".to_string(),
+        "sh".to_string(),
+        "-c".to_string(),
+        tail.join(" "),
+    ]
+}
+
+/// Prints the server's logs for the tunnel `service_name`, streaming new
+/// lines until interrupted if `follow` is set. Logs from all sources are
+/// interleaved, each run of lines headed by its file name.
+pub fn stream_logs(
+    service_name: &str,
+    sources: &[LogSource],
+    lines: u32,
+    follow: bool,
+) -> Result<()> {
+    let code = SshClient::for_tunnel(service_name)?
+        .exec_passthrough(&tail_cmd(sources, lines, follow))
+        .context("ssh command failed")?;
+    match code {
+        Some(0) => {}
+        _ if follow => {}
+        Some(code) => return Err(anyhow!("Failed to read some logs: exit code {}", code)),
+        None => return Err(anyhow!("Failed to read some logs: tail was killed")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tail_covers_each_source() -> Result<()> {
+        let cmd = tail_cmd(&LogSource::ALL, 50, true);
+        assert_eq!(
+            cmd.join(" "),
+            "sudo sh -c tail -n 50 -F /var/log/nginx/*.log /var/log/cloud-init.log /var/log/cloud-init-output.log"
+        );
+        let cmd = tail_cmd(&["nginx".parse()?], 10, false);
+        assert_eq!(cmd.join(" "), "sudo sh -c tail -n 10 /var/log/nginx/*.log");
+        let cmd = tail_cmd(&["caddy".parse()?], 10, false);
+        assert_eq!(cmd.join(" "), "sudo sh -c tail -n 10 /var/log/caddy/*.log");
+        let cmd = tail_cmd(&["innisfree".parse()?], 10, false);
+        assert_eq!(
+            cmd.join(" "),
+            "sudo sh -c tail -n 10 /var/log/innisfree/*.log"
+        );
+        assert!("syslog".parse::<LogSource>().is_err());
+        Ok(())
+    }
+}
@@ -1,18 +1,176 @@
 //! High-level controller logic for managing
 //! service proxies, i.e. [TunnelManager].
 
-use crate::config::{clean_config_dir, make_config_dir, ServicePort};
+use crate::access_log::AccessLog;
+use crate::balance::Strategy;
+use crate::caddy;
+use crate::config::{clean_config_dir, make_config_dir, Protocol, ServicePort};
+use crate::control;
+use crate::error::{self, InnisfreeError};
+use crate::forwarder;
 
-use crate::proxy::proxy_handler;
-use crate::server::digitalocean::server::Droplet;
-use crate::server::InnisfreeServer;
-use crate::ssh::SshKeypair;
-use crate::wg::WireguardManager;
+mod builder;
+pub use builder::TunnelManagerBuilder;
+
+use crate::event::{TunnelEvent, EVENT_CAPACITY};
+use crate::net::{choose_subnet, generate_unused_subnet_in, tcp_ports_in_use, INNISFREE_SUBNET};
+use crate::pool::{self, ParkedServer};
+use crate::proxy::{
+    proxy_handler, proxy_protocol_handler, tls_proxy_handler, ByteCounts, ConnTimeouts,
+    ProxyConfig, SocketOptions, TrafficCounters,
+};
+use crate::remote_health;
+use crate::secrets;
+use crate::server::cloudinit::{
+    firewall_config, forwarding_config, generate_user_data, stream_traffic, CloudConfigOptions,
+    RemoteProxy, STREAM_LOG_PATH,
+};
+use crate::server::{poll_interval, ApiRequest, InnisfreeServer, ProviderRegistry, ServerProvider};
+use crate::share;
+use crate::ssh::agent;
+use crate::ssh::client::SshClient;
+use crate::ssh::forward::ReverseForwards;
+use crate::ssh::{SshCommandError, SshKeypair, SshOutput};
+use crate::state::{self, TunnelState};
+use crate::udp2raw;
+use crate::webhook::{self, Webhook};
+use crate::wg::{
+    latest_handshake, peer_stats, set_peer_endpoint, without_private_key, PeerStats,
+    WireguardDevice, WireguardManager, WireguardTransport,
+};
 use anyhow::{anyhow, Context, Result};
 use futures::future::join_all;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::net::{IpAddr, SocketAddr, TcpStream};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::runtime::RuntimeFlavor;
 use tokio::signal;
+use tokio::sync::broadcast;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+
+/// How often [TunnelManager::watchdog] checks the tunnel's health.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(30);
+/// Consecutive failed health checks before re-establishing the tunnel.
+const WATCHDOG_MAX_FAILURES: u32 = 3;
+/// Consecutive failed health checks before recreating a server that still
+/// exists, but can't be reached, if self-healing is enabled.
+const WATCHDOG_RECREATE_FAILURES: u32 = 10;
+/// How often [TunnelManager::wait_until_idle] checks the tunnel's traffic.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Traffic per check below which the tunnel counts as idle. Keepalives and
+/// handshakes alone amount to a few hundred bytes a minute.
+const IDLE_MAX_BYTES: u64 = 4096;
+/// Exit code of `cloud-init status` when it finished with recoverable errors.
+const CLOUDINIT_RECOVERABLE_CODE: i32 = 2;
+/// Times SSH is retried while waiting on cloud-init, if it can't connect.
+const CLOUDINIT_SSH_RETRIES: u32 = 6;
+/// How often blocking waits check whether [TunnelManager::shutdown] was called.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Age after which a handshake no longer shows the tunnel is healthy.
+/// With keepalives, handshakes recur about every two minutes.
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Tracks how long the tunnel has gone without traffic, from the total
+/// bytes transferred over its Wireguard interface.
+#[derive(Debug, Default)]
+struct IdleTracker {
+    last_bytes: Option<u64>,
+    idle_for: Duration,
+}
+
+impl IdleTracker {
+    /// Records the total bytes transferred, `elapsed` after the previous
+    /// check, and returns how long the tunnel has been idle.
+    fn record(&mut self, total: u64, elapsed: Duration) -> Duration {
+        let delta = match self.last_bytes {
+            // Counters start over if the interface was recreated.
+            Some(last) => total.checked_sub(last).unwrap_or(total),
+            None => 0,
+        };
+        self.last_bytes = Some(total);
+        if delta > IDLE_MAX_BYTES {
+            self.idle_for = Duration::ZERO;
+        } else {
+            self.idle_for += elapsed;
+        }
+        self.idle_for
+    }
+}
+
+/// Everything `innisfree up` would create, rendered without creating
+/// any of it, so changes can be reviewed. See [TunnelManager::plan].
+#[derive(Debug, Serialize)]
+pub struct TunnelPlan {
+    /// Cloud-init user data for the server, before any changes a provider
+    /// makes, e.g. for prebuilt images.
+    pub user_data: String,
+    /// Wireguard config for the local end of the tunnel. The server's
+    /// IP isn't known yet, so the peer has no endpoint.
+    pub wg_local_config: String,
+    /// Wireguard config for the remote end of the tunnel.
+    pub wg_remote_config: String,
+    /// Path on the server of the forwarding config, i.e. nginx streams
+    /// or nftables DNAT rules.
+    pub forwarding_path: String,
+    /// Contents of the forwarding config.
+    pub forwarding_config: String,
+    /// Requests to the provider's API, in order, see [ServerProvider::plan].
+    pub requests: Vec<ApiRequest>,
+}
+
+/// Returns the range within which the tunnel's subnet is chosen.
+fn parent_subnet(options: &CloudConfigOptions) -> Result<ipnet::IpNet> {
+    match options.wg_subnet {
+        Some(n) => Ok(n),
+        None => Ok(INNISFREE_SUBNET.parse()?),
+    }
+}
+
+/// Builds both ends of the tunnel within `wg_subnet`, applying the options.
+fn tunnel_wg(
+    tunnel_name: &str,
+    wg_subnet: ipnet::IpNet,
+    options: &CloudConfigOptions,
+) -> Result<WireguardManager> {
+    let mut wg = WireguardManager::with_subnet(tunnel_name, wg_subnet)?;
+    wg.set_mtu(options.wg_mtu);
+    if let Some(port) = options.wg_port {
+        wg.set_listen_port(port);
+    }
+    Ok(wg)
+}
+
+/// Returns the local end of the tunnel `wg`, pointed at the server at `server_ip`.
+fn local_device(
+    wg: &WireguardManager,
+    server_ip: IpAddr,
+    options: &CloudConfigOptions,
+) -> WireguardDevice {
+    let mut device = wg.wg_local_device.clone();
+    device.peer.endpoint = match options.wg_transport {
+        WireguardTransport::Udp => Some(server_ip),
+        // Packets go via the local end of udp2raw, listening on the same port.
+        WireguardTransport::Tcp => Some(udp2raw::LOCAL_IP),
+    };
+    device.dnat = options.dnat;
+    device
+}
+
+/// Runs `f`, which blocks, e.g. waiting on the server over SSH, from async
+/// code, handing the worker's other tasks, such as the proxies, to other
+/// workers meanwhile. Only a multi-threaded runtime has others to take them.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current().map(|h| h.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
 
 /// Controller class for handling tunnel configurations.
 /// Handles the soup-to-nuts configuration, including server creation,
@@ -21,8 +179,9 @@ pub struct TunnelManager {
     /// List of `ServicePort`s to manage connections for.
     pub services: Vec<ServicePort>,
     // dest_ip: IpAddr,
-    /// Remote server handling public ingress.
-    pub server: Box<dyn InnisfreeServer>,
+    /// Remote server handling public ingress, replaced if it's recreated,
+    /// see [TunnelManager::server].
+    server: RwLock<Arc<dyn InnisfreeServer>>,
     /// Human-readable name for this service manager.
     pub name: String,
     /// Controller for Wireguard tunnels.
@@ -33,79 +192,692 @@ pub struct TunnelManager {
     pub ssh_server_keypair: SshKeypair,
     /// Static IP to be attached to the server, for stable DNS entries on recreation.
     pub static_ip: Option<IpAddr>,
+    /// Name of the cloud provider backing the remote server.
+    pub provider: String,
+    /// Customizations applied to the server on first boot, e.g. HTTPS.
+    pub options: CloudConfigOptions,
+    /// Provider with which to recreate the server if it disappears,
+    /// see [TunnelManager::enable_self_heal].
+    healer: Option<Arc<dyn ServerProvider>>,
+    /// Endpoints notified of lifecycle events, see [TunnelManager::notify].
+    webhooks: Vec<Webhook>,
+    /// Channel on which lifecycle events are broadcast, see [TunnelManager::subscribe].
+    events: broadcast::Sender<TunnelEvent>,
+    /// How long `up()` waits for SSH on the new server, if not indefinitely.
+    ssh_timeout: Option<Duration>,
+    /// Fired on shutdown, interrupting waits and stopping the proxies,
+    /// see [TunnelManager::shutdown].
+    cancel: CancellationToken,
+    /// SSH session to the server, reused across commands, see [TunnelManager::run_ssh_cmd].
+    ssh: Mutex<Option<SshClient>>,
+    /// Whether the server's SSH is closed to the internet, so it's reached
+    /// over the tunnel, see [TunnelManager::close_public_ssh].
+    ssh_over_tunnel: AtomicBool,
+    /// Whether services are forwarded over SSH, rather than Wireguard,
+    /// see [TunnelManager::forwarding_over_ssh].
+    ssh_fallback: Arc<AtomicBool>,
+    /// Local end of the TCP transport, if used, see [crate::udp2raw].
+    udp2raw: Mutex<Option<Child>>,
+    /// Whether the server was claimed from the pool, rather than created,
+    /// so `up()` must replace the config it booted with, see [crate::pool].
+    claimed: bool,
+    /// Settings shared by the local proxies, with their traffic counters.
+    proxy: ProxyConfig,
 }
 
 impl TunnelManager {
+    /// Returns a builder for a new controller, the preferred way to create one,
+    /// e.g. `TunnelManager::builder().name("foo").services(services).build().await`.
+    pub fn builder() -> TunnelManagerBuilder {
+        TunnelManagerBuilder::default()
+    }
     /// Create a new controller for managing a collection of services.
-    /// Call `up()` to build.
+    /// The `provider` selects the cloud backend used to create the server,
+    /// and the `options` customize its configuration. Call `up()` to build.
     pub async fn new(
         tunnel_name: &str,
         services: Vec<ServicePort>,
         static_ip: Option<IpAddr>,
-    ) -> Result<TunnelManager> {
-        clean_config_dir(tunnel_name)?;
-        let wg = WireguardManager::new(tunnel_name)?;
-        // Create new ephemeral ssh keypair
-        let ssh_client_keypair = SshKeypair::new("client")?;
-        let ssh_server_keypair = SshKeypair::new("server")?;
-        let server = Droplet::new(
+        provider: &dyn ServerProvider,
+        options: CloudConfigOptions,
+    ) -> Result<TunnelManager, InnisfreeError> {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        TunnelManager::new_with_events(tunnel_name, services, static_ip, provider, options, events)
+            .await
+    }
+    /// Like [TunnelManager::new], but broadcasts events on `events` from the
+    /// start, so subscribers see the server being created, too.
+    pub async fn new_with_events(
+        tunnel_name: &str,
+        services: Vec<ServicePort>,
+        static_ip: Option<IpAddr>,
+        provider: &dyn ServerProvider,
+        options: CloudConfigOptions,
+        events: broadcast::Sender<TunnelEvent>,
+    ) -> Result<TunnelManager, InnisfreeError> {
+        let cancel = CancellationToken::new();
+        let mgr = TunnelManager::create(
             tunnel_name,
-            services.clone(),
-            wg.clone(),
-            &ssh_client_keypair,
-            &ssh_server_keypair,
+            services,
+            static_ip,
+            provider,
+            options,
+            events,
+            cancel,
         )
         .await?;
+        Ok(mgr)
+    }
+    /// Creates the server, as [TunnelManager::new_with_events] does, giving up
+    /// and destroying it if `cancel` fires first. The manager keeps `cancel`,
+    /// see [TunnelManager::shutdown].
+    async fn create(
+        tunnel_name: &str,
+        services: Vec<ServicePort>,
+        static_ip: Option<IpAddr>,
+        provider: &dyn ServerProvider,
+        options: CloudConfigOptions,
+        events: broadcast::Sender<TunnelEvent>,
+        cancel: CancellationToken,
+    ) -> Result<TunnelManager> {
+        options.validate(&services).map_err(error::config)?;
+        clean_config_dir(tunnel_name)?;
+        if let Some(host) = options.share.clone() {
+            return TunnelManager::attach(
+                tunnel_name,
+                &host,
+                services,
+                provider,
+                options,
+                events,
+                cancel,
+            )
+            .await;
+        }
+        let mut options = options;
+        let parked = match options.pool {
+            true => claim_parked(provider, &options, &services).await?,
+            false => None,
+        };
+        if let Some((p, _)) = &parked {
+            // The cloud firewall is open for the parked server's port.
+            options.wg_port = p.options.wg_port;
+        }
+        let wg_subnet = choose_subnet(tunnel_name, parent_subnet(&options)?)?;
+        let wg = tunnel_wg(tunnel_name, wg_subnet, &options)?;
+        let claimed = parked.is_some();
+        let (server, ssh_client_keypair, ssh_server_keypair, server_name) = match parked {
+            Some((p, server)) => {
+                tracing::info!("Claimed parked server {} from the pool", p.server_id);
+                let server_name = (p.server_name != tunnel_name).then_some(p.server_name);
+                (
+                    server,
+                    p.ssh_client_keypair,
+                    p.ssh_server_keypair,
+                    server_name,
+                )
+            }
+            None => {
+                // Create new ephemeral ssh keypair
+                let ssh_client_keypair = SshKeypair::generate("client", options.ssh_key_type)?;
+                let ssh_server_keypair = SshKeypair::generate("server", options.ssh_key_type)?;
+                let _ = events.send(TunnelEvent::ServerCreating);
+                let server = provider
+                    .create_cancellable(
+                        tunnel_name,
+                        services.clone(),
+                        wg.clone(),
+                        &ssh_client_keypair,
+                        &ssh_server_keypair,
+                        &options,
+                        &cancel,
+                    )
+                    .await?;
+                (server, ssh_client_keypair, ssh_server_keypair, None)
+            }
+        };
 
+        // Assigning needs the server's ID, only known once the provider has
+        // created it, within the boot wait, so can't overlap with booting.
         if let Some(ip) = static_ip {
-            server.assign_floating_ip(ip).await?;
+            tracing::debug!("Assigning floating IP {} to server", ip);
+            if let Err(e) = server.assign_floating_ip(ip).await {
+                let _ = server.destroy().await;
+                return Err(e.context("Failed to assign floating IP"));
+            }
+        }
+        let server_ip = server.ipv4_address()?;
+        let _ = events.send(TunnelEvent::ServerReady { ip: server_ip });
+        let state = TunnelState {
+            provider: provider.name().to_string(),
+            server_id: server.id(),
+            created_at: state::now(),
+            server_ip,
+            public_ip: static_ip.unwrap_or(server_ip),
+            public_ipv6: server.ipv6_address()?,
+            wg_subnet,
+            services: services.clone(),
+            options: options.clone(),
+            wg: wg.clone(),
+            ssh_client_keypair: ssh_client_keypair.clone(),
+            ssh_server_keypair: ssh_server_keypair.clone(),
+            ssh_over_tunnel: false,
+            server_name,
+            dns: None,
+        };
+        if let Err(e) = state.save(tunnel_name) {
+            let _ = server.destroy().await;
+            return Err(e);
         }
 
         Ok(TunnelManager {
             name: tunnel_name.to_owned(),
             services,
-            server: Box::new(server),
+            server: RwLock::new(Arc::from(server)),
             ssh_client_keypair,
             ssh_server_keypair,
             static_ip,
+            provider: provider.name().to_string(),
+            options,
+            wg,
+            healer: None,
+            webhooks: vec![],
+            events,
+            ssh_timeout: None,
+            cancel,
+            ssh: Mutex::new(None),
+            ssh_over_tunnel: AtomicBool::new(false),
+            ssh_fallback: Arc::default(),
+            udp2raw: Mutex::new(None),
+            claimed,
+            proxy: ProxyConfig::default(),
+        })
+    }
+    /// Shares the server of the running tunnel `host`, rather than creating
+    /// one, as [TunnelManager::create] does for `--share`: opens the cloud
+    /// firewall for the services, then records the tunnel, with the host's
+    /// server and keys. `up()` joins the host's Wireguard interface.
+    async fn attach(
+        tunnel_name: &str,
+        host: &str,
+        services: Vec<ServicePort>,
+        provider: &dyn ServerProvider,
+        options: CloudConfigOptions,
+        events: broadcast::Sender<TunnelEvent>,
+        cancel: CancellationToken,
+    ) -> Result<TunnelManager> {
+        let host_state = share::host_state(host, provider.name())?;
+        share::check_ports(host, tunnel_name, &services).map_err(error::config)?;
+        let wg_subnet = choose_subnet(tunnel_name, parent_subnet(&options)?)?;
+        let wg = share::guest_wg(tunnel_name, wg_subnet, &host_state.wg)?;
+        let server_name = host_state.server_name(host).to_string();
+        let server = provider.adopt(&server_name, &host_state.server_id).await?;
+        for s in &services {
+            server.open_port(s).await?;
+        }
+        let _ = events.send(TunnelEvent::ServerReady {
+            ip: host_state.server_ip,
+        });
+        let state = TunnelState {
+            provider: host_state.provider,
+            server_id: host_state.server_id,
+            created_at: state::now(),
+            server_ip: host_state.server_ip,
+            public_ip: host_state.public_ip,
+            public_ipv6: host_state.public_ipv6,
+            wg_subnet,
+            services: services.clone(),
+            options: options.clone(),
+            wg: wg.clone(),
+            ssh_client_keypair: host_state.ssh_client_keypair.clone(),
+            ssh_server_keypair: host_state.ssh_server_keypair.clone(),
+            ssh_over_tunnel: false,
+            server_name: Some(server_name),
+            dns: None,
+        };
+        state.save(tunnel_name)?;
+        Ok(TunnelManager {
+            name: tunnel_name.to_owned(),
+            services,
+            server: RwLock::new(Arc::from(server)),
+            ssh_client_keypair: state.ssh_client_keypair,
+            ssh_server_keypair: state.ssh_server_keypair,
+            static_ip: (state.public_ip != state.server_ip).then_some(state.public_ip),
+            provider: state.provider,
+            options,
             wg,
+            healer: None,
+            webhooks: vec![],
+            events,
+            ssh_timeout: None,
+            cancel,
+            ssh: Mutex::new(None),
+            ssh_over_tunnel: AtomicBool::new(false),
+            ssh_fallback: Arc::default(),
+            udp2raw: Mutex::new(None),
+            claimed: false,
+            proxy: ProxyConfig::default(),
+        })
+    }
+    /// Re-attaches to the tunnel left running by an earlier process, e.g. one
+    /// killed before it could clean up, as recorded in its [TunnelState].
+    /// Fails unless the saved tunnel matches the requested options, and its
+    /// server is still running. Call `reconcile()` to converge on the requested
+    /// services, then `resume()` to reconnect.
+    pub async fn adopt(
+        tunnel_name: &str,
+        static_ip: Option<IpAddr>,
+        provider: &dyn ServerProvider,
+        options: &CloudConfigOptions,
+    ) -> Result<TunnelManager, InnisfreeError> {
+        let state = TunnelState::load(tunnel_name)?;
+        if let Some(msg) = state.mismatch(provider.name(), options, static_ip) {
+            return Err(error::config(msg).into());
+        }
+        let server = provider
+            .adopt(state.server_name(tunnel_name), &state.server_id)
+            .await?;
+        Ok(TunnelManager {
+            name: tunnel_name.to_owned(),
+            services: state.services,
+            server: RwLock::new(Arc::from(server)),
+            ssh_client_keypair: state.ssh_client_keypair,
+            ssh_server_keypair: state.ssh_server_keypair,
+            static_ip: (state.public_ip != state.server_ip).then_some(state.public_ip),
+            provider: state.provider,
+            options: state.options,
+            wg: state.wg,
+            healer: None,
+            webhooks: vec![],
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            ssh_timeout: None,
+            cancel: CancellationToken::new(),
+            ssh: Mutex::new(None),
+            ssh_over_tunnel: AtomicBool::new(state.ssh_over_tunnel),
+            ssh_fallback: Arc::default(),
+            udp2raw: Mutex::new(None),
+            claimed: false,
+            proxy: ProxyConfig::default(),
         })
     }
+    /// Converges an adopted tunnel's server on the desired services, opening
+    /// and closing ports and reloading its forwarding config as needed,
+    /// so re-running `up` with an edited port list keeps the server.
+    /// Call before `resume()`, which applies the services locally.
+    pub async fn reconcile(&mut self, services: Vec<ServicePort>) -> Result<(), InnisfreeError> {
+        self.converge(&self.services, &services).await?;
+        self.services = services;
+        Ok(())
+    }
+    /// Converges the server on the `desired` services, given the `current`
+    /// ones, and records them in the tunnel's saved state. Doesn't change
+    /// the local end, see [TunnelManager::set_local_port_open].
+    pub async fn converge(
+        &self,
+        current: &[ServicePort],
+        desired: &[ServicePort],
+    ) -> Result<(), InnisfreeError> {
+        if current == desired {
+            return Ok(());
+        }
+        self.options.validate(desired).map_err(error::config)?;
+        for s in desired
+            .iter()
+            .filter(|s| !current.iter().any(|c| c.same_port(s)))
+        {
+            tracing::info!("Opening {} on server", s);
+            self.server().open_port(s).await?;
+        }
+        self.reload_services(desired)?;
+        for s in current
+            .iter()
+            .filter(|c| !desired.iter().any(|s| s.same_port(c)))
+        {
+            tracing::info!("Closing {} on server", s);
+            self.server().close_port(s).await?;
+        }
+        TunnelState::update(&self.name, |s| s.services = desired.to_vec())?;
+        Ok(())
+    }
+    /// Renders everything [TunnelManager::new] would create, with throwaway
+    /// keys, but creates nothing: neither the server, nor the tunnel's
+    /// config dir or state. Used by `innisfree up --dry-run`.
+    pub async fn plan(
+        tunnel_name: &str,
+        services: &[ServicePort],
+        provider: &dyn ServerProvider,
+        options: &CloudConfigOptions,
+    ) -> Result<TunnelPlan, InnisfreeError> {
+        options.validate(services).map_err(error::config)?;
+        if options.share.is_some() {
+            return Err(error::config("--dry-run can't plan sharing a server").into());
+        }
+        // Unlike choose_subnet, doesn't record the subnet for reuse.
+        let wg_subnet = generate_unused_subnet_in(parent_subnet(options)?)?;
+        let wg = tunnel_wg(tunnel_name, wg_subnet, options)?;
+        let ssh_client_keypair = SshKeypair::generate("client", options.ssh_key_type)?;
+        let ssh_server_keypair = SshKeypair::generate("server", options.ssh_key_type)?;
+        let user_data = generate_user_data(
+            &ssh_client_keypair,
+            &ssh_server_keypair,
+            &wg,
+            services,
+            options,
+        )
+        .await?;
+        let mut local = wg.wg_local_device.clone();
+        local.dnat = options.dnat;
+        let (forwarding_path, forwarding_config) =
+            forwarding_config(services, wg.wg_local_device.interface.address, options)?;
+        let requests = provider
+            .plan(
+                tunnel_name,
+                &user_data,
+                services,
+                &wg,
+                &ssh_client_keypair,
+                options,
+            )
+            .await?;
+        Ok(TunnelPlan {
+            user_data,
+            wg_local_config: local.config_with_services(&options.local_services(services))?,
+            wg_remote_config: wg.wg_remote_device.config()?,
+            forwarding_path: forwarding_path.to_string(),
+            forwarding_config,
+            requests,
+        })
+    }
+    /// Reconnects to an adopted server, rather than configuring it from
+    /// scratch as `up()` does. Fails if the tunnel can't be re-established.
+    pub fn resume(&self) -> Result<(), InnisfreeError> {
+        self.local_wg_device()?
+            .write_locally(&self.name, &self.options.local_services(&self.services))
+            .context("failed to write wireguard configs")?;
+        self.bring_up_local_wg()
+            .context("failed to bring up local wg interface")?;
+        if !self.tunnel_healthy() {
+            // The server may have rebooted, taking its interface down.
+            let _ = self.bring_up_remote_wg();
+            if !self.ping_remote() {
+                return Err(error::wireguard("Remote Wireguard interface unreachable").into());
+            }
+        }
+        self.emit(TunnelEvent::WireguardUp);
+        Ok(())
+    }
     /// Create remote and local infrastructure. Creates a cloud server,
     /// configures it to forward public ports over its Wireguard interface,
     /// to a local Wireguard interface
-    pub fn up(&self) -> Result<()> {
+    /// Fails with [InnisfreeError::Cancelled] if interrupted via `shutdown()`.
+    pub fn up(&self) -> Result<(), InnisfreeError> {
         self.wait_for_ssh()?;
         tracing::debug!("Configuring remote proxy...");
         self.wait_for_cloudinit()
             .context("failed while waiting for cloudinit")?;
+        if let Some(binary) = &self.options.forwarder_binary {
+            self.upload_forwarder(binary)
+                .context("failed to upload innisfree to server")?;
+        }
+        if self.claimed {
+            self.reconfigure_claimed()
+                .context("failed to reconfigure parked server")?;
+        }
         // Write out cloudinit config locally, for debugging
-        // self.server.write_user_data();
-        let ip = self.server.ipv4_address()?;
+        // self.server().write_user_data();
         tracing::debug!("Configuring tunnel...");
-        let mut wg = self.wg.wg_local_device.clone();
-        wg.peer.endpoint = Some(ip);
-        wg.write_locally(&self.name, &self.services)
+        self.local_wg_device()?
+            .write_locally(&self.name, &self.options.local_services(&self.services))
             .context("failed to write wireguard configs")?;
         tracing::debug!("Bringing up remote Wireguard interface");
         self.bring_up_remote_wg()
             .context("failed to bring up remote wg interface")?;
+        if self.options.share.is_some() {
+            // The server was configured with the host's services only.
+            self.push_forwarding_config(&self.services, self.wg.wg_local_device.interface.address)
+                .context("failed to add services to shared server")?;
+        }
         tracing::debug!("Bringing up local Wireguard interface");
         self.bring_up_local_wg()
             .context("failed to bring up local wg interface")?;
 
         tracing::trace!("Testing connection");
-        self.test_connection()
+        let confirmed = self.ping_remote();
+        if confirmed {
+            tracing::debug!("Confirmed tunnel is established, able to ping across it");
+            self.emit(TunnelEvent::WireguardUp);
+        } else if self.udp_blocked() {
+            self.start_ssh_fallback()?;
+        } else {
+            tracing::warn!("Remote Wireguard interface isn't answering pings");
+            self.emit(TunnelEvent::WireguardUp);
+        }
+
+        if let Some(domain) = &self.options.https_domain {
+            tracing::info!(
+                "Requesting TLS certificate for {}, its DNS record must point to {}",
+                domain,
+                self.public_ip()?
+            );
+            self.obtain_certificate(domain)
+                .context("failed to obtain TLS certificate")?;
+        }
+        if self.options.private_ssh {
+            if confirmed {
+                self.close_public_ssh()
+                    .context("failed to close SSH on server")?;
+            } else {
+                tracing::warn!("Leaving SSH open on the server, since the tunnel is unconfirmed");
+            }
+        }
+        Ok(())
+    }
+    /// Returns the local end of the tunnel, pointed at the server.
+    fn local_wg_device(&self) -> Result<WireguardDevice> {
+        let server_ip = self.server().ipv4_address()?;
+        Ok(local_device(&self.wg, server_ip, &self.options))
+    }
+    /// Returns traffic and handshake stats for the local Wireguard
+    /// interface's peers, i.e. the remote server.
+    pub fn stats(&self) -> Result<Vec<PeerStats>> {
+        peer_stats(&self.name)
+    }
+    /// Returns the bytes passed per service by the server's nginx, read
+    /// from its logs, rotated ones included, see [stream_traffic]. `None`
+    /// if nginx doesn't forward the services, e.g. in DNAT mode.
+    pub fn server_traffic(&self) -> Result<Option<BTreeMap<String, ByteCounts>>> {
+        if self.options.remote_proxy != RemoteProxy::Nginx || self.options.dnat {
+            return Ok(None);
+        }
+        // sh expands the glob, and zcat passes plain files through.
+        let zcat = format!("zcat -f {}*", STREAM_LOG_PATH);
+        let output = self.run_ssh_cmd(vec!["sudo", "sh", "-c", &zcat])?;
+        Ok(Some(stream_traffic(&output.stdout)))
+    }
+    /// Returns the remote server. It may be replaced while the tunnel runs,
+    /// if self-healing is enabled, so avoid holding onto it.
+    pub fn server(&self) -> Arc<dyn InnisfreeServer> {
+        match self.server.read() {
+            Ok(s) => s.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+    /// Lets [TunnelManager::watchdog] recreate the server via `provider` if it
+    /// disappears, e.g. because the provider deleted it, or if it stays
+    /// unreachable. The new server gets the same config and keys, and the
+    /// static IP, if any, so the tunnel comes back at the same address.
+    pub fn enable_self_heal(&mut self, provider: Arc<dyn ServerProvider>) {
+        self.healer = Some(provider);
+    }
+    /// Returns the counters for traffic through the local proxies, shared
+    /// with the proxies started for the tunnel, see [run_proxy].
+    pub fn traffic(&self) -> &TrafficCounters {
+        &self.proxy.traffic
+    }
+    /// Logs each connection through the local proxies to the tunnel's
+    /// access log, see [crate::access_log].
+    pub fn enable_access_log(&mut self) -> Result<()> {
+        self.proxy.access_log = Some(AccessLog::open(&self.name)?);
+        Ok(())
+    }
+    /// Sets when the local proxies close connections, see [ConnTimeouts].
+    pub fn set_conn_timeouts(&mut self, timeouts: ConnTimeouts) {
+        self.proxy.timeouts = timeouts;
+    }
+    /// Sets the options for the local proxies' sockets, see [SocketOptions].
+    pub fn set_socket_options(&mut self, sockets: SocketOptions) {
+        self.proxy.sockets = sockets;
+    }
+    /// Sets the response sent to clients turned away while their service
+    /// fails its health check, see [crate::service_health].
+    pub fn set_unavailable_response(&mut self, response: Vec<u8>) {
+        self.proxy.unavailable = Some(response.into());
+    }
+    /// Sets how the local proxies pick among a service's backends, see
+    /// [crate::balance].
+    pub fn set_balance(&mut self, strategy: Strategy) {
+        self.proxy.balance = strategy;
+    }
+    /// Sets how many times the local proxies retry connecting to a service
+    /// before dropping the client's connection.
+    pub fn set_connect_retries(&mut self, retries: u32) {
+        self.proxy.connect_retries = retries;
+    }
+    /// Returns the settings for the proxies started for the tunnel, see
+    /// [run_proxy]: its traffic counters, client limits, and access log.
+    pub fn proxy_config(&self) -> ProxyConfig {
+        ProxyConfig {
+            limits: self.options.client_limits,
+            ..self.proxy.clone()
+        }
+    }
+    /// Returns the token fired by [TunnelManager::shutdown], e.g. to stop
+    /// proxies started alongside the tunnel.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+    /// Interrupts any wait in progress, e.g. an `up()` waiting on the
+    /// server's boot, stops the proxies, then destroys the tunnel,
+    /// as [TunnelManager::clean] does.
+    pub async fn shutdown(&self) -> Result<(), InnisfreeError> {
+        self.cancel.cancel();
+        self.clean().await
+    }
+    /// Fails if [TunnelManager::shutdown] was called.
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(error::cancelled("Tunnel is shutting down"));
+        }
+        Ok(())
+    }
+    /// Sleeps for `duration`, unless [TunnelManager::shutdown] is called first.
+    fn pause(&self, duration: Duration) -> Result<()> {
+        let deadline = std::time::Instant::now() + duration;
+        while std::time::Instant::now() < deadline {
+            self.check_cancelled()?;
+            std::thread::sleep(CANCEL_POLL_INTERVAL.min(duration));
+        }
+        self.check_cancelled()
+    }
+    /// Sets the endpoints notified of the tunnel's lifecycle events.
+    pub fn set_webhooks(&mut self, webhooks: Vec<Webhook>) {
+        self.webhooks = webhooks;
+    }
+    /// Returns a receiver for the tunnel's lifecycle events, from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.events.subscribe()
+    }
+    /// Broadcasts `event` to subscribers, if any.
+    pub fn emit(&self, event: TunnelEvent) {
+        tracing::trace!("Emitting event: {}", event.message(&self.name));
+        // Only fails if there are no subscribers.
+        let _ = self.events.send(event);
+    }
+    /// Broadcasts `event`, as [TunnelManager::emit] does, and notifies
+    /// the webhooks, if any, of it. Failures are only logged.
+    pub async fn notify(&self, event: TunnelEvent) {
+        self.emit(event.clone());
+        webhook::notify(&self.webhooks, &self.name, &event).await;
+    }
+    /// Returns the public IPv4 address for the tunnel. If a static IP
+    /// was attached to the server, that's the public address; otherwise,
+    /// it's the address of the server itself.
+    pub fn public_ip(&self) -> Result<IpAddr, InnisfreeError> {
+        match self.static_ip {
+            Some(ip) => Ok(ip),
+            None => Ok(self.server().ipv4_address()?),
+        }
+    }
+    /// Waits until the public IP accepts connections on the first TCP
+    /// service's port, confirming the server is serving traffic.
+    /// Returns immediately if only UDP services are forwarded.
+    pub async fn wait_for_public_port(&self, timeout: Duration) -> Result<(), InnisfreeError> {
+        let service = match self.services.iter().find(|s| s.protocol == Protocol::Tcp) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let port = u16::try_from(service.port).map_err(error::config)?;
+        let addr = SocketAddr::new(self.public_ip()?, port);
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let attempt =
+                tokio::time::timeout(Duration::from_secs(5), tokio::net::TcpStream::connect(addr));
+            match attempt.await {
+                Ok(Ok(_)) => return Ok(()),
+                Ok(Err(e)) => tracing::debug!("Connecting to {} failed: {}", addr, e),
+                Err(_) => tracing::debug!("Connecting to {} timed out", addr),
+            }
+            if self.cancel.is_cancelled() {
+                return Err(error::cancelled("Tunnel is shutting down").into());
+            }
+            if tokio::time::Instant::now() >= deadline {
+                let msg = format!("Public IP not accepting connections on {}", addr);
+                return Err(error::timeout(msg).into());
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
     }
     /// Blocks until the server's cloudinit process reports completion.
+    /// Recoverable errors, e.g. deprecated config keys, are only logged.
+    /// SSH is retried a few times, since sshd restarts during boot.
     fn wait_for_cloudinit(&self) -> Result<()> {
-        let cmd: Vec<&str> = vec!["cloud-init", "status", "--long", "--wait"];
-        self.run_ssh_cmd(cmd)
+        let mut attempts = 0;
+        loop {
+            let cmd: Vec<&str> = vec!["cloud-init", "status", "--long", "--wait"];
+            let e = match self.run_ssh_cmd(cmd) {
+                Ok(_) => return Ok(()),
+                Err(e) => e,
+            };
+            match e.downcast_ref::<SshCommandError>() {
+                Some(c) if c.code == Some(CLOUDINIT_RECOVERABLE_CODE) => {
+                    tracing::warn!("cloud-init finished with recoverable errors: {}", c.stdout);
+                    return Ok(());
+                }
+                // Failing to connect, rather than the command failing.
+                None if attempts < CLOUDINIT_SSH_RETRIES => {
+                    tracing::debug!("Retrying SSH to server: {:#}", e);
+                    attempts += 1;
+                    self.pause(Duration::from_secs(5))?;
+                }
+                _ => return Err(e),
+            }
+        }
     }
-    /// Blocks until 22/TCP is available on the server.
+    /// Blocks until 22/TCP is available on the server, failing after
+    /// the SSH timeout, if one was set. Polls often at first, then less so,
+    /// see [poll_interval].
     fn wait_for_ssh(&self) -> Result<()> {
-        let dest_ip = SocketAddr::new(self.server.ipv4_address()?, 22);
+        let dest_ip = SocketAddr::new(self.server().ipv4_address()?, 22);
+        let deadline = self.ssh_timeout.map(|t| std::time::Instant::now() + t);
+        let mut attempt = 0;
         loop {
+            self.check_cancelled()?;
+            if deadline.is_some_and(|d| std::time::Instant::now() >= d) {
+                return Err(error::timeout(format!(
+                    "Timed out waiting for SSH on {}",
+                    dest_ip
+                )));
+            }
             let stream = TcpStream::connect(dest_ip);
             match stream {
                 Ok(_) => {
@@ -115,7 +887,8 @@ impl TunnelManager {
                 Err(_) => {
                     tracing::debug!("Waiting for ssh...");
                     tracing::trace!("Polling socket {})...", dest_ip);
-                    std::thread::sleep(std::time::Duration::from_secs(10));
+                    self.pause(poll_interval(attempt))?;
+                    attempt += 1;
                 }
             }
         }
@@ -127,7 +900,7 @@ impl TunnelManager {
         match signal::ctrl_c().await {
             Ok(()) => {
                 tracing::warn!("Received stop signal, exiting gracefully");
-                self.clean().await?;
+                self.shutdown().await?;
                 tracing::info!("Clean up complete, exiting");
                 std::process::exit(0);
             }
@@ -137,21 +910,322 @@ impl TunnelManager {
             }
         }
     }
-    /// Ping remote remote Wireguard IP from local Wireguard device.
-    /// Ensures connectivity is established between remote and local interfaces.
-    fn test_connection(&self) -> Result<()> {
-        tracing::trace!("Inside test connection, setting up vars");
-        let ip = &self.wg.wg_remote_ip;
-        tracing::trace!("Inside test connection, running ping cmd");
+    /// Checks the tunnel's health periodically, for as long as it runs.
+    /// After several consecutive failures, tries to re-establish it,
+    /// rather than leaving a dead tunnel running. Never returns.
+    pub async fn watchdog(&self) -> Result<()> {
+        let mut failures = 0;
+        loop {
+            tokio::time::sleep(WATCHDOG_INTERVAL).await;
+            if blocking(|| self.tunnel_healthy()) {
+                if failures > 0 {
+                    tracing::info!("Tunnel healthy again");
+                }
+                failures = 0;
+                continue;
+            }
+            failures += 1;
+            tracing::warn!(
+                "Tunnel health check failed ({}/{})",
+                failures,
+                WATCHDOG_MAX_FAILURES
+            );
+            if failures == WATCHDOG_MAX_FAILURES {
+                self.notify(TunnelEvent::Degraded { failures }).await;
+            }
+            if failures >= WATCHDOG_MAX_FAILURES {
+                match self.reconnect().await {
+                    Ok(()) => {
+                        tracing::info!("Tunnel re-established");
+                        failures = 0;
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to re-establish tunnel, will retry: {:#}", e);
+                        if self.heal(failures).await {
+                            failures = 0;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    /// Recreates the server if self-healing is enabled, and the server is
+    /// gone or has failed `failures` health checks in a row. Returns whether
+    /// the tunnel was re-established on a new server.
+    async fn heal(&self, failures: u32) -> bool {
+        let provider = match &self.healer {
+            Some(p) => p,
+            None => return false,
+        };
+        let id = self.server().id();
+        let gone = match provider.server_exists(&self.name, &id).await {
+            Ok(exists) => !exists,
+            Err(e) => {
+                tracing::debug!("Failed to look up server {}: {:#}", id, e);
+                false
+            }
+        };
+        if !gone && failures < WATCHDOG_RECREATE_FAILURES {
+            return false;
+        }
+        tracing::warn!(
+            "Recreating server for '{}', since it's unreachable",
+            self.name
+        );
+        match self.recreate(provider.as_ref()).await {
+            Ok(()) => {
+                tracing::info!("Tunnel re-established on new server");
+                if let Ok(public_ip) = self.public_ip() {
+                    if let Ok(TunnelState { dns: Some(r), .. }) = TunnelState::load(&self.name) {
+                        if let Err(e) = r.point(public_ip).await {
+                            tracing::warn!("Failed to point DNS record at new server: {:#}", e);
+                        }
+                    }
+                    self.notify(TunnelEvent::Recreated { public_ip }).await;
+                }
+                true
+            }
+            Err(e) => {
+                tracing::warn!("Failed to recreate server, will retry: {:#}", e);
+                false
+            }
+        }
+    }
+    /// Replaces the server with a new one, configured identically, then
+    /// re-attaches the static IP, if any, and re-peers the local end.
+    async fn recreate(&self, provider: &dyn ServerProvider) -> Result<()> {
+        if let Some(host) = &self.options.share {
+            return Err(anyhow!("The server is managed by tunnel '{}'", host));
+        }
+        // Destroying a server can remove resources shared by the tunnel's
+        // servers, e.g. firewalls, so the old one goes first.
+        if let Err(e) = self.server().destroy().await {
+            tracing::debug!("Failed to destroy old server: {:#}", e);
+        }
+        // Services may have changed since the tunnel started.
+        let services = match TunnelState::load(&self.name) {
+            Ok(s) => s.services,
+            Err(_) => self.services.clone(),
+        };
+        self.emit(TunnelEvent::ServerCreating);
+        let server: Arc<dyn InnisfreeServer> = Arc::from(
+            provider
+                .create_cancellable(
+                    &self.name,
+                    services,
+                    self.wg.clone(),
+                    &self.ssh_client_keypair,
+                    &self.ssh_server_keypair,
+                    &self.options,
+                    &self.cancel,
+                )
+                .await?,
+        );
+        if let Some(ip) = self.static_ip {
+            tracing::debug!("Assigning floating IP {} to new server", ip);
+            if let Err(e) = server.assign_floating_ip(ip).await {
+                let _ = server.destroy().await;
+                return Err(e.context("Failed to assign floating IP"));
+            }
+        }
+        let ip = server.ipv4_address()?;
+        self.emit(TunnelEvent::ServerReady { ip });
+        let ipv6 = server.ipv6_address()?;
+        let id = server.id();
+        match self.server.write() {
+            Ok(mut s) => *s = server,
+            Err(e) => *e.into_inner() = server,
+        }
+        // The session is to the old server, if any.
+        match self.ssh.lock() {
+            Ok(mut s) => *s = None,
+            Err(e) => *e.into_inner() = None,
+        }
+        self.ssh_over_tunnel.store(false, Ordering::SeqCst);
+        TunnelState::update(&self.name, |s| {
+            s.ssh_over_tunnel = false;
+            s.server_name = None;
+            s.server_id = id;
+            s.server_ip = ip;
+            s.public_ipv6 = ipv6;
+            s.created_at = state::now();
+        })?;
+        blocking(|| self.configure_recreated(ip))
+    }
+    /// Configures the server recreated at `ip` as `up()` did the old one,
+    /// then re-peers the local end. Blocks while the server boots, for minutes.
+    fn configure_recreated(&self, ip: IpAddr) -> Result<()> {
+        self.wait_for_ssh()?;
+        self.wait_for_cloudinit()
+            .context("failed while waiting for cloudinit")?;
+        if let Some(binary) = &self.options.forwarder_binary {
+            self.upload_forwarder(binary)
+                .context("failed to upload innisfree to server")?;
+        }
+        if let Some(domain) = &self.options.https_domain {
+            self.obtain_certificate(domain)
+                .context("failed to obtain TLS certificate")?;
+        }
+        self.bring_up_remote_wg()
+            .context("failed to bring up remote wg interface")?;
+        self.repeer(ip)?;
+        if self.options.private_ssh {
+            self.close_public_ssh()
+                .context("failed to close SSH on server")?;
+        }
+        Ok(())
+    }
+    /// Returns once no traffic has flowed through the tunnel for `timeout`,
+    /// e.g. so that a forgotten tunnel can be torn down.
+    pub async fn wait_until_idle(&self, timeout: Duration) {
+        let mut tracker = IdleTracker::default();
+        loop {
+            tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+            let total = match self.stats() {
+                Ok(peers) => peers.iter().map(|p| p.rx_bytes + p.tx_bytes).sum(),
+                Err(e) => {
+                    tracing::debug!("Failed to read tunnel traffic: {:#}", e);
+                    continue;
+                }
+            };
+            if tracker.record(total, IDLE_CHECK_INTERVAL) >= timeout {
+                return;
+            }
+        }
+    }
+    /// Whether the tunnel is passing traffic: either a handshake happened
+    /// recently, or the remote Wireguard IP answers a ping.
+    fn tunnel_healthy(&self) -> bool {
+        if self.forwarding_over_ssh() {
+            return true;
+        }
+        if let Ok(Some(t)) = latest_handshake(&self.name) {
+            if t.elapsed().is_ok_and(|e| e < HANDSHAKE_TIMEOUT) {
+                return true;
+            }
+        }
+        self.ping_remote()
+    }
+    /// Pings the remote Wireguard IP once, returning whether it answered.
+    fn ping_remote(&self) -> bool {
         std::process::Command::new("ping")
             .arg("-c1")
             .arg("-w5")
-            .arg(&ip.to_string())
+            .arg(self.wg.wg_remote_ip.to_string())
             .stdout(std::process::Stdio::null())
             .stderr(std::process::Stdio::null())
             .status()
-            .context("Failed to ping remote Wireguard interface, tunnel broken")?;
-        tracing::debug!("Confirmed tunnel is established, able to ping across it");
+            .is_ok_and(|s| s.success())
+    }
+    /// Re-establishes a broken tunnel. Looks up the server's IP again,
+    /// restores the remote interface in case the server rebooted, then
+    /// points the local interface at the server, recreating it if it's gone.
+    async fn reconnect(&self) -> Result<()> {
+        let ip = self.server().refresh_ipv4_address().await?;
+        tracing::info!("Re-establishing tunnel to {}", ip);
+        if let Err(e) = TunnelState::update(&self.name, |s| s.server_ip = ip) {
+            tracing::warn!("Failed to record new server IP: {:#}", e);
+        }
+        blocking(|| {
+            // Fails harmlessly if the remote interface is still up.
+            let _ = self.bring_up_remote_wg();
+            match self.repeer(ip) {
+                Err(_) if self.udp_blocked() => self.start_ssh_fallback(),
+                r => r,
+            }
+        })
+    }
+    /// Points the local interface at the server's address `ip`,
+    /// recreating the interface if it's gone, then checks the tunnel works.
+    fn repeer(&self, ip: IpAddr) -> Result<()> {
+        let endpoint = SocketAddr::new(ip, u16::try_from(self.wg.wg_local_device.peer.listenport)?);
+        let pubkey = self.wg.wg_remote_device.interface.keypair.public();
+        let endpoint = match self.options.wg_transport {
+            WireguardTransport::Udp => endpoint,
+            WireguardTransport::Tcp => {
+                self.start_udp2raw(endpoint)?;
+                udp2raw::local_endpoint(endpoint.port())
+            }
+        };
+        if set_peer_endpoint(&self.name, pubkey, endpoint).is_err() {
+            self.recreate_local_wg(ip)?;
+        }
+        if !self.ping_remote() {
+            return Err(error::wireguard("Remote Wireguard interface unreachable"));
+        }
+        Ok(())
+    }
+    /// Recreates the local interface, pointed at the server's address `ip`,
+    /// from a freshly written config, since the one on disk lacks the
+    /// private key if secrets are encrypted, see [TunnelManager::redact_local_wg_config].
+    fn recreate_local_wg(&self, ip: IpAddr) -> Result<()> {
+        // Services may have changed since the tunnel started.
+        let services = match TunnelState::load(&self.name) {
+            Ok(s) => s.services,
+            Err(_) => self.services.clone(),
+        };
+        local_device(&self.wg, ip, &self.options)
+            .write_locally(&self.name, &self.options.local_services(&services))
+            .context("failed to write wireguard configs")?;
+        self.bring_up_local_wg()
+    }
+    /// Whether Wireguard has never completed a handshake with the server,
+    /// e.g. because the local network blocks outbound UDP.
+    fn udp_blocked(&self) -> bool {
+        matches!(latest_handshake(&self.name), Ok(None))
+    }
+    /// Whether services are forwarded over SSH, because Wireguard couldn't
+    /// reach the server. See [TunnelManager::up].
+    pub fn forwarding_over_ssh(&self) -> bool {
+        self.ssh_fallback.load(Ordering::SeqCst)
+    }
+    /// Forwards the services over SSH, rather than Wireguard, by pointing the
+    /// server's forwarding config at ports forwarded over SSH, as `ssh -R`
+    /// does. Slower, since traffic is relayed over TCP, so only used when
+    /// Wireguard can't reach the server. Only plain TCP services are supported.
+    fn start_ssh_fallback(&self) -> Result<()> {
+        let services = self.options.local_services(&self.services);
+        if self.options.dnat
+            || self.options.https_domain.is_some()
+            || services.len() != self.services.len()
+            || services.iter().any(|s| s.protocol != Protocol::Tcp)
+        {
+            return Err(error::wireguard(
+                "Wireguard handshake with the server never completed, and forwarding over SSH \
+                 instead only supports plain TCP services, without DNAT, HTTPS, or host routes",
+            ));
+        }
+        tracing::warn!(
+            "Wireguard handshake with the server never completed, outbound UDP may be blocked. \
+             Falling back to forwarding services over SSH, which is slower"
+        );
+        let local_ip = self.wg.wg_local_device.interface.address;
+        let targets = self
+            .services
+            .iter()
+            .map(|s| Ok(SocketAddr::new(local_ip, u16::try_from(s.local_port)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let forwards = ReverseForwards::listen(self.ssh_client()?, &targets)?;
+        // Traffic reaches the forwarded ports on the server's loopback, not the tunnel.
+        let services: Vec<ServicePort> = self
+            .services
+            .iter()
+            .zip(forwards.remote_ports())
+            .map(|(s, port)| ServicePort {
+                local_port: (*port).into(),
+                ..s.clone()
+            })
+            .collect();
+        self.push_forwarding_config(&services, IpAddr::from([127, 0, 0, 1]))?;
+        let cancel = self.cancel.clone();
+        let active = self.ssh_fallback.clone();
+        active.store(true, Ordering::SeqCst);
+        std::thread::spawn(move || {
+            if let Err(e) = forwards.run(&cancel) {
+                tracing::error!("Forwarding over SSH stopped: {:#}", e);
+            }
+            active.store(false, Ordering::SeqCst);
+        });
         Ok(())
     }
     /// Returns `PathBuf`, creating directory if necessary.
@@ -159,10 +1233,51 @@ impl TunnelManager {
         make_config_dir(&self.name)
     }
     /// Runs `wg-quick up` on remote server to bring up its Wireguard interface.
+    /// Common failures are explained, along with how to fix them.
+    /// On a shared server, the tunnel joins the host's interface instead.
     fn bring_up_remote_wg(&self) -> Result<()> {
+        if self.options.share.is_some() {
+            for cmd in share::join_cmds(&self.wg) {
+                self.run_ssh_cmd(cmd.iter().map(String::as_str).collect())?;
+            }
+            return Ok(());
+        }
         let cmd = vec!["wg-quick", "up", "/tmp/innisfree.conf"];
         tracing::trace!("Activating remote wg interface");
-        self.run_ssh_cmd(cmd)
+        match self.run_ssh_cmd(cmd) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let hint = e
+                    .downcast_ref::<SshCommandError>()
+                    .and_then(|c| wg_quick_hint(&c.stderr));
+                match hint {
+                    Some(hint) => Err(error::wireguard_context(e, hint)),
+                    None => Err(e),
+                }
+            }
+        }
+    }
+    /// Runs certbot on the remote server to obtain a Let's Encrypt certificate
+    /// for the domain. The nginx plugin adds the 443/TCP listener and the
+    /// redirect from HTTP, and the certbot package's timer handles renewal.
+    fn obtain_certificate(&self, domain: &str) -> Result<()> {
+        if self.options.remote_proxy == RemoteProxy::Caddy {
+            // Caddy obtains and renews the certificate itself, in the background.
+            return Ok(());
+        }
+        let cmd = vec![
+            "sudo",
+            "certbot",
+            "--nginx",
+            "--non-interactive",
+            "--agree-tos",
+            "--register-unsafely-without-email",
+            "--redirect",
+            "-d",
+            domain,
+        ];
+        self.run_ssh_cmd(cmd)?;
+        Ok(())
     }
     /// Runs `wg-quick up` on localhost to bring up local Wireguard interface.
     fn bring_up_local_wg(&self) -> Result<()> {
@@ -173,85 +1288,509 @@ impl TunnelManager {
         // Bring down in case the config was running with a different host
         let mut fpath = std::path::PathBuf::from(&self.config_dir()?);
         fpath.push(format!("{}.conf", &self.name));
+        if self.options.wg_transport == WireguardTransport::Tcp {
+            let port = u16::try_from(self.wg.wg_local_device.peer.listenport)?;
+            self.start_udp2raw(SocketAddr::new(self.server().ipv4_address()?, port))?;
+        }
         tracing::trace!("Running local wg-quick cmd");
         std::process::Command::new("wg-quick")
             .arg("up")
             .arg(fpath.display().to_string())
             .stdout(std::process::Stdio::null())
             .stderr(std::process::Stdio::null())
-            .status()?;
+            .status()
+            .map_err(error::wireguard)
+            .context("Failed to run wg-quick")?;
+        self.redact_local_wg_config()
+    }
+    /// Removes the private key from the local Wireguard config, once the
+    /// interface is up, if secrets are encrypted at rest, see [secrets],
+    /// since the key is kept in the encrypted state anyway.
+    fn redact_local_wg_config(&self) -> Result<()> {
+        if secrets::encrypting() {
+            let fpath = self.config_dir()?.join(format!("{}.conf", &self.name));
+            let config = std::fs::read_to_string(&fpath)?;
+            std::fs::write(&fpath, without_private_key(&config))?;
+        }
+        Ok(())
+    }
+    /// Starts the local end of the TCP transport, connecting to `server`,
+    /// replacing any already running, e.g. for an old server.
+    fn start_udp2raw(&self, server: SocketAddr) -> Result<()> {
+        self.stop_udp2raw();
+        let key = self.wg.wg_remote_device.interface.keypair.public();
+        let child = udp2raw::spawn_client(server, key).map_err(error::wireguard)?;
+        match self.udp2raw.lock() {
+            Ok(mut c) => *c = Some(child),
+            Err(e) => *e.into_inner() = Some(child),
+        }
         Ok(())
     }
+    /// Stops the local end of the TCP transport, if running.
+    fn stop_udp2raw(&self) {
+        let child = match self.udp2raw.lock() {
+            Ok(mut c) => c.take(),
+            Err(e) => e.into_inner().take(),
+        };
+        if let Some(mut child) = child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
     /// Run `wg-quick down` on localhost to destroy local Wireguard interface.
     fn bring_down_local_wg(&self) -> Result<()> {
-        let cmd = "wg-quick";
-        let fpath = make_config_dir(&self.name)?.join(format!("{}.conf", &self.name));
-        let fpath_s = &fpath.display().to_string();
-        std::process::Command::new(cmd)
-            .args(vec!["down", fpath_s])
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .status()
-            .context("Failed to remove local Wireguard interface")?;
-        Ok(())
+        bring_down_local_wg(&self.name)
     }
     /// Generates an SSH known_hosts file, containing the automatically
     /// generated SSH hostkey for the remote server. Doing so allows
-    /// us to verify the SSH connection on first use.
+    /// manual `ssh` sessions to verify the server on first use.
     fn known_hosts(&self) -> Result<String> {
-        let ipv4_address = &self.server.ipv4_address()?;
         let server_host_key = &self.ssh_server_keypair.public;
-        let host_line = format!("{} {}", ipv4_address, server_host_key);
+        let host_line = format!("{} {}", self.ssh_ip()?, server_host_key);
         let fpath = make_config_dir(&self.name)?.join("known_hosts");
         std::fs::write(&fpath, host_line).context("Failed to create known_hosts")?;
         Ok(fpath.display().to_string())
     }
-    /// Execute a shell command on the remote server.
-    fn run_ssh_cmd(&self, cmd: Vec<&str>) -> Result<()> {
+    /// Connects to the remote server, authenticating both ends with the
+    /// generated keypairs. Also writes them to the config dir, for `ssh -i`.
+    fn ssh_client(&self) -> Result<SshClient> {
+        self.ssh_client_keypair.write_locally(&self.name)?;
+        self.known_hosts()?;
+        let connect = |ip| {
+            SshClient::connect(
+                ip,
+                &self.ssh_client_keypair.private,
+                &self.ssh_server_keypair.public,
+            )
+        };
+        match connect(self.ssh_ip()?) {
+            // The server may have rebooted, dropping its firewall and
+            // taking down its end of the tunnel, so try the public IP too.
+            Err(e) if self.ssh_over_tunnel.load(Ordering::SeqCst) => {
+                tracing::debug!("SSH over the tunnel failed: {:#}", e);
+                connect(self.server().ipv4_address()?)
+            }
+            r => r,
+        }
+    }
+    /// Returns the address on which the server accepts SSH, see [TunnelState::ssh_ip].
+    fn ssh_ip(&self) -> Result<IpAddr> {
+        if self.ssh_over_tunnel.load(Ordering::SeqCst) {
+            Ok(self.wg.wg_remote_ip)
+        } else {
+            self.server().ipv4_address()
+        }
+    }
+    /// Closes the server's SSH to the internet, leaving it reachable only
+    /// over the tunnel, for `--private-ssh`. The session in use is kept,
+    /// but later connections go over the tunnel.
+    fn close_public_ssh(&self) -> Result<()> {
+        let services = match TunnelState::load(&self.name) {
+            Ok(s) => s.services,
+            Err(_) => self.services.clone(),
+        };
+        let (path, ruleset) = self.server_firewall(&services, false)?;
+        self.run_ssh_cmd_with_input(vec!["sudo", "tee", path], &ruleset)?;
+        self.run_ssh_cmd(vec!["sudo", "nft", "-f", path])?;
+        self.ssh_over_tunnel.store(true, Ordering::SeqCst);
+        TunnelState::update(&self.name, |s| s.ssh_over_tunnel = true)?;
+        self.known_hosts()?;
+        tracing::info!("Closed SSH on server, it's only reachable over the tunnel now");
+        Ok(())
+    }
+    /// Execute a shell command on the remote server, returning its output.
+    /// Fails with an [SshCommandError] if the command does. Abandoned if
+    /// [TunnelManager::shutdown] is called, e.g. while waiting on cloud-init.
+    fn run_ssh_cmd(&self, cmd: Vec<&str>) -> Result<SshOutput> {
         tracing::trace!("Entering run_ssh_cmd");
-        let ssh_kp = &self
-            .ssh_client_keypair
-            .write_locally(&self.name)?;
-        let ssh_kp_s = ssh_kp.display().to_string();
-        let known_hosts_opt = format!("UserKnownHostsFile={}", &self.known_hosts()?);
-        let ipv4_address = &self.server.ipv4_address()?.to_string();
-        let mut cmd_args = vec![
-            "-l",
-            "innisfree",
-            "-i",
-            &ssh_kp_s,
-            "-o",
-            &known_hosts_opt,
-            "-o",
-            "ConnectTimeout=5",
-            ipv4_address,
-        ];
-        cmd_args.extend(cmd);
-        std::process::Command::new("ssh")
-            .args(cmd_args)
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
+        self.run_ssh_cmd_with_input(cmd, "")
+    }
+    /// Execute a shell command on the remote server, passing `input` on stdin,
+    /// and returning its output. Fails with an [SshCommandError] if the command does.
+    fn run_ssh_cmd_with_input(&self, cmd: Vec<&str>, input: &str) -> Result<SshOutput> {
+        self.with_ssh(|c| c.exec(&cmd, input.as_bytes(), &|| self.check_cancelled()))
+    }
+    /// Calls `f` with an SSH session to the server. Callers share one session,
+    /// connected on first use, rather than each paying for a handshake;
+    /// it's reconnected if SSH itself fails.
+    fn with_ssh<T>(&self, f: impl FnOnce(&SshClient) -> Result<T>) -> Result<T> {
+        let mut session = match self.ssh.lock() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+        let client = match session.take() {
+            Some(client) => client,
+            None => self.ssh_client()?,
+        };
+        let result = f(&client);
+        // Keep the session unless it broke, e.g. because sshd restarted during boot.
+        match &result {
+            Err(e) if e.downcast_ref::<SshCommandError>().is_none() => {}
+            _ => *session = Some(client),
+        }
+        result
+    }
+    /// Copies the local file `local` to `remote` on the server, e.g. an
+    /// updated nginx snippet. The file is written as the `innisfree` user.
+    pub fn push_file(&self, local: &Path, remote: &str) -> Result<(), InnisfreeError> {
+        let size = self.with_ssh(|c| c.push_file(local, remote))?;
+        tracing::debug!("Copied {} bytes to {} on server", size, remote);
+        Ok(())
+    }
+    /// Uploads `binary`, a static build of innisfree, to the server, where
+    /// it replaces nginx, then restarts its service to run it.
+    fn upload_forwarder(&self, binary: &Path) -> Result<()> {
+        let uploaded = "/tmp/innisfree";
+        self.push_file(binary, uploaded)?;
+        let cmd = forwarder::install_cmd(uploaded);
+        self.run_ssh_cmd(cmd.iter().map(String::as_str).collect())?;
+        Ok(())
+    }
+    /// Copies the file `remote` on the server to `local`, e.g. a log file.
+    pub fn pull_file(&self, remote: &str, local: &Path) -> Result<(), InnisfreeError> {
+        let size = self.with_ssh(|c| c.pull_file(remote, local))?;
+        tracing::debug!("Copied {} bytes from {} on server", size, remote);
+        Ok(())
+    }
+    /// Pushes the forwarding config for `services` to the running server and
+    /// reloads it, then updates the local Wireguard config to match, so that
+    /// services can be added or removed without recreating the tunnel.
+    /// Doesn't touch the cloud firewall, see [InnisfreeServer::open_port].
+    pub fn reload_services(&self, services: &[ServicePort]) -> Result<(), InnisfreeError> {
+        if self.forwarding_over_ssh() {
+            let msg = "Services can't be changed while forwarding over SSH, restart the tunnel";
+            return Err(error::config(msg).into());
+        }
+        let dest_ip = self.wg.wg_local_device.interface.address;
+        self.push_forwarding_config(services, dest_ip)?;
+        self.local_wg_device()?
+            .write_locally(&self.name, &self.options.local_services(services))
+            .context("failed to write wireguard configs")?;
+        self.redact_local_wg_config()?;
+        Ok(())
+    }
+    /// Pushes the forwarding config for `services`, sending traffic to
+    /// `dest_ip`, to the server and reloads it, along with the firewall.
+    fn push_forwarding_config(&self, services: &[ServicePort], dest_ip: IpAddr) -> Result<()> {
+        let public_ssh = !self.ssh_over_tunnel.load(Ordering::SeqCst);
+        let (path, ruleset) = self.server_firewall(services, public_ssh)?;
+        self.run_ssh_cmd_with_input(vec!["sudo", "tee", path], &ruleset)?;
+        self.run_ssh_cmd(vec!["sudo", "nft", "-f", path])
+            .context("failed to reload firewall on server")?;
+        let (path, config) = forwarding_config(services, dest_ip, &self.options)?;
+        // Guests' streams sit alongside the host's.
+        let guest_path = share::stream_config_path(&self.name);
+        let path = match self.options.share {
+            Some(_) => guest_path.as_str(),
+            None => path,
+        };
+        tracing::debug!("Updating {} on server", path);
+        self.run_ssh_cmd_with_input(vec!["sudo", "tee", path], &config)?;
+        let reload = if self.options.dnat {
+            vec!["sudo", "nft", "-f", path]
+        } else if self.options.remote_proxy == RemoteProxy::Caddy {
+            vec!["sudo", "systemctl", "reload", caddy::SERVICE_NAME]
+        } else if self.options.remote_proxy == RemoteProxy::Innisfree {
+            // Proxies are bound at startup, so open connections are dropped.
+            vec!["sudo", "systemctl", "restart", forwarder::SERVICE_NAME]
+        } else {
+            vec!["sudo", "systemctl", "reload", "nginx"]
+        };
+        self.run_ssh_cmd_with_input(reload, "")
+            .context("failed to reload forwarding config on server")?;
+        if self.options.health_port.is_some() {
+            // Read on each check, so needs no reload.
+            let targets = remote_health::targets(services, dest_ip);
+            self.run_ssh_cmd_with_input(
+                vec!["sudo", "tee", remote_health::TARGETS_PATH],
+                &targets,
+            )?;
+        }
+        Ok(())
+    }
+    /// Returns the path and contents of the server's firewall, accepting
+    /// `services`, plus those of the other tunnels sharing the server, if
+    /// any, see [crate::share]. On a shared server, the host's settings apply.
+    fn server_firewall(
+        &self,
+        services: &[ServicePort],
+        public_ssh: bool,
+    ) -> Result<(&'static str, String)> {
+        if let Some(host) = &self.options.share {
+            return share::server_firewall(host, &self.name, services);
+        }
+        let all = share::server_services(&self.name, &self.name, services)?;
+        let wg_port = u16::try_from(self.wg.wg_remote_device.interface.listenport)?;
+        firewall_config(&all, wg_port, &self.options, public_ssh)
+    }
+    /// Allows or blocks traffic from the tunnel to a service's local port,
+    /// mirroring the rules in the local Wireguard config. Only TCP services
+    /// are filtered, as in the config.
+    pub fn set_local_port_open(&self, service: &ServicePort, open: bool) -> Result<()> {
+        if service.protocol != Protocol::Tcp {
+            return Ok(());
+        }
+        // Insert, rather than append, so the rule precedes the catch-all drop.
+        let action = if open { "-I" } else { "-D" };
+        let port = service.local_port.to_string();
+        let status = std::process::Command::new("iptables")
+            .args([
+                action, "INPUT", "-i", &self.name, "-m", "tcp", "-p", "tcp", "--dport", &port,
+                "-j", "ACCEPT",
+            ])
             .status()
-            .context("ssh command failed")?;
+            .context("Failed to run iptables")?;
+        if !status.success() {
+            return Err(anyhow!("Failed to update firewall for {}/TCP", port));
+        }
+        Ok(())
+    }
+    /// Pushes the tunnel's config to a server claimed from the pool, in
+    /// place of the one it booted with: the remote end of the tunnel, keyed
+    /// for this tunnel, and the forwarding config and firewall for its services.
+    fn reconfigure_claimed(&self) -> Result<()> {
+        let remote = &self.wg.wg_remote_device;
+        self.run_ssh_cmd_with_input(
+            vec!["sudo", "tee", "/tmp/innisfree.conf"],
+            &remote.config()?,
+        )?;
+        if self.options.wg_transport == WireguardTransport::Tcp {
+            // The transport is keyed with the remote end's public key.
+            let port = u16::try_from(remote.interface.listenport)?;
+            let unit = udp2raw::server_unit(port, remote.interface.keypair.public())?;
+            self.run_ssh_cmd_with_input(vec!["sudo", "tee", udp2raw::SERVER_UNIT_PATH], &unit)?;
+            self.run_ssh_cmd(vec!["sudo", "systemctl", "daemon-reload"])?;
+            self.run_ssh_cmd(vec!["sudo", "systemctl", "restart", "innisfree-udp2raw"])?;
+        }
+        self.push_forwarding_config(&self.services, self.wg.wg_local_device.interface.address)
+    }
+    /// Parks the server in the pool, for the next pooled tunnel to claim,
+    /// see [crate::pool]. SSH is reopened to the internet, then the server's
+    /// end of the tunnel taken down, and its firewall closed to the services.
+    /// Servers with a reserved IP aren't parked, since the IP would follow
+    /// the server to another tunnel.
+    fn park(&self) -> Result<()> {
+        if self.static_ip.is_some() {
+            return Err(anyhow!("the server has a reserved IP"));
+        }
+        if !share::guests(&self.name)?.is_empty() {
+            return Err(anyhow!("the server is shared with other tunnels"));
+        }
+        let state = TunnelState::load(&self.name)?;
+        let (path, ruleset) = self.server_firewall(&[], true)?;
+        self.run_ssh_cmd_with_input(vec!["sudo", "tee", path], &ruleset)?;
+        self.run_ssh_cmd(vec!["sudo", "nft", "-f", path])?;
+        // Later sessions can't go over the tunnel, which is about to go down.
+        self.ssh_over_tunnel.store(false, Ordering::SeqCst);
+        match self.ssh.lock() {
+            Ok(mut s) => *s = None,
+            Err(e) => *e.into_inner() = None,
+        }
+        self.run_ssh_cmd(vec!["sudo", "wg-quick", "down", "/tmp/innisfree.conf"])?;
+        self.run_ssh_cmd(vec!["sudo", "rm", "/tmp/innisfree.conf"])?;
+        pool::park(&ParkedServer {
+            server_name: state.server_name(&self.name).to_string(),
+            provider: state.provider,
+            server_id: state.server_id,
+            server_ip: state.server_ip,
+            services: state.services,
+            options: self.options.clone(),
+            ssh_client_keypair: self.ssh_client_keypair.clone(),
+            ssh_server_keypair: self.ssh_server_keypair.clone(),
+            parked_at: state::now(),
+        })?;
+        tracing::info!(
+            "Parked server {} for the next pooled tunnel",
+            self.server().id()
+        );
         Ok(())
     }
     /// Destroys all infrastructure, including local Wireguard interfaces,
     /// remote server, and local config dir.
-    pub async fn clean(&self) -> Result<()> {
+    pub async fn clean(&self) -> Result<(), InnisfreeError> {
+        // Parked while the tunnel is up, in case SSH is only reachable over it.
+        let parked = self.options.pool
+            && match self.park() {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!("Failed to park server, destroying it: {:#}", e);
+                    false
+                }
+            };
         tracing::debug!("removing local Wireguard interface");
         // Ignore errors, since we want to try all handlers
         let _ = self.bring_down_local_wg();
-        let _ = self.server.destroy().await;
+        self.stop_udp2raw();
+        // In case an `innisfree ssh --agent` session was killed, leaving the key.
+        let _ = agent::remove(&self.ssh_client_keypair.private);
+        if let Ok(TunnelState { dns: Some(r), .. }) = TunnelState::load(&self.name) {
+            if let Err(e) = r.tear_down().await {
+                tracing::warn!("Failed to tear down DNS record: {:#}", e);
+            }
+        }
+        if self.options.share.is_some() {
+            if let Err(e) = share::leave(&self.name, self.server().as_ref()).await {
+                tracing::warn!("Failed to remove tunnel from shared server: {:#}", e);
+            }
+        } else if !parked {
+            let guests: Vec<String> = share::guests(&self.name)?
+                .into_iter()
+                .map(|(n, _)| n)
+                .collect();
+            if !guests.is_empty() {
+                tracing::warn!(
+                    "Destroying server shared with tunnels: {}",
+                    guests.join(", ")
+                );
+            }
+            let _ = self.server().destroy().await;
+        }
         clean_config_dir(&self.name)?;
+        self.notify(TunnelEvent::Destroyed).await;
         Ok(())
     }
 }
 
+/// Claims the longest-parked server from `provider` compatible with
+/// `options`, if any, see [pool::claim], and opens its cloud firewall for
+/// `services`, closing it to the parked tunnel's. Parked servers that can't
+/// be adopted, e.g. because they were destroyed since, are dropped.
+async fn claim_parked(
+    provider: &dyn ServerProvider,
+    options: &CloudConfigOptions,
+    services: &[ServicePort],
+) -> Result<Option<(ParkedServer, Box<dyn InnisfreeServer>)>> {
+    while let Some(parked) = pool::claim(provider.name(), options)? {
+        let server = match provider.adopt(&parked.server_name, &parked.server_id).await {
+            Ok(server) => server,
+            Err(e) => {
+                tracing::warn!("Dropping parked server {}: {:#}", parked.server_id, e);
+                continue;
+            }
+        };
+        let converged: Result<()> = async {
+            for s in services
+                .iter()
+                .filter(|s| !parked.services.iter().any(|p| p.same_port(s)))
+            {
+                server.open_port(s).await?;
+            }
+            for s in parked
+                .services
+                .iter()
+                .filter(|p| !services.iter().any(|s| s.same_port(p)))
+            {
+                server.close_port(s).await?;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = converged {
+            // Left for the next tunnel, or `innisfree pool drain`.
+            let _ = pool::park(&parked);
+            return Err(e.context("Failed to open ports on parked server"));
+        }
+        return Ok(Some((parked, server)));
+    }
+    Ok(None)
+}
+
+/// Run `wg-quick down` on localhost to destroy the local Wireguard interface
+/// for the tunnel `service_name`.
+fn bring_down_local_wg(service_name: &str) -> Result<()> {
+    let cmd = "wg-quick";
+    let fpath = make_config_dir(service_name)?.join(format!("{}.conf", service_name));
+    let fpath_s = &fpath.display().to_string();
+    std::process::Command::new(cmd)
+        .args(vec!["down", fpath_s])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .context("Failed to remove local Wireguard interface")?;
+    Ok(())
+}
+
+/// Checks, before creating any cloud resources, that the tunnel `name` can
+/// forward `services`: that the ports its local proxies would listen on, on
+/// the Wireguard interface, aren't already taken by something listening on
+/// all addresses, and, if it's to `share` another tunnel's server, that the
+/// server doesn't already forward any of its public ports.
+pub fn preflight(
+    name: &str,
+    services: &[ServicePort],
+    dest_ip: IpAddr,
+    tls: bool,
+    share: Option<&str>,
+) -> Result<()> {
+    let in_use = tcp_ports_in_use();
+    if let Some(s) = services.iter().find(|s| {
+        s.protocol == Protocol::Tcp
+            && control::needs_proxy(s, dest_ip, tls && s.is_https())
+            && in_use.iter().any(|p| i32::from(*p) == s.local_port)
+    }) {
+        let msg = format!(
+            "Local port {} for {}/TCP is already in use, so it can't be proxied on the Wireguard interface",
+            s.local_port, s.port
+        );
+        return Err(error::config(msg));
+    }
+    if let Some(host) = share {
+        share::check_ports(host, name, services).map_err(error::config)?;
+    }
+    Ok(())
+}
+
+/// Tears down a tunnel running in a separate process, or left behind by one
+/// that was killed. Tears down the tunnel's DNS record, if any, destroys the
+/// remote server via the provider recorded in the tunnel's state, then the
+/// local Wireguard interface and config dir.
+pub async fn down(service_name: &str, registry: &ProviderRegistry) -> Result<(), InnisfreeError> {
+    let state = TunnelState::load(service_name)?;
+    if let Some(r) = &state.dns {
+        if let Err(e) = r.tear_down().await {
+            tracing::warn!("Failed to tear down DNS record: {:#}", e);
+        }
+    }
+    if state.options.share.is_some() {
+        // The server belongs to the host tunnel, so only this one leaves it.
+        let server = registry
+            .get(&state.provider)?
+            .adopt(state.server_name(service_name), &state.server_id)
+            .await?;
+        share::leave(service_name, server.as_ref())
+            .await
+            .context("Failed to remove tunnel from shared server")?;
+        if let Err(e) = bring_down_local_wg(service_name) {
+            tracing::warn!("{}", e);
+        }
+        return Ok(clean_config_dir(service_name)?);
+    }
+    tracing::debug!(
+        "Destroying {} server {} for tunnel '{}'",
+        state.provider,
+        state.server_id,
+        service_name
+    );
+    registry
+        .get(&state.provider)?
+        .destroy(state.server_name(service_name), &state.server_id)
+        .await
+        .context("Failed to destroy server")?;
+    if let Err(e) = bring_down_local_wg(service_name) {
+        tracing::warn!("{}", e);
+    }
+    let _ = agent::remove(&state.ssh_client_keypair.private);
+    Ok(clean_config_dir(service_name)?)
+}
+
 /// Look up IPv4 address for remote server. Accepts a service name,
 /// so that `innisfree ip` on the CLI can return an answer by inspecting
 /// the on-disk config for an instance running in a separate process.
-// TODO: store ip in config file locally
+/// Falls back to the known_hosts file, for tunnels without saved state.
 pub fn get_server_ip(service_name: &str) -> Result<IpAddr> {
+    if let Ok(state) = TunnelState::load(service_name) {
+        return Ok(state.server_ip);
+    }
     tracing::trace!("Looking up server IP from known_hosts file");
     let fpath = make_config_dir(service_name)?.join("known_hosts");
     let known_hosts = std::fs::read_to_string(&fpath)?;
@@ -260,51 +1799,81 @@ pub fn get_server_ip(service_name: &str) -> Result<IpAddr> {
     Ok(ip)
 }
 
-/// Create an interface SSH session on remote server.
-pub fn open_shell(service_name: &str) -> Result<()> {
-    let client_key = make_config_dir(service_name)?.join("client_id_ed25519");
-    let client_key_s = client_key.display().to_string();
-    let known_hosts = make_config_dir(service_name)?.join("known_hosts");
-    let known_hosts_opt = format!("UserKnownHostsFile={}", known_hosts.display());
-    let ipv4_address = get_server_ip(service_name)?.to_string();
-    let cmd_args = vec![
-        "-l",
-        "innisfree",
-        "-i",
-        &client_key_s,
-        "-o",
-        &known_hosts_opt,
-        "-o",
-        "ConnectTimeout=5",
-        &ipv4_address,
-    ];
-    std::process::Command::new("ssh")
-        .args(cmd_args)
-        .status()
-        .context("SSH interactive session failed")?;
+/// Create an interface SSH session on remote server. With `agent`, the client
+/// key is added to the running ssh-agent for the duration of the session.
+pub fn open_shell(service_name: &str, agent: bool) -> Result<()> {
+    let client = if agent {
+        SshClient::for_tunnel_via_agent(service_name)?
+    } else {
+        SshClient::for_tunnel(service_name)?
+    };
+    client.shell().context("SSH interactive session failed")?;
     Ok(())
 }
 
+/// Runs a command on the remote server, for `innisfree exec`, passing
+/// through its stdin, stdout, and stderr. Returns its exit code, or `None`
+/// if killed, so it can be propagated. Arguments are passed as they are,
+/// so shell syntax, e.g. pipes, needs `sh -c`.
+pub fn exec_remote(service_name: &str, cmd: &[String]) -> Result<Option<i32>> {
+    SshClient::for_tunnel(service_name)?
+        .exec_passthrough(cmd)
+        .context("ssh command failed")
+}
+
+/// Execute a command on the remote server of an instance running in a
+/// separate process, e.g. from `innisfree peer add`, returning its output.
+/// Fails with an [SshCommandError] if the command does.
+pub fn run_remote_cmd(service_name: &str, cmd: &[&str]) -> Result<SshOutput> {
+    SshClient::for_tunnel(service_name)?.exec(cmd, &[], &|| Ok(()))
+}
+
+/// Explains common reasons for `wg-quick up` failing on the server,
+/// given its stderr, and how to fix them.
+fn wg_quick_hint(stderr: &str) -> Option<&'static str> {
+    if stderr.contains("already exists") {
+        Some("the Wireguard interface is already up on the server")
+    } else if stderr.contains("Protocol not supported")
+        || stderr.contains("Unknown device type")
+        || stderr.contains("Operation not supported")
+    {
+        Some("the server's kernel lacks Wireguard support, try a newer image via --image")
+    } else if stderr.contains("command not found") || stderr.contains("No such file") {
+        Some("Wireguard isn't installed on the server, check 'innisfree logs --source cloud-init'")
+    } else if stderr.contains("Address already in use") {
+        Some("the Wireguard port is taken on the server, pick another via --wg-port")
+    } else {
+        None
+    }
+}
+
 /// Spin up local network proxy to handle passing traffic
 /// between the local service(s) and the remote server.
+/// Stops once `cancel` fires.
 pub async fn run_proxy(
     local_ip: IpAddr,
     dest_ip: IpAddr,
     services: Vec<ServicePort>,
+    proxy: ProxyConfig,
+    cancel: CancellationToken,
 ) -> Result<()> {
     // We'll kick off a dedicated proxy for each service,
     // and collect the handles to await them all together, concurrently.
     let mut tasks = vec![];
     for s in services {
-        let listen_addr: SocketAddr = format!("{}:{}", local_ip, &s.local_port).parse()?;
-        let dest_addr: SocketAddr = format!("{}:{}", dest_ip, &s.port).parse()?;
-        let h = proxy_handler(listen_addr, dest_addr);
+        // Build sockets directly from IPs, rather than parsing strings,
+        // so that IPv6 addresses work too.
+        let listen_addr = SocketAddr::new(local_ip, u16::try_from(s.local_port)?);
+        let dest_addr = SocketAddr::new(dest_ip, u16::try_from(s.port)?);
+        let h = proxy_handler(listen_addr, dest_addr, proxy.service(&s), cancel.clone());
         tasks.push(h);
     }
-    // We expect the proxies to block indefinitely, except ctrl+c.
+    // We expect the proxies to block until cancelled, e.g. via ctrl+c.
     // If they return earlier, we'll be able to inspect the errors.
     let proxy_tasks = join_all(tasks).await;
-    tracing::warn!("Proxy stopped unexpectedly, no longer forwarding traffic");
+    if !cancel.is_cancelled() {
+        tracing::warn!("Proxy stopped unexpectedly, no longer forwarding traffic");
+    }
     for t in proxy_tasks {
         match t {
             Ok(t) => {
@@ -318,3 +1887,119 @@ pub async fn run_proxy(
     }
     Ok(())
 }
+
+/// Spin up a local proxy terminating TLS for a single service. It listens
+/// on the service's local port on the Wireguard interface, where the server
+/// passes encrypted traffic through, and forwards plaintext to the same port
+/// on the dest IP. Stops once `cancel` fires.
+pub async fn run_tls_proxy(
+    local_ip: IpAddr,
+    dest_ip: IpAddr,
+    service: ServicePort,
+    acceptor: TlsAcceptor,
+    proxy: ProxyConfig,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let port = u16::try_from(service.local_port)?;
+    tls_proxy_handler(
+        SocketAddr::new(local_ip, port),
+        SocketAddr::new(dest_ip, port),
+        acceptor,
+        proxy.service(&service),
+        cancel,
+    )
+    .await
+    .map_err(|e| anyhow!("TLS proxy failed: {}", e))
+}
+
+/// Spin up a local proxy for a single service with the PROXY protocol enabled.
+/// It listens on the service's local port on the Wireguard interface, and
+/// rewrites the header sent by the server before forwarding to the same port
+/// on the dest IP. Not needed for [crate::config::ProxyProtocol::V1], which
+/// nginx sends as-is. Stops once `cancel` fires.
+pub async fn run_proxy_protocol(
+    local_ip: IpAddr,
+    dest_ip: IpAddr,
+    service: ServicePort,
+    proxy: ProxyConfig,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let port = u16::try_from(service.local_port)?;
+    let mode = service
+        .proxy_protocol
+        .ok_or_else(|| anyhow!("PROXY protocol not enabled for {}", service.port))?;
+    proxy_protocol_handler(
+        SocketAddr::new(local_ip, port),
+        SocketAddr::new(dest_ip, port),
+        mode,
+        proxy.service(&service),
+        cancel,
+    )
+    .await
+    .map_err(|e| anyhow!("PROXY protocol proxy failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wg_quick_failures_explained() {
+        let hint = wg_quick_hint("wg-quick: `innisfree' already exists");
+        assert!(hint.is_some_and(|h| h.contains("already up")));
+        let hint = wg_quick_hint(
+            "RTNETLINK answers: Operation not supported\nUnable to access interface: Protocol not supported",
+        );
+        assert!(hint.is_some_and(|h| h.contains("kernel")));
+        assert_eq!(wg_quick_hint("sudo: a password is required"), None);
+    }
+
+    #[test]
+    fn recreated_interface_gets_private_key() -> Result<()> {
+        let wg = WireguardManager::with_subnet("test", "10.50.0.0/30".parse()?)?;
+        let options = CloudConfigOptions::default();
+        let services = vec![ServicePort::try_from("443:8443/TCP")?];
+        // With secrets encrypted, the config on disk is redacted once up.
+        let config =
+            local_device(&wg, "203.0.113.5".parse()?, &options).config_with_services(&services)?;
+        assert!(!without_private_key(&config).contains("PrivateKey"));
+        // Reconnecting to a new address rewrites it in full.
+        let config =
+            local_device(&wg, "203.0.113.9".parse()?, &options).config_with_services(&services)?;
+        let key = config.lines().find_map(|l| l.strip_prefix("PrivateKey = "));
+        assert!(key.is_some_and(|k| !k.trim().is_empty()));
+        assert!(config.contains("Endpoint = 203.0.113.9:"));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn blocking_leaves_other_tasks_running() -> Result<()> {
+        let ticker = tokio::spawn(async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            std::time::Instant::now()
+        });
+        let finished = blocking(|| {
+            std::thread::sleep(Duration::from_millis(500));
+            std::time::Instant::now()
+        });
+        assert!(ticker.await? < finished);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocking_runs_inline_on_current_thread() {
+        assert_eq!(blocking(|| 1), 1);
+    }
+
+    #[test]
+    fn keepalives_count_as_idle() {
+        let mut tracker = IdleTracker::default();
+        let minute = Duration::from_secs(60);
+        assert_eq!(tracker.record(10_000, minute), minute);
+        assert_eq!(tracker.record(10_300, minute), 2 * minute);
+        assert_eq!(tracker.record(50_000, minute), Duration::ZERO);
+        assert_eq!(tracker.record(50_200, minute), minute);
+        // A recreated interface starts its counters over.
+        assert_eq!(tracker.record(100, minute), 2 * minute);
+    }
+}
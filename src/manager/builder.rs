@@ -0,0 +1,326 @@
+//! Builder for [TunnelManager], so that new options can be added
+//! without breaking callers, as they would with positional arguments.
+
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ServicePort;
+use crate::error::{self, InnisfreeError};
+use crate::event::TunnelEvent;
+use crate::proxy::ClientLimits;
+use crate::server::cloudinit::{CloudConfigOptions, RemoteProxy};
+use crate::server::{ProviderRegistry, ServerProvider};
+use crate::ssh::SshKeyType;
+use crate::webhook::Webhook;
+use crate::wg::WireguardTransport;
+
+use super::TunnelManager;
+
+#[derive(Default)]
+/// Configures and creates a [TunnelManager], via [TunnelManager::builder].
+/// Only the services are required; the provider defaults to the first
+/// in [ProviderRegistry::default].
+pub struct TunnelManagerBuilder {
+    name: Option<String>,
+    services: Vec<ServicePort>,
+    provider: Option<Arc<dyn ServerProvider>>,
+    provider_name: Option<String>,
+    region: Option<String>,
+    size: Option<String>,
+    floating_ip: Option<IpAddr>,
+    options: CloudConfigOptions,
+    events: Option<broadcast::Sender<TunnelEvent>>,
+    webhooks: Vec<Webhook>,
+    self_heal: bool,
+    ssh_timeout: Option<Duration>,
+    cancel: Option<CancellationToken>,
+}
+
+impl TunnelManagerBuilder {
+    /// Sets the tunnel's name, used for the server and config dir. Defaults to `innisfree`.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Sets the services to forward.
+    pub fn services(mut self, services: Vec<ServicePort>) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// Sets the provider with which to create the server.
+    pub fn provider(mut self, provider: Arc<dyn ServerProvider>) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// Sets the provider by name, e.g. `linode`, from [ProviderRegistry::default].
+    /// Ignored if a provider was set via [TunnelManagerBuilder::provider].
+    pub fn provider_name(mut self, name: &str) -> Self {
+        self.provider_name = Some(name.to_string());
+        self
+    }
+
+    /// Sets the region for the server, e.g. `sfo2`. Only supported
+    /// by DigitalOcean, when the provider is chosen by name.
+    pub fn region(mut self, region: &str) -> Self {
+        self.region = Some(region.to_string());
+        self
+    }
+
+    /// Sets the size of the server, e.g. `s-1vcpu-1gb`. Only supported
+    /// by DigitalOcean, when the provider is chosen by name.
+    pub fn size(mut self, size: &str) -> Self {
+        self.size = Some(size.to_string());
+        self
+    }
+
+    /// Sets a pre-existing Floating IP to attach to the server.
+    pub fn floating_ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.floating_ip = ip;
+        self
+    }
+
+    /// Sets the server's customizations, e.g. HTTPS. Replaces any Wireguard
+    /// options set so far, so call this before the `wg_*` methods.
+    pub fn options(mut self, options: CloudConfigOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets the MTU for both ends of the Wireguard tunnel.
+    pub fn wg_mtu(mut self, mtu: u16) -> Self {
+        self.options.wg_mtu = Some(mtu);
+        self
+    }
+
+    /// Sets the range from which the Wireguard tunnel's subnet is chosen.
+    pub fn wg_subnet(mut self, subnet: IpNet) -> Self {
+        self.options.wg_subnet = Some(subnet);
+        self
+    }
+
+    /// Sets the UDP port on which the server listens for Wireguard.
+    pub fn wg_port(mut self, port: u16) -> Self {
+        self.options.wg_port = Some(port);
+        self
+    }
+
+    /// Sets how Wireguard's packets reach the server, e.g. over TCP.
+    pub fn wg_transport(mut self, transport: WireguardTransport) -> Self {
+        self.options.wg_transport = transport;
+        self
+    }
+
+    /// Sets the algorithm of the generated SSH keys, e.g. RSA.
+    pub fn ssh_key_type(mut self, key_type: SshKeyType) -> Self {
+        self.options.ssh_key_type = key_type;
+        self
+    }
+
+    /// Sets cloud-config YAML to deep-merge into the generated one,
+    /// e.g. to install extra packages on the server.
+    pub fn extra_user_data(mut self, yaml: &str) -> Self {
+        self.options.extra_user_data = Some(yaml.to_string());
+        self
+    }
+
+    /// Adds an apt package to install on the server, e.g. a monitoring agent.
+    pub fn package(mut self, name: &str) -> Self {
+        self.options.extra_packages.push(name.to_string());
+        self
+    }
+
+    /// Adds a shell command to run once on the server, after it's configured.
+    pub fn runcmd(mut self, cmd: &str) -> Self {
+        self.options.extra_runcmd.push(cmd.to_string());
+        self
+    }
+
+    /// Sets the proxy on the server, e.g. Caddy rather than nginx.
+    pub fn remote_proxy(mut self, proxy: RemoteProxy) -> Self {
+        self.options.remote_proxy = proxy;
+        self
+    }
+
+    /// Uploads a static build of innisfree to the server, when it's the
+    /// remote proxy, rather than downloading the release.
+    pub fn forwarder_binary(mut self, path: &Path) -> Self {
+        self.options.forwarder_binary = Some(path.to_path_buf());
+        self
+    }
+
+    /// Closes SSH on the server to the internet once the tunnel is up.
+    pub fn private_ssh(mut self, private: bool) -> Self {
+        self.options.private_ssh = private;
+        self
+    }
+
+    /// Sets whether to skip installing security updates on the server automatically.
+    pub fn no_auto_updates(mut self, disabled: bool) -> Self {
+        self.options.no_auto_updates = disabled;
+        self
+    }
+
+    /// Sets the server's hostname, rather than naming it after the tunnel.
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.options.hostname = Some(hostname.to_string());
+        self
+    }
+
+    /// Sets whether to claim a parked server, and park it on teardown, see [crate::pool].
+    pub fn pool(mut self, enabled: bool) -> Self {
+        self.options.pool = enabled;
+        self
+    }
+
+    /// Shares the server of the running tunnel `host`, rather than creating
+    /// one, see [crate::share].
+    pub fn share(mut self, host: &str) -> Self {
+        self.options.share = Some(host.to_owned());
+        self
+    }
+
+    /// Limits each client's connections and bandwidth, see [ClientLimits].
+    pub fn client_limits(mut self, limits: ClientLimits) -> Self {
+        self.options.client_limits = limits;
+        self
+    }
+
+    /// Sets the TCP port on which the server answers health checks.
+    pub fn health_port(mut self, port: u16) -> Self {
+        self.options.health_port = Some(port);
+        self
+    }
+
+    /// Sets the channel on which to broadcast events, so subscribers
+    /// see the server being created, too. See [TunnelManager::subscribe].
+    pub fn events(mut self, events: broadcast::Sender<TunnelEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Sets the token with which to interrupt creating the server, e.g. on
+    /// ctrl+c, destroying it once created. The manager keeps the token,
+    /// see [TunnelManager::shutdown].
+    pub fn cancel_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Sets the endpoints notified of lifecycle events.
+    pub fn webhooks(mut self, webhooks: Vec<Webhook>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Recreates the server if it disappears, see [TunnelManager::enable_self_heal].
+    pub fn self_heal(mut self, enabled: bool) -> Self {
+        self.self_heal = enabled;
+        self
+    }
+
+    /// Sets how long to wait for SSH on the new server, before giving up
+    /// on `up()`. By default, waits indefinitely.
+    pub fn ssh_timeout(mut self, timeout: Duration) -> Self {
+        self.ssh_timeout = Some(timeout);
+        self
+    }
+
+    /// Resolves the provider, applying the region and size, if any.
+    fn resolve_provider(&self) -> Result<Arc<dyn ServerProvider>> {
+        let registry = ProviderRegistry::default();
+        let provider = match (&self.provider, &self.provider_name) {
+            (Some(p), _) => p.clone(),
+            (None, Some(name)) => registry.shared(name)?,
+            (None, None) => registry
+                .names()
+                .first()
+                .map(|n| registry.shared(n))
+                .ok_or_else(|| anyhow!("No providers enabled"))??,
+        };
+        if self.region.is_none() && self.size.is_none() {
+            return Ok(provider);
+        }
+        #[cfg(feature = "digitalocean")]
+        if self.provider.is_none() && provider.name() == "digitalocean" {
+            use crate::server::digitalocean::server::DigitalOceanProvider;
+            return Ok(Arc::new(DigitalOceanProvider::new(
+                self.region.clone(),
+                self.size.clone(),
+                None,
+            )));
+        }
+        Err(anyhow!(
+            "Region and size only apply to DigitalOcean, chosen via provider_name"
+        ))
+    }
+
+    /// Creates the server, as [TunnelManager::new] does. Call `up()` on
+    /// the result to build the tunnel.
+    pub async fn build(self) -> Result<TunnelManager, InnisfreeError> {
+        if self.services.is_empty() {
+            return Err(error::config("No services to forward").into());
+        }
+        let provider = self.resolve_provider().map_err(error::config)?;
+        let name = self.name.unwrap_or_else(|| "innisfree".to_string());
+        let events = self
+            .events
+            .unwrap_or_else(|| broadcast::channel(super::EVENT_CAPACITY).0);
+        let mut mgr = TunnelManager::create(
+            &name,
+            self.services,
+            self.floating_ip,
+            provider.as_ref(),
+            self.options,
+            events,
+            self.cancel.unwrap_or_default(),
+        )
+        .await?;
+        mgr.set_webhooks(self.webhooks);
+        mgr.ssh_timeout = self.ssh_timeout;
+        if self.self_heal {
+            mgr.enable_self_heal(provider);
+        }
+        Ok(mgr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wg_options_apply_over_defaults() -> Result<()> {
+        let builder = TunnelManager::builder()
+            .options(CloudConfigOptions {
+                dnat: true,
+                ..Default::default()
+            })
+            .wg_mtu(1380)
+            .wg_port(51821);
+        assert!(builder.options.dnat);
+        assert_eq!(builder.options.wg_mtu, Some(1380));
+        assert_eq!(builder.options.wg_port, Some(51821));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature = "digitalocean", feature = "linode"))]
+    fn region_only_applies_to_digitalocean() -> Result<()> {
+        let builder = TunnelManager::builder().region("ams3");
+        assert_eq!(builder.resolve_provider()?.name(), "digitalocean");
+        let builder = builder.provider_name("linode");
+        assert!(builder.resolve_provider().is_err());
+        let builder = TunnelManager::builder().provider_name("linode");
+        assert_eq!(builder.resolve_provider()?.name(), "linode");
+        Ok(())
+    }
+}
@@ -1,9 +1,15 @@
 //! Utility functions for looking up available
-//! IP ranges for establishing the Wireguard interface.
+//! IP ranges for establishing the Wireguard interface,
+//! and for probing the path MTU to size it.
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use ipnet::IpNet;
 use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::config::make_config_dir;
+
 /// Network subnet range for doling out IP addresses for the Innisfree tunnels.
 /// Each instance of innisfree, regardless of the number of [crate::config::ServicePort]s
 /// in play, requires a `/30` subnet, that is, two (2) unique IP addresses.
@@ -11,12 +17,24 @@ use std::net::IpAddr;
 /// IPs are already claimed, whether by a different instance of innisfree,
 /// or something else entirely.
 pub const INNISFREE_SUBNET: &str = "10.50.0.1/28";
+/// IPv6 equivalent of [INNISFREE_SUBNET], within the Unique Local Address range.
+pub const INNISFREE_SUBNET_V6: &str = "fd50::/124";
+/// Smallest path MTU considered when probing, leaving room for the
+/// Wireguard overhead above the IPv6 minimum of 1280.
+const PMTU_MIN: u16 = 1360;
+/// Largest path MTU considered when probing, i.e. standard Ethernet.
+const PMTU_MAX: u16 = 1500;
 
 /// Checks whether IpAddr exists on local system, whether
 /// it is bound to a local device. If not, assumed to be available.
-fn address_in_use(ip: IpAddr) -> bool {
+/// Addresses bound to the interface named `owner`, if any, don't count,
+/// e.g. a tunnel's own interface left up from a previous run.
+fn address_in_use(ip: IpAddr, owner: Option<&str>) -> bool {
     let mut in_use = false;
     for iface in pnet::datalink::interfaces() {
+        if owner == Some(iface.name.as_str()) {
+            continue;
+        }
         for i in iface.ips {
             if i.ip() == ip {
                 in_use = true;
@@ -28,10 +46,10 @@ fn address_in_use(ip: IpAddr) -> bool {
 
 /// Returns true if none of the addresses in the subnet
 /// are bound on the current system, i.e. all are available.
-fn subnet_available(n: IpNet) -> bool {
+fn subnet_available(n: IpNet, owner: Option<&str>) -> bool {
     let mut is_available = true;
     for h in n.hosts() {
-        if address_in_use(h) {
+        if address_in_use(h, owner) {
             is_available = false;
         }
     }
@@ -43,15 +61,25 @@ fn subnet_available(n: IpNet) -> bool {
 /// an error is returned. The /30 setting for child subnets is hardcoded,
 /// because the WireguardManager only cares about pairs of 2 addresses, i.e. /30.
 pub fn generate_unused_subnet() -> Result<IpNet> {
-    let parent_net: IpNet = INNISFREE_SUBNET.parse()?;
-    let subnets = parent_net.subnets(30)?.collect::<Vec<IpNet>>();
+    generate_unused_subnet_in(INNISFREE_SUBNET.parse()?)
+}
+
+/// Like [generate_unused_subnet], but within an arbitrary `parent_net`,
+/// which may be IPv4 or IPv6. Child subnets hold 2 usable hosts,
+/// i.e. /30 for IPv4 and /127 for IPv6, which has no broadcast address.
+pub fn generate_unused_subnet_in(parent_net: IpNet) -> Result<IpNet> {
+    let prefix_len = match parent_net {
+        IpNet::V4(_) => 30,
+        IpNet::V6(_) => 127,
+    };
+    let subnets = parent_net.subnets(prefix_len)?.collect::<Vec<IpNet>>();
     for subnet in subnets {
         // Skip initial subnet, which is the entirety of the parent_net, /28.
         // We only consider /30s.
         if subnet.hosts().count() > 2 {
             continue;
         }
-        if subnet_available(subnet) {
+        if subnet_available(subnet, None) {
             return Ok(subnet);
         }
     }
@@ -61,6 +89,149 @@ pub fn generate_unused_subnet() -> Result<IpNet> {
     )))
 }
 
+/// Parses a parent range for tunnel subnets, as passed via `--wg-subnet`.
+/// It must be a private range, i.e. RFC 1918 or carrier-grade NAT for IPv4,
+/// or Unique Local Addresses for IPv6, with room for at least one child subnet.
+pub fn parse_parent_subnet(s: &str) -> Result<IpNet> {
+    let parent: IpNet = s
+        .parse()
+        .map_err(|_| anyhow!("Invalid subnet '{}', expected CIDR notation", s))?;
+    let private: &[&str] = match parent {
+        IpNet::V4(_) => &[
+            "10.0.0.0/8",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "100.64.0.0/10",
+        ],
+        IpNet::V6(_) => &["fc00::/7"],
+    };
+    if !private
+        .iter()
+        .any(|p| p.parse::<IpNet>().is_ok_and(|p| p.contains(&parent)))
+    {
+        return Err(anyhow!(
+            "Subnet {} isn't a private range, e.g. within 10.0.0.0/8",
+            parent
+        ));
+    }
+    let max_prefix_len = match parent {
+        IpNet::V4(_) => 30,
+        IpNet::V6(_) => 127,
+    };
+    if parent.prefix_len() > max_prefix_len {
+        return Err(anyhow!(
+            "Subnet {} is too small, needs a prefix of /{} or shorter",
+            parent,
+            max_prefix_len
+        ));
+    }
+    Ok(parent.trunc())
+}
+
+/// Path to the file recording the subnet used by the tunnel `name`.
+/// Stored outside the tunnel's config dir, which is removed on clean,
+/// so that restarts reuse the same addresses.
+fn recorded_subnet_path(name: &str) -> Result<PathBuf> {
+    Ok(make_config_dir(".wg-subnets")?.join(name))
+}
+
+/// Looks up the subnet last used by the tunnel `name`, if any.
+pub fn recorded_subnet(name: &str) -> Result<Option<IpNet>> {
+    let fpath = recorded_subnet_path(name)?;
+    if !fpath.exists() {
+        return Ok(None);
+    }
+    let subnet = std::fs::read_to_string(&fpath)
+        .with_context(|| format!("Failed to read {}", fpath.display()))?;
+    Ok(Some(subnet.trim().parse()?))
+}
+
+/// Returns a subnet for the tunnel `name` within `parent_net`. Reuses the
+/// subnet recorded by a previous run if it's still within the parent and
+/// available, allowing for the tunnel's own interface; otherwise finds an
+/// unused one, and records it for next time.
+pub fn choose_subnet(name: &str, parent_net: IpNet) -> Result<IpNet> {
+    if let Some(subnet) = recorded_subnet(name)? {
+        if parent_net.contains(&subnet) && subnet_available(subnet, Some(name)) {
+            tracing::debug!("Reusing tunnel subnet {}", subnet);
+            return Ok(subnet);
+        }
+    }
+    let subnet = generate_unused_subnet_in(parent_net)?;
+    std::fs::write(recorded_subnet_path(name)?, subnet.to_string())
+        .context("Failed to record tunnel subnet")?;
+    Ok(subnet)
+}
+
+/// Tables of the machine's TCP sockets, for IPv4 and IPv6.
+const TCP_TABLES: [&str; 2] = ["/proc/net/tcp", "/proc/net/tcp6"];
+
+/// Returns the ports listened on for all addresses, e.g. `0.0.0.0:8000`,
+/// from a table of TCP sockets, as in `/proc/net/tcp`: local addresses are
+/// `ADDR:PORT`, in hex, and listening sockets have state `0A`.
+fn wildcard_listeners(table: &str) -> Vec<u16> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (addr, port) = fields.get(1)?.split_once(':')?;
+            let wildcard = fields.get(3) == Some(&"0A") && addr.chars().all(|c| c == '0');
+            wildcard.then(|| u16::from_str_radix(port, 16).ok())?
+        })
+        .collect()
+}
+
+/// Returns the TCP ports that something on this machine listens on for all
+/// addresses, so that listening on them on a particular address, e.g. the
+/// Wireguard interface's, would fail. Empty where the tables of sockets
+/// can't be read, e.g. on macOS.
+pub fn tcp_ports_in_use() -> Vec<u16> {
+    let mut ports: Vec<u16> = TCP_TABLES
+        .iter()
+        .filter_map(|t| std::fs::read_to_string(t).ok())
+        .flat_map(|t| wildcard_listeners(&t))
+        .collect();
+    ports.sort_unstable();
+    ports.dedup();
+    ports
+}
+
+/// Finds the path MTU to `target`, by sending pings with fragmentation
+/// disallowed via `ping -M do`, and searching for the largest that succeeds.
+/// Only IPv4 targets are supported, since the header sizes differ.
+pub fn probe_path_mtu(target: IpAddr) -> Result<u16> {
+    if !target.is_ipv4() {
+        return Err(anyhow!("Path MTU probing only supports IPv4 targets"));
+    }
+    // Pings carry 28 bytes of IPv4 and ICMP headers on top of the payload.
+    let fits = |mtu: u16| -> Result<bool> {
+        let status = Command::new("ping")
+            .args(["-M", "do", "-c1", "-W2", "-s"])
+            .arg((mtu - 28).to_string())
+            .arg(target.to_string())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .context("Failed to run ping, is it installed?")?;
+        Ok(status.success())
+    };
+    let (mut low, mut high) = (PMTU_MIN, PMTU_MAX);
+    if !fits(low)? {
+        return Err(anyhow!("Unable to ping {} to probe path MTU", target));
+    }
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if fits(mid)? {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    tracing::debug!("Probed path MTU to {}: {}", target, low);
+    Ok(low)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,4 +245,44 @@ mod tests {
         assert_eq!(n, x);
         Ok(())
     }
+
+    #[test]
+    fn parent_subnet_validation() -> anyhow::Result<()> {
+        let n = parse_parent_subnet("10.99.0.7/24")?;
+        assert_eq!(n, "10.99.0.0/24".parse::<IpNet>()?);
+        assert!(parse_parent_subnet("172.20.0.0/16").is_ok());
+        assert!(parse_parent_subnet("fd00:1::/64").is_ok());
+        // Public ranges would shadow real hosts
+        assert!(parse_parent_subnet("8.8.8.0/24").is_err());
+        assert!(parse_parent_subnet("2001:db8::/64").is_err());
+        // No room for a pair of addresses
+        assert!(parse_parent_subnet("10.99.0.0/31").is_err());
+        assert!(parse_parent_subnet("10.99.0.0").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn wildcard_listeners_found() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:1F40 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 1 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:0CEA 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 2 1 0000000000000000 100 0 0 10 0
+   2: 00000000:01BB 0100007F:D431 01 00000000:00000000 00:00000000 00000000  1000        0 3 1 0000000000000000 100 0 0 10 0
+";
+        // Only 0.0.0.0:8000, not 127.0.0.1:3306, nor a connection from 443
+        assert_eq!(wildcard_listeners(table), [8000]);
+        let table6 =
+            "  sl  local_address                         remote_address                        st
+   0: 00000000000000000000000000000000:0050 00000000000000000000000000000000:0000 0A
+";
+        assert_eq!(wildcard_listeners(table6), [80]);
+    }
+
+    #[test]
+    fn subnet_generation_ipv6() -> anyhow::Result<()> {
+        let n = generate_unused_subnet_in(INNISFREE_SUBNET_V6.parse()?)?;
+        let x: ipnet::IpNet = "fd50::/127".parse()?;
+        assert_eq!(n, x);
+        assert_eq!(n.hosts().count(), 2);
+        Ok(())
+    }
 }
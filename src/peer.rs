@@ -0,0 +1,244 @@
+//! Sharing a running tunnel with additional Wireguard peers, e.g. a phone
+//! or a teammate's laptop, via `innisfree peer add`. Each peer connects to
+//! the remote server directly, and reaches the exposed services on the
+//! server's Wireguard address, as nginx listens on all interfaces.
+
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use crate::config::make_config_dir;
+use crate::manager::run_remote_cmd;
+use crate::secrets;
+use crate::wg::{WireguardDevice, WireguardHost, WireguardKeypair};
+
+/// Range from which peers are assigned addresses, one per peer. Kept apart
+/// from [crate::net::INNISFREE_SUBNET], which holds only the tunnel's two ends.
+pub const INNISFREE_PEER_SUBNET: &str = "10.50.128.0/24";
+/// IPv6 equivalent of [INNISFREE_PEER_SUBNET], for IPv6 tunnels.
+pub const INNISFREE_PEER_SUBNET_V6: &str = "fd50:0:0:80::/120";
+/// Name of the Wireguard interface on the remote server.
+const REMOTE_INTERFACE: &str = "innisfree";
+
+#[derive(Debug, PartialEq, Eq)]
+/// Details of the remote end of a running tunnel, as recorded
+/// in the local Wireguard config.
+struct RemoteEnd {
+    /// Public key of the remote server's Wireguard interface.
+    public_key: String,
+    /// Public address and port on which the server listens for Wireguard.
+    endpoint: SocketAddr,
+    /// Address of the server's Wireguard interface, where peers reach services.
+    address: IpAddr,
+    /// MTU of the tunnel, if set, which peers should match.
+    mtu: Option<u16>,
+}
+
+/// Reads the remote end's details from the local Wireguard config.
+fn parse_local_config(config: &str) -> Result<RemoteEnd> {
+    let mut public_key = None;
+    let mut endpoint = None;
+    let mut allowed_ips = None;
+    let mut mtu = None;
+    for line in config.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some((k, v)) if !k.trim_start().starts_with('#') => (k.trim(), v.trim()),
+            _ => continue,
+        };
+        match key {
+            "PublicKey" => public_key = Some(value.to_string()),
+            "Endpoint" => endpoint = Some(value.parse::<SocketAddr>()?),
+            "AllowedIPs" => allowed_ips = Some(value.parse::<IpNet>()?),
+            "MTU" => mtu = Some(value.parse::<u16>()?),
+            _ => {}
+        }
+    }
+    let allowed_ips = allowed_ips.ok_or_else(|| anyhow!("No AllowedIPs in tunnel config"))?;
+    // In DNAT mode, the local end routes everything to the server,
+    // and the server forwards public traffic only.
+    if allowed_ips.prefix_len() != allowed_ips.max_prefix_len() {
+        return Err(anyhow!("Peers aren't supported for tunnels in DNAT mode"));
+    }
+    Ok(RemoteEnd {
+        public_key: public_key.ok_or_else(|| anyhow!("No peer PublicKey in tunnel config"))?,
+        endpoint: endpoint.ok_or_else(|| anyhow!("No peer Endpoint in tunnel config"))?,
+        address: allowed_ips.addr(),
+        mtu,
+    })
+}
+
+/// Returns the address for the `index`th peer, within the peer subnet
+/// matching the tunnel's address family.
+fn peer_address(tunnel_address: IpAddr, index: usize) -> Result<IpAddr> {
+    let subnet: IpNet = match tunnel_address {
+        IpAddr::V4(_) => INNISFREE_PEER_SUBNET.parse()?,
+        IpAddr::V6(_) => INNISFREE_PEER_SUBNET_V6.parse()?,
+    };
+    // IPv6 subnets have no broadcast address, so their hosts include the
+    // network address, which is skipped to match IPv4.
+    subnet
+        .hosts()
+        .filter(|ip| *ip != subnet.network())
+        .nth(index)
+        .ok_or_else(|| anyhow!("No addresses left for peers within {}", subnet))
+}
+
+/// Returns the directory holding configs for the tunnel's peers.
+fn peers_dir(service_name: &str) -> Result<PathBuf> {
+    let dir = make_config_dir(service_name)?.join("peers");
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Adds a peer named `peer_name` to the running tunnel `service_name`.
+/// Generates a keypair and address for the peer, registers it with the
+/// server's Wireguard interface, and writes a config for the peer to use,
+/// returning its path and contents.
+pub fn add_peer(service_name: &str, peer_name: &str) -> Result<(PathBuf, String)> {
+    if peer_name.is_empty()
+        || !peer_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow!(
+            "Invalid peer name '{}', use letters, digits, '-', and '_'",
+            peer_name
+        ));
+    }
+    let local_config = make_config_dir(service_name)?.join(format!("{}.conf", service_name));
+    let remote = parse_local_config(&std::fs::read_to_string(&local_config).context(
+        "Tunnel not found. Try running 'innisfree up' first, or pass --name=<service>",
+    )?)?;
+    let dir = peers_dir(service_name)?;
+    let fpath = dir.join(format!("{}.conf", peer_name));
+    if fpath.exists() {
+        return Err(anyhow!("Peer '{}' already exists", peer_name));
+    }
+    let index = std::fs::read_dir(&dir)?.count();
+    let address = peer_address(remote.address, index)?;
+
+    let keypair = WireguardKeypair::new()?;
+    let prefix = if address.is_ipv4() { 32 } else { 128 };
+    let allowed_ips = format!("{}/{}", address, prefix);
+    tracing::debug!("Registering peer {} as {} on server", peer_name, address);
+    run_remote_cmd(
+        service_name,
+        &[
+            "sudo",
+            "wg",
+            "set",
+            REMOTE_INTERFACE,
+            "peer",
+            keypair.public(),
+            "allowed-ips",
+            &allowed_ips,
+        ],
+    )?;
+    run_remote_cmd(
+        service_name,
+        &[
+            "sudo",
+            "ip",
+            "route",
+            "add",
+            &allowed_ips,
+            "dev",
+            REMOTE_INTERFACE,
+        ],
+    )?;
+
+    let device = WireguardDevice {
+        name: peer_name.to_string(),
+        interface: WireguardHost {
+            name: peer_name.to_string(),
+            address,
+            endpoint: None,
+            listenport: 0,
+            keypair,
+        },
+        peer: WireguardHost {
+            name: format!("{}-remote", service_name),
+            address: remote.address,
+            endpoint: Some(remote.endpoint.ip()),
+            listenport: i32::from(remote.endpoint.port()),
+            keypair: WireguardKeypair::from_public(&remote.public_key),
+        },
+        dnat: false,
+        mtu: remote.mtu,
+    };
+    let config = device.config()?;
+    let data = secrets::encrypt(&config)?;
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&fpath)
+        .context("failed to write peer config")?;
+    f.write_all(&data)?;
+    Ok((fpath, config))
+}
+
+/// Renders a config as a QR code, for scanning with the Wireguard mobile apps.
+pub fn qr_code(config: &str) -> Result<String> {
+    let code = qrcode::QrCode::new(config.as_bytes())?;
+    Ok(code
+        .render::<qrcode::render::unicode::Dense1x2>()
+        .quiet_zone(true)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL_CONFIG: &str = "[Interface]
+PrivateKey = yPgz26A4S6RcniNaikFZrc0C0SyCW1moXmDP7AMeimE=
+Address = 10.50.0.1/30
+MTU = 1380
+# PostUp = iptables -A INPUT -i %i -m tcp -p tcp --dport 443 -j ACCEPT
+
+[Peer]
+PublicKey = ISRq2SHZQDnSfV0VlmMEP4MbwfExE/iNHzthMQ7eNmY=
+Endpoint = 203.0.113.5:51820
+PersistentKeepalive = 25
+AllowedIPs = 10.50.0.2/32
+";
+
+    #[test]
+    fn remote_end_parsed_from_local_config() -> Result<()> {
+        let remote = parse_local_config(LOCAL_CONFIG)?;
+        assert_eq!(
+            remote,
+            RemoteEnd {
+                public_key: "ISRq2SHZQDnSfV0VlmMEP4MbwfExE/iNHzthMQ7eNmY=".to_string(),
+                endpoint: "203.0.113.5:51820".parse()?,
+                address: "10.50.0.2".parse()?,
+                mtu: Some(1380),
+            }
+        );
+        let dnat = LOCAL_CONFIG.replace("AllowedIPs = 10.50.0.2/32", "AllowedIPs = 0.0.0.0/0");
+        assert!(parse_local_config(&dnat).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn peers_get_sequential_addresses() -> Result<()> {
+        let v4: IpAddr = "10.50.0.2".parse()?;
+        assert_eq!(peer_address(v4, 0)?, "10.50.128.1".parse::<IpAddr>()?);
+        assert_eq!(peer_address(v4, 1)?, "10.50.128.2".parse::<IpAddr>()?);
+        assert!(peer_address(v4, 254).is_err());
+        let v6: IpAddr = "fd50::1".parse()?;
+        assert_eq!(peer_address(v6, 0)?, "fd50:0:0:80::1".parse::<IpAddr>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn peer_config_renders_as_qr_code() -> Result<()> {
+        let qr = qr_code(LOCAL_CONFIG)?;
+        assert!(qr.lines().count() > 10);
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+//! Changes `innisfree up` would make to a tunnel, for `innisfree plan`.
+//! The tunnel as configured, on the command line or in a tunnel file, is
+//! compared with its saved state, see [crate::state], and whether its
+//! server still exists: a tunnel that can be re-attached to keeps its
+//! server, with services added, changed, or removed, as `up` would
+//! converge them; otherwise the server is replaced.
+
+use serde::Serialize;
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::config::ServicePort;
+use crate::dns::DnsRecord;
+use crate::server::cloudinit::CloudConfigOptions;
+use crate::state::TunnelState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+/// What happens to a resource.
+pub enum Action {
+    /// Created, e.g. a new server.
+    Create,
+    /// Changed in place, e.g. a service's local port.
+    Change,
+    /// Destroyed, or no longer forwarded.
+    Destroy,
+}
+
+impl fmt::Display for Action {
+    /// Formats the action as a diff marker, e.g. `+`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let marker = match self {
+            Action::Create => "+",
+            Action::Change => "~",
+            Action::Destroy => "-",
+        };
+        write!(f, "{}", marker)
+    }
+}
+
+#[derive(Debug, Serialize)]
+/// A change to one of the tunnel's resources.
+pub struct Change {
+    /// Whether the resource is created, changed, or destroyed.
+    pub action: Action,
+    /// The resource, e.g. `service 443/TCP`.
+    pub resource: String,
+    /// What changes, or why.
+    pub detail: String,
+}
+
+impl Change {
+    fn new(action: Action, resource: impl Into<String>, detail: impl Into<String>) -> Change {
+        Change {
+            action,
+            resource: resource.into(),
+            detail: detail.into(),
+        }
+    }
+}
+
+/// The tunnel as configured, which `up` would bring about.
+pub struct Desired<'a> {
+    /// Name of the cloud provider for the server, e.g. `digitalocean`.
+    pub provider: &'a str,
+    /// Services to forward.
+    pub services: &'a [ServicePort],
+    /// Customizations for the server.
+    pub options: &'a CloudConfigOptions,
+    /// Floating IP to publish services on, if any.
+    pub floating_ip: Option<IpAddr>,
+    /// Whether to reserve a Floating IP, see `up --reserve-ip`.
+    pub reserve_ip: bool,
+    /// DNS record to point at the public IP, if any.
+    pub dns: Option<&'a DnsRecord>,
+}
+
+/// Describes a service's settings, e.g. `to local port 8000`.
+fn describe(service: &ServicePort) -> String {
+    format!("to local port {}", service.local_port)
+}
+
+/// Returns the changes to the services, from `current` to `desired`.
+fn service_changes(current: &[ServicePort], desired: &[ServicePort]) -> Vec<Change> {
+    let resource = |s: &ServicePort| format!("service {}/{}", s.port, s.protocol);
+    let mut changes = vec![];
+    for d in desired {
+        match current.iter().find(|c| c.same_port(d)) {
+            None => changes.push(Change::new(Action::Create, resource(d), describe(d))),
+            Some(c) if c != d => {
+                let detail = match c.local_port != d.local_port {
+                    true => format!("local port {} => {}", c.local_port, d.local_port),
+                    false => "settings changed".to_string(),
+                };
+                changes.push(Change::new(Action::Change, resource(d), detail));
+            }
+            Some(_) => {}
+        }
+    }
+    for c in current {
+        if !desired.iter().any(|d| d.same_port(c)) {
+            changes.push(Change::new(
+                Action::Destroy,
+                resource(c),
+                "no longer forwarded",
+            ));
+        }
+    }
+    changes
+}
+
+/// Returns the changes `up` would make to the tunnel `name`, from its saved
+/// state, `current`, if any, to `desired`. `server_exists` tells whether the
+/// saved server still exists, and `running` whether an `up` process is
+/// serving the tunnel, in which case only services are updated.
+pub fn diff(
+    name: &str,
+    current: Option<&TunnelState>,
+    server_exists: bool,
+    running: bool,
+    desired: &Desired,
+) -> Vec<Change> {
+    let server = format!("server '{}'", name);
+    let mut changes = vec![];
+    // Why the saved server is replaced, if it is.
+    let replaced = match current {
+        Some(state) if running => return service_changes(&state.services, desired.services),
+        Some(state) => state
+            .mismatch(desired.provider, desired.options, desired.floating_ip)
+            .or_else(|| (!server_exists).then(|| "Saved server no longer exists".to_string())),
+        None => None,
+    };
+    match (current, &replaced) {
+        (Some(state), None) => {
+            changes.extend(service_changes(&state.services, desired.services));
+        }
+        (state, _) => {
+            if let (Some(state), Some(reason)) = (state, &replaced) {
+                let old = format!("server {} on {}", state.server_id, state.provider);
+                changes.push(Change::new(Action::Destroy, old, reason.as_str()));
+            }
+            changes.push(Change::new(
+                Action::Create,
+                server.as_str(),
+                format!("on {}", desired.provider),
+            ));
+            changes.extend(service_changes(&[], desired.services));
+            if let Some(ip) = desired.floating_ip {
+                changes.push(Change::new(
+                    Action::Create,
+                    format!("floating IP {}", ip),
+                    format!("attached to {}", server),
+                ));
+            }
+        }
+    }
+    // Reserved once, then reused, so only new unless the tunnel has one.
+    let reserved = current.is_some_and(|s| s.public_ip != s.server_ip);
+    if desired.reserve_ip && desired.floating_ip.is_none() && !reserved {
+        changes.push(Change::new(
+            Action::Create,
+            "reserved IP",
+            format!("attached to {}", server),
+        ));
+    }
+    if let Some(record) = desired.dns {
+        let saved = current
+            .and_then(|s| s.dns.as_ref())
+            .is_some_and(|r| r.hostname == record.hostname);
+        // A new server has a new IP, unless published on a reserved one.
+        let moved = replaced.is_some() && desired.floating_ip.is_none() && !desired.reserve_ip;
+        let resource = format!("DNS record {}", record.hostname);
+        if !saved {
+            changes.push(Change::new(
+                Action::Create,
+                resource,
+                "pointed at the public IP",
+            ));
+        } else if moved {
+            changes.push(Change::new(
+                Action::Change,
+                resource,
+                "pointed at the new server's IP",
+            ));
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    use crate::ssh::SshKeypair;
+    use crate::wg::WireguardManager;
+
+    #[test]
+    fn changes_planned_against_saved_state() -> anyhow::Result<()> {
+        let options = CloudConfigOptions::default();
+        let services = ServicePort::from_str_multi("80:8000/TCP,443:8443/TCP")?;
+        let desired = Desired {
+            provider: "digitalocean",
+            services: &services,
+            options: &options,
+            floating_ip: None,
+            reserve_ip: false,
+            dns: None,
+        };
+        let summary = |changes: Vec<Change>| -> Vec<String> {
+            changes
+                .into_iter()
+                .map(|c| format!("{} {}: {}", c.action, c.resource, c.detail))
+                .collect()
+        };
+        assert_eq!(
+            summary(diff("web", None, false, false, &desired)),
+            [
+                "+ server 'web': on digitalocean",
+                "+ service 80/TCP: to local port 8000",
+                "+ service 443/TCP: to local port 8443",
+            ]
+        );
+
+        let state = TunnelState {
+            provider: "digitalocean".to_string(),
+            server_id: "12345".to_string(),
+            created_at: 1700000000,
+            server_ip: "203.0.113.5".parse()?,
+            public_ip: "203.0.113.5".parse()?,
+            public_ipv6: None,
+            wg_subnet: "10.50.0.0/30".parse()?,
+            services: vec![
+                ServicePort::try_from("443:9443/TCP")?,
+                ServicePort::try_from("22/TCP")?,
+            ],
+            options: CloudConfigOptions::default(),
+            wg: WireguardManager::with_subnet("web", "10.50.0.0/30".parse()?)?,
+            ssh_client_keypair: SshKeypair::new("client")?,
+            ssh_server_keypair: SshKeypair::new("server")?,
+            ssh_over_tunnel: false,
+            server_name: None,
+            dns: None,
+        };
+        let converged = [
+            "+ service 80/TCP: to local port 8000",
+            "~ service 443/TCP: local port 9443 => 8443",
+            "- service 22/TCP: no longer forwarded",
+        ];
+        assert_eq!(
+            summary(diff("web", Some(&state), true, false, &desired)),
+            converged
+        );
+        assert_eq!(
+            summary(diff("web", Some(&state), true, true, &desired)),
+            converged
+        );
+
+        // A new server, once the saved one's gone
+        let record = DnsRecord {
+            hostname: "web.example.com".to_string(),
+            on_down: Default::default(),
+            provider: Default::default(),
+        };
+        let desired = Desired {
+            dns: Some(&record),
+            ..desired
+        };
+        let changes = summary(diff("web", Some(&state), false, false, &desired));
+        assert_eq!(
+            changes[..2],
+            [
+                "- server 12345 on digitalocean: Saved server no longer exists",
+                "+ server 'web': on digitalocean",
+            ]
+        );
+        assert_eq!(
+            changes.last().map(String::as_str),
+            Some("+ DNS record web.example.com: pointed at the public IP")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+//! Parked servers, reused across tunnels, see `innisfree up --pool`. Rather
+//! than destroying its server, a pooled tunnel parks it on teardown: its end
+//! of the tunnel is taken down, and it's recorded here, along with the keys
+//! to reach it. The next pooled tunnel with the same options claims it, and
+//! pushes its own Wireguard and forwarding configs over SSH, which takes
+//! seconds, rather than the minutes a fresh server needs to boot.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use crate::config::{list_config_dirs, make_config_dir, ServicePort};
+use crate::server::cloudinit::CloudConfigOptions;
+use crate::server::ProviderRegistry;
+use crate::ssh::SshKeypair;
+use crate::state::TunnelState;
+
+#[derive(Debug, Serialize, Deserialize)]
+/// A server parked by a tunnel, waiting to be claimed by the next one.
+pub struct ParkedServer {
+    /// Name of the tunnel the server was created for. Providers tag the
+    /// server, and its firewall, with it, so it's kept across claims.
+    pub server_name: String,
+    /// Name of the cloud provider backing the server, e.g. `digitalocean`.
+    pub provider: String,
+    /// Provider's identifier for the server, see [crate::server::InnisfreeServer::id].
+    pub server_id: String,
+    /// Public IPv4 address of the server.
+    pub server_ip: IpAddr,
+    /// Services last forwarded, whose ports are open in the cloud firewall.
+    pub services: Vec<ServicePort>,
+    /// Customizations the server was configured with on first boot.
+    pub options: CloudConfigOptions,
+    /// SSH keypair for connecting to the server.
+    pub ssh_client_keypair: SshKeypair,
+    /// SSH keypair identifying the server.
+    pub ssh_server_keypair: SshKeypair,
+    /// When the server was parked, as seconds since the unix epoch.
+    pub parked_at: u64,
+}
+
+/// Returns the path to the record of the parked server `id`. Stored outside
+/// any tunnel's config dir, which is removed on clean.
+fn parked_path(provider: &str, id: &str) -> Result<PathBuf> {
+    Ok(make_config_dir(".pool")?.join(format!("{}-{}.json", provider, id)))
+}
+
+/// Whether a server configured with `parked` options can be claimed by a
+/// tunnel requesting `requested`. Wireguard is reconfigured on claim, and
+/// SSH closed afterwards, so only the options applied on first boot, e.g.
+/// the remote proxy or extra packages, must match. The Wireguard port is
+/// kept from the parked server, since its cloud firewall is opened for it.
+pub fn compatible(parked: &CloudConfigOptions, requested: &CloudConfigOptions) -> bool {
+    let boot_options = |o: &CloudConfigOptions| CloudConfigOptions {
+        wg_port: None,
+        wg_mtu: None,
+        wg_subnet: None,
+        private_ssh: false,
+        ..o.clone()
+    };
+    boot_options(parked) == boot_options(requested)
+}
+
+/// Records the server as parked, so the next pooled tunnel can claim it.
+pub fn park(server: &ParkedServer) -> Result<()> {
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(parked_path(&server.provider, &server.server_id)?)
+        .context("Failed to record parked server")?;
+    f.write_all(serde_json::to_string_pretty(server)?.as_bytes())?;
+    Ok(())
+}
+
+/// Lists the parked servers, longest parked first.
+pub fn list() -> Result<Vec<ParkedServer>> {
+    let mut parked = vec![];
+    for entry in std::fs::read_dir(make_config_dir(".pool")?)? {
+        let path = entry?.path();
+        let s = std::fs::read_to_string(&path)?;
+        match serde_json::from_str::<ParkedServer>(&s) {
+            Ok(p) => parked.push(p),
+            Err(e) => tracing::warn!("Ignoring invalid parked server {}: {}", path.display(), e),
+        }
+    }
+    parked.sort_by_key(|p| p.parked_at);
+    Ok(parked)
+}
+
+/// Removes a parked server from the pool, returning it, e.g. so a tunnel
+/// can claim it. Returns `None` if another process already removed it.
+pub fn take(provider: &str, id: &str) -> Result<Option<ParkedServer>> {
+    let path = parked_path(provider, id)?;
+    let s = match std::fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match std::fs::remove_file(&path) {
+        Ok(()) => Ok(Some(serde_json::from_str(&s)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Removes the longest-parked server from `provider` compatible with
+/// `options` from the pool, returning it, see [compatible].
+pub fn claim(provider: &str, options: &CloudConfigOptions) -> Result<Option<ParkedServer>> {
+    for p in list()? {
+        if p.provider != provider || !compatible(&p.options, options) {
+            continue;
+        }
+        // Another process may have claimed it since it was listed.
+        if let Some(p) = take(&p.provider, &p.server_id)? {
+            return Ok(Some(p));
+        }
+    }
+    Ok(None)
+}
+
+/// Checks whether servers created for the tunnel `name` are still in use,
+/// though the tunnel itself is gone, i.e. parked, or claimed by another
+/// tunnel. Used to spare them from `innisfree gc`.
+pub fn in_use(name: &str) -> Result<bool> {
+    if list()?.iter().any(|p| p.server_name == name) {
+        return Ok(true);
+    }
+    for tunnel in list_config_dirs()? {
+        if let Ok(state) = TunnelState::load(&tunnel) {
+            if state.server_name.as_deref() == Some(name) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Destroys every parked server, returning how many were destroyed.
+/// Servers that fail to be destroyed are kept in the pool.
+pub async fn drain(registry: &ProviderRegistry) -> Result<usize> {
+    let mut destroyed = 0;
+    for p in list()? {
+        let p = match take(&p.provider, &p.server_id)? {
+            Some(p) => p,
+            None => continue,
+        };
+        tracing::info!("Destroying parked {} server {}", p.provider, p.server_id);
+        let result = match registry.get(&p.provider) {
+            Ok(provider) => provider.destroy(&p.server_name, &p.server_id).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => destroyed += 1,
+            Err(e) => {
+                tracing::warn!("Failed to destroy server {}: {:#}", p.server_id, e);
+                park(&p)?;
+            }
+        }
+    }
+    Ok(destroyed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claims_only_matching_boot_options() {
+        let parked = CloudConfigOptions {
+            pool: true,
+            wg_port: Some(51820),
+            ..Default::default()
+        };
+        // Reconfigured on claim
+        let requested = CloudConfigOptions {
+            pool: true,
+            wg_port: Some(50000),
+            wg_mtu: Some(1380),
+            private_ssh: true,
+            ..Default::default()
+        };
+        assert!(compatible(&parked, &requested));
+        // Applied on first boot
+        let requested = CloudConfigOptions {
+            pool: true,
+            extra_packages: vec!["htop".to_string()],
+            ..Default::default()
+        };
+        assert!(!compatible(&parked, &requested));
+        let requested = CloudConfigOptions {
+            pool: true,
+            hostname: Some("mail.example.com".to_string()),
+            ..Default::default()
+        };
+        assert!(!compatible(&parked, &requested));
+    }
+}
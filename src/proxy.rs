@@ -5,49 +5,1346 @@
 //! The methods exposed here are low-level. More user-friendly abstractions
 //! can be found in the [crate::manager::TunnelManager] class..
 
-use anyhow::Result;
-use futures::FutureExt;
-use std::net::SocketAddr;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, Sleep};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
 
-// Taken from Tokio proxy example (MIT license):
-// https://github.com/tokio-rs/tokio/blob/a08ce0d3e06d650361283dc87c8fe14b146df15d/examples/proxy.rs
-/// Handle proxying traffic along a given `TcpStream` to a given
-/// destination socket.
-pub async fn transfer(mut inbound: TcpStream, proxy_addr: SocketAddr) -> Result<()> {
-    let mut outbound = TcpStream::connect(proxy_addr).await?;
+use crate::access_log::{AccessEntry, AccessLog};
+use crate::balance::{BackendConn, Balancer, Strategy};
+use crate::config::{HealthCheck, ProxyProtocol, ServicePort};
+use crate::service_health::Health;
+use crate::wg::human_bytes;
 
-    let (mut ri, mut wi) = inbound.split();
-    let (mut ro, mut wo) = outbound.split();
+/// Signature that starts every PROXY protocol v2 header.
+const PROXY_V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+/// Longest possible PROXY protocol v1 header, including the trailing CRLF.
+const PROXY_V1_MAX_LEN: usize = 107;
 
-    let client_to_server = async {
-        tokio::io::copy(&mut ri, &mut wo).await?;
-        wo.shutdown().await
-    };
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// Bytes passed through a service, in each direction.
+pub struct ByteCounts {
+    /// Bytes received from clients, i.e. uploaded to the service.
+    pub rx_bytes: u64,
+    /// Bytes sent to clients, i.e. downloaded from the service.
+    pub tx_bytes: u64,
+}
+
+impl std::fmt::Display for ByteCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} received, {} sent",
+            human_bytes(self.rx_bytes),
+            human_bytes(self.tx_bytes)
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+/// Bytes passed through a service's proxy so far, counted as they pass,
+/// rather than when connections close, so long-lived ones show up.
+pub struct Traffic {
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+}
+
+impl Traffic {
+    /// Returns the bytes counted so far.
+    pub fn counts(&self) -> ByteCounts {
+        ByteCounts {
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Traffic for each service proxied locally, keyed by public port and
+/// protocol, e.g. `443/TCP`. Clones share the same counters, so proxies
+/// count into them, while `innisfree status` reads them.
+pub struct TrafficCounters(Arc<Mutex<BTreeMap<String, Arc<Traffic>>>>);
 
-    let server_to_client = async {
-        tokio::io::copy(&mut ro, &mut wi).await?;
-        wi.shutdown().await
+impl TrafficCounters {
+    /// Returns the counter for the service, created on first use. Kept if
+    /// the service is removed, so totals survive it being added back.
+    pub fn service(&self, service: &ServicePort) -> Arc<Traffic> {
+        let mut counters = match self.0.lock() {
+            Ok(c) => c,
+            Err(e) => e.into_inner(),
+        };
+        counters.entry(service_key(service)).or_default().clone()
+    }
+
+    /// Returns the bytes counted for each service so far.
+    pub fn totals(&self) -> BTreeMap<String, ByteCounts> {
+        let counters = match self.0.lock() {
+            Ok(c) => c,
+            Err(e) => e.into_inner(),
+        };
+        counters
+            .iter()
+            .map(|(k, t)| (k.clone(), t.counts()))
+            .collect()
+    }
+}
+
+/// Names the service by its public port and protocol, e.g. `443/TCP`.
+fn service_key(service: &ServicePort) -> String {
+    format!("{}/{}", service.port, service.protocol)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// Limits on each client of the services, so one can't saturate the
+/// uplink, see `innisfree up --max-client-conns`. Enforced by the server's
+/// nginx, and by the local proxies, in case the server's config is stale.
+pub struct ClientLimits {
+    /// Most connections open at once per client address.
+    pub max_conns: Option<u32>,
+    /// Most bytes per second per client, in each direction. nginx can only
+    /// limit each connection, while the local proxies share the rate among
+    /// a client's connections where its address is known, see
+    /// [crate::config::ProxyProtocol].
+    pub max_rate: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// How long the local proxies keep connections open, see `innisfree up
+/// --conn-idle-timeout`, so ones whose peer vanished without closing them
+/// don't stay open forever.
+pub struct ConnTimeouts {
+    /// Closes connections once no bytes have passed either way for this long.
+    pub idle: Option<Duration>,
+    /// Closes connections once they've been open for this long.
+    pub max_lifetime: Option<Duration>,
+}
+
+/// Parses a positive number of bytes, with an optional binary suffix,
+/// as nginx accepts it, e.g. `512k` or `2m`.
+fn parse_bytes(s: &str) -> Option<u64> {
+    let lower = s.to_lowercase();
+    let (digits, unit) = match lower.strip_suffix('k') {
+        Some(d) => (d, 1024),
+        None => match lower.strip_suffix('m') {
+            Some(d) => (d, 1024 * 1024),
+            None => (lower.as_str(), 1),
+        },
     };
+    match u64::from_str(digits) {
+        Ok(n) if n > 0 => n.checked_mul(unit),
+        _ => None,
+    }
+}
 
-    tokio::try_join!(client_to_server, server_to_client)?;
+/// Parses a rate in bytes per second, e.g. `512k` or `2m`.
+pub fn parse_rate(s: &str) -> Result<u64> {
+    parse_bytes(s)
+        .ok_or_else(|| anyhow!("Invalid rate '{}', expected bytes per second, e.g. 512k", s))
+}
+
+/// Parses a socket buffer size in bytes, e.g. `256k` or `4m`.
+pub fn parse_buffer_size(s: &str) -> Result<u32> {
+    parse_bytes(s)
+        .and_then(|n| u32::try_from(n).ok())
+        .ok_or_else(|| anyhow!("Invalid buffer size '{}', expected bytes, e.g. 256k", s))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Options for the local proxies' sockets, both those accepted from the
+/// server and those to the local services, see `innisfree up --tcp-nodelay`.
+/// Latency-sensitive services, e.g. game servers, want small writes sent at
+/// once, while throughput-heavy ones want larger buffers.
+pub struct SocketOptions {
+    /// Sends small writes at once, rather than batching them.
+    pub nodelay: bool,
+    /// Probes connections once idle for this long, closing them if the
+    /// peer's gone.
+    pub keepalive: Option<Duration>,
+    /// Time between keepalive probes, if not the system's default.
+    pub keepalive_interval: Option<Duration>,
+    /// Size of the kernel's send and receive buffers, in bytes, if not the
+    /// system's default.
+    pub buffer_size: Option<u32>,
+}
+
+impl SocketOptions {
+    /// Returns a socket for `addr`'s address family, with the buffer sizes
+    /// set, before connecting or listening, so TCP's window scales to them.
+    fn socket(&self, addr: SocketAddr) -> std::io::Result<TcpSocket> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(size) = self.buffer_size {
+            socket.set_recv_buffer_size(size)?;
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(socket)
+    }
+
+    /// Listens on `addr`. Accepted connections inherit the buffer sizes,
+    /// and take the other options via [SocketOptions::apply].
+    pub fn bind(&self, addr: SocketAddr) -> std::io::Result<TcpListener> {
+        let socket = self.socket(addr)?;
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+        socket.listen(1024)
+    }
+
+    /// Connects to `addr`, with all the options set.
+    pub async fn connect(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+        let stream = self.socket(addr)?.connect(addr).await?;
+        self.apply(&stream)?;
+        Ok(stream)
+    }
+
+    /// Sets the options not inherited from the listener on an accepted
+    /// connection.
+    pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            let mut keepalive = socket2::TcpKeepalive::new().with_time(time);
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+/// Connections open per client address, so each can be capped.
+#[derive(Debug, Clone, Default)]
+struct ClientConns(Arc<Mutex<HashMap<IpAddr, u32>>>);
+
+/// Open connection from a client, counted until dropped.
+struct ClientConn {
+    conns: ClientConns,
+    ip: IpAddr,
+}
+
+impl ClientConns {
+    /// Counts a new connection from `ip`, unless it already has `max` open.
+    fn open(&self, ip: IpAddr, max: Option<u32>) -> Option<ClientConn> {
+        let mut conns = match self.0.lock() {
+            Ok(c) => c,
+            Err(e) => e.into_inner(),
+        };
+        let open = conns.entry(ip).or_default();
+        if max.is_some_and(|max| *open >= max) {
+            return None;
+        }
+        *open += 1;
+        Some(ClientConn {
+            conns: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ClientConn {
+    fn drop(&mut self) {
+        let mut conns = match self.conns.0.lock() {
+            Ok(c) => c,
+            Err(e) => e.into_inner(),
+        };
+        if let Some(open) = conns.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                conns.remove(&self.ip);
+            }
+        }
+    }
+}
 
+/// Bytes passed in one direction during the current one-second window,
+/// by one connection, or by all of a client's, see [ClientRates].
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    used: u64,
+}
+
+impl Default for Window {
+    fn default() -> Window {
+        Window {
+            start: Instant::now(),
+            used: 0,
+        }
+    }
+}
+
+/// Windows for the bytes from and to a client, shared by its connections.
+type Budget = (Arc<Mutex<Window>>, Arc<Mutex<Window>>);
+
+/// A [Budget] kept only while a connection uses it.
+type WeakBudget = (Weak<Mutex<Window>>, Weak<Mutex<Window>>);
+
+/// Budgets per client address, so each client's connections share its
+/// rate, see [ClientLimits::max_rate]. Kept while any connection uses them.
+#[derive(Debug, Clone, Default)]
+struct ClientRates(Arc<Mutex<HashMap<IpAddr, WeakBudget>>>);
+
+impl ClientRates {
+    /// Returns the budget of the client at `ip`, shared with its open connections.
+    fn budget(&self, ip: IpAddr) -> Budget {
+        let mut budgets = match self.0.lock() {
+            Ok(b) => b,
+            Err(e) => e.into_inner(),
+        };
+        if let Some((Some(rx), Some(tx))) =
+            budgets.get(&ip).map(|(r, t)| (r.upgrade(), t.upgrade()))
+        {
+            return (rx, tx);
+        }
+        // Forget clients whose connections have all closed.
+        budgets.retain(|_, (rx, _)| rx.strong_count() > 0);
+        let budget: Budget = Default::default();
+        budgets.insert(ip, (Arc::downgrade(&budget.0), Arc::downgrade(&budget.1)));
+        budget
+    }
+}
+
+/// Caps the bytes passing in one direction to `rate` per second,
+/// counted over one-second windows, which may be shared, see [ClientRates].
+struct Throttle {
+    rate: u64,
+    window: Arc<Mutex<Window>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    fn new(rate: u64, window: Arc<Mutex<Window>>) -> Throttle {
+        Throttle {
+            rate,
+            window,
+            sleep: None,
+        }
+    }
+
+    /// Locks the window, even if another connection panicked holding it.
+    fn window(&self) -> std::sync::MutexGuard<'_, Window> {
+        match self.window.lock() {
+            Ok(w) => w,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    /// Returns how many of `want` bytes may pass now, or waits for the next window.
+    fn poll_allowed(&mut self, cx: &mut Context<'_>, want: usize) -> Poll<usize> {
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.sleep = None;
+            }
+            let next = {
+                let mut window = self.window();
+                let next = window.start + Duration::from_secs(1);
+                if Instant::now() >= next {
+                    *window = Window::default();
+                }
+                if window.used < self.rate {
+                    let left = usize::try_from(self.rate - window.used).unwrap_or(usize::MAX);
+                    return Poll::Ready(want.min(left));
+                }
+                next
+            };
+            self.sleep = Some(Box::pin(tokio::time::sleep_until(next)));
+        }
+    }
+
+    /// Counts `n` bytes as passed.
+    fn record(&self, n: usize) {
+        self.window().used += n as u64;
+    }
+}
+
+/// Client connection counting the bytes read from it, i.e. received
+/// from the client, and written to it, into its service's [Traffic], and
+/// its own, and throttling each direction, if limited.
+struct Counted<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+    conn: Arc<Traffic>,
+    throttles: Option<(Throttle, Throttle)>,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S, traffic: Arc<Traffic>, limits: ClientLimits) -> Counted<S> {
+        Counted {
+            inner,
+            traffic,
+            conn: Arc::default(),
+            throttles: limits.max_rate.map(|r| {
+                (
+                    Throttle::new(r, Arc::default()),
+                    Throttle::new(r, Arc::default()),
+                )
+            }),
+        }
+    }
+
+    /// Throttles the connection as one of the client at `ip`'s, sharing
+    /// its rate with the client's others, rather than having its own.
+    fn limit_client(&mut self, rates: &ClientRates, ip: IpAddr) {
+        if let Some((rx, _)) = &self.throttles {
+            let rate = rx.rate;
+            let (rx, tx) = rates.budget(ip);
+            self.throttles = Some((Throttle::new(rate, rx), Throttle::new(rate, tx)));
+        }
+    }
+
+    /// Records the bytes read from the connection.
+    fn add_rx(&self, n: usize) {
+        self.traffic.rx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+        self.conn.rx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Records the bytes written to the connection.
+    fn add_tx(&self, n: usize) {
+        self.traffic.tx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+        self.conn.tx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let read = match this.throttles.as_mut() {
+            None => {
+                let before = buf.filled().len();
+                futures::ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+                buf.filled().len() - before
+            }
+            Some((throttle, _)) => {
+                let allowed = futures::ready!(throttle.poll_allowed(cx, buf.remaining()));
+                let mut limited = buf.take(allowed);
+                let ptr = limited.filled().as_ptr();
+                futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+                // Ensure the inner reader didn't swap in another buffer.
+                assert_eq!(ptr, limited.filled().as_ptr());
+                let n = limited.filled().len();
+                // SAFETY: `limited` is still `buf`'s unfilled part, as just
+                // asserted, whose first `n` bytes the read initialized.
+                unsafe { buf.assume_init(n) };
+                buf.advance(n);
+                throttle.record(n);
+                n
+            }
+        };
+        this.add_rx(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let allowed = match this.throttles.as_mut() {
+            None => buf.len(),
+            Some((_, throttle)) => futures::ready!(throttle.poll_allowed(cx, buf.len())),
+        };
+        let n = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+        if let Some((_, throttle)) = &this.throttles {
+            throttle.record(n);
+        }
+        this.add_tx(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Settings shared by a tunnel's local proxies.
+pub struct ProxyConfig {
+    /// Counters for the bytes passed, per service.
+    pub traffic: TrafficCounters,
+    /// Limits on each client, see [ClientLimits].
+    pub limits: ClientLimits,
+    /// Where to log each connection, if anywhere, see [crate::access_log].
+    pub access_log: Option<AccessLog>,
+    /// When to close connections, see [ConnTimeouts].
+    pub timeouts: ConnTimeouts,
+    /// Options for the sockets, see [SocketOptions].
+    pub sockets: SocketOptions,
+    /// Sent to clients turned away while their service is unhealthy, e.g.
+    /// an HTTP 503 response, see [crate::service_health]. Else they're
+    /// just disconnected.
+    pub unavailable: Option<Arc<[u8]>>,
+    /// How to pick among a service's backends, if it has several.
+    pub balance: Strategy,
+    /// Times to retry connecting to a service, e.g. while it restarts,
+    /// before dropping the client's connection, see [connect_backoff].
+    pub connect_retries: u32,
+}
+
+impl ProxyConfig {
+    /// Returns the settings for the proxy of a single service.
+    pub fn service(&self, service: &ServicePort) -> ServiceProxy {
+        ServiceProxy {
+            service: service_key(service),
+            name: service.name.clone(),
+            traffic: self.traffic.service(service),
+            limits: self.limits,
+            access_log: self.access_log.clone(),
+            timeouts: self.timeouts,
+            sockets: self.sockets,
+            health_check: service.health_check.clone(),
+            health: Health::default(),
+            unavailable: self.unavailable.clone(),
+            connect_retries: self.connect_retries,
+            balancer: if service.backends.is_empty() {
+                None
+            } else {
+                Some(Arc::new(Balancer::new(&service.backends, self.balance)))
+            },
+            slots: Arc::new(Semaphore::new(
+                service
+                    .max_conns
+                    .map_or(Semaphore::MAX_PERMITS, |n| n as usize),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// How a service's proxy handles each connection: counting its traffic,
+/// applying the limits, and logging it once closed, see [ProxyConfig].
+pub struct ServiceProxy {
+    service: String,
+    name: Option<String>,
+    traffic: Arc<Traffic>,
+    limits: ClientLimits,
+    access_log: Option<AccessLog>,
+    timeouts: ConnTimeouts,
+    sockets: SocketOptions,
+    health_check: Option<HealthCheck>,
+    health: Health,
+    unavailable: Option<Arc<[u8]>>,
+    connect_retries: u32,
+    /// Picks a destination per connection, if the service has backends.
+    balancer: Option<Arc<Balancer>>,
+    /// One per connection the service may have open at once.
+    slots: Arc<Semaphore>,
+}
+
+/// A connection accepted by a service's proxy, tracked until it closes.
+struct Connection {
+    client: SocketAddr,
+    time: u64,
+    started: std::time::Instant,
+    bytes: Arc<Traffic>,
+    /// Held until the connection closes, see [ServicePort::max_conns].
+    _slot: OwnedSemaphorePermit,
+}
+
+impl ServiceProxy {
+    /// Listens on `listen_addr`, and starts checking the service at
+    /// `dest_addr`, or its backends, if it has a health check, until
+    /// `cancel` fires.
+    fn listen(
+        &self,
+        listen_addr: SocketAddr,
+        dest_addr: SocketAddr,
+        cancel: &CancellationToken,
+    ) -> Result<TcpListener> {
+        let listener = self.sockets.bind(listen_addr)?;
+        match (&self.health_check, &self.balancer) {
+            (Some(check), Some(balancer)) => balancer.watch(check, cancel),
+            (Some(check), None) => self.health.watch(check.clone(), dest_addr, cancel.clone()),
+            (None, _) => {}
+        }
+        Ok(listener)
+    }
+
+    /// Whether the service passed its latest health check, or any of its
+    /// backends did.
+    fn is_healthy(&self) -> bool {
+        match &self.balancer {
+            Some(b) => b.is_healthy(),
+            None => self.health.is_healthy(),
+        }
+    }
+
+    /// Connects to the service at `dest_addr`, or to one of its backends,
+    /// which counts the connection until the returned [BackendConn] drops.
+    /// Failures are retried, as configured, see [connect_backoff].
+    async fn connect(&self, dest_addr: SocketAddr) -> Result<(TcpStream, Option<BackendConn>)> {
+        let mut attempt = 0;
+        loop {
+            let r = match &self.balancer {
+                Some(b) => b
+                    .connect(&self.sockets)
+                    .await
+                    .map(|(stream, open)| (stream, Some(open))),
+                None => match self.sockets.connect(dest_addr).await {
+                    Ok(stream) => Ok((stream, None)),
+                    Err(e) => Err(e.into()),
+                },
+            };
+            match r {
+                Err(e) if attempt < self.connect_retries => {
+                    let delay = connect_backoff(attempt);
+                    tracing::debug!(
+                        "Failed to connect to {}, retrying in {}ms: {}",
+                        self.service,
+                        delay.as_millis(),
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                r => return r,
+            }
+        }
+    }
+
+    /// Waits for room for another connection, if the service caps them,
+    /// then accepts it, returning it wrapped, so its traffic is counted, and
+    /// throttled if limited. Connections are turned away while the service
+    /// is unhealthy. Returns `None` once `cancel` fires, or if accepting
+    /// fails. Connections already accepted are left open.
+    async fn accept(
+        &self,
+        listener: &TcpListener,
+        cancel: &CancellationToken,
+    ) -> Option<(Counted<TcpStream>, Connection)> {
+        let slot = tokio::select! {
+            _ = cancel.cancelled() => return None,
+            s = self.slots.clone().acquire_owned() => s.ok()?,
+        };
+        let (inbound, client) = loop {
+            let (inbound, client) = tokio::select! {
+                _ = cancel.cancelled() => return None,
+                r = listener.accept() => r.ok()?,
+            };
+            if self.is_healthy() {
+                break (inbound, client);
+            }
+            self.turn_away(inbound, client);
+        };
+        if let Err(e) = self.sockets.apply(&inbound) {
+            tracing::warn!("Failed to set socket options for {}: {}", client, e);
+        }
+        let inbound = Counted::new(inbound, self.traffic.clone(), self.limits);
+        let conn = Connection {
+            client,
+            time: crate::state::now(),
+            started: std::time::Instant::now(),
+            bytes: inbound.conn.clone(),
+            _slot: slot,
+        };
+        Some((inbound, conn))
+    }
+
+    /// Disconnects a client while the service is unhealthy, first sending
+    /// the unavailable response, if any.
+    fn turn_away(&self, mut inbound: TcpStream, client: SocketAddr) {
+        tracing::debug!("Turning away {} from unhealthy {}", client, self.service);
+        if let Some(response) = self.unavailable.clone() {
+            tokio::spawn(async move {
+                let sent = async {
+                    inbound.write_all(&response).await?;
+                    inbound.shutdown().await
+                };
+                // Slow clients mustn't hold the connection open.
+                let _ = tokio::time::timeout(Duration::from_secs(5), sent).await;
+            });
+        }
+    }
+
+    /// Runs a connection's `transfer` until it finishes, or until it times
+    /// out, see [ConnTimeouts], dropping it, which closes both sides.
+    /// `bytes` are the connection's counts, checked for idleness.
+    async fn timed<F>(&self, bytes: Arc<Traffic>, transfer: F) -> Result<()>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        let lifetime = async {
+            match self.timeouts.max_lifetime {
+                Some(t) => tokio::time::sleep(t).await,
+                None => std::future::pending().await,
+            }
+        };
+        let idle = async {
+            let timeout = match self.timeouts.idle {
+                Some(t) => t,
+                None => return std::future::pending().await,
+            };
+            // Checked a few times per timeout, so the connection closes soon after.
+            let mut check = tokio::time::interval((timeout / 4).max(Duration::from_millis(10)));
+            let (mut last, mut since) = (bytes.counts(), Instant::now());
+            loop {
+                check.tick().await;
+                let counts = bytes.counts();
+                if counts != last {
+                    (last, since) = (counts, Instant::now());
+                } else if since.elapsed() >= timeout {
+                    return;
+                }
+            }
+        };
+        tokio::select! {
+            r = transfer => r,
+            _ = lifetime => {
+                tracing::debug!("Closing {} connection at its max lifetime", self.service);
+                Ok(())
+            }
+            _ = idle => {
+                tracing::debug!("Closing idle {} connection", self.service);
+                Ok(())
+            }
+        }
+    }
+
+    /// Reports a closed connection, logging it, if enabled.
+    fn close(&self, conn: Connection, result: Result<()>, kind: &str) {
+        if let Err(e) = &result {
+            tracing::warn!("{} connection from {} dropped: {}", kind, conn.client, e);
+        }
+        if let Some(log) = &self.access_log {
+            let bytes = conn.bytes.counts();
+            log.record(&AccessEntry {
+                time: conn.time,
+                service: self.service.clone(),
+                name: self.name.clone(),
+                client: conn.client,
+                duration_ms: u64::try_from(conn.started.elapsed().as_millis()).unwrap_or(u64::MAX),
+                rx_bytes: bytes.rx_bytes,
+                tx_bytes: bytes.tx_bytes,
+                error: result.err().map(|e| e.to_string()),
+            });
+        }
+    }
+}
+
+/// Delay before connecting to a service the first time it's retried.
+const CONNECT_BACKOFF: Duration = Duration::from_millis(100);
+/// Longest delay between retries to connect to a service.
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Computes the delay before connection retry number `attempt`, starting
+/// from 0: doubling each time, up to a limit, then jittered down by up to
+/// half, so clients turned away together don't all retry together.
+fn connect_backoff(attempt: u32) -> Duration {
+    let delay = CONNECT_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_CONNECT_BACKOFF);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+// Taken from Tokio proxy example (MIT license):
+// https://github.com/tokio-rs/tokio/blob/a08ce0d3e06d650361283dc87c8fe14b146df15d/examples/proxy.rs
+/// Handle proxying traffic along a given inbound stream to a given
+/// outbound stream, connected to the destination.
+pub async fn transfer<S>(mut inbound: S, mut outbound: TcpStream) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Shuts down each direction once the other side has finished sending.
+    tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await?;
+    Ok(())
+}
+
+/// Passes traffic both ways between a client's connection and the
+/// destination, via `splice(2)` on Linux, so bytes aren't copied through
+/// user space, unless the connection is throttled.
+async fn relay(inbound: &mut Counted<TcpStream>, outbound: &mut TcpStream) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    if inbound.throttles.is_none() {
+        let inbound = &*inbound;
+        crate::splice::bidirectional(
+            &inbound.inner,
+            outbound,
+            |n| inbound.add_rx(n),
+            |n| inbound.add_tx(n),
+        )
+        .await?;
+        return Ok(());
+    }
+    tokio::io::copy_bidirectional(inbound, outbound).await?;
     Ok(())
 }
 
 /// Create a blocking service proxy that passes TCP traffic
-/// between two sockets.
-pub async fn proxy_handler(listen_addr: SocketAddr, dest_addr: SocketAddr) -> Result<()> {
+/// between two sockets, handling connections as `proxy` says, until
+/// `cancel` fires. Connections all come from the server, so only the
+/// limits' rate applies.
+pub async fn proxy_handler(
+    listen_addr: SocketAddr,
+    dest_addr: SocketAddr,
+    proxy: ServiceProxy,
+    cancel: CancellationToken,
+) -> Result<()> {
     tracing::debug!("Proxying traffic: {} -> {}", listen_addr, dest_addr);
-    let listener = tokio::net::TcpListener::bind(&listen_addr).await?;
-    while let Ok((inbound, _)) = listener.accept().await {
-        let transfer = transfer(inbound, dest_addr).map(|r| {
-            if let Err(e) = r {
-                tracing::warn!("Proxy connection dropped, creating new handler: {}", e);
-            }
+    let listener = proxy.listen(listen_addr, dest_addr, &cancel)?;
+    while let Some((mut inbound, conn)) = proxy.accept(&listener, &cancel).await {
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            let r = async {
+                let (mut outbound, _backend) = proxy.connect(dest_addr).await?;
+                relay(&mut inbound, &mut outbound).await
+            };
+            let r = proxy.timed(conn.bytes.clone(), r);
+            proxy.close(conn, r.await, "Proxy");
+        });
+    }
+    Ok(())
+}
+
+/// Create a blocking service proxy that terminates TLS on inbound
+/// connections, then passes the decrypted traffic to the destination
+/// socket as plaintext. A failed handshake only drops that connection.
+/// Encrypted traffic is counted, as it crosses the tunnel, and throttled
+/// as for [proxy_handler]. Stops accepting connections once `cancel` fires.
+pub async fn tls_proxy_handler(
+    listen_addr: SocketAddr,
+    dest_addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    proxy: ServiceProxy,
+    cancel: CancellationToken,
+) -> Result<()> {
+    tracing::debug!("Proxying TLS traffic: {} -> {}", listen_addr, dest_addr);
+    // Clients expect a TLS handshake, so are only disconnected while unhealthy.
+    let proxy = ServiceProxy {
+        unavailable: None,
+        ..proxy
+    };
+    let listener = proxy.listen(listen_addr, dest_addr, &cancel)?;
+    while let Some((inbound, conn)) = proxy.accept(&listener, &cancel).await {
+        let acceptor = acceptor.clone();
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            let r = async {
+                let inbound = acceptor.accept(inbound).await?;
+                let (outbound, _backend) = proxy.connect(dest_addr).await?;
+                transfer(inbound, outbound).await
+            };
+            let r = proxy.timed(conn.bytes.clone(), r);
+            proxy.close(conn, r.await, "TLS proxy");
+        });
+    }
+    Ok(())
+}
+
+/// Original client and destination addresses of a proxied connection,
+/// as reported in a PROXY protocol header. `None` if the sender
+/// didn't know them, i.e. `PROXY UNKNOWN`.
+pub type ProxiedAddrs = Option<(SocketAddr, SocketAddr)>;
+
+/// Reads a PROXY protocol v1 header, as sent by nginx, from the start
+/// of the stream. Reads a byte at a time, so no payload is consumed.
+pub async fn read_proxy_v1<R: AsyncRead + Unpin>(stream: &mut R) -> Result<ProxiedAddrs> {
+    let mut line = Vec::with_capacity(PROXY_V1_MAX_LEN);
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= PROXY_V1_MAX_LEN {
+            return Err(anyhow!("PROXY protocol header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_proxy_v1(&line)
+}
+
+/// Parses a PROXY protocol v1 header line, e.g.
+/// `PROXY TCP4 203.0.113.7 10.50.0.1 51234 443\r\n`.
+fn parse_proxy_v1(line: &[u8]) -> Result<ProxiedAddrs> {
+    let line = std::str::from_utf8(line)?.trim_end();
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, dst, src_port, dst_port] => Ok(Some((
+            SocketAddr::new(src.parse()?, src_port.parse()?),
+            SocketAddr::new(dst.parse()?, dst_port.parse()?),
+        ))),
+        _ => Err(anyhow!("Invalid PROXY protocol header: '{}'", line)),
+    }
+}
+
+/// Encodes a PROXY protocol v1 header for the addresses.
+pub fn encode_proxy_v1(addrs: ProxiedAddrs) -> Vec<u8> {
+    match addrs {
+        Some((src, dst)) if src.is_ipv4() == dst.is_ipv4() => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if src.is_ipv4() { "TCP4" } else { "TCP6" },
+            src.ip(),
+            dst.ip(),
+            src.port(),
+            dst.port()
+        )
+        .into_bytes(),
+        _ => b"PROXY UNKNOWN\r\n".to_vec(),
+    }
+}
+
+/// Encodes a binary PROXY protocol v2 header for the addresses. Unknown
+/// or mixed-family addresses get a `LOCAL` header, which carries none.
+pub fn encode_proxy_v2(addrs: ProxiedAddrs) -> Vec<u8> {
+    let mut header = PROXY_V2_SIGNATURE.to_vec();
+    match addrs {
+        Some((SocketAddr::V4(src), SocketAddr::V4(dst))) => {
+            // Version 2, PROXY command; TCP over IPv4
+            header.extend([0x21, 0x11]);
+            header.extend(12u16.to_be_bytes());
+            header.extend(src.ip().octets());
+            header.extend(dst.ip().octets());
+            header.extend(src.port().to_be_bytes());
+            header.extend(dst.port().to_be_bytes());
+        }
+        Some((SocketAddr::V6(src), SocketAddr::V6(dst))) => {
+            // Version 2, PROXY command; TCP over IPv6
+            header.extend([0x21, 0x21]);
+            header.extend(36u16.to_be_bytes());
+            header.extend(src.ip().octets());
+            header.extend(dst.ip().octets());
+            header.extend(src.port().to_be_bytes());
+            header.extend(dst.port().to_be_bytes());
+        }
+        _ => {
+            // Version 2, LOCAL command; unspecified family
+            header.extend([0x20, 0x00]);
+            header.extend(0u16.to_be_bytes());
+        }
+    }
+    header
+}
+
+/// Create a blocking service proxy that reads the PROXY protocol v1 header
+/// sent by the server's nginx, and rewrites it for the destination
+/// according to the `mode`, before passing the rest of the traffic through,
+/// handling connections as `proxy` says. The header names each client, so
+/// all the limits apply. Stops accepting connections once `cancel` fires.
+pub async fn proxy_protocol_handler(
+    listen_addr: SocketAddr,
+    dest_addr: SocketAddr,
+    mode: ProxyProtocol,
+    proxy: ServiceProxy,
+    cancel: CancellationToken,
+) -> Result<()> {
+    tracing::debug!(
+        "Proxying traffic with PROXY protocol {:?}: {} -> {}",
+        mode,
+        listen_addr,
+        dest_addr
+    );
+    let listener = proxy.listen(listen_addr, dest_addr, &cancel)?;
+    let conns = ClientConns::default();
+    let rates = ClientRates::default();
+    while let Some((inbound, conn)) = proxy.accept(&listener, &cancel).await {
+        let conns = conns.clone();
+        let rates = rates.clone();
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            let (mut inbound, mut conn) = (inbound, conn);
+            let bytes = conn.bytes.clone();
+            let r = async {
+                let addrs = read_proxy_v1(&mut inbound).await?;
+                // Held until the connection closes. Unknown clients aren't limited.
+                let mut _open = None;
+                if let Some((src, _)) = addrs {
+                    conn.client = src;
+                    _open = conns.open(src.ip(), proxy.limits.max_conns);
+                    if _open.is_none() {
+                        return Err(anyhow!("too many connections from {}", src.ip()));
+                    }
+                    inbound.limit_client(&rates, src.ip());
+                }
+                let (mut outbound, _backend) = proxy.connect(dest_addr).await?;
+                match mode {
+                    ProxyProtocol::V1 => outbound.write_all(&encode_proxy_v1(addrs)).await?,
+                    ProxyProtocol::V2 => outbound.write_all(&encode_proxy_v2(addrs)).await?,
+                    ProxyProtocol::Strip => {
+                        if let Some((src, _)) = addrs {
+                            tracing::info!("Connection to {} from {}", dest_addr, src);
+                        }
+                    }
+                }
+                relay(&mut inbound, &mut outbound).await
+            };
+            let r = proxy.timed(bytes, r).await;
+            proxy.close(conn, r, "PROXY protocol");
         });
-        tokio::spawn(transfer);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn proxy_stops_when_cancelled() -> Result<()> {
+        let cancel = CancellationToken::new();
+        let proxy = proxy_handler(
+            "127.0.0.1:0".parse()?,
+            "127.0.0.1:9".parse()?,
+            ProxyConfig::default().service(&ServicePort::try_from("8443/tcp")?),
+            cancel.clone(),
+        );
+        cancel.cancel();
+        tokio::time::timeout(std::time::Duration::from_secs(5), proxy).await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxied_connections_counted_and_logged() -> Result<()> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let dest_addr = upstream.local_addr()?;
+        tokio::spawn(async move {
+            let (mut s, _) = upstream.accept().await?;
+            let mut request = [0; 5];
+            s.read_exact(&mut request).await?;
+            s.write_all(b"hello, client").await?;
+            Ok::<(), std::io::Error>(())
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let listen_addr = listener.local_addr()?;
+        let dir = std::env::temp_dir().join(format!("innisfree-proxy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let log_path = dir.join("access.log");
+        let config = ProxyConfig {
+            access_log: Some(AccessLog::open_path(&log_path, u64::MAX)?),
+            ..Default::default()
+        };
+        let counters = config.traffic.clone();
+        let service = ServicePort::try_from("8443/tcp")?;
+        let proxy = config.service(&service);
+        let handled = tokio::spawn(async move {
+            let (mut inbound, conn) = proxy.accept(&listener, &CancellationToken::new()).await?;
+            let peer = conn.client;
+            let (mut outbound, _) = proxy.connect(dest_addr).await.ok()?;
+            let r = relay(&mut inbound, &mut outbound).await;
+            proxy.close(conn, r, "Proxy");
+            Some(peer)
+        });
+
+        let mut client = TcpStream::connect(listen_addr).await?;
+        client.write_all(b"hello").await?;
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await?;
+        assert_eq!(reply, b"hello, client");
+        let totals = counters.totals();
+        assert_eq!(
+            totals["8443/TCP"],
+            ByteCounts {
+                rx_bytes: 5,
+                tx_bytes: 13
+            }
+        );
+        // Shared with the running proxy
+        assert_eq!(counters.service(&service).counts(), totals["8443/TCP"]);
+
+        // Logged once closed
+        drop(client);
+        let client_addr = handled.await?.expect("connection not accepted");
+        let entry: AccessEntry = serde_json::from_str(&std::fs::read_to_string(&log_path)?)?;
+        assert_eq!(entry.service, "8443/TCP");
+        assert_eq!(entry.client, client_addr);
+        assert_eq!((entry.rx_bytes, entry.tx_bytes), (5, 13));
+        assert!(entry.error.is_none());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_connections_capped() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service = ServicePort {
+            max_conns: Some(1),
+            ..ServicePort::try_from("8443/tcp")?
+        };
+        let proxy = ProxyConfig::default().service(&service);
+        let cancel = CancellationToken::new();
+        let _clients = (
+            TcpStream::connect(addr).await?,
+            TcpStream::connect(addr).await?,
+        );
+        let (_, first) = proxy
+            .accept(&listener, &cancel)
+            .await
+            .expect("not accepted");
+        // The second waits until the first closes
+        let second =
+            tokio::time::timeout(Duration::from_millis(200), proxy.accept(&listener, &cancel));
+        assert!(second.await.is_err());
+        drop(first);
+        let second = tokio::time::timeout(Duration::from_secs(5), proxy.accept(&listener, &cancel));
+        assert!(second.await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unhealthy_service_turns_clients_away() -> Result<()> {
+        let dest_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let service = ServicePort {
+            health_check: Some(HealthCheck::Tcp),
+            ..ServicePort::try_from("8443/tcp")?
+        };
+        let config = ProxyConfig {
+            unavailable: Some(Arc::from(&b"HTTP/1.1 503 Service Unavailable\r\n\r\n"[..])),
+            ..Default::default()
+        };
+        let proxy = config.service(&service);
+        let cancel = CancellationToken::new();
+        let listener = proxy.listen("127.0.0.1:0".parse()?, dest_addr, &cancel)?;
+        let listen_addr = listener.local_addr()?;
+        let accepting = {
+            let (proxy, cancel) = (proxy.clone(), cancel.clone());
+            tokio::spawn(async move { proxy.accept(&listener, &cancel).await.is_some() })
+        };
+        let mut client = TcpStream::connect(listen_addr).await?;
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await?;
+        assert!(reply.starts_with(b"HTTP/1.1 503"));
+        cancel.cancel();
+        assert!(!accepting.await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_retried_until_service_up() -> Result<()> {
+        for attempt in 0..10 {
+            let delay = connect_backoff(attempt);
+            assert!(delay >= CONNECT_BACKOFF / 2 && delay <= MAX_CONNECT_BACKOFF);
+        }
+        assert!(connect_backoff(0) <= CONNECT_BACKOFF);
+
+        let dest_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let service = ServicePort::try_from("8443/tcp")?;
+        assert!(ProxyConfig::default()
+            .service(&service)
+            .connect(dest_addr)
+            .await
+            .is_err());
+        // The service comes back while retrying
+        let restarted = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            TcpListener::bind(dest_addr).await?.accept().await
+        });
+        let config = ProxyConfig {
+            connect_retries: 5,
+            ..Default::default()
+        };
+        let (stream, _) = config.service(&service).connect(dest_addr).await?;
+        assert_eq!(stream.peer_addr()?, dest_addr);
+        restarted.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_connections_closed() -> Result<()> {
+        let service = ServicePort::try_from("8443/tcp")?;
+        let mut config = ProxyConfig {
+            timeouts: ConnTimeouts {
+                idle: Some(Duration::from_millis(200)),
+                max_lifetime: None,
+            },
+            ..Default::default()
+        };
+        // A peer vanished, so the transfer never finishes.
+        let bytes = Arc::new(Traffic::default());
+        let start = Instant::now();
+        let proxy = config.service(&service);
+        proxy.timed(bytes.clone(), std::future::pending()).await?;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(1));
+
+        // Traffic keeps it open, until its lifetime is up.
+        config.timeouts.max_lifetime = Some(Duration::from_millis(600));
+        let busy = bytes.clone();
+        let traffic = tokio::spawn(async move {
+            loop {
+                busy.rx_bytes.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        let start = Instant::now();
+        let proxy = config.service(&service);
+        proxy.timed(bytes, std::future::pending()).await?;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(600) && elapsed < Duration::from_secs(2));
+        traffic.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn socket_options_applied() -> Result<()> {
+        assert_eq!(parse_buffer_size("256k")?, 256 * 1024);
+        assert!(parse_buffer_size("8192m").is_err());
+        let sockets = SocketOptions {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(60)),
+            keepalive_interval: Some(Duration::from_secs(10)),
+            buffer_size: Some(256 * 1024),
+        };
+        let listener = sockets.bind("127.0.0.1:0".parse()?)?;
+        let outbound = sockets.connect(listener.local_addr()?).await?;
+        let (inbound, _) = listener.accept().await?;
+        sockets.apply(&inbound)?;
+        for stream in [&outbound, &inbound] {
+            let socket = socket2::SockRef::from(stream);
+            assert!(socket.nodelay()?);
+            assert!(socket.keepalive()?);
+            assert_eq!(socket.keepalive_time()?, Duration::from_secs(60));
+            assert_eq!(socket.keepalive_interval()?, Duration::from_secs(10));
+            // Linux doubles the size, for its own bookkeeping
+            assert!(socket.recv_buffer_size()? >= 256 * 1024);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn client_limits_enforced() -> Result<()> {
+        assert_eq!(parse_rate("512k")?, 512 * 1024);
+        assert_eq!(parse_rate("2M")?, 2 * 1024 * 1024);
+        assert_eq!(parse_rate("1000")?, 1000);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+
+        let conns = ClientConns::default();
+        let ip: IpAddr = "203.0.113.7".parse()?;
+        let first = conns.open(ip, Some(2));
+        let second = conns.open(ip, Some(2));
+        assert!(first.is_some() && second.is_some());
+        assert!(conns.open(ip, Some(2)).is_none());
+        // Others aren't affected
+        assert!(conns.open("198.51.100.1".parse()?, Some(2)).is_some());
+        drop(first);
+        assert!(conns.open(ip, Some(2)).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn throttled_to_rate() -> Result<()> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let limits = ClientLimits {
+            max_rate: Some(4000),
+            ..Default::default()
+        };
+        let traffic = Arc::new(Traffic::default());
+        let mut server = Counted::new(server, traffic.clone(), limits);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        client_write.write_all(&[0; 6000]).await?;
+        let start = Instant::now();
+        let mut received = vec![0; 6000];
+        server.read_exact(&mut received).await?;
+        // The first window's 4000 bytes pass at once, the rest a second later.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(900) && elapsed < Duration::from_secs(2));
+        server.write_all(&[0; 3000]).await?;
+        client_read.read_exact(&mut received[..3000]).await?;
+        assert_eq!(traffic.counts().tx_bytes, 3000);
+        assert_eq!(traffic.counts().rx_bytes, 6000);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_rate_shared() -> Result<()> {
+        let limits = ClientLimits {
+            max_rate: Some(4000),
+            ..Default::default()
+        };
+        let traffic = Arc::new(Traffic::default());
+        let rates = ClientRates::default();
+        let ip: IpAddr = "203.0.113.7".parse()?;
+        let mut servers = vec![];
+        for _ in 0..2 {
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let mut server = Counted::new(server, traffic.clone(), limits);
+            server.limit_client(&rates, ip);
+            client.write_all(&[0; 3000]).await?;
+            servers.push((client, server));
+        }
+        // Both connections' 3000 bytes don't fit in the client's 4000.
+        let start = Instant::now();
+        let mut received = vec![0; 3000];
+        for (_, server) in servers.iter_mut() {
+            server.read_exact(&mut received).await?;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(900) && elapsed < Duration::from_secs(2));
+        assert_eq!(traffic.counts().rx_bytes, 6000);
+
+        // Other clients have their own budget.
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut server = Counted::new(server, traffic.clone(), limits);
+        server.limit_client(&rates, "198.51.100.1".parse()?);
+        client.write_all(&[0; 3000]).await?;
+        let start = Instant::now();
+        server.read_exact(&mut received).await?;
+        assert!(start.elapsed() < Duration::from_millis(500));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_v1_header_parsed() -> Result<()> {
+        let mut stream: &[u8] = b"PROXY TCP4 203.0.113.7 10.50.0.1 51234 443\r\nGET / HTTP/1.1";
+        let addrs = read_proxy_v1(&mut stream).await?;
+        let (src, dst) = addrs.expect("addresses missing");
+        assert_eq!(src, "203.0.113.7:51234".parse()?);
+        assert_eq!(dst, "10.50.0.1:443".parse()?);
+        // Payload after the header is left unread
+        assert_eq!(stream, b"GET / HTTP/1.1");
+        assert_eq!(
+            encode_proxy_v1(addrs),
+            b"PROXY TCP4 203.0.113.7 10.50.0.1 51234 443\r\n"
+        );
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert!(read_proxy_v1(&mut stream).await?.is_none());
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_proxy_v1(&mut stream).await.is_err());
+        let mut stream: &[u8] = &[b'A'; 200];
+        assert!(read_proxy_v1(&mut stream).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn proxy_v2_header_encoded() -> Result<()> {
+        let addrs = Some(("203.0.113.7:51234".parse()?, "10.50.0.1:443".parse()?));
+        let header = encode_proxy_v2(addrs);
+        assert_eq!(header.len(), 16 + 12);
+        assert_eq!(&header[..12], &PROXY_V2_SIGNATURE);
+        assert_eq!(&header[12..16], &[0x21, 0x11, 0x00, 0x0C]);
+        assert_eq!(&header[16..20], &[203, 0, 113, 7]);
+        assert_eq!(&header[24..26], &51234u16.to_be_bytes());
+
+        let addrs = Some(("[2001:db8::7]:51234".parse()?, "[fd50::1]:443".parse()?));
+        assert_eq!(encode_proxy_v2(addrs).len(), 16 + 36);
+        assert_eq!(encode_proxy_v2(None).len(), 16);
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+//! Reloading a running tunnel's file, see `innisfree up --file`, on SIGHUP,
+//! e.g. via `systemctl reload`. The file's services, and dest IP, if set,
+//! are applied as `innisfree up` would apply them to a running tunnel:
+//! via the control socket, see [crate::control], which opens and closes
+//! ports in the server's firewall, rewrites its nginx config over SSH, and
+//! starts and stops local proxies, all without recreating the server.
+//! Settings given on the command line, or in env vars, still win over the
+//! file, as they did at startup. Other settings only apply once the tunnel
+//! is restarted.
+
+use anyhow::Result;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::{services_from_vars, ServicePort, TunnelFile};
+use crate::control::{self, ControlRequest};
+
+/// Env vars for the settings of `up` that a reload re-reads.
+pub const RELOADED_VARS: [&str; 8] = [
+    "INNISFREE_PORTS",
+    "INNISFREE_DEST_IP",
+    "INNISFREE_PROXY_PROTOCOL",
+    "INNISFREE_ALLOW_CIDR",
+    "INNISFREE_DENY_CIDR",
+    "INNISFREE_MAX_CONNS",
+    "INNISFREE_HEALTH_CHECK",
+    "INNISFREE_BACKEND",
+];
+
+/// Settings of [RELOADED_VARS] from outside the tunnel file, as env vars,
+/// merged with the file's on reload as `up` merges them at startup.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    /// Given on the command line, or in env vars, so win over the file.
+    pub given: Vec<(&'static str, String)>,
+    /// Defaults, used unless the file or [Overrides::given] sets them.
+    pub defaults: Vec<(&'static str, String)>,
+}
+
+impl Overrides {
+    /// Merges `vars`, the tunnel file's settings, with the overrides.
+    fn merge(&self, vars: Vec<(&'static str, String)>) -> Vec<(&'static str, String)> {
+        let mut merged = self.defaults.clone();
+        for (k, v) in vars.into_iter().chain(self.given.iter().cloned()) {
+            merged.retain(|(n, _)| *n != k);
+            merged.push((k, v));
+        }
+        merged
+    }
+}
+
+/// Returns the dest IP and services of the tunnel file at `path`, merged
+/// with `overrides`, falling back to forwarding to `dest_ip`.
+fn desired(
+    path: &Path,
+    dest_ip: IpAddr,
+    overrides: &Overrides,
+) -> Result<(IpAddr, Vec<ServicePort>)> {
+    let file = TunnelFile::load(path)?;
+    // Relative paths are resolved as at startup, rather than from the
+    // working directory, e.g. `/` under systemd.
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let vars = overrides.merge(file.env_vars(base_dir));
+    let dest_ip = match vars.iter().find(|(k, _)| *k == "INNISFREE_DEST_IP") {
+        Some((_, ip)) => ip.parse()?,
+        None => dest_ip,
+    };
+    Ok((dest_ip, services_from_vars(&vars, dest_ip)?))
+}
+
+/// Reloads the tunnel `name` from its file at `path`, sending the control
+/// requests to converge on it. Returns the dest IP forwarded to from now on.
+async fn reload(name: &str, path: &Path, dest_ip: IpAddr, overrides: &Overrides) -> Result<IpAddr> {
+    // Parse before changing anything, so a broken file changes nothing.
+    let (new_dest_ip, services) = desired(path, dest_ip, overrides)?;
+    if new_dest_ip != dest_ip {
+        let request = ControlRequest::SetDest {
+            dest_ip: new_dest_ip,
+        };
+        tracing::info!("{}", control::send(name, &request).await?);
+    }
+    let msg = control::send(name, &ControlRequest::SetPorts { services }).await?;
+    tracing::info!("{}", msg);
+    Ok(new_dest_ip)
+}
+
+/// Reloads the tunnel `name` from its file at `path` on each SIGHUP, for
+/// as long as the process runs, starting from forwarding to `dest_ip`.
+/// Failures are only reported, leaving the tunnel as it was.
+pub fn watch(name: &str, path: PathBuf, mut dest_ip: IpAddr, overrides: Overrides) -> Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    let name = name.to_string();
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            tracing::info!("Received SIGHUP, reloading {}", path.display());
+            match reload(&name, &path, dest_ip, &overrides).await {
+                Ok(ip) => dest_ip = ip,
+                Err(e) => tracing::warn!("Failed to reload {}: {:#}", path.display(), e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProxyProtocol;
+
+    #[test]
+    fn overrides_win_over_file() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("innisfree-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("tunnel.toml");
+        std::fs::write(&path, "ports = [\"80:8000/TCP\"]\n")?;
+        let defaults = vec![
+            ("INNISFREE_PORTS", "80:8000/TCP".to_string()),
+            ("INNISFREE_DEST_IP", "127.0.0.1".to_string()),
+        ];
+        let localhost: IpAddr = "127.0.0.1".parse()?;
+
+        // As after `up -F tunnel.toml --ports 8080/TCP`
+        let overrides = Overrides {
+            given: vec![("INNISFREE_PORTS", "8080/TCP".to_string())],
+            defaults: defaults.clone(),
+        };
+        let (dest_ip, services) = desired(&path, localhost, &overrides)?;
+        assert_eq!(dest_ip, localhost);
+        assert_eq!(services.len(), 1);
+        assert_eq!((services[0].port, services[0].local_port), (8080, 8080));
+
+        // The file's ports apply once nothing overrides them.
+        let overrides = Overrides {
+            given: vec![],
+            defaults,
+        };
+        std::fs::write(
+            &path,
+            "ports = [\"443:8443/TCP\"]\ndest_ip = \"10.0.0.5\"\n",
+        )?;
+        let (dest_ip, services) = desired(&path, localhost, &overrides)?;
+        assert_eq!(dest_ip, "10.0.0.5".parse::<IpAddr>()?);
+        assert_eq!(services[0].local_port, 8443);
+
+        // Without ports in the file, the defaults still apply.
+        std::fs::write(&path, "proxy_protocol = \"80\"\n")?;
+        let (_, services) = desired(&path, localhost, &overrides)?;
+        assert_eq!(services[0].port, 80);
+        assert_eq!(services[0].proxy_protocol, Some(ProxyProtocol::V2));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
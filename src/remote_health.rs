@@ -0,0 +1,147 @@
+//! Health endpoint served by the server, see `innisfree up --health-port`.
+//! Each request runs a short script, via a socket-activated systemd service,
+//! reporting whether the local end of the tunnel has a recent Wireguard
+//! handshake, and whether each TCP service accepts connections over it.
+//! It answers 200 if so, else 503, and not at all if the server is down.
+
+use anyhow::{Context, Result};
+use std::net::IpAddr;
+
+use crate::config::{Protocol, ServicePort};
+use crate::manager::HANDSHAKE_TIMEOUT;
+
+/// Path to the health check script on the server.
+const SCRIPT_PATH: &str = "/usr/local/bin/innisfree-health";
+/// Path on the server to the addresses of the services to check.
+pub const TARGETS_PATH: &str = "/etc/innisfree/health-targets";
+/// Name of the socket unit on the server.
+const SOCKET_NAME: &str = "innisfree-health.socket";
+/// Name of the server's Wireguard interface.
+const INTERFACE: &str = "innisfree";
+
+/// Renders the health check script, answering one request on stdin.
+pub fn script() -> Result<String> {
+    let mut context = tera::Context::new();
+    context.insert("interface", INTERFACE);
+    context.insert("max_handshake_age", &HANDSHAKE_TIMEOUT.as_secs());
+    context.insert("targets", TARGETS_PATH);
+    tera::Tera::one_off(include_str!("../files/health.sh.j2"), &context, false)
+        .context("Failed to render health check script")
+}
+
+/// Lists the TCP services' local ports on `dest_ip`, one per line, as read
+/// by the script. UDP services can't be checked by connecting to them.
+pub fn targets(services: &[ServicePort], dest_ip: IpAddr) -> String {
+    services
+        .iter()
+        .filter(|s| s.protocol == Protocol::Tcp)
+        .map(|s| format!("{} {}\n", dest_ip, s.local_port))
+        .collect()
+}
+
+/// Files for cloud-init to write, as `(path, permissions, contents)`:
+/// the script, and the units serving it on `port`.
+pub fn server_files(port: u16) -> Result<Vec<(&'static str, &'static str, String)>> {
+    let mut context = tera::Context::new();
+    context.insert("port", &port);
+    context.insert("script", SCRIPT_PATH);
+    let socket = tera::Tera::one_off(include_str!("../files/health.socket.j2"), &context, false)
+        .context("Failed to render health socket unit")?;
+    let service = tera::Tera::one_off(include_str!("../files/health@.service.j2"), &context, false)
+        .context("Failed to render health service unit")?;
+    Ok(vec![
+        (SCRIPT_PATH, "0755", script()?),
+        (
+            "/etc/systemd/system/innisfree-health.socket",
+            "0644",
+            socket,
+        ),
+        (
+            "/etc/systemd/system/innisfree-health@.service",
+            "0644",
+            service,
+        ),
+    ])
+}
+
+/// Commands for cloud-init, starting to serve the endpoint.
+pub fn server_runcmd() -> Vec<Vec<String>> {
+    vec![vec![
+        "systemctl".to_string(),
+        "enable".to_string(),
+        "--now".to_string(),
+        SOCKET_NAME.to_string(),
+    ]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn script_reports_tunnel_and_upstreams() -> Result<()> {
+        let services = ServicePort::from_str_multi("443:8443/TCP,53/UDP")?;
+        assert_eq!(targets(&services, "10.50.0.2".parse()?), "10.50.0.2 8443\n");
+        let files = server_files(8099)?;
+        assert!(files[1].2.contains("ListenStream=8099"));
+        assert!(files[2]
+            .2
+            .contains("ExecStart=/usr/local/bin/innisfree-health"));
+
+        // Run the script against a stub `wg`, a listening upstream, and a closed one.
+        let dir = std::env::temp_dir().join(format!("innisfree-health-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let open = listener.local_addr()?.port();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let run = |handshake: &str, ports: &[u16]| -> Result<String> {
+            std::fs::write(
+                dir.join("wg"),
+                format!("#!/bin/sh\necho \"peer {}\"\n", handshake),
+            )?;
+            Command::new("chmod")
+                .arg("+x")
+                .arg(dir.join("wg"))
+                .status()?;
+            let lines: String = ports.iter().map(|p| format!("127.0.0.1 {}\n", p)).collect();
+            std::fs::write(dir.join("targets"), lines)?;
+            let script =
+                script()?.replace(TARGETS_PATH, &dir.join("targets").display().to_string());
+            let path = format!("{}:{}", dir.display(), std::env::var("PATH")?);
+            let mut child = Command::new("bash")
+                .args(["-c", &script])
+                .env("PATH", path)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()?;
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")?;
+            Ok(String::from_utf8(child.wait_with_output()?.stdout)?)
+        };
+        let now = crate::state::now().to_string();
+        let response = run(&now, &[open])?;
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains(&format!(
+            "{{\"healthy\":true,\"tunnel\":true,\"handshake_age\":0,\"upstreams\":{{\"{}\":true}}}}",
+            open
+        )));
+        let response = run(&now, &[open, closed])?;
+        assert!(response.starts_with("HTTP/1.0 503"));
+        assert!(response.contains(&format!("\"{}\":false", closed)));
+        // The local end is offline
+        let response = run("0", &[])?;
+        assert!(response.starts_with("HTTP/1.0 503"));
+        assert!(response.contains("\"tunnel\":false,\"handshake_age\":null"));
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -1,41 +1,71 @@
 //! Abstract representation of remote server.
-//! Designed to be modular in terms of providers, but really
-//! only supports DigitalOcean. The abstract struct
-//! is [InnisfreeServer], but underneath it assumes implementation
-//! as a DigitalOcean Droplet.
+//! Designed to be modular in terms of providers. The abstract struct
+//! is [InnisfreeServer], implemented by e.g. a DigitalOcean Droplet,
+//! a Linode, an Azure VM, a Scaleway instance, or an OCI instance, or
+//! faked locally, see [mock].
+//! Servers are created via a [ServerProvider] factory, looked up by name
+//! in a [ProviderRegistry], so new providers can be added without
+//! changes to [crate::manager::TunnelManager].
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use serde::Serialize;
 use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 use crate::config::ServicePort;
+use crate::error;
+use crate::server::cloudinit::CloudConfigOptions;
 use crate::ssh::SshKeypair;
 use crate::wg::WireguardManager;
 
+#[cfg(feature = "azure")]
+pub mod azure;
 pub mod cloudinit;
+#[cfg(feature = "digitalocean")]
 pub mod digitalocean;
+#[cfg(feature = "linode")]
+pub mod linode;
+pub mod mock;
+#[cfg(feature = "oci")]
+pub mod oci;
+#[cfg(feature = "scaleway")]
+pub mod scaleway;
 
 /// Manager class, wraps a cloudserver VM type, such as Droplet,
 /// to make it a bit easier to work with. Bootstraps the necessary keypairs
 /// for services like SSH (both client and keyserver need keypairs), and Wireguard.
 #[async_trait]
-pub trait InnisfreeServer {
-    /// Create new [InnisfreeServer]. Requires a name for the service,
-    /// a list of `ServicePort`s, and a [WireguardManager].
-    async fn new(
-        name: &str,
-        services: Vec<ServicePort>,
-        wg_mgr: WireguardManager,
-        ssh_client_keypair: &SshKeypair,
-        ssh_server_keypair: &SshKeypair,
-    ) -> Result<Self>
-    where
-        Self: Sized;
-
+pub trait InnisfreeServer: Send + Sync {
     /// Returns the IPv4 address for the remote server. Used for both
     /// SSH connections and the remote Wireguard peer interface.
     fn ipv4_address(&self) -> Result<IpAddr>;
 
+    /// Returns the provider's identifier for the server, e.g. a Droplet ID,
+    /// persisted so that [ServerProvider::destroy] can find it later.
+    fn id(&self) -> String;
+
+    /// Returns the public IPv6 address for the remote server, if it has one.
+    /// Services are published on it alongside the IPv4 address.
+    fn ipv6_address(&self) -> Result<Option<IpAddr>> {
+        Ok(None)
+    }
+
+    /// Returns the name to which the server's IPv4 address resolves via
+    /// reverse DNS, for providers that set it, e.g. from the hostname.
+    fn reverse_dns(&self) -> Option<String> {
+        None
+    }
+
+    /// Looks up the server's current public IPv4 address via the provider's
+    /// API, e.g. when re-establishing a broken tunnel. Defaults to the
+    /// address known since creation, for providers where it can't change.
+    async fn refresh_ipv4_address(&self) -> Result<IpAddr> {
+        self.ipv4_address()
+    }
+
     /// Attaches a reserved IP to the remote server. Makes it easier
     /// to use DNS, since the record needs to be updated only once,
     /// and the IP address can be reused repeatedly on multiple hosts after that.
@@ -43,4 +73,308 @@ pub trait InnisfreeServer {
 
     /// Destroy the cloud server backing the remote end of the Wireguard tunnel.
     async fn destroy(&self) -> Result<()>;
+
+    /// Allows public traffic to a service added to a running tunnel.
+    /// Only needed for providers that filter inbound traffic
+    /// outside the server, e.g. via a cloud firewall.
+    async fn open_port(&self, _service: &ServicePort) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stops allowing public traffic to a service removed from a running
+    /// tunnel. The counterpart to [InnisfreeServer::open_port].
+    async fn close_port(&self, _service: &ServicePort) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+/// A request to a provider's API, as shown by `innisfree up --dry-run`.
+pub struct ApiRequest {
+    /// HTTP method, e.g. `POST`.
+    pub method: &'static str,
+    /// Full URL of the endpoint.
+    pub url: String,
+    /// Request body, as sent. Plain-text bodies are held as a JSON string.
+    pub body: serde_json::Value,
+}
+
+impl ApiRequest {
+    /// Builds a request with the given body, serialized as JSON.
+    pub fn new<T: Serialize>(method: &'static str, url: &str, body: &T) -> Result<Self> {
+        Ok(ApiRequest {
+            method,
+            url: url.to_string(),
+            body: serde_json::to_value(body)?,
+        })
+    }
+}
+
+/// Shortest wait between polls of a booting server, see [poll_interval].
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Longest wait between polls of a booting server, see [poll_interval].
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Returns how long to wait before polling a booting server, e.g. via its
+/// provider's API, after `attempt` polls: briefly at first, since servers
+/// often boot within seconds, then half again as long each time, up to
+/// [MAX_POLL_INTERVAL], so slow boots aren't polled needlessly often.
+pub fn poll_interval(attempt: u32) -> Duration {
+    MIN_POLL_INTERVAL
+        .mul_f64(1.5_f64.powi(attempt.min(8) as i32))
+        .min(MAX_POLL_INTERVAL)
+}
+
+/// Longest to wait on a new server's boot before giving up, see [wait_to_poll].
+pub const BOOT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Waits [poll_interval] before polling a booting server again, after
+/// `attempt` polls. Fails once `deadline` passes, e.g. [BOOT_TIMEOUT] after
+/// creating the server, or once `cancel` fires, saying `what` was awaited.
+pub async fn wait_to_poll(
+    attempt: u32,
+    deadline: tokio::time::Instant,
+    cancel: &CancellationToken,
+    what: &str,
+) -> Result<()> {
+    if tokio::time::Instant::now() >= deadline {
+        return Err(error::timeout(format!("Timed out waiting for {}", what)));
+    }
+    let next = tokio::time::Instant::now() + poll_interval(attempt);
+    tokio::select! {
+        _ = cancel.cancelled() => {
+            Err(error::cancelled(format!("Cancelled while waiting for {}", what)))
+        }
+        _ = tokio::time::sleep_until(next.min(deadline)) => Ok(()),
+    }
+}
+
+/// Reads the env var `key`, falling back to a `<KEY>` placeholder,
+/// so a dry run can show requests without any credentials configured.
+pub fn env_or_placeholder(key: &str) -> String {
+    std::env::var(key).unwrap_or_else(|_| format!("<{}>", key))
+}
+
+/// Factory for creating an [InnisfreeServer] on a given cloud provider.
+/// Implementations are registered in a [ProviderRegistry], so the
+/// concrete server type can be chosen at runtime.
+#[async_trait]
+pub trait ServerProvider: Send + Sync {
+    /// Short name used to select the provider, e.g. `digitalocean`.
+    fn name(&self) -> &'static str;
+
+    /// Create new [InnisfreeServer]. Requires a name for the service,
+    /// a list of `ServicePort`s, and a [WireguardManager].
+    async fn create(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<Box<dyn InnisfreeServer>>;
+
+    /// Creates a server as [ServerProvider::create] does, but gives up once
+    /// `cancel` fires, destroying whatever was created. By default, that's
+    /// only checked once creation finishes; providers may override this
+    /// to stop waiting on the server's boot sooner.
+    #[allow(clippy::too_many_arguments)]
+    async fn create_cancellable(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        cancel: &CancellationToken,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let server = self
+            .create(
+                name,
+                services,
+                wg_mgr,
+                ssh_client_keypair,
+                ssh_server_keypair,
+                options,
+            )
+            .await?;
+        if cancel.is_cancelled() {
+            if let Err(e) = server.destroy().await {
+                tracing::warn!("Failed to destroy cancelled server: {:#}", e);
+            }
+            return Err(error::cancelled("Cancelled while creating server"));
+        }
+        Ok(server)
+    }
+
+    /// Returns the API requests that [ServerProvider::create] would send, in
+    /// order, without sending any, e.g. for `innisfree up --dry-run`.
+    /// Values only known once an earlier request completes, such as IDs,
+    /// or that need credentials to look up, are shown as placeholders.
+    async fn plan(
+        &self,
+        name: &str,
+        user_data: &str,
+        services: &[ServicePort],
+        wg_mgr: &WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<Vec<ApiRequest>>;
+
+    /// Destroys a server created by another process, given its name
+    /// and [InnisfreeServer::id], e.g. for `innisfree down`.
+    async fn destroy(&self, name: &str, id: &str) -> Result<()>;
+
+    /// Checks via the API whether the server still exists, given its name
+    /// and [InnisfreeServer::id], e.g. to spot tunnels whose server is gone.
+    async fn server_exists(&self, name: &str, id: &str) -> Result<bool>;
+
+    /// Looks up a server created by an earlier process, given its name
+    /// and [InnisfreeServer::id], so that a new process can re-attach to it.
+    /// Fails if the server no longer exists, or isn't running.
+    async fn adopt(&self, name: &str, id: &str) -> Result<Box<dyn InnisfreeServer>>;
+}
+
+/// Collection of [ServerProvider]s, looked up by name.
+/// The default registry contains all built-in providers
+/// enabled via cargo features.
+pub struct ProviderRegistry {
+    providers: Vec<Arc<dyn ServerProvider>>,
+}
+
+impl ProviderRegistry {
+    /// Creates an empty registry. Use [ProviderRegistry::default]
+    /// for one pre-populated with the built-in providers.
+    pub fn new() -> Self {
+        ProviderRegistry { providers: vec![] }
+    }
+
+    /// Adds a provider to the registry. A provider registered under
+    /// an existing name replaces the earlier one.
+    pub fn register(&mut self, provider: Box<dyn ServerProvider>) {
+        self.providers.retain(|p| p.name() != provider.name());
+        self.providers.push(Arc::from(provider));
+    }
+
+    /// Looks up a provider by name, case-insensitively.
+    pub fn get(&self, name: &str) -> Result<&dyn ServerProvider> {
+        self.find(name).map(|p| p.as_ref())
+    }
+
+    /// Looks up a provider by name, as [ProviderRegistry::get] does, returning
+    /// a handle that can outlive the registry, e.g. for
+    /// [crate::manager::TunnelManager::enable_self_heal].
+    pub fn shared(&self, name: &str) -> Result<Arc<dyn ServerProvider>> {
+        self.find(name).cloned()
+    }
+
+    /// Finds the provider registered under `name`, case-insensitively.
+    fn find(&self, name: &str) -> Result<&Arc<dyn ServerProvider>> {
+        self.providers
+            .iter()
+            .find(|p| p.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unsupported provider '{}', expected one of: {}",
+                    name,
+                    self.names().join(", ")
+                )
+            })
+    }
+
+    /// Returns the names of all registered providers, in registration order.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.providers.iter().map(|p| p.name()).collect()
+    }
+}
+
+impl Default for ProviderRegistry {
+    fn default() -> Self {
+        #[allow(unused_mut)]
+        let mut registry = ProviderRegistry::new();
+        #[cfg(feature = "digitalocean")]
+        registry.register(Box::new(
+            digitalocean::server::DigitalOceanProvider::default(),
+        ));
+        #[cfg(feature = "linode")]
+        registry.register(Box::new(linode::server::LinodeProvider));
+        #[cfg(feature = "azure")]
+        registry.register(Box::new(azure::server::AzureProvider));
+        #[cfg(feature = "scaleway")]
+        registry.register(Box::new(scaleway::server::ScalewayProvider));
+        #[cfg(feature = "oci")]
+        registry.register(Box::new(oci::server::OciProvider));
+        registry.register(Box::new(mock::MockProvider::default()));
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(all(
+        feature = "digitalocean",
+        feature = "linode",
+        feature = "azure",
+        feature = "scaleway",
+        feature = "oci"
+    ))]
+    fn default_registry_has_builtin_providers() -> Result<()> {
+        let registry = ProviderRegistry::default();
+        assert_eq!(
+            registry.names(),
+            vec!["digitalocean", "linode", "azure", "scaleway", "oci", "mock"]
+        );
+        assert_eq!(registry.get("DigitalOcean")?.name(), "digitalocean");
+        assert_eq!(registry.shared("OCI")?.name(), "oci");
+        Ok(())
+    }
+
+    #[test]
+    fn poll_interval_grows() {
+        assert_eq!(poll_interval(0), Duration::from_secs(2));
+        assert_eq!(poll_interval(1), Duration::from_secs(3));
+        assert!(poll_interval(2) < poll_interval(3));
+        assert_eq!(poll_interval(4), MAX_POLL_INTERVAL);
+        assert_eq!(poll_interval(u32::MAX), MAX_POLL_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn boot_wait_gives_up() {
+        let now = tokio::time::Instant::now();
+        let cancel = CancellationToken::new();
+        let e = wait_to_poll(0, now, &cancel, "boot").await.err();
+        let e = e.map(error::InnisfreeError::from);
+        assert!(matches!(e, Some(error::InnisfreeError::Timeout(_))));
+
+        cancel.cancel();
+        let deadline = now + BOOT_TIMEOUT;
+        let e = wait_to_poll(0, deadline, &cancel, "boot").await.err();
+        let e = e.map(error::InnisfreeError::from);
+        assert!(matches!(e, Some(error::InnisfreeError::Cancelled(_))));
+        assert!(now.elapsed() < poll_interval(0));
+    }
+
+    #[test]
+    fn unknown_provider_lists_choices() {
+        let registry = ProviderRegistry::default();
+        let e = registry.get("aws").err().map(|e| e.to_string());
+        let e = e.unwrap_or_default();
+        assert!(e.contains("aws"));
+        assert!(e.contains(&registry.names().join(", ")));
+    }
+
+    #[test]
+    #[cfg(all(feature = "digitalocean", feature = "linode"))]
+    fn registering_replaces_by_name() {
+        let mut registry = ProviderRegistry::default();
+        let count = registry.names().len();
+        registry.register(Box::new(linode::server::LinodeProvider));
+        assert_eq!(registry.names().len(), count);
+        assert_eq!(registry.names().last(), Some(&"linode"));
+    }
 }
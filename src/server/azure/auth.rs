@@ -0,0 +1,71 @@
+//! Logic to obtain an OAuth2 access token for the Azure Resource Manager API,
+//! via a service principal's client credentials. The credentials are read from
+//! the same env vars the official Azure SDKs use.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::env;
+
+const AZURE_LOGIN_BASE_URL: &str = "https://login.microsoftonline.com";
+const AZURE_MANAGEMENT_SCOPE: &str = "https://management.azure.com/.default";
+
+/// Service principal credentials for authenticating against Azure.
+/// See documentation for creating a service principal:
+/// <https://learn.microsoft.com/en-us/cli/azure/azure-cli-sp-tutorial-1>.
+#[derive(Debug, Clone)]
+pub struct AzureCredentials {
+    /// Directory (tenant) ID that the service principal belongs to.
+    pub tenant_id: String,
+    /// Application (client) ID of the service principal.
+    pub client_id: String,
+    /// Client secret for the service principal.
+    client_secret: String,
+    /// Subscription ID in which resources will be created.
+    pub subscription_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+/// Response body from the token endpoint. Only the token itself is needed.
+struct TokenResponse {
+    access_token: String,
+}
+
+impl AzureCredentials {
+    /// Reads credentials from the `AZURE_TENANT_ID`, `AZURE_CLIENT_ID`,
+    /// `AZURE_CLIENT_SECRET`, and `AZURE_SUBSCRIPTION_ID` env vars.
+    pub fn from_env() -> Result<AzureCredentials> {
+        Ok(AzureCredentials {
+            tenant_id: env::var("AZURE_TENANT_ID").context("AZURE_TENANT_ID not set.")?,
+            client_id: env::var("AZURE_CLIENT_ID").context("AZURE_CLIENT_ID not set.")?,
+            client_secret: env::var("AZURE_CLIENT_SECRET")
+                .context("AZURE_CLIENT_SECRET not set.")?,
+            subscription_id: env::var("AZURE_SUBSCRIPTION_ID")
+                .context("AZURE_SUBSCRIPTION_ID not set.")?,
+        })
+    }
+
+    /// Requests a short-lived bearer token for the Azure Resource Manager API.
+    pub async fn access_token(&self) -> Result<String> {
+        let request_url = format!(
+            "{}/{}/oauth2/v2.0/token",
+            AZURE_LOGIN_BASE_URL, self.tenant_id
+        );
+        let form = [
+            ("grant_type", "client_credentials"),
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("scope", AZURE_MANAGEMENT_SCOPE),
+        ];
+        let client = reqwest::Client::new();
+        let response = client
+            .post(request_url)
+            .form(&form)
+            .send()
+            .await
+            .context("Network error, check connection")?
+            .error_for_status()
+            .context("Failed to authenticate with Azure")?;
+        let token: TokenResponse = response.json().await?;
+        Ok(token.access_token)
+    }
+}
@@ -0,0 +1,6 @@
+//! Support for the Azure cloud provider.
+//! Each tunnel gets a dedicated resource group, containing the VM
+//! and its networking resources, so teardown is a single API call.
+
+pub mod auth;
+pub mod server;
@@ -0,0 +1,760 @@
+//! Logic for managing a remote server via the Azure cloud provider.
+//! Creating a VM on Azure requires a handful of supporting resources:
+//! a resource group, public IP, virtual network, network security group,
+//! and network interface. All of them live in a resource group named
+//! after the tunnel, which is deleted wholesale on destroy.
+
+use async_trait::async_trait;
+use base64::Engine;
+use serde_json::json;
+
+use anyhow::{anyhow, Context, Result};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{Protocol, ServicePort};
+use crate::error;
+use crate::server::azure::auth::AzureCredentials;
+use crate::server::cloudinit::{generate_user_data, CloudConfigOptions};
+use crate::server::{
+    env_or_placeholder, poll_interval, ApiRequest, InnisfreeServer, ServerProvider,
+};
+use crate::ssh::SshKeypair;
+use crate::wg::WireguardManager;
+
+/// The region in which the resources will be created, e.g. `westus2`.
+/// See docs for more info: <https://learn.microsoft.com/en-us/azure/reliability/availability-zones-overview>.
+pub const AZURE_LOCATION: &str = "westus2";
+/// The type of VM instance to create, e.g. `Standard_B1s`.
+/// See docs for more info: <https://learn.microsoft.com/en-us/azure/virtual-machines/sizes>.
+pub const AZURE_VM_SIZE: &str = "Standard_B1s";
+/// Marketplace image used for the VM, as `publisher:offer:sku`.
+/// Defaults to Debian Stable, to match the other providers.
+pub const AZURE_IMAGE: &str = "Debian:debian-11:11-gen2";
+/// Admin user created by the Azure provisioning agent. Kept distinct from
+/// the `innisfree` user that cloudinit creates, to avoid clobbering it.
+const AZURE_ADMIN_USER: &str = "azureuser";
+const AZURE_API_BASE_URL: &str = "https://management.azure.com";
+const AZURE_RESOURCE_API_VERSION: &str = "2021-04-01";
+const AZURE_NETWORK_API_VERSION: &str = "2023-04-01";
+const AZURE_COMPUTE_API_VERSION: &str = "2023-03-01";
+/// How long to wait for a resource to finish provisioning, before giving up.
+const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Representation of an Azure VM, along with the resource group containing it.
+/// See more documentation at
+/// <https://learn.microsoft.com/en-us/rest/api/compute/virtual-machines>.
+#[derive(Debug)]
+pub struct AzureVm {
+    /// Name of the resource group holding the VM and its networking.
+    pub resource_group: String,
+    /// Name of the VM resource within the resource group.
+    pub name: String,
+    /// Static public IPv4 address attached to the VM's network interface.
+    ip: IpAddr,
+    /// Credentials used to manage the resources, retained for teardown.
+    credentials: AzureCredentials,
+}
+
+/// Builds the URL for a resource within the resource group,
+/// e.g. `Microsoft.Network/publicIPAddresses/foo`.
+fn resource_url(subscription_id: &str, resource_group: &str, resource: &str) -> String {
+    format!(
+        "{}/subscriptions/{}/resourceGroups/{}/providers/{}",
+        AZURE_API_BASE_URL, subscription_id, resource_group, resource
+    )
+}
+
+/// Builds the URL for the resource group itself.
+fn resource_group_url(subscription_id: &str, resource_group: &str) -> String {
+    format!(
+        "{}/subscriptions/{}/resourceGroups/{}?api-version={}",
+        AZURE_API_BASE_URL, subscription_id, resource_group, AZURE_RESOURCE_API_VERSION
+    )
+}
+
+/// Builds the URL for creating a networking resource for the VM `name`,
+/// e.g. `Microsoft.Network/publicIPAddresses` for its public IP.
+fn network_url(subscription_id: &str, name: &str, resource_type: &str, suffix: &str) -> String {
+    format!(
+        "{}?api-version={}",
+        resource_url(
+            subscription_id,
+            name,
+            &format!("Microsoft.Network/{}/{}-{}", resource_type, name, suffix),
+        ),
+        AZURE_NETWORK_API_VERSION
+    )
+}
+
+/// Builds the URL for creating the VM `name` itself.
+fn vm_url(subscription_id: &str, name: &str) -> String {
+    format!(
+        "{}?api-version={}",
+        resource_url(
+            subscription_id,
+            name,
+            &format!("Microsoft.Compute/virtualMachines/{}", name),
+        ),
+        AZURE_COMPUTE_API_VERSION
+    )
+}
+
+/// Builds the request body for the VM's static public IP.
+fn public_ip_body() -> serde_json::Value {
+    json!({
+        "location": AZURE_LOCATION,
+        "sku": { "name": "Standard" },
+        "properties": { "publicIPAllocationMethod": "Static" },
+    })
+}
+
+/// Builds the request body for the network security group,
+/// allowing inbound traffic to SSH, Wireguard, and the services.
+fn nsg_body(services: &[ServicePort], wg_port: i32) -> serde_json::Value {
+    json!({
+        "location": AZURE_LOCATION,
+        "properties": { "securityRules": security_rules(services, wg_port) },
+    })
+}
+
+/// Builds the request body for the virtual network, with a single subnet.
+fn vnet_body() -> serde_json::Value {
+    json!({
+        "location": AZURE_LOCATION,
+        "properties": {
+            "addressSpace": { "addressPrefixes": ["10.1.0.0/16"] },
+            "subnets": [
+                { "name": "default", "properties": { "addressPrefix": "10.1.0.0/24" } },
+            ],
+        },
+    })
+}
+
+/// Builds the request body for the network interface, given the
+/// IDs of the resources created before it.
+fn nic_body(
+    nsg_id: &serde_json::Value,
+    subnet_id: &serde_json::Value,
+    ip_id: &serde_json::Value,
+) -> serde_json::Value {
+    json!({
+        "location": AZURE_LOCATION,
+        "properties": {
+            "networkSecurityGroup": { "id": nsg_id },
+            "ipConfigurations": [{
+                "name": "ipconfig1",
+                "properties": {
+                    "subnet": { "id": subnet_id },
+                    "publicIPAddress": { "id": ip_id },
+                },
+            }],
+        },
+    })
+}
+
+/// Builds the request body for the VM `name`, attached to the network interface `nic_id`.
+fn vm_body(
+    name: &str,
+    user_data: &str,
+    ssh_client_keypair: &SshKeypair,
+    nic_id: &serde_json::Value,
+) -> serde_json::Value {
+    let image: Vec<&str> = AZURE_IMAGE.split(':').collect();
+    json!({
+        "location": AZURE_LOCATION,
+        "properties": {
+            "hardwareProfile": { "vmSize": AZURE_VM_SIZE },
+            "storageProfile": {
+                "imageReference": {
+                    "publisher": image[0],
+                    "offer": image[1],
+                    "sku": image[2],
+                    "version": "latest",
+                },
+                "osDisk": { "createOption": "FromImage", "deleteOption": "Delete" },
+            },
+            "osProfile": {
+                "computerName": name,
+                "adminUsername": AZURE_ADMIN_USER,
+                "customData": base64::engine::general_purpose::STANDARD.encode(user_data),
+                "linuxConfiguration": {
+                    "disablePasswordAuthentication": true,
+                    "ssh": {
+                        "publicKeys": [{
+                            "path": format!("/home/{}/.ssh/authorized_keys", AZURE_ADMIN_USER),
+                            "keyData": ssh_client_keypair.public,
+                        }],
+                    },
+                },
+            },
+            "networkProfile": {
+                "networkInterfaces": [{ "id": nic_id }],
+            },
+        },
+    })
+}
+
+/// Deletes the resource group, along with every resource within it.
+async fn delete_resource_group(credentials: &AzureCredentials, resource_group: &str) -> Result<()> {
+    let token = credentials.access_token().await?;
+    let client = reqwest::Client::new();
+    client
+        .delete(resource_group_url(
+            &credentials.subscription_id,
+            resource_group,
+        ))
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()
+        .context("Failed to destroy resource group")?;
+
+    tracing::debug!("Resource group deletion requested");
+    Ok(())
+}
+
+/// Creates or updates a resource via PUT, then waits until the API reports
+/// that provisioning has succeeded. Returns the final resource body.
+/// Gives up after [PROVISIONING_TIMEOUT], or once `cancel` fires.
+async fn put_resource(
+    token: &str,
+    request_url: &str,
+    body: &serde_json::Value,
+    cancel: &CancellationToken,
+) -> Result<serde_json::Value> {
+    let client = reqwest::Client::new();
+    client
+        .put(request_url)
+        .json(body)
+        .bearer_auth(token)
+        .send()
+        .await
+        .context("Network error, check connection")?
+        .error_for_status()?;
+    let deadline = tokio::time::Instant::now() + PROVISIONING_TIMEOUT;
+    let mut attempt = 0;
+    loop {
+        let resource: serde_json::Value = client
+            .get(request_url)
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match resource["properties"]["provisioningState"].as_str() {
+            Some("Succeeded") => return Ok(resource),
+            Some("Failed") | Some("Canceled") => {
+                return Err(anyhow!("Provisioning failed for {}", request_url));
+            }
+            _ => tracing::trace!("Waiting on provisioning for {}", request_url),
+        }
+        if tokio::time::Instant::now() >= deadline {
+            let msg = format!("Provisioning didn't finish for {}", request_url);
+            return Err(error::timeout(msg));
+        }
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                return Err(error::cancelled("Cancelled while provisioning Azure resources"));
+            }
+            _ = tokio::time::sleep(poll_interval(attempt)) => {}
+        }
+        attempt += 1;
+    }
+}
+
+/// Generates inbound security rules allowing SSH, WireGuard, and the
+/// exposed services. Azure's Standard public IPs deny all inbound
+/// traffic unless a network security group permits it.
+fn security_rules(services: &[ServicePort], wg_port: i32) -> Vec<serde_json::Value> {
+    let mut allowed: Vec<(String, i32, &str)> = vec![
+        ("ssh".to_string(), 22, "Tcp"),
+        ("wireguard".to_string(), wg_port, "Udp"),
+    ];
+    for s in services {
+        allowed.push((format!("service-{}", s.port), s.port, rule_protocol(s)));
+    }
+    allowed
+        .iter()
+        .enumerate()
+        .map(|(i, (name, port, protocol))| {
+            json!({
+                "name": format!("allow-{}-{}", name, protocol.to_lowercase()),
+                "properties": security_rule(*port, protocol, 100 + i as i64),
+            })
+        })
+        .collect()
+}
+
+/// Maps a service's protocol to the spelling used in security rules.
+fn rule_protocol(service: &ServicePort) -> &'static str {
+    match service.protocol {
+        Protocol::Udp => "Udp",
+        Protocol::Tcp => "Tcp",
+    }
+}
+
+/// Builds the properties of an inbound rule allowing a port from anywhere.
+fn security_rule(port: i32, protocol: &str, priority: i64) -> serde_json::Value {
+    json!({
+        "priority": priority,
+        "direction": "Inbound",
+        "access": "Allow",
+        "protocol": protocol,
+        "sourceAddressPrefix": "*",
+        "sourcePortRange": "*",
+        "destinationAddressPrefix": "*",
+        "destinationPortRange": port.to_string(),
+    })
+}
+
+/// Creates the VM `name`, and its networking, in the resource group of the
+/// same name, which must already exist. Returns the VM's public IP.
+#[allow(clippy::too_many_arguments)]
+async fn provision(
+    token: &str,
+    subscription_id: &str,
+    name: &str,
+    user_data: &str,
+    public_ports: &[ServicePort],
+    wg_port: i32,
+    ssh_client_keypair: &SshKeypair,
+    cancel: &CancellationToken,
+) -> Result<IpAddr> {
+    tracing::debug!("Creating public IP");
+    let ip_resource = put_resource(
+        token,
+        &network_url(subscription_id, name, "publicIPAddresses", "ip"),
+        &public_ip_body(),
+        cancel,
+    )
+    .await
+    .context("Failed to create public IP")?;
+
+    tracing::debug!("Creating network security group");
+    let nsg_resource = put_resource(
+        token,
+        &network_url(subscription_id, name, "networkSecurityGroups", "nsg"),
+        &nsg_body(public_ports, wg_port),
+        cancel,
+    )
+    .await
+    .context("Failed to create network security group")?;
+
+    tracing::debug!("Creating virtual network");
+    let vnet_resource = put_resource(
+        token,
+        &network_url(subscription_id, name, "virtualNetworks", "vnet"),
+        &vnet_body(),
+        cancel,
+    )
+    .await
+    .context("Failed to create virtual network")?;
+
+    tracing::debug!("Creating network interface");
+    let nic_resource = put_resource(
+        token,
+        &network_url(subscription_id, name, "networkInterfaces", "nic"),
+        &nic_body(
+            &nsg_resource["id"],
+            &vnet_resource["properties"]["subnets"][0]["id"],
+            &ip_resource["id"],
+        ),
+        cancel,
+    )
+    .await
+    .context("Failed to create network interface")?;
+
+    tracing::debug!("Creating virtual machine");
+    put_resource(
+        token,
+        &vm_url(subscription_id, name),
+        &vm_body(name, user_data, ssh_client_keypair, &nic_resource["id"]),
+        cancel,
+    )
+    .await
+    .context("Failed to create virtual machine")?;
+
+    // The public IP is static, so it was allocated on creation.
+    Ok(ip_resource["properties"]["ipAddress"]
+        .as_str()
+        .ok_or_else(|| anyhow!("No IP address allocated for public IP resource"))?
+        .parse()?)
+}
+
+impl AzureVm {
+    /// Make a series of API requests to create a new Azure VM,
+    /// along with its resource group and networking. Blocks until
+    /// the VM has been provisioned, which usually takes a few minutes.
+    pub async fn new(
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<AzureVm> {
+        AzureVm::create(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+            &CancellationToken::new(),
+        )
+        .await
+    }
+
+    /// Creates the VM, as [AzureVm::new] does, giving up once `cancel`
+    /// fires. If anything fails once the resource group exists, it's
+    /// deleted, along with whatever was created in it, e.g. the public IP.
+    async fn create(
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        cancel: &CancellationToken,
+    ) -> Result<AzureVm> {
+        tracing::debug!("Creating new Azure VM");
+        let credentials = AzureCredentials::from_env()?;
+        let token = credentials.access_token().await?;
+        let user_data = generate_user_data(
+            ssh_client_keypair,
+            ssh_server_keypair,
+            &wg_mgr,
+            &services,
+            options,
+        )
+        .await?;
+        let resource_group = name.to_string();
+
+        tracing::debug!("Creating resource group '{}'", resource_group);
+        let provisioned = async {
+            put_resource(
+                &token,
+                &resource_group_url(&credentials.subscription_id, &resource_group),
+                &json!({ "location": AZURE_LOCATION }),
+                cancel,
+            )
+            .await
+            .context("Failed to create resource group")?;
+            provision(
+                &token,
+                &credentials.subscription_id,
+                name,
+                &user_data,
+                &options.public_ports(&services),
+                wg_mgr.wg_remote_device.interface.listenport,
+                ssh_client_keypair,
+                cancel,
+            )
+            .await
+        };
+        let ip = match provisioned.await {
+            Ok(ip) => ip,
+            Err(e) => {
+                if let Err(e) = delete_resource_group(&credentials, &resource_group).await {
+                    tracing::warn!(
+                        "Failed to delete resource group '{}': {:#}",
+                        resource_group,
+                        e
+                    );
+                }
+                return Err(e);
+            }
+        };
+        tracing::debug!("Server created, public IP is {}", ip);
+        Ok(AzureVm {
+            resource_group,
+            name: name.to_string(),
+            ip,
+            credentials,
+        })
+    }
+
+    /// Builds the URL for the VM's network security group, or for one of
+    /// its security rules if `rule` is non-empty.
+    fn nsg_url(&self, rule: &str) -> String {
+        let mut resource = format!("Microsoft.Network/networkSecurityGroups/{}-nsg", self.name);
+        if !rule.is_empty() {
+            resource = format!("{}/securityRules/{}", resource, rule);
+        }
+        format!(
+            "{}?api-version={}",
+            resource_url(
+                &self.credentials.subscription_id,
+                &self.resource_group,
+                &resource,
+            ),
+            AZURE_NETWORK_API_VERSION
+        )
+    }
+}
+
+#[async_trait]
+impl InnisfreeServer for AzureVm {
+    /// Returns the static public IPv4 address allocated during creation.
+    fn ipv4_address(&self) -> Result<IpAddr> {
+        Ok(self.ip)
+    }
+
+    /// Returns the name of the resource group, which holds all resources for the VM.
+    fn id(&self) -> String {
+        self.resource_group.clone()
+    }
+
+    async fn assign_floating_ip(&self, _floating_ip: IpAddr) -> Result<()> {
+        Err(anyhow!("Floating IPs are not supported for Azure"))
+    }
+
+    /// Calls the API to delete the resource group, which destroys the VM
+    /// and all of its supporting resources.
+    async fn destroy(&self) -> Result<()> {
+        delete_resource_group(&self.credentials, &self.resource_group).await
+    }
+
+    /// Adds a rule for the service to the network security group, with
+    /// the next free priority, since priorities must be unique.
+    async fn open_port(&self, service: &ServicePort) -> Result<()> {
+        let token = self.credentials.access_token().await?;
+        let nsg: serde_json::Value = reqwest::Client::new()
+            .get(self.nsg_url(""))
+            .bearer_auth(&token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let priority = nsg["properties"]["securityRules"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|r| r["properties"]["priority"].as_i64())
+            .max()
+            .unwrap_or(99)
+            + 1;
+        put_resource(
+            &token,
+            &self.nsg_url(&service_rule_name(service)),
+            &json!({
+                "properties": security_rule(service.port, rule_protocol(service), priority),
+            }),
+            &CancellationToken::new(),
+        )
+        .await
+        .context("Failed to add security rule")?;
+        Ok(())
+    }
+
+    /// Deletes the service's rule from the network security group.
+    async fn close_port(&self, service: &ServicePort) -> Result<()> {
+        let token = self.credentials.access_token().await?;
+        reqwest::Client::new()
+            .delete(self.nsg_url(&service_rule_name(service)))
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Failed to remove security rule")?;
+        Ok(())
+    }
+}
+
+/// Name of the security rule for a service, matching [security_rules].
+fn service_rule_name(service: &ServicePort) -> String {
+    format!(
+        "allow-service-{}-{}",
+        service.port,
+        rule_protocol(service).to_lowercase()
+    )
+}
+
+/// Factory for creating [AzureVm] servers, registered as `azure`.
+pub struct AzureProvider;
+
+#[async_trait]
+impl ServerProvider for AzureProvider {
+    fn name(&self) -> &'static str {
+        "azure"
+    }
+
+    async fn create(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let server = AzureVm::new(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+        )
+        .await?;
+        Ok(Box::new(server))
+    }
+
+    /// Stops waiting on provisioning as soon as `cancel` fires, deleting
+    /// the resource group.
+    async fn create_cancellable(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        cancel: &CancellationToken,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let server = AzureVm::create(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+            cancel,
+        )
+        .await?;
+        Ok(Box::new(server))
+    }
+
+    async fn plan(
+        &self,
+        name: &str,
+        user_data: &str,
+        services: &[ServicePort],
+        wg_mgr: &WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<Vec<ApiRequest>> {
+        let subscription_id = env_or_placeholder("AZURE_SUBSCRIPTION_ID");
+        let nsg = nsg_body(
+            &options.public_ports(services),
+            wg_mgr.wg_remote_device.interface.listenport,
+        );
+        let nic = nic_body(
+            &json!("<network-security-group-id>"),
+            &json!("<subnet-id>"),
+            &json!("<public-ip-id>"),
+        );
+        let vm = vm_body(
+            name,
+            user_data,
+            ssh_client_keypair,
+            &json!("<network-interface-id>"),
+        );
+        Ok(vec![
+            ApiRequest::new(
+                "PUT",
+                &resource_group_url(&subscription_id, name),
+                &json!({ "location": AZURE_LOCATION }),
+            )?,
+            ApiRequest::new(
+                "PUT",
+                &network_url(&subscription_id, name, "publicIPAddresses", "ip"),
+                &public_ip_body(),
+            )?,
+            ApiRequest::new(
+                "PUT",
+                &network_url(&subscription_id, name, "networkSecurityGroups", "nsg"),
+                &nsg,
+            )?,
+            ApiRequest::new(
+                "PUT",
+                &network_url(&subscription_id, name, "virtualNetworks", "vnet"),
+                &vnet_body(),
+            )?,
+            ApiRequest::new(
+                "PUT",
+                &network_url(&subscription_id, name, "networkInterfaces", "nic"),
+                &nic,
+            )?,
+            ApiRequest::new("PUT", &vm_url(&subscription_id, name), &vm)?,
+        ])
+    }
+
+    async fn destroy(&self, _name: &str, id: &str) -> Result<()> {
+        delete_resource_group(&AzureCredentials::from_env()?, id).await
+    }
+
+    async fn server_exists(&self, _name: &str, id: &str) -> Result<bool> {
+        let credentials = AzureCredentials::from_env()?;
+        let response = reqwest::Client::new()
+            .get(resource_group_url(&credentials.subscription_id, id))
+            .bearer_auth(credentials.access_token().await?)
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    /// The VM's static public IP is read back from its resource.
+    async fn adopt(&self, name: &str, id: &str) -> Result<Box<dyn InnisfreeServer>> {
+        let credentials = AzureCredentials::from_env()?;
+        let ip_url = resource_url(
+            &credentials.subscription_id,
+            id,
+            &format!("Microsoft.Network/publicIPAddresses/{}-ip", name),
+        );
+        let ip_resource: serde_json::Value = reqwest::Client::new()
+            .get(format!(
+                "{}?api-version={}",
+                ip_url, AZURE_NETWORK_API_VERSION
+            ))
+            .bearer_auth(credentials.access_token().await?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let ip = ip_resource["properties"]["ipAddress"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No IP address allocated for public IP resource"))?
+            .parse()?;
+        Ok(Box::new(AzureVm {
+            resource_group: id.to_string(),
+            name: name.to_string(),
+            ip,
+            credentials,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn security_rules_include_services() -> Result<()> {
+        let services = vec![
+            ServicePort::try_from("443/TCP")?,
+            ServicePort::try_from("53/UDP")?,
+        ];
+        let rules = security_rules(&services, 51820);
+        assert_eq!(rules.len(), 4);
+        assert_eq!(rules[0]["properties"]["destinationPortRange"], "22");
+        assert_eq!(rules[1]["properties"]["protocol"], "Udp");
+        assert_eq!(rules[2]["properties"]["destinationPortRange"], "443");
+        assert_eq!(rules[3]["properties"]["protocol"], "Udp");
+        // Priorities must be unique within the security group
+        assert_eq!(rules[3]["properties"]["priority"], 103);
+        // Rules added later are named the same way, so they can be removed
+        assert_eq!(rules[3]["name"], service_rule_name(&services[1]));
+        Ok(())
+    }
+}
@@ -1,16 +1,351 @@
 //! Stores business logic around creating the "cloud-init.cfg" YAML file,
 //! used to customize a server on first boot.
+use std::collections::BTreeMap;
 use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
 extern crate serde;
 use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
 
-use crate::config::ServicePort;
+use crate::caddy;
+use crate::config::{HostRoute, Protocol, ServicePort};
+use crate::forwarder;
+use crate::proxy::{ByteCounts, ClientLimits};
+use crate::remote_health;
 // TODO the ssh key impl should be provider agnostic
-use crate::server::digitalocean::ssh_key::get_all_keys;
-use crate::ssh::SshKeypair;
-use crate::wg::WireguardManager;
+#[cfg(feature = "digitalocean")]
+use crate::server::digitalocean::ssh_key::{get_all_keys, KeyFilter};
+use crate::ssh::{SshKeyType, SshKeypair};
+use crate::udp2raw;
+use crate::wg::{WireguardManager, WireguardTransport, WIREGUARD_LISTEN_PORT};
+
+/// Packages needed on every server.
+const PACKAGES: [&str; 6] = [
+    "nginx",
+    "nftables",
+    "sudo",
+    "unattended-upgrades",
+    "wireguard",
+    "wireguard-tools",
+];
+/// Packages needed to obtain and renew TLS certificates via Let's Encrypt,
+/// when terminating HTTPS on the server.
+const HTTPS_PACKAGES: [&str; 2] = ["certbot", "python3-certbot-nginx"];
+/// Largest user data accepted, since most providers reject more than 64 KiB.
+const MAX_USER_DATA_SIZE: usize = 64 * 1024;
+/// Path on the server to the nginx config forwarding the TCP and UDP streams.
+pub const STREAM_CONFIG_PATH: &str = "/etc/nginx/conf.d/stream/innisfree.conf";
+/// Path on the server to nginx's log of the bytes passed per stream session,
+/// as configured in `cloudinit.cfg`.
+pub const STREAM_LOG_PATH: &str = "/var/log/nginx/stream-bytes.log";
+/// Path on the server to the nftables ruleset used in DNAT mode.
+pub const DNAT_CONFIG_PATH: &str = "/etc/innisfree/dnat.nft";
+/// Path on the server to the nftables ruleset filtering inbound traffic.
+pub const FIREWALL_CONFIG_PATH: &str = "/etc/innisfree/firewall.nft";
+/// Port on which the server's sshd listens.
+const SSH_PORT: u16 = 22;
+/// Path on the server to apt's config scheduling unattended upgrades.
+const AUTO_UPGRADES_PATH: &str = "/etc/apt/apt.conf.d/20auto-upgrades";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// Proxy on the server, forwarding public traffic over the tunnel.
+pub enum RemoteProxy {
+    /// Nginx, from the distro's packages.
+    #[default]
+    Nginx,
+    /// Caddy, with the layer4 app for plain streams, see [crate::caddy].
+    Caddy,
+    /// innisfree itself, running `innisfree proxy`, see [crate::forwarder].
+    Innisfree,
+}
+
+impl FromStr for RemoteProxy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "nginx" => Ok(RemoteProxy::Nginx),
+            "caddy" => Ok(RemoteProxy::Caddy),
+            "innisfree" => Ok(RemoteProxy::Innisfree),
+            _ => Err(anyhow!(
+                "Invalid remote proxy '{}', expected 'nginx', 'caddy', or 'innisfree'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+/// Optional customizations to the cloudinit config, beyond the
+/// keys, tunnel, and services that every server needs.
+pub struct CloudConfigOptions {
+    /// Domain for which to terminate HTTPS on the server, with a certificate
+    /// from Let's Encrypt. Traffic to 443/TCP is forwarded over the tunnel
+    /// as plaintext HTTP, rather than passed through as a TCP stream.
+    pub https_domain: Option<String>,
+    /// Routes for TLS connections on 443/TCP, by SNI hostname, to other
+    /// local ports. Connections for unlisted hostnames go to the 443/TCP
+    /// service's local port. The TLS traffic itself is passed through.
+    pub sni_routes: Vec<HostRoute>,
+    /// Routes for HTTP requests on 80/TCP, by Host header, to local ports.
+    /// The server proxies at layer 7, logging each host separately.
+    /// Requests for unlisted hosts go to the 80/TCP service, if any.
+    pub vhost_routes: Vec<HostRoute>,
+    /// Forward services via nftables DNAT, rather than nginx, so clients'
+    /// original addresses reach the local services. Replies are routed back
+    /// over the tunnel, see [crate::wg::WireguardDevice::dnat].
+    pub dnat: bool,
+    /// MTU for both ends of the Wireguard tunnel, see [crate::wg::WireguardDevice::mtu].
+    pub wg_mtu: Option<u16>,
+    /// Parent range for the tunnel's subnet, if not [crate::net::INNISFREE_SUBNET],
+    /// e.g. to avoid clashing with a VPN. See [crate::net::choose_subnet].
+    pub wg_subnet: Option<IpNet>,
+    /// UDP port on which the server listens for Wireguard, if not the default.
+    /// Cloud firewalls are opened for it, too.
+    pub wg_port: Option<u16>,
+    /// How Wireguard's packets reach the server, e.g. over TCP, if UDP is
+    /// throttled. For TCP, the Wireguard port is opened for TCP, too.
+    #[serde(default)]
+    pub wg_transport: WireguardTransport,
+    /// Algorithm of the generated SSH keys, both the server's host key and
+    /// the client's login key.
+    #[serde(default)]
+    pub ssh_key_type: SshKeyType,
+    /// Additional cloud-config YAML, e.g. to install a monitoring agent,
+    /// deep-merged into the generated config, see [merge_yaml].
+    pub extra_user_data: Option<String>,
+    /// Additional apt packages to install on the server, optionally pinned
+    /// to a version, as with `apt-get install <name>=<version>`.
+    #[serde(default)]
+    pub extra_packages: Vec<String>,
+    /// Shell commands to run once on the server, after it's configured.
+    #[serde(default)]
+    pub extra_runcmd: Vec<String>,
+    /// Proxy on the server, forwarding public traffic over the tunnel.
+    #[serde(default)]
+    pub remote_proxy: RemoteProxy,
+    /// Static build of innisfree to upload to the server, when it's the
+    /// remote proxy, rather than downloading the release.
+    pub forwarder_binary: Option<PathBuf>,
+    /// Close SSH to the internet once the tunnel is up, so the server is only
+    /// managed over the tunnel, see [firewall_config].
+    #[serde(default)]
+    pub private_ssh: bool,
+    /// Skip installing security updates on the server automatically, which
+    /// unattended-upgrades otherwise does daily, without rebooting.
+    #[serde(default)]
+    pub no_auto_updates: bool,
+    /// Hostname for the server, if not the tunnel's name, e.g. a fully
+    /// qualified name to which its IP resolves via reverse DNS, for SMTP.
+    pub hostname: Option<String>,
+    /// TCP port on which the server answers health checks, reporting whether
+    /// the tunnel and the services behind it are up, see [remote_health].
+    pub health_port: Option<u16>,
+    /// Park the server on teardown, rather than destroying it, and claim a
+    /// parked one on startup, rather than creating one, see [crate::pool].
+    #[serde(default)]
+    pub pool: bool,
+    /// Name of a running tunnel whose server to share, rather than creating
+    /// one, see [crate::share].
+    pub share: Option<String>,
+    /// Limits on each client of the services, applied by nginx, see
+    /// [ClientLimits].
+    #[serde(default)]
+    pub client_limits: ClientLimits,
+}
+
+impl CloudConfigOptions {
+    /// Ensures the services are compatible with the options. HTTPS mode
+    /// requires a 443/TCP service to terminate, and reserves 80/TCP for
+    /// certificate validation and redirects.
+    pub fn validate(&self, services: &[ServicePort]) -> Result<()> {
+        if self.https_domain.is_some() {
+            match https_service(services) {
+                None => {
+                    return Err(anyhow!(
+                        "HTTPS mode requires a 443/TCP service, e.g. --ports 443:8000/TCP"
+                    ));
+                }
+                Some(s) if s.proxy_protocol.is_some() => {
+                    return Err(anyhow!(
+                        "HTTPS mode forwards client addresses via X-Forwarded-For, not the PROXY protocol"
+                    ));
+                }
+                Some(_) => {}
+            }
+            if http_service(services).is_some() {
+                return Err(anyhow!(
+                    "HTTPS mode reserves 80/TCP for certificate validation, remove it from the ports"
+                ));
+            }
+        }
+        if !self.sni_routes.is_empty() {
+            if self.https_domain.is_some() {
+                return Err(anyhow!(
+                    "SNI routing passes TLS through, so it can't be combined with HTTPS mode"
+                ));
+            }
+            if https_service(services).is_none() {
+                return Err(anyhow!(
+                    "SNI routing requires a 443/TCP service, as the default route, e.g. --ports 443:8443/TCP"
+                ));
+            }
+        }
+        if self.dnat
+            && (self.https_domain.is_some()
+                || !self.sni_routes.is_empty()
+                || !self.vhost_routes.is_empty()
+                || services.iter().any(|s| s.proxy_protocol.is_some()))
+        {
+            return Err(anyhow!(
+                "DNAT mode bypasses nginx, so it can't be combined with HTTPS, SNI, vhosts, or the PROXY protocol"
+            ));
+        }
+        if self.dnat && self.remote_proxy != RemoteProxy::Nginx {
+            return Err(anyhow!(
+                "DNAT mode bypasses the server's proxy, so it can't be combined with --remote-proxy"
+            ));
+        }
+        if self.remote_proxy == RemoteProxy::Innisfree {
+            if self.https_domain.is_some()
+                || !self.sni_routes.is_empty()
+                || !self.vhost_routes.is_empty()
+            {
+                return Err(anyhow!(
+                    "innisfree on the server only forwards streams, so it can't be combined with HTTPS, SNI, or vhosts"
+                ));
+            }
+            if let Some(s) = services
+                .iter()
+                .find(|s| s.protocol != Protocol::Tcp || s.proxy_protocol.is_some())
+            {
+                return Err(anyhow!(
+                    "innisfree on the server only forwards TCP, without the PROXY protocol, so it can't forward {}/{}",
+                    s.port,
+                    s.protocol
+                ));
+            }
+        } else if self.forwarder_binary.is_some() {
+            return Err(anyhow!(
+                "Uploading innisfree to the server requires --remote-proxy innisfree"
+            ));
+        }
+        if !self.vhost_routes.is_empty() && self.https_domain.is_some() {
+            return Err(anyhow!(
+                "HTTPS mode already serves 80/TCP, so it can't be combined with HTTP vhosts"
+            ));
+        }
+        if let Some(extra) = &self.extra_user_data {
+            parse_extra_user_data(extra)?;
+        }
+        if let Some(p) = self.extra_packages.iter().find(|p| !valid_package(p)) {
+            return Err(anyhow!("Invalid package name '{}'", p));
+        }
+        if self.extra_runcmd.iter().any(|c| c.trim().is_empty()) {
+            return Err(anyhow!("Commands to run on the server can't be empty"));
+        }
+        if self.client_limits != ClientLimits::default()
+            && (self.remote_proxy != RemoteProxy::Nginx || self.dnat)
+        {
+            return Err(anyhow!(
+                "Client limits are applied by nginx on the server, so they can't be combined with DNAT or --remote-proxy"
+            ));
+        }
+        if self.share.is_some() {
+            // The host's server was configured on its first boot, and the
+            // tunnel takes the host's Wireguard settings.
+            let guest = CloudConfigOptions {
+                share: self.share.clone(),
+                wg_mtu: self.wg_mtu,
+                wg_subnet: self.wg_subnet,
+                wg_port: self.wg_port,
+                ssh_key_type: self.ssh_key_type,
+                ..Default::default()
+            };
+            if *self != guest {
+                return Err(anyhow!(
+                    "Sharing a server only adds plain streams, so it can't be combined with options configuring the server"
+                ));
+            }
+        }
+        if let Some(h) = self.hostname.as_deref().filter(|h| !valid_hostname(h)) {
+            return Err(anyhow!("Invalid hostname '{}'", h));
+        }
+        if let Some(port) = self.health_port {
+            let others = CloudConfigOptions {
+                health_port: None,
+                ..self.clone()
+            };
+            if port == SSH_PORT
+                || others
+                    .public_ports(services)
+                    .iter()
+                    .any(|s| s.port == i32::from(port) && s.protocol == Protocol::Tcp)
+            {
+                return Err(anyhow!(
+                    "Health port {}/TCP is already in use on the server",
+                    port
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the services reachable on the local end of the tunnel:
+    /// the services, plus the local ports of any SNI or vhost routes.
+    pub fn local_services(&self, services: &[ServicePort]) -> Vec<ServicePort> {
+        let mut local = services.to_vec();
+        local.extend(self.sni_routes.iter().map(|r| r.service()));
+        local.extend(self.vhost_routes.iter().map(|r| r.service()));
+        local
+    }
+
+    /// Returns the ports that must be reachable from the internet:
+    /// the services, plus 80/TCP in HTTPS mode, for Let's Encrypt validation.
+    pub fn public_ports(&self, services: &[ServicePort]) -> Vec<ServicePort> {
+        let mut ports = services.to_vec();
+        if (self.https_domain.is_some() || !self.vhost_routes.is_empty())
+            && http_service(services).is_none()
+        {
+            ports.push(ServicePort::default());
+        }
+        if self.wg_transport == WireguardTransport::Tcp {
+            let port = self.wg_port.map(i32::from).unwrap_or(WIREGUARD_LISTEN_PORT);
+            ports.push(ServicePort {
+                port,
+                local_port: port,
+                protocol: Protocol::Tcp,
+                ..Default::default()
+            });
+        }
+        if let Some(port) = self.health_port.map(i32::from) {
+            ports.push(ServicePort {
+                port,
+                local_port: port,
+                protocol: Protocol::Tcp,
+                ..Default::default()
+            });
+        }
+        ports
+    }
+}
+
+/// Finds the plain HTTP service, i.e. 80/TCP.
+fn http_service(services: &[ServicePort]) -> Option<&ServicePort> {
+    services
+        .iter()
+        .find(|s| s.port == 80 && s.protocol == Protocol::Tcp)
+}
+
+/// Finds the service terminated by nginx in HTTPS mode, i.e. 443/TCP.
+fn https_service(services: &[ServicePort]) -> Option<&ServicePort> {
+    services.iter().find(|s| s.is_https())
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 /// Representation of a cloudinit YAML file.
@@ -23,6 +358,14 @@ pub struct CloudConfig {
     ssh_keys: std::collections::HashMap<String, String>,
     write_files: Vec<CloudConfigFile>,
     packages: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    runcmd: Vec<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fqdn: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    manage_etc_hosts: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -52,17 +395,39 @@ pub async fn generate_user_data(
     ssh_server_keypair: &SshKeypair,
     wg_mgr: &WireguardManager,
     services: &[ServicePort],
+    options: &CloudConfigOptions,
 ) -> Result<String> {
     let user_data = include_str!("../../files/cloudinit.cfg");
     let user_data = user_data.to_string();
 
     let mut cloud_config = serde_yaml::from_str::<CloudConfig>(&user_data)?;
+    cloud_config
+        .packages
+        .extend(PACKAGES.iter().map(|p| p.to_string()));
+    if options.no_auto_updates {
+        // Prebuilt images have the package already, so disable it, too.
+        cloud_config.packages.retain(|p| p != "unattended-upgrades");
+        for f in cloud_config.write_files.iter_mut() {
+            if f.path == AUTO_UPGRADES_PATH {
+                f.content = f.content.replace("\"1\"", "\"0\"");
+            }
+        }
+    }
+    if let Some(hostname) = &options.hostname {
+        // The short name is the first label, and the FQDN resolves locally,
+        // e.g. for mail servers announcing themselves by name.
+        let short = hostname.split('.').next().unwrap_or(hostname);
+        cloud_config.hostname = Some(short.to_string());
+        cloud_config.fqdn = Some(hostname.to_string());
+        cloud_config.manage_etc_hosts = true;
+    }
+    let key_name = ssh_server_keypair.key_type.name();
     cloud_config.ssh_keys.insert(
-        "ed25519_public".to_string(),
+        format!("{}_public", key_name),
         ssh_server_keypair.public.to_string(),
     );
     cloud_config.ssh_keys.insert(
-        "ed25519_private".to_string(),
+        format!("{}_private", key_name),
         ssh_server_keypair.private.to_string(),
     );
 
@@ -75,21 +440,68 @@ pub async fn generate_user_data(
     };
     cloud_config.write_files.push(wg);
 
-    let nginx = CloudConfigFile {
-        content: nginx_streams(services, wg_mgr.wg_local_device.interface.address)?,
+    if options.wg_transport == WireguardTransport::Tcp {
+        let port = u16::try_from(wg_mgr.wg_remote_device.interface.listenport)?;
+        let unit = CloudConfigFile {
+            content: udp2raw::server_unit(
+                port,
+                wg_mgr.wg_remote_device.interface.keypair.public(),
+            )?,
+            owner: String::from("root:root"),
+            permissions: String::from("0644"),
+            path: String::from(udp2raw::SERVER_UNIT_PATH),
+        };
+        cloud_config.write_files.push(unit);
+        cloud_config.runcmd.extend(udp2raw::server_runcmd());
+    }
+
+    let dest_ip = wg_mgr.wg_local_device.interface.address;
+    match options.remote_proxy {
+        RemoteProxy::Nginx => add_nginx(&mut cloud_config, services, dest_ip, options)?,
+        RemoteProxy::Caddy => add_caddy(&mut cloud_config, services, dest_ip, options)?,
+        RemoteProxy::Innisfree => add_forwarder(&mut cloud_config, services, dest_ip, options)?,
+    }
+    if let Some(port) = options.health_port {
+        for (path, permissions, content) in remote_health::server_files(port)? {
+            cloud_config.write_files.push(CloudConfigFile {
+                content,
+                owner: String::from("root:root"),
+                permissions: String::from(permissions),
+                path: String::from(path),
+            });
+        }
+        cloud_config.write_files.push(CloudConfigFile {
+            content: remote_health::targets(services, dest_ip),
+            owner: String::from("root:root"),
+            permissions: String::from("0644"),
+            path: String::from(remote_health::TARGETS_PATH),
+        });
+        cloud_config.runcmd.extend(remote_health::server_runcmd());
+    }
+
+    let wg_port = u16::try_from(wg_mgr.wg_remote_device.interface.listenport)?;
+    // SSH stays open until the tunnel is up, since it's used to bring it up.
+    let (path, content) = firewall_config(services, wg_port, options, true)?;
+    let firewall = CloudConfigFile {
+        content,
         owner: String::from("root:root"),
         permissions: String::from("0644"),
-        path: String::from("/etc/nginx/conf.d/stream/innisfree.conf"),
+        path: String::from(path),
     };
-    cloud_config.write_files.push(nginx);
+    cloud_config.write_files.push(firewall);
+    cloud_config
+        .runcmd
+        .push(vec!["nft".to_string(), "-f".to_string(), path.to_string()]);
 
     // Build list of pubkeys to add to cloudinit. There may be no keys
     // returned from the API, e.g. during testing. That's fine,
     // we'll just use the one we generated.
+    #[allow(unused_mut)]
     let mut cloud_config_ssh_keys = vec![ssh_client_keypair.public.to_string()];
+    #[cfg(feature = "digitalocean")]
     match get_all_keys().await {
         Ok(r) => {
-            for k in r {
+            for k in KeyFilter::from_env()?.apply(r) {
                 cloud_config_ssh_keys.extend(vec![k.public_key.to_owned()]);
             }
         }
@@ -100,7 +512,442 @@ pub async fn generate_user_data(
 
     cloud_config.users[0].ssh_authorized_keys = cloud_config_ssh_keys;
 
-    let cc_rendered: String = serde_yaml::to_string(&cloud_config)?;
+    cloud_config
+        .packages
+        .extend(options.extra_packages.iter().cloned());
+    cloud_config.runcmd.extend(
+        options
+            .extra_runcmd
+            .iter()
+            .map(|c| vec!["sh".to_string(), "-c".to_string(), c.to_string()]),
+    );
+
+    let user_data = match &options.extra_user_data {
+        Some(extra) => {
+            let mut merged = serde_yaml::to_value(&cloud_config)?;
+            merge_yaml(&mut merged, Value::Mapping(parse_extra_user_data(extra)?));
+            render(&merged)?
+        }
+        None => render(&cloud_config)?,
+    };
+    validate_user_data(&user_data)?;
+    Ok(user_data)
+}
+
+/// Adds nginx's config to `cloud_config`, forwarding the services to
+/// `dest_ip`, or nftables rules in DNAT mode.
+fn add_nginx(
+    cloud_config: &mut CloudConfig,
+    services: &[ServicePort],
+    dest_ip: IpAddr,
+    options: &CloudConfigOptions,
+) -> Result<()> {
+    let mut streams = forwarded_streams(services, options);
+    if let Some(domain) = &options.https_domain {
+        let service = https_service(services)
+            .ok_or_else(|| anyhow!("HTTPS mode requires a 443/TCP service"))?;
+        let https = CloudConfigFile {
+            content: nginx_https(domain, service, dest_ip)?,
+            owner: String::from("root:root"),
+            permissions: String::from("0644"),
+            path: String::from("/etc/nginx/conf.d/innisfree-https.conf"),
+        };
+        cloud_config.write_files.push(https);
+        cloud_config
+            .packages
+            .extend(HTTPS_PACKAGES.iter().map(|p| p.to_string()));
+    }
+    if !options.vhost_routes.is_empty() {
+        let default = http_service(services);
+        let vhost = CloudConfigFile {
+            content: nginx_vhosts(&options.vhost_routes, default, dest_ip)?,
+            owner: String::from("root:root"),
+            permissions: String::from("0644"),
+            path: String::from("/etc/nginx/conf.d/innisfree-vhost.conf"),
+        };
+        cloud_config.write_files.push(vhost);
+    }
+
+    if options.dnat {
+        if !dest_ip.is_ipv4() {
+            return Err(anyhow!("DNAT mode requires an IPv4 tunnel"));
+        }
+        let nft = CloudConfigFile {
+            content: nftables_dnat(&streams, dest_ip)?,
+            owner: String::from("root:root"),
+            permissions: String::from("0644"),
+            path: String::from(DNAT_CONFIG_PATH),
+        };
+        cloud_config.write_files.push(nft);
+        let sysctl = CloudConfigFile {
+            content: String::from("net.ipv4.ip_forward = 1\n"),
+            owner: String::from("root:root"),
+            permissions: String::from("0644"),
+            path: String::from("/etc/sysctl.d/99-innisfree.conf"),
+        };
+        cloud_config.write_files.push(sysctl);
+        cloud_config.runcmd.extend(vec![
+            vec!["sysctl".to_string(), "--system".to_string()],
+            vec![
+                "nft".to_string(),
+                "-f".to_string(),
+                DNAT_CONFIG_PATH.to_string(),
+            ],
+        ]);
+        // Nginx is skipped entirely, since the kernel forwards the traffic.
+        streams.clear();
+    }
+
+    let nginx = CloudConfigFile {
+        content: nginx_streams(
+            &streams,
+            dest_ip,
+            &options.sni_routes,
+            options.client_limits,
+        )?,
+        owner: String::from("root:root"),
+        permissions: String::from("0644"),
+        path: String::from(STREAM_CONFIG_PATH),
+    };
+    cloud_config.write_files.push(nginx);
+    Ok(())
+}
+
+/// Adds Caddy's config to `cloud_config`, forwarding the services to
+/// `dest_ip`, replacing nginx and its config from the template.
+fn add_caddy(
+    cloud_config: &mut CloudConfig,
+    services: &[ServicePort],
+    dest_ip: IpAddr,
+    options: &CloudConfigOptions,
+) -> Result<()> {
+    cloud_config.packages.retain(|p| p != "nginx");
+    cloud_config
+        .write_files
+        .retain(|f| !f.path.starts_with("/etc/nginx/"));
+    let caddyfile = CloudConfigFile {
+        content: caddyfile(services, dest_ip, options)?,
+        owner: String::from("root:root"),
+        permissions: String::from("0644"),
+        path: String::from(caddy::CADDYFILE_PATH),
+    };
+    cloud_config.write_files.push(caddyfile);
+    let unit = CloudConfigFile {
+        content: caddy::server_unit()?,
+        owner: String::from("root:root"),
+        permissions: String::from("0644"),
+        path: String::from(caddy::SERVER_UNIT_PATH),
+    };
+    cloud_config.write_files.push(unit);
+    cloud_config.runcmd.extend(caddy::server_runcmd());
+    Ok(())
+}
+
+/// Adds the config for innisfree's own proxy to `cloud_config`, forwarding
+/// the services to `dest_ip`, replacing nginx and its config from the template.
+fn add_forwarder(
+    cloud_config: &mut CloudConfig,
+    services: &[ServicePort],
+    dest_ip: IpAddr,
+    options: &CloudConfigOptions,
+) -> Result<()> {
+    cloud_config.packages.retain(|p| p != "nginx");
+    cloud_config
+        .write_files
+        .retain(|f| !f.path.starts_with("/etc/nginx/"));
+    let env = CloudConfigFile {
+        content: forwarder::server_env(services, dest_ip),
+        owner: String::from("root:root"),
+        permissions: String::from("0644"),
+        path: String::from(forwarder::ENV_PATH),
+    };
+    cloud_config.write_files.push(env);
+    let unit = CloudConfigFile {
+        content: forwarder::server_unit()?,
+        owner: String::from("root:root"),
+        permissions: String::from("0644"),
+        path: String::from(forwarder::SERVER_UNIT_PATH),
+    };
+    cloud_config.write_files.push(unit);
+    cloud_config
+        .runcmd
+        .extend(forwarder::server_runcmd(options.forwarder_binary.is_none()));
+    Ok(())
+}
+
+/// Checks generated user data before it's submitted to a provider: it must
+/// parse as a cloud config, authorize the client's key, and fit in 64 KiB.
+fn validate_user_data(user_data: &str) -> Result<()> {
+    if user_data.len() > MAX_USER_DATA_SIZE {
+        return Err(anyhow!(
+            "Generated cloud-init user data is {} bytes, over the limit of {}",
+            user_data.len(),
+            MAX_USER_DATA_SIZE
+        ));
+    }
+    let cloud_config: Value =
+        serde_yaml::from_str(user_data).context("Generated cloud-init user data is invalid")?;
+    if !cloud_config.is_mapping() {
+        return Err(anyhow!("Generated cloud-init user data is not a mapping"));
+    }
+    let keys = &cloud_config["users"][0]["ssh_authorized_keys"];
+    if keys.as_sequence().is_none_or(|k| k.is_empty()) {
+        return Err(anyhow!(
+            "Generated cloud-init user data doesn't authorize an SSH key"
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `package` is a valid Debian package name, optionally followed
+/// by `=<version>`.
+fn valid_package(package: &str) -> bool {
+    let (name, version) = match package.split_once('=') {
+        Some((name, version)) => (name, Some(version)),
+        None => (package, None),
+    };
+    let name_ok = name.len() >= 2
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.".contains(c));
+    let version_ok = version.is_none_or(|v| {
+        !v.is_empty()
+            && v.chars()
+                .all(|c| c.is_ascii_alphanumeric() || ".+~:-".contains(c))
+    });
+    name_ok && version_ok
+}
+
+/// Whether `hostname` is a valid hostname, optionally fully qualified:
+/// dot-separated labels of letters, digits, and inner hyphens.
+fn valid_hostname(hostname: &str) -> bool {
+    hostname.len() <= 253
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Parses additional cloud-config YAML, which must be a mapping. It may not
+/// replace the server's host keys, since the client pins them.
+fn parse_extra_user_data(extra: &str) -> Result<Mapping> {
+    let extra: Value =
+        serde_yaml::from_str(extra).context("Failed to parse additional cloud-config YAML")?;
+    match extra {
+        Value::Mapping(m) if m.contains_key(&Value::from("ssh_keys")) => Err(anyhow!(
+            "Additional cloud-config can't set 'ssh_keys', since the server's host keys are generated"
+        )),
+        Value::Mapping(m) => Ok(m),
+        Value::Null => Ok(Mapping::new()),
+        _ => Err(anyhow!("Additional cloud-config must be a YAML mapping")),
+    }
+}
+
+/// Deep-merges `extra` into `base`: mappings are merged key by key, lists
+/// are appended to, e.g. `packages` and `runcmd`, and anything else is replaced.
+fn merge_yaml(base: &mut Value, extra: Value) {
+    match (base, extra) {
+        (Value::Mapping(base), Value::Mapping(extra)) => {
+            for (k, v) in extra {
+                match base.get_mut(&k) {
+                    Some(b) => merge_yaml(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(extra)) => base.extend(extra),
+        (base, extra) => *base = extra,
+    }
+}
+
+/// Returns the services forwarded as plain streams, i.e. those not
+/// already handled by the http server in HTTPS or vhost mode.
+fn forwarded_streams(services: &[ServicePort], options: &CloudConfigOptions) -> Vec<ServicePort> {
+    let mut streams = services.to_vec();
+    // The http server listens on 443/TCP or 80/TCP, so the stream server must not.
+    if options.https_domain.is_some() {
+        streams.retain(|s| !s.is_https());
+    }
+    if !options.vhost_routes.is_empty() {
+        if let Some(service) = http_service(services) {
+            streams.retain(|s| s.port != service.port || s.protocol != service.protocol);
+        }
+    }
+    streams
+}
+
+/// Returns the path and contents of the server config that forwards the
+/// services over the tunnel: the nginx stream config, the nftables ruleset
+/// in DNAT mode, or the config of another remote proxy. Used to update the
+/// services on a running server.
+pub fn forwarding_config(
+    services: &[ServicePort],
+    dest_ip: IpAddr,
+    options: &CloudConfigOptions,
+) -> Result<(&'static str, String)> {
+    if options.remote_proxy == RemoteProxy::Caddy {
+        return Ok((
+            caddy::CADDYFILE_PATH,
+            caddyfile(services, dest_ip, options)?,
+        ));
+    }
+    if options.remote_proxy == RemoteProxy::Innisfree {
+        return Ok((
+            forwarder::ENV_PATH,
+            forwarder::server_env(services, dest_ip),
+        ));
+    }
+    let streams = forwarded_streams(services, options);
+    if options.dnat {
+        Ok((DNAT_CONFIG_PATH, nftables_dnat(&streams, dest_ip)?))
+    } else {
+        Ok((
+            STREAM_CONFIG_PATH,
+            nginx_streams(
+                &streams,
+                dest_ip,
+                &options.sni_routes,
+                options.client_limits,
+            )?,
+        ))
+    }
+}
+
+/// Returns the path and contents of the nftables ruleset filtering inbound
+/// traffic on the server: only the public ports, Wireguard on `wg_port`,
+/// SSH, if `public_ssh` is set, and some ICMP are accepted. Traffic over the
+/// tunnel is always accepted, SSH included. Used to update the services on
+/// a running server, along with [forwarding_config].
+pub fn firewall_config(
+    services: &[ServicePort],
+    wg_port: u16,
+    options: &CloudConfigOptions,
+    public_ssh: bool,
+) -> Result<(&'static str, String)> {
+    let nft_config = include_str!("../../files/firewall.nft.j2");
+    let mut context = tera::Context::new();
+    context.insert("ports", &options.public_ports(services));
+    context.insert("wg_port", &wg_port);
+    context.insert("ssh_port", &public_ssh.then_some(SSH_PORT));
+    context.insert("sources", &source_rules(services));
+    let ruleset =
+        tera::Tera::one_off(nft_config, &context, false).context("Template generation failed")?;
+    Ok((FIREWALL_CONFIG_PATH, ruleset))
+}
+
+/// Lists the source ranges denied and allowed for each service limiting
+/// them, by nftables address family, for the firewall's `sources` chain.
+fn source_rules(services: &[ServicePort]) -> Vec<serde_json::Value> {
+    let by_family = |ranges: &[ipnet::IpNet]| {
+        let mut families = BTreeMap::<&str, Vec<String>>::new();
+        for r in ranges {
+            let family = match r {
+                ipnet::IpNet::V4(_) => "ip",
+                ipnet::IpNet::V6(_) => "ip6",
+            };
+            families.entry(family).or_default().push(r.to_string());
+        }
+        families
+    };
+    services
+        .iter()
+        .filter(|s| !s.allow.is_empty() || !s.deny.is_empty())
+        .map(|s| {
+            serde_json::json!({
+                "protocol": s.protocol.lowercase(),
+                "port": s.port,
+                "allow": by_family(&s.allow),
+                "deny": by_family(&s.deny),
+            })
+        })
+        .collect()
+}
+
+/// Returns a cloudinit YAML file for building a prebuilt image: it installs
+/// the packages required by innisfree, then powers off the server,
+/// so it's ready to be snapshotted.
+pub fn generate_image_user_data() -> Result<String> {
+    let user_data = include_str!("../../files/cloudinit.cfg");
+    let mut cloud_config = serde_yaml::from_str::<CloudConfig>(user_data)?;
+    cloud_config.packages.extend(image_packages());
+    let image_config = serde_json::json!({
+        "package_update": true,
+        "packages": cloud_config.packages,
+        "power_state": { "mode": "poweroff", "condition": true },
+    });
+    render(&image_config)
+}
+
+/// Packages installed in a prebuilt image: those needed on every server,
+/// plus the HTTPS packages, so the image works in either mode.
+fn image_packages() -> impl Iterator<Item = String> {
+    PACKAGES
+        .iter()
+        .chain(HTTPS_PACKAGES.iter())
+        .map(|p| p.to_string())
+}
+
+/// Adapts a cloudinit YAML file, as returned by [generate_user_data],
+/// for a server booting from a prebuilt image: innisfree's packages are
+/// already installed, so only install any extras, and restart nginx to
+/// pick up the config, unless another proxy replaces it.
+pub fn prebuilt_user_data(user_data: &str) -> Result<String> {
+    let mut cloud_config = serde_yaml::from_str::<serde_yaml::Value>(user_data)?;
+    if let Some(m) = cloud_config.as_mapping_mut() {
+        let installed: Vec<Value> = image_packages().map(Value::from).collect();
+        let extras: Vec<Value> = m
+            .get(&"packages".into())
+            .and_then(|p| p.as_sequence())
+            .map(|p| {
+                p.iter()
+                    .filter(|p| !installed.contains(p))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        if extras.is_empty() {
+            m.remove(&"packages".into());
+        } else {
+            m.insert("packages".into(), Value::Sequence(extras));
+        }
+    }
+    let replaced = cloud_config["write_files"].as_sequence().is_some_and(|f| {
+        f.iter()
+            .any(|f| f["path"] == caddy::CADDYFILE_PATH || f["path"] == forwarder::ENV_PATH)
+    });
+    if replaced {
+        return render(&cloud_config);
+    }
+    append_runcmd(&render(&cloud_config)?, &["systemctl", "restart", "nginx"])
+}
+
+/// Adds a command to the end of the `runcmd` list in a cloudinit YAML file,
+/// as returned by [generate_user_data], creating the list if necessary.
+pub fn append_runcmd(user_data: &str, cmd: &[&str]) -> Result<String> {
+    let mut cloud_config = serde_yaml::from_str::<serde_yaml::Value>(user_data)?;
+    let m = cloud_config
+        .as_mapping_mut()
+        .ok_or_else(|| anyhow!("cloudinit config is not a mapping"))?;
+    let runcmd = m
+        .entry("runcmd".into())
+        .or_insert_with(|| serde_yaml::Value::Sequence(vec![]));
+    runcmd
+        .as_sequence_mut()
+        .ok_or_else(|| anyhow!("cloudinit runcmd is not a list"))?
+        .push(serde_yaml::to_value(cmd)?);
+    render(&cloud_config)
+}
+
+/// Serializes a cloudinit config to YAML, with the `#cloud-config` header
+/// in place of the YAML document start marker.
+fn render<T: Serialize>(cloud_config: &T) -> Result<String> {
+    let cc_rendered: String = serde_yaml::to_string(cloud_config)?;
     let cc_rendered_no_header = &cc_rendered.as_bytes()[4..];
     let cc_rendered = std::str::from_utf8(cc_rendered_no_header)?;
     let mut cc: String = String::from("#cloud-config");
@@ -109,23 +956,139 @@ pub async fn generate_user_data(
     Ok(cc)
 }
 
+/// Sums nginx's log of stream sessions, see [STREAM_LOG_PATH], into the
+/// bytes passed per public port and protocol, e.g. `443/TCP`. Sessions are
+/// logged as they close, so open ones aren't counted yet.
+pub fn stream_traffic(log: &str) -> BTreeMap<String, ByteCounts> {
+    let mut totals = BTreeMap::<String, ByteCounts>::new();
+    for line in log.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [service, rx, tx] = fields[..] {
+            if let (Ok(rx), Ok(tx)) = (rx.parse::<u64>(), tx.parse::<u64>()) {
+                let t = totals.entry(service.to_string()).or_default();
+                t.rx_bytes += rx;
+                t.tx_bytes += tx;
+            }
+        }
+    }
+    totals
+}
+
 /// Generates an nginx stream configuration file as a string,
 /// for use configuring the remote server's nginx proxy.
+/// If there are SNI routes, the HTTPS service is routed by hostname
+/// via `ssl_preread`, rather than passed to a single port.
 // TODO consider using caddy for this. Ideally we'd terminate
 // TLS locally, but it'd sure be convenient.
-fn nginx_streams(services: &[ServicePort], dest_ip: IpAddr) -> Result<String> {
+fn nginx_streams(
+    services: &[ServicePort],
+    dest_ip: IpAddr,
+    sni_routes: &[HostRoute],
+    limits: ClientLimits,
+) -> Result<String> {
     let nginx_config = include_str!("../../files/stream.conf.j2");
     let mut context = tera::Context::new();
-    context.insert("services", services);
-    context.insert("dest_ip", &dest_ip.to_string());
+    let mut services = services.to_vec();
+    if !sni_routes.is_empty() {
+        if let Some(i) = services.iter().position(|s| s.is_https()) {
+            context.insert("sni_service", &services.remove(i));
+            context.insert("sni_routes", sni_routes);
+        }
+    }
+    context.insert("services", &services);
+    context.insert("dest_ip", &bracketed(dest_ip));
+    context.insert("limits", &limits);
     // Disable autoescaping, since it breaks wg key contents
     tera::Tera::one_off(nginx_config, &context, false).context("Template generation failed")
 }
 
+/// Generates an nginx http configuration file as a string, proxying
+/// requests for the domain to the service's local port. Only serves
+/// plain HTTP until certbot adds the certificate, after boot.
+fn nginx_https(domain: &str, service: &ServicePort, dest_ip: IpAddr) -> Result<String> {
+    let nginx_config = include_str!("../../files/https.conf.j2");
+    let mut context = tera::Context::new();
+    context.insert("domain", domain);
+    context.insert("local_port", &service.local_port);
+    context.insert("dest_ip", &bracketed(dest_ip));
+    tera::Tera::one_off(nginx_config, &context, false).context("Template generation failed")
+}
+
+/// Generates an nginx http configuration file as a string, routing
+/// requests on 80/TCP by Host header. Requests for other hosts go to
+/// the `default` service's local port, or get a 404 if there's none.
+fn nginx_vhosts(
+    routes: &[HostRoute],
+    default: Option<&ServicePort>,
+    dest_ip: IpAddr,
+) -> Result<String> {
+    let nginx_config = include_str!("../../files/vhost.conf.j2");
+    let mut context = tera::Context::new();
+    context.insert("port", &ServicePort::default().port);
+    context.insert("routes", routes);
+    context.insert("default_port", &default.map(|s| s.local_port));
+    context.insert("dest_ip", &bracketed(dest_ip));
+    tera::Tera::one_off(nginx_config, &context, false).context("Template generation failed")
+}
+
+/// Generates a Caddyfile as a string, forwarding plain streams via the layer4
+/// app, and terminating HTTPS or routing HTTP requests by Host header, as
+/// nginx does in those modes.
+fn caddyfile(
+    services: &[ServicePort],
+    dest_ip: IpAddr,
+    options: &CloudConfigOptions,
+) -> Result<String> {
+    let caddy_config = include_str!("../../files/Caddyfile.j2");
+    let mut context = tera::Context::new();
+    let mut streams = forwarded_streams(services, options);
+    if !options.sni_routes.is_empty() {
+        if let Some(i) = streams.iter().position(|s| s.is_https()) {
+            context.insert("sni_service", &streams.remove(i));
+            context.insert("sni_routes", &options.sni_routes);
+        }
+    }
+    context.insert("services", &streams);
+    if let (Some(domain), Some(service)) = (&options.https_domain, https_service(services)) {
+        context.insert("domain", domain);
+        context.insert("https_port", &service.local_port);
+    }
+    context.insert("routes", &options.vhost_routes);
+    if !options.vhost_routes.is_empty() {
+        context.insert("port", &ServicePort::default().port);
+        context.insert(
+            "default_port",
+            &http_service(services).map(|s| s.local_port),
+        );
+    }
+    context.insert("dest_ip", &bracketed(dest_ip));
+    tera::Tera::one_off(caddy_config, &context, false).context("Template generation failed")
+}
+
+/// Generates an nftables ruleset as a string, forwarding the services'
+/// public ports to their local ports on the Wireguard interface via DNAT.
+fn nftables_dnat(services: &[ServicePort], dest_ip: IpAddr) -> Result<String> {
+    let nft_config = include_str!("../../files/dnat.nft.j2");
+    let mut context = tera::Context::new();
+    context.insert("services", services);
+    context.insert("dest_ip", &dest_ip);
+    tera::Tera::one_off(nft_config, &context, false).context("Template generation failed")
+}
+
+/// Formats an IP for use in front of a port, since
+/// IPv6 addresses must be bracketed when followed by a port.
+fn bracketed(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::wg::{WireguardHost, WireguardKeypair};
+    use std::convert::TryFrom;
 
     // Helper function for reusable structs
     // This function is copied from src/wg.rs,
@@ -157,10 +1120,733 @@ mod tests {
         let kp2 = SshKeypair::new("server-test2")?;
         let wg_mgr = WireguardManager::new("foo-test")?;
         let ports = vec![];
-        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &ports).await?;
+        let user_data =
+            generate_user_data(&kp1, &kp2, &wg_mgr, &ports, &CloudConfigOptions::default()).await?;
         assert!(user_data.ends_with(""));
         assert!(user_data.starts_with("#cloud-config"));
         assert!(user_data.starts_with("#cloud-config\n"));
         Ok(())
     }
+
+    #[test]
+    fn nginx_listens_on_ipv4_and_ipv6() -> Result<()> {
+        let services = ServicePort::from_str_multi("443/TCP")?;
+        let config = nginx_streams(
+            &services,
+            "10.50.0.1".parse()?,
+            &[],
+            ClientLimits::default(),
+        )?;
+        assert!(config.contains("listen 443;"));
+        assert!(config.contains("listen [::]:443;"));
+        let config = nginx_streams(&services, "fd50::1".parse()?, &[], ClientLimits::default())?;
+        assert!(config.contains("proxy_pass [fd50::1]:443;"));
+        assert!(!config.contains("limit_conn"));
+        // Named services are labelled
+        let services = ServicePort::from_str_multi("web=443/TCP,22/TCP")?;
+        let config = nginx_streams(&services, "fd50::1".parse()?, &[], ClientLimits::default())?;
+        assert!(config.contains("# web\nserver {"));
+        assert_eq!(config.matches("server {").count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn nginx_limits_clients() -> Result<()> {
+        assert!(include_str!("../../files/cloudinit.cfg").contains("zone=innisfree_clients:"));
+        let services = ServicePort::from_str_multi("443:8443/TCP,22/TCP")?;
+        let limits = ClientLimits {
+            max_conns: Some(10),
+            max_rate: Some(512 * 1024),
+        };
+        let routes = vec![HostRoute::try_from("app.example.com=9443")?];
+        let config = nginx_streams(&services, "10.50.0.1".parse()?, &routes, limits)?;
+        // On the SNI server, and the plain stream
+        assert_eq!(
+            config.matches("limit_conn innisfree_clients 10;").count(),
+            2
+        );
+        assert_eq!(config.matches("proxy_upload_rate 524288;").count(), 2);
+        assert_eq!(config.matches("proxy_download_rate 524288;").count(), 2);
+
+        let options = CloudConfigOptions {
+            client_limits: limits,
+            dnat: true,
+            ..Default::default()
+        };
+        assert!(options.validate(&services).is_err());
+        let options = CloudConfigOptions {
+            client_limits: limits,
+            remote_proxy: RemoteProxy::Caddy,
+            ..Default::default()
+        };
+        assert!(options.validate(&services).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn nginx_routes_by_sni() -> Result<()> {
+        let services = ServicePort::from_str_multi("443:8443/TCP,22/TCP")?;
+        let routes = vec![
+            HostRoute::try_from("app.example.com=9443")?,
+            HostRoute::try_from("*.example.org=10443")?,
+        ];
+        let options = CloudConfigOptions {
+            sni_routes: routes.clone(),
+            ..Default::default()
+        };
+        options.validate(&services)?;
+        assert_eq!(options.local_services(&services).len(), 4);
+
+        let config = nginx_streams(
+            &services,
+            "10.50.0.1".parse()?,
+            &routes,
+            ClientLimits::default(),
+        )?;
+        assert!(config.contains("ssl_preread on;"));
+        assert!(config.contains("app.example.com 10.50.0.1:9443;"));
+        assert!(config.contains("*.example.org 10.50.0.1:10443;"));
+        assert!(config.contains("default 10.50.0.1:8443;"));
+        assert!(config.contains("proxy_pass 10.50.0.1:22;"));
+        assert!(!config.contains("proxy_pass 10.50.0.1:8443;"));
+
+        // The default route is required
+        let services = ServicePort::from_str_multi("22/TCP")?;
+        assert!(options.validate(&services).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn https_mode_terminates_tls() -> Result<()> {
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let options = CloudConfigOptions {
+            https_domain: Some("example.com".to_string()),
+            ..Default::default()
+        };
+        let services = ServicePort::from_str_multi("443:8000/TCP,2222:22/TCP")?;
+        options.validate(&services)?;
+        assert_eq!(options.public_ports(&services).len(), 3);
+
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        let cloud_config = serde_yaml::from_str::<CloudConfig>(&user_data)?;
+        assert!(cloud_config.packages.contains(&"certbot".to_string()));
+        let https = cloud_config
+            .write_files
+            .iter()
+            .find(|f| f.path.ends_with("innisfree-https.conf"))
+            .expect("https config missing");
+        assert!(https.content.contains("server_name example.com;"));
+        assert!(https.content.contains(":8000;"));
+        let streams = cloud_config
+            .write_files
+            .iter()
+            .find(|f| f.path.ends_with("stream/innisfree.conf"))
+            .expect("stream config missing");
+        assert!(!streams.content.contains("listen 443;"));
+        assert!(streams.content.contains("listen 2222;"));
+
+        // The default ports collide with validation on 80/TCP
+        assert!(options
+            .validate(&ServicePort::from_str_multi("80:8000/TCP")?)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn nginx_routes_by_host_header() -> Result<()> {
+        let routes = vec![
+            HostRoute::try_from("app.example.com=8001")?,
+            HostRoute::try_from("*.example.org=8002")?,
+        ];
+        let config = nginx_vhosts(&routes, None, "10.50.0.1".parse()?)?;
+        assert!(config.contains("server_name app.example.com;"));
+        assert!(config.contains("proxy_pass http://10.50.0.1:8001;"));
+        assert!(config.contains("innisfree-_.example.org.access.log"));
+        assert!(config.contains("return 404;"));
+
+        let services = ServicePort::from_str_multi("80:8000/TCP")?;
+        let config = nginx_vhosts(&routes, services.first(), "10.50.0.1".parse()?)?;
+        assert!(config.contains("proxy_pass http://10.50.0.1:8000;"));
+        assert!(!config.contains("return 404;"));
+
+        let options = CloudConfigOptions {
+            vhost_routes: routes,
+            ..Default::default()
+        };
+        // Port 80 is opened even without a plain HTTP service
+        assert_eq!(options.public_ports(&[]).len(), 1);
+        assert_eq!(options.public_ports(&services).len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_transport_runs_udp2raw() -> Result<()> {
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let options = CloudConfigOptions {
+            wg_transport: WireguardTransport::Tcp,
+            wg_port: Some(51821),
+            ..Default::default()
+        };
+        let services = ServicePort::from_str_multi("8080/TCP")?;
+        let ports = options.public_ports(&services);
+        assert!(ports
+            .iter()
+            .any(|p| p.port == 51821 && p.protocol == Protocol::Tcp));
+
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        let cloud_config = serde_yaml::from_str::<CloudConfig>(&user_data)?;
+        assert!(cloud_config
+            .write_files
+            .iter()
+            .any(|f| f.path == udp2raw::SERVER_UNIT_PATH));
+        assert!(cloud_config
+            .runcmd
+            .iter()
+            .flatten()
+            .any(|c| c == "innisfree-udp2raw"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn host_keys_named_by_type() -> Result<()> {
+        let kp1 = SshKeypair::generate("client-test", SshKeyType::Ecdsa)?;
+        let kp2 = SshKeypair::generate("server-test", SshKeyType::Ecdsa)?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let services = ServicePort::from_str_multi("8080/TCP")?;
+        let options = CloudConfigOptions::default();
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        let cloud_config = serde_yaml::from_str::<CloudConfig>(&user_data)?;
+        assert_eq!(cloud_config.ssh_keys["ecdsa_public"], kp2.public);
+        assert!(!cloud_config.ssh_keys.contains_key("ed25519_public"));
+        assert_eq!(cloud_config.users[0].ssh_authorized_keys[0], kp1.public);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extra_user_data_merged() -> Result<()> {
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let services = ServicePort::from_str_multi("8080/TCP")?;
+        let extra = r#"#cloud-config
+package_update: true
+packages: [prometheus-node-exporter]
+write_files:
+  - path: /etc/extra.conf
+    content: "extra"
+runcmd:
+  - [systemctl, enable, --now, prometheus-node-exporter]
+timezone: UTC
+"#;
+        let options = CloudConfigOptions {
+            extra_user_data: Some(extra.to_string()),
+            ..Default::default()
+        };
+        options.validate(&services)?;
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        assert!(user_data.starts_with("#cloud-config\n"));
+        let merged: Value = serde_yaml::from_str(&user_data)?;
+        assert_eq!(merged["package_update"], Value::from(true));
+        assert_eq!(merged["timezone"], Value::from("UTC"));
+        let packages = merged["packages"].as_sequence().unwrap();
+        assert!(packages.contains(&Value::from("nginx")));
+        assert!(packages.contains(&Value::from("prometheus-node-exporter")));
+        let files = merged["write_files"].as_sequence().unwrap();
+        assert!(files.iter().any(|f| f["path"] == STREAM_CONFIG_PATH));
+        assert_eq!(
+            files.last().unwrap()["path"],
+            Value::from("/etc/extra.conf")
+        );
+        // Appended after the firewall's
+        let runcmd = merged["runcmd"].as_sequence().unwrap();
+        assert_eq!(runcmd.len(), 2);
+        assert_eq!(runcmd[1][0], Value::from("systemctl"));
+        assert_eq!(
+            merged["users"][0]["ssh_authorized_keys"][0],
+            Value::from(kp1.public)
+        );
+
+        for bad in ["ssh_keys: {rsa_private: x}", "[nginx]", "packages: ["] {
+            let options = CloudConfigOptions {
+                extra_user_data: Some(bad.to_string()),
+                ..Default::default()
+            };
+            assert!(options.validate(&services).is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn caddy_replaces_nginx() -> Result<()> {
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let options = CloudConfigOptions {
+            remote_proxy: "caddy".parse()?,
+            sni_routes: vec![HostRoute::try_from("app.example.com=9443")?],
+            vhost_routes: vec![HostRoute::try_from("blog.example.com=8001")?],
+            ..Default::default()
+        };
+        let services = ServicePort::from_str_multi("443:8443/TCP,53/UDP,2222:22/TCP")?;
+        options.validate(&services)?;
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        let cloud_config = serde_yaml::from_str::<CloudConfig>(&user_data)?;
+        assert!(!cloud_config.packages.contains(&"nginx".to_string()));
+        assert!(!cloud_config
+            .write_files
+            .iter()
+            .any(|f| f.path.starts_with("/etc/nginx/")));
+        assert!(cloud_config
+            .runcmd
+            .iter()
+            .flatten()
+            .any(|c| c == caddy::SERVICE_NAME));
+
+        let dest = wg_mgr.wg_local_device.interface.address;
+        let (path, config) = forwarding_config(&services, dest, &options)?;
+        assert_eq!(path, caddy::CADDYFILE_PATH);
+        assert!(config.contains("@sni1 tls sni app.example.com"));
+        assert!(config.contains(&format!("upstream {}:9443", dest)));
+        assert!(config.contains(&format!("upstream {}:8443", dest)));
+        assert!(config.contains("udp/:53 {"));
+        assert!(config.contains(&format!("upstream udp/{}:53", dest)));
+        assert!(config.contains(":2222 {"));
+        assert!(config.contains("http://blog.example.com {"));
+        assert!(config.contains("respond 404"));
+
+        // Caddy terminates HTTPS itself
+        let options = CloudConfigOptions {
+            remote_proxy: RemoteProxy::Caddy,
+            https_domain: Some("example.com".to_string()),
+            ..Default::default()
+        };
+        let services = ServicePort::from_str_multi("443:8000/TCP")?;
+        let (_, config) = forwarding_config(&services, dest, &options)?;
+        assert!(config.contains("example.com {"));
+        assert!(config.contains(&format!("reverse_proxy {}:8000", dest)));
+        assert!(!config.contains("layer4 {"));
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        let prebuilt: Value = serde_yaml::from_str(&prebuilt_user_data(&user_data)?)?;
+        let restart = serde_yaml::to_value(["systemctl", "restart", "nginx"])?;
+        assert!(!prebuilt["runcmd"].as_sequence().unwrap().contains(&restart));
+
+        let options = CloudConfigOptions {
+            remote_proxy: RemoteProxy::Caddy,
+            dnat: true,
+            ..Default::default()
+        };
+        assert!(options.validate(&services).is_err());
+        assert!("haproxy".parse::<RemoteProxy>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn innisfree_replaces_nginx() -> Result<()> {
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let options = CloudConfigOptions {
+            remote_proxy: "innisfree".parse()?,
+            ..Default::default()
+        };
+        let services = ServicePort::from_str_multi("443:8443/TCP,2222:22/TCP")?;
+        options.validate(&services)?;
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        let cloud_config = serde_yaml::from_str::<CloudConfig>(&user_data)?;
+        assert!(!cloud_config.packages.contains(&"nginx".to_string()));
+        assert!(!cloud_config
+            .write_files
+            .iter()
+            .any(|f| f.path.starts_with("/etc/nginx/")));
+        assert!(cloud_config
+            .runcmd
+            .iter()
+            .flatten()
+            .any(|c| c.contains(forwarder::RELEASE_URL)));
+        let prebuilt: Value = serde_yaml::from_str(&prebuilt_user_data(&user_data)?)?;
+        let restart = serde_yaml::to_value(["systemctl", "restart", "nginx"])?;
+        assert!(!prebuilt["runcmd"].as_sequence().unwrap().contains(&restart));
+
+        let dest = wg_mgr.wg_local_device.interface.address;
+        let (path, config) = forwarding_config(&services, dest, &options)?;
+        assert_eq!(path, forwarder::ENV_PATH);
+        assert!(config.contains("INNISFREE_PORTS=8443:443/TCP,22:2222/TCP\n"));
+        assert!(config.contains(&format!("INNISFREE_DEST_IP={}\n", dest)));
+
+        // An uploaded build isn't downloaded
+        let options = CloudConfigOptions {
+            remote_proxy: RemoteProxy::Innisfree,
+            forwarder_binary: Some(PathBuf::from("innisfree")),
+            ..Default::default()
+        };
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        assert!(!user_data.contains(forwarder::RELEASE_URL));
+
+        // Only plain TCP streams are forwarded
+        let udp = ServicePort::from_str_multi("53/UDP")?;
+        assert!(options.validate(&udp).is_err());
+        let mut proxied = services.clone();
+        crate::config::apply_proxy_protocol(&mut proxied, "443")?;
+        assert!(options.validate(&proxied).is_err());
+        let sni = CloudConfigOptions {
+            sni_routes: vec![HostRoute::try_from("app.example.com=9443")?],
+            ..options.clone()
+        };
+        assert!(sni.validate(&services).is_err());
+        let nginx = CloudConfigOptions {
+            remote_proxy: RemoteProxy::Nginx,
+            ..options
+        };
+        assert!(nginx.validate(&services).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dnat_mode_skips_nginx() -> Result<()> {
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let options = CloudConfigOptions {
+            dnat: true,
+            ..Default::default()
+        };
+        let services = ServicePort::from_str_multi("443:8443/TCP,53/UDP")?;
+        options.validate(&services)?;
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        let cloud_config = serde_yaml::from_str::<CloudConfig>(&user_data)?;
+        let nft = cloud_config
+            .write_files
+            .iter()
+            .find(|f| f.path.ends_with("dnat.nft"))
+            .expect("nftables config missing");
+        let dest = wg_mgr.wg_local_device.interface.address;
+        assert!(nft
+            .content
+            .contains(&format!("tcp dport 443 dnat to {}:8443", dest)));
+        assert!(nft
+            .content
+            .contains(&format!("udp dport 53 dnat to {}:53", dest)));
+        let streams = cloud_config
+            .write_files
+            .iter()
+            .find(|f| f.path.ends_with("stream/innisfree.conf"))
+            .expect("stream config missing");
+        assert!(!streams.content.contains("listen"));
+        // The DNAT ruleset, then the firewall's
+        assert_eq!(cloud_config.runcmd.len(), 3);
+
+        // Runs on prebuilt images, along with the nginx restart
+        let user_data = prebuilt_user_data(&user_data)?;
+        let cloud_config = serde_yaml::from_str::<serde_yaml::Value>(&user_data)?;
+        assert_eq!(
+            cloud_config["runcmd"].as_sequence().map(|c| c.len()),
+            Some(4)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn firewall_accepts_only_public_ports() -> Result<()> {
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let options = CloudConfigOptions {
+            https_domain: Some("example.com".to_string()),
+            wg_port: Some(51900),
+            ..Default::default()
+        };
+        let services = ServicePort::from_str_multi("443:8443/TCP,53/UDP")?;
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        let cloud_config = serde_yaml::from_str::<CloudConfig>(&user_data)?;
+        let nft = cloud_config
+            .write_files
+            .iter()
+            .find(|f| f.path == FIREWALL_CONFIG_PATH)
+            .expect("firewall ruleset missing");
+        let apply = ["nft", "-f", FIREWALL_CONFIG_PATH]
+            .map(String::from)
+            .to_vec();
+        assert!(cloud_config.runcmd.contains(&apply));
+        let wg_port = wg_mgr.wg_remote_device.interface.listenport;
+        assert!(nft.content.contains("policy drop;"));
+        assert!(nft.content.contains("tcp dport 22 accept"));
+        assert!(nft
+            .content
+            .contains(&format!("udp dport {} accept", wg_port)));
+        assert!(nft.content.contains("tcp dport 443 accept"));
+        assert!(nft.content.contains("udp dport 53 accept"));
+        // For certificate validation in HTTPS mode
+        assert!(nft.content.contains("tcp dport 80 accept"));
+        assert!(nft.content.contains("echo-request limit rate"));
+        assert!(!nft.content.contains("dport 8443"));
+
+        // Reloaded with the services
+        let services = ServicePort::from_str_multi("8080/TCP")?;
+        let options = CloudConfigOptions::default();
+        let (path, ruleset) = firewall_config(&services, 51900, &options, true)?;
+        assert_eq!(path, FIREWALL_CONFIG_PATH);
+        assert!(ruleset.contains("tcp dport 8080 accept"));
+        assert!(ruleset.contains("udp dport 51900 accept"));
+        assert!(!ruleset.contains("dport 80 "));
+
+        // SSH closed to the internet, but open over the tunnel
+        let (_, ruleset) = firewall_config(&services, 51900, &options, false)?;
+        assert!(!ruleset.contains("dport 22"));
+        assert!(ruleset.contains("iifname \"innisfree\" accept"));
+        assert!(!ruleset.contains("chain sources"));
+
+        // Sources limited per service
+        let mut services = ServicePort::from_str_multi("443/TCP,8443/TCP")?;
+        crate::config::apply_allow_cidrs(&mut services, "8443=203.0.113.0/24,8443=2001:db8::/32")?;
+        crate::config::apply_deny_cidrs(&mut services, "443=198.51.100.0/24,443=192.0.2.1")?;
+        let (_, ruleset) = firewall_config(&services, 51900, &options, true)?;
+        assert!(ruleset.contains("hook prerouting priority raw;"));
+        assert!(ruleset.contains(
+            "iifname != \"innisfree\" ip saddr { 198.51.100.0/24, 192.0.2.1/32 } tcp dport 443 drop\n"
+        ));
+        assert!(ruleset.contains(
+            "iifname != \"innisfree\" ip saddr { 203.0.113.0/24 } tcp dport 8443 accept\n"
+        ));
+        assert!(ruleset.contains(
+            "iifname != \"innisfree\" ip6 saddr { 2001:db8::/32 } tcp dport 8443 accept\n"
+        ));
+        assert!(ruleset.contains("iifname != \"innisfree\" tcp dport 8443 drop\n"));
+        assert!(!ruleset.contains("\"innisfree\" tcp dport 443 drop"));
+        Ok(())
+    }
+
+    #[test]
+    fn stream_log_summed_per_service() {
+        assert!(include_str!("../../files/cloudinit.cfg").contains(STREAM_LOG_PATH));
+        let log = "443/TCP 120 4000\n53/UDP 40 80\n443/TCP 30 1000\ngarbage\n";
+        let totals = stream_traffic(log);
+        assert_eq!(totals.len(), 2);
+        assert_eq!(
+            totals["443/TCP"],
+            ByteCounts {
+                rx_bytes: 150,
+                tx_bytes: 5000
+            }
+        );
+        assert_eq!(totals["53/UDP"].tx_bytes, 80);
+    }
+
+    #[tokio::test]
+    async fn health_endpoint_served() -> Result<()> {
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let options = CloudConfigOptions {
+            health_port: Some(8099),
+            ..Default::default()
+        };
+        let services = ServicePort::from_str_multi("443:8443/TCP")?;
+        options.validate(&services)?;
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        let cloud_config = serde_yaml::from_str::<CloudConfig>(&user_data)?;
+        let targets = cloud_config
+            .write_files
+            .iter()
+            .find(|f| f.path == remote_health::TARGETS_PATH)
+            .expect("health targets missing");
+        let dest_ip = wg_mgr.wg_local_device.interface.address;
+        assert_eq!(targets.content, format!("{} 8443\n", dest_ip));
+        assert!(cloud_config
+            .runcmd
+            .contains(&remote_health::server_runcmd()[0]));
+        let (_, ruleset) = firewall_config(&services, 51900, &options, true)?;
+        assert!(ruleset.contains("tcp dport 8099 accept"));
+
+        // Clashing with a service, or SSH
+        assert!(options
+            .validate(&ServicePort::from_str_multi("8099/TCP")?)
+            .is_err());
+        let options = CloudConfigOptions {
+            health_port: Some(22),
+            ..Default::default()
+        };
+        assert!(options.validate(&services).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn forwarding_config_matches_mode() -> Result<()> {
+        let services = ServicePort::from_str_multi("443:8443/TCP,8080:8000/TCP")?;
+        let dest_ip = "10.50.0.1".parse()?;
+        let options = CloudConfigOptions {
+            https_domain: Some("example.com".to_string()),
+            ..Default::default()
+        };
+        let (path, config) = forwarding_config(&services, dest_ip, &options)?;
+        assert_eq!(path, STREAM_CONFIG_PATH);
+        assert!(config.contains("listen 8080;"));
+        // Terminated by the http server, rather than forwarded as a stream
+        assert!(!config.contains("listen 443;"));
+
+        let options = CloudConfigOptions {
+            dnat: true,
+            ..Default::default()
+        };
+        let (path, config) = forwarding_config(&services, dest_ip, &options)?;
+        assert_eq!(path, DNAT_CONFIG_PATH);
+        // Flushed first, so reloading replaces the previous rules
+        assert!(config.contains("flush table ip innisfree"));
+        assert!(config.contains("tcp dport 8080 dnat to 10.50.0.1:8000"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prebuilt_image_skips_packages() -> Result<()> {
+        let image_data = generate_image_user_data()?;
+        assert!(image_data.starts_with("#cloud-config\n"));
+        assert!(image_data.contains("wireguard"));
+        assert!(image_data.contains("poweroff"));
+
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let user_data =
+            generate_user_data(&kp1, &kp2, &wg_mgr, &[], &CloudConfigOptions::default()).await?;
+        let user_data = prebuilt_user_data(&user_data)?;
+        assert!(user_data.starts_with("#cloud-config\n"));
+        let cloud_config = serde_yaml::from_str::<serde_yaml::Value>(&user_data)?;
+        assert!(cloud_config.get("packages").is_none());
+        assert!(cloud_config.get("write_files").is_some());
+
+        // Extra packages aren't in the image, so are still installed
+        let options = CloudConfigOptions {
+            extra_packages: vec!["htop".to_string()],
+            ..Default::default()
+        };
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &[], &options).await?;
+        let cloud_config: Value = serde_yaml::from_str(&prebuilt_user_data(&user_data)?)?;
+        assert_eq!(cloud_config["packages"], serde_yaml::to_value(["htop"])?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auto_updates_opt_out() -> Result<()> {
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let auto_upgrades = |user_data: &str| -> Result<String> {
+            let cloud_config = serde_yaml::from_str::<CloudConfig>(user_data)?;
+            let installed = cloud_config
+                .packages
+                .contains(&"unattended-upgrades".to_string());
+            let config = cloud_config
+                .write_files
+                .into_iter()
+                .find(|f| f.path == AUTO_UPGRADES_PATH)
+                .expect("auto upgrades config missing")
+                .content;
+            assert_eq!(installed, config.contains("Unattended-Upgrade \"1\""));
+            Ok(config)
+        };
+        let options = CloudConfigOptions::default();
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &[], &options).await?;
+        let config = auto_upgrades(&user_data)?;
+        assert!(config.contains("APT::Periodic::Unattended-Upgrade \"1\";"));
+        assert!(user_data.contains("Automatic-Reboot"));
+
+        let options = CloudConfigOptions {
+            no_auto_updates: true,
+            ..Default::default()
+        };
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &[], &options).await?;
+        let config = auto_upgrades(&user_data)?;
+        assert!(config.contains("APT::Periodic::Unattended-Upgrade \"0\";"));
+        assert!(config.contains("APT::Periodic::Update-Package-Lists \"0\";"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extra_packages_and_commands() -> Result<()> {
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let services = ServicePort::from_str_multi("8080/TCP")?;
+        let options = CloudConfigOptions {
+            extra_packages: vec!["htop".to_string(), "fail2ban=1.0.2-2".to_string()],
+            extra_runcmd: vec!["echo hello > /tmp/hello".to_string()],
+            ..Default::default()
+        };
+        options.validate(&services)?;
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        let cloud_config = serde_yaml::from_str::<CloudConfig>(&user_data)?;
+        assert!(cloud_config.packages.contains(&"nginx".to_string()));
+        assert!(cloud_config
+            .packages
+            .contains(&"fail2ban=1.0.2-2".to_string()));
+        assert_eq!(
+            cloud_config.runcmd.last().unwrap(),
+            &["sh", "-c", "echo hello > /tmp/hello"]
+        );
+
+        for package in ["", "Htop", "htop; rm -rf /", "htop="] {
+            let options = CloudConfigOptions {
+                extra_packages: vec![package.to_string()],
+                ..Default::default()
+            };
+            assert!(options.validate(&services).is_err(), "{}", package);
+        }
+        let options = CloudConfigOptions {
+            extra_runcmd: vec![" ".to_string()],
+            ..Default::default()
+        };
+        assert!(options.validate(&services).is_err());
+
+        // Too large for providers to accept
+        let options = CloudConfigOptions {
+            extra_runcmd: vec!["x".repeat(MAX_USER_DATA_SIZE)],
+            ..Default::default()
+        };
+        assert!(generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hostname_set() -> Result<()> {
+        let kp1 = SshKeypair::new("server-test1")?;
+        let kp2 = SshKeypair::new("server-test2")?;
+        let wg_mgr = WireguardManager::new("foo-test")?;
+        let services = ServicePort::from_str_multi("25/TCP")?;
+        let options = CloudConfigOptions {
+            hostname: Some("mail.example.com".to_string()),
+            ..Default::default()
+        };
+        options.validate(&services)?;
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        let cloud_config = serde_yaml::from_str::<CloudConfig>(&user_data)?;
+        assert_eq!(cloud_config.hostname.as_deref(), Some("mail"));
+        assert_eq!(cloud_config.fqdn.as_deref(), Some("mail.example.com"));
+        assert!(cloud_config.manage_etc_hosts);
+
+        // Left to the provider by default
+        let options = CloudConfigOptions::default();
+        let user_data = generate_user_data(&kp1, &kp2, &wg_mgr, &services, &options).await?;
+        assert!(!user_data.contains("fqdn"));
+        assert!(!user_data.contains("manage_etc_hosts"));
+
+        for bad in [
+            "",
+            "mail..example.com",
+            "-mail.example.com",
+            "mail_1",
+            "a b",
+        ] {
+            let options = CloudConfigOptions {
+                hostname: Some(bad.to_string()),
+                ..Default::default()
+            };
+            assert!(options.validate(&services).is_err(), "{}", bad);
+        }
+        Ok(())
+    }
 }
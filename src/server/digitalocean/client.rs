@@ -0,0 +1,373 @@
+//! Shared HTTP client for the DigitalOcean API. Retries transient failures,
+//! such as network errors and 5xx responses, with exponential backoff,
+//! though POSTs only if they can't have been processed, see [retryable],
+//! waits out rate limits as reported by the `RateLimit-*` headers,
+//! and converts error responses into a typed [DoApiError].
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::time;
+
+use crate::token;
+
+const DO_API_BASE_URL: &str = "https://api.digitalocean.com/v2";
+
+/// Builds the full URL for an API path, e.g. `/droplets`.
+pub fn api_url(path: &str) -> String {
+    DO_API_BASE_URL.to_owned() + path
+}
+
+/// How many times to retry a request that failed transiently.
+const MAX_RETRIES: u32 = 5;
+/// Delay before the first retry, doubled on each subsequent one.
+const INITIAL_BACKOFF: time::Duration = time::Duration::from_millis(500);
+/// Upper bound on time spent waiting for a rate limit to reset.
+const MAX_RATE_LIMIT_WAIT: time::Duration = time::Duration::from_secs(60);
+
+/// Error returned by the DigitalOcean API, classified by cause,
+/// so that callers can report something more useful than a status code.
+#[derive(Debug)]
+pub enum DoApiError {
+    /// The API token is missing, invalid, or lacks the required scopes.
+    Unauthorized(String),
+    /// The requested resource doesn't exist.
+    NotFound(String),
+    /// The account has hit a resource limit, e.g. its droplet limit.
+    QuotaExceeded(String),
+    /// The requested region doesn't exist, or doesn't support the resource.
+    InvalidRegion(String),
+    /// Too many requests; retries were exhausted while waiting on the limit.
+    RateLimited,
+    /// Any other error response.
+    Api {
+        /// HTTP status code of the response.
+        status: u16,
+        /// Machine-readable error ID, e.g. `unprocessable_entity`.
+        id: String,
+        /// Human-readable error message.
+        message: String,
+    },
+}
+
+impl fmt::Display for DoApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DoApiError::Unauthorized(m) => {
+                write!(f, "Unauthorized, check DIGITALOCEAN_API_TOKEN: {}", m)
+            }
+            DoApiError::NotFound(m) => write!(f, "Resource not found: {}", m),
+            DoApiError::QuotaExceeded(m) => write!(f, "Account limit exceeded: {}", m),
+            DoApiError::InvalidRegion(m) => write!(f, "Invalid region: {}", m),
+            DoApiError::RateLimited => write!(f, "Rate limited by API, try again later"),
+            DoApiError::Api {
+                status,
+                id,
+                message,
+            } => write!(f, "API error {} ({}): {}", status, id, message),
+        }
+    }
+}
+
+impl std::error::Error for DoApiError {}
+
+#[derive(Debug, Default, Deserialize)]
+/// Body of an error response, as returned by the API.
+struct ErrorBody {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    message: String,
+}
+
+impl DoApiError {
+    /// Classifies an error response, given its status code and body.
+    fn from_response(status: u16, body: &str) -> DoApiError {
+        let b: ErrorBody = serde_json::from_str(body).unwrap_or_default();
+        let message = b.message.to_lowercase();
+        match status {
+            401 | 403 => DoApiError::Unauthorized(b.message),
+            404 => DoApiError::NotFound(b.message),
+            429 => DoApiError::RateLimited,
+            422 if message.contains("limit") || message.contains("exceed") => {
+                DoApiError::QuotaExceeded(b.message)
+            }
+            422 if message.contains("region") => DoApiError::InvalidRegion(b.message),
+            _ => DoApiError::Api {
+                status,
+                id: b.id,
+                message: b.message,
+            },
+        }
+    }
+}
+
+/// Finds the URL for the next page of a list response, if any.
+/// See <https://docs.digitalocean.com/reference/api/api-reference/#section/Introduction/Links-and-Pagination>.
+fn next_page(j: &serde_json::Value) -> Option<String> {
+    j["links"]["pages"]["next"].as_str().map(|s| s.to_string())
+}
+
+/// Client for the DigitalOcean API, authenticated via
+/// the `DIGITALOCEAN_API_TOKEN` env var, or another source, see [crate::token].
+pub struct DoApiClient {
+    client: reqwest::Client,
+    api_key: String,
+}
+
+/// Computes the delay before retry number `attempt`, starting from 0.
+fn backoff(attempt: u32) -> time::Duration {
+    INITIAL_BACKOFF * 2u32.pow(attempt)
+}
+
+/// Whether to retry a `method` request that failed with `status`, or, if
+/// `None`, without a response, in which case `connected` tells whether it
+/// may have been sent. A POST that reached the API may have created its
+/// resource, e.g. a droplet, before failing, and a second one would be
+/// left running, untracked, so POSTs are only retried if rate limited,
+/// or if they never connected.
+fn retryable(
+    method: &reqwest::Method,
+    status: Option<reqwest::StatusCode>,
+    connected: bool,
+) -> bool {
+    match status {
+        Some(s) if s == reqwest::StatusCode::TOO_MANY_REQUESTS => true,
+        Some(s) => s.is_server_error() && method != reqwest::Method::POST,
+        None => !connected || method != reqwest::Method::POST,
+    }
+}
+
+/// Computes how long to wait for the rate limit to reset,
+/// given the `RateLimit-Reset` header, as a unix timestamp.
+fn rate_limit_wait(headers: &reqwest::header::HeaderMap) -> Option<time::Duration> {
+    let reset: u64 = headers
+        .get("RateLimit-Reset")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some(time::Duration::from_secs(reset.saturating_sub(now)).min(MAX_RATE_LIMIT_WAIT))
+}
+
+impl DoApiClient {
+    /// Creates a new client. Fails if the API token can't be read.
+    pub fn new() -> Result<DoApiClient> {
+        let api_key = token::digitalocean()?;
+        Ok(DoApiClient {
+            client: reqwest::Client::new(),
+            api_key,
+        })
+    }
+
+    /// Sends a GET request to `path`, e.g. `/droplets`, returning the response JSON.
+    pub async fn get(&self, path: &str) -> Result<serde_json::Value> {
+        self.request(reqwest::Method::GET, path, &[], None).await
+    }
+
+    /// Sends a GET request to `path`, with query parameters.
+    pub async fn get_with_query(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<serde_json::Value> {
+        self.request(reqwest::Method::GET, path, query, None).await
+    }
+
+    /// Sends GET requests to the list endpoint `path`, following pagination links,
+    /// and collects the items under `key` from every page, e.g. `droplets`.
+    /// Query parameters only apply to the first request, since the
+    /// links for subsequent pages include them already.
+    pub async fn get_all_pages(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+        key: &str,
+    ) -> Result<Vec<serde_json::Value>> {
+        let mut query: Vec<(&str, &str)> = query.to_vec();
+        query.push(("per_page", "200"));
+        let mut items = vec![];
+        let mut j = self.get_with_query(path, &query).await?;
+        loop {
+            if let Some(page) = j[key].as_array() {
+                items.extend(page.iter().cloned());
+            }
+            match next_page(&j) {
+                Some(url) => j = self.get(&url).await?,
+                None => return Ok(items),
+            }
+        }
+    }
+
+    /// Sends a POST request to `path` with a JSON body, returning the response JSON.
+    pub async fn post<T: serde::Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<serde_json::Value> {
+        let body = serde_json::to_value(body)?;
+        self.request(reqwest::Method::POST, path, &[], Some(body))
+            .await
+    }
+
+    /// Sends a PATCH request to `path` with a JSON body, returning the response JSON.
+    pub async fn patch<T: serde::Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<serde_json::Value> {
+        let body = serde_json::to_value(body)?;
+        self.request(reqwest::Method::PATCH, path, &[], Some(body))
+            .await
+    }
+
+    /// Sends a DELETE request to `path`.
+    pub async fn delete(&self, path: &str) -> Result<()> {
+        self.request(reqwest::Method::DELETE, path, &[], None)
+            .await?;
+        Ok(())
+    }
+
+    /// Sends a DELETE request to `path` with a JSON body, for endpoints
+    /// that remove part of a resource, e.g. firewall rules.
+    pub async fn delete_with_body<T: serde::Serialize>(&self, path: &str, body: &T) -> Result<()> {
+        let body = serde_json::to_value(body)?;
+        self.request(reqwest::Method::DELETE, path, &[], Some(body))
+            .await?;
+        Ok(())
+    }
+
+    /// Sends a request, retrying transient failures. The `path` is relative
+    /// to the API base URL, unless it's a full URL, e.g. from pagination links.
+    /// Returns `null` for empty responses, e.g. on deletion.
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value> {
+        let url = if path.starts_with("https://") {
+            path.to_string()
+        } else {
+            api_url(path)
+        };
+        let mut attempt = 0;
+        loop {
+            let mut request = self
+                .client
+                .request(method.clone(), &url)
+                .query(query)
+                .bearer_auth(&self.api_key);
+            if let Some(b) = &body {
+                request = request.json(b);
+            }
+            let response = match request.send().await {
+                Ok(r) => r,
+                Err(e) if attempt < MAX_RETRIES && retryable(&method, None, !e.is_connect()) => {
+                    tracing::debug!("Network error on {} {}, retrying: {}", method, url, e);
+                    tokio::time::sleep(backoff(attempt)).await;
+                    attempt += 1;
+                    continue;
+                }
+                Err(e) => return Err(e).context("Network error, check connection"),
+            };
+            let status = response.status();
+            if status.is_success() {
+                let text = response.text().await?;
+                if text.is_empty() {
+                    return Ok(serde_json::Value::Null);
+                }
+                return Ok(serde_json::from_str(&text)?);
+            }
+            if attempt < MAX_RETRIES && retryable(&method, Some(status), true) {
+                if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    let wait =
+                        rate_limit_wait(response.headers()).unwrap_or_else(|| backoff(attempt));
+                    tracing::warn!("Rate limited by API, waiting {}s...", wait.as_secs());
+                    tokio::time::sleep(wait).await;
+                    attempt += 1;
+                    continue;
+                }
+                tracing::debug!("Server error {} on {} {}, retrying", status, method, url);
+                tokio::time::sleep(backoff(attempt)).await;
+                attempt += 1;
+                continue;
+            }
+            let text = response.text().await.unwrap_or_default();
+            return Err(DoApiError::from_response(status.as_u16(), &text).into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_responses_are_classified() {
+        let e = DoApiError::from_response(
+            422,
+            r#"{"id":"unprocessable_entity","message":"creating this/these droplet(s) will exceed your droplet limit"}"#,
+        );
+        assert!(matches!(e, DoApiError::QuotaExceeded(_)));
+        let e = DoApiError::from_response(
+            422,
+            r#"{"id":"unprocessable_entity","message":"Region is not available"}"#,
+        );
+        assert!(matches!(e, DoApiError::InvalidRegion(_)));
+        let e = DoApiError::from_response(
+            401,
+            r#"{"id":"unauthorized","message":"Unable to authenticate you"}"#,
+        );
+        assert!(matches!(e, DoApiError::Unauthorized(_)));
+        let e = DoApiError::from_response(500, "<html>Bad gateway</html>");
+        assert_eq!(e.to_string(), "API error 500 (): ");
+    }
+
+    #[test]
+    fn pagination_links_are_followed() {
+        let j = serde_json::json!({
+            "ssh_keys": [],
+            "links": { "pages": {
+                "last": "https://api.digitalocean.com/v2/account/keys?page=3&per_page=200",
+                "next": "https://api.digitalocean.com/v2/account/keys?page=2&per_page=200",
+            }},
+            "meta": { "total": 450 },
+        });
+        assert_eq!(
+            next_page(&j).as_deref(),
+            Some("https://api.digitalocean.com/v2/account/keys?page=2&per_page=200")
+        );
+        let j = serde_json::json!({ "ssh_keys": [], "links": {}, "meta": { "total": 1 } });
+        assert_eq!(next_page(&j), None);
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        assert_eq!(backoff(0), time::Duration::from_millis(500));
+        assert_eq!(backoff(3), time::Duration::from_secs(4));
+    }
+
+    #[test]
+    fn posts_retried_only_if_unprocessed() {
+        use reqwest::{Method, StatusCode};
+        let unavailable = Some(StatusCode::SERVICE_UNAVAILABLE);
+        // The droplet may exist already, so a retry could create a second.
+        assert!(!retryable(&Method::POST, unavailable, true));
+        assert!(!retryable(&Method::POST, None, true));
+        assert!(retryable(&Method::POST, None, false));
+        assert!(retryable(
+            &Method::POST,
+            Some(StatusCode::TOO_MANY_REQUESTS),
+            true
+        ));
+        assert!(retryable(&Method::GET, unavailable, true));
+        assert!(retryable(&Method::DELETE, None, true));
+        assert!(!retryable(&Method::GET, Some(StatusCode::NOT_FOUND), true));
+    }
+}
@@ -0,0 +1,173 @@
+//! Logic to create a DigitalOcean Cloud Firewall for the Droplet,
+//! so that only the exposed services, SSH, and Wireguard are reachable
+//! from the internet. The firewall is destroyed along with the Droplet.
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config::ServicePort;
+use crate::server::digitalocean::client::DoApiClient;
+/// Source and destination addresses for rules, i.e. anywhere.
+const ALL_ADDRESSES: [&str; 2] = ["0.0.0.0/0", "::/0"];
+
+#[derive(Clone, Debug, Deserialize)]
+/// Representation of a Cloud Firewall, as defined by the DigitalOcean API.
+/// For more information, see
+/// <https://docs.digitalocean.com/reference/api/api-reference/#tag/Firewalls>.
+pub struct Firewall {
+    /// UUID, created automatically by the DigitalOcean API, for this firewall.
+    pub id: String,
+    /// Human-readable name for the firewall.
+    pub name: String,
+    /// IDs of the Droplets to which the firewall is applied.
+    #[serde(default)]
+    pub droplet_ids: Vec<u32>,
+}
+
+/// Retrieves all Cloud Firewalls on the DigitalOcean account.
+pub async fn get_all_firewalls() -> Result<Vec<Firewall>> {
+    let firewalls = DoApiClient::new()?
+        .get_all_pages("/firewalls", &[], "firewalls")
+        .await?;
+    let firewalls: Vec<Firewall> = serde_json::from_value(serde_json::Value::Array(firewalls))?;
+    Ok(firewalls)
+}
+
+/// Builds the inbound rules for the firewall: SSH, Wireguard on `wg_port`,
+/// and the exposed services, all reachable from anywhere.
+fn inbound_rules(services: &[ServicePort], wg_port: i32) -> Vec<serde_json::Value> {
+    let mut rules = vec![inbound_rule(22, "tcp"), inbound_rule(wg_port, "udp")];
+    rules.extend(service_rules(services));
+    rules
+}
+
+/// Builds inbound rules for the exposed services alone.
+fn service_rules(services: &[ServicePort]) -> Vec<serde_json::Value> {
+    services
+        .iter()
+        .map(|s| inbound_rule(s.port, s.protocol.lowercase()))
+        .collect()
+}
+
+/// Builds a single inbound rule, allowing a port from anywhere.
+fn inbound_rule(port: i32, protocol: &str) -> serde_json::Value {
+    json!({
+        "protocol": protocol,
+        "ports": port.to_string(),
+        "sources": { "addresses": ALL_ADDRESSES },
+    })
+}
+
+/// Builds the outbound rules for the firewall. Once a firewall is applied,
+/// outbound traffic is denied unless allowed, so permit all of it.
+fn outbound_rules() -> Vec<serde_json::Value> {
+    ["tcp", "udp", "icmp"]
+        .iter()
+        .map(|protocol| {
+            let mut rule = json!({
+                "protocol": protocol,
+                "destinations": { "addresses": ALL_ADDRESSES },
+            });
+            if *protocol != "icmp" {
+                rule["ports"] = json!("0");
+            }
+            rule
+        })
+        .collect()
+}
+
+impl Firewall {
+    /// Builds the request body to create the firewall `name`, for the given Droplet.
+    pub fn create_body<T: Serialize>(
+        name: &str,
+        droplet_id: T,
+        services: &[ServicePort],
+        wg_port: i32,
+    ) -> serde_json::Value {
+        json!({
+            "name": name,
+            "inbound_rules": inbound_rules(services, wg_port),
+            "outbound_rules": outbound_rules(),
+            "droplet_ids": [droplet_id],
+        })
+    }
+
+    /// Creates a new Cloud Firewall via the API, and applies it
+    /// to the Droplet specified by `droplet_id`.
+    pub async fn new(
+        name: &str,
+        droplet_id: u32,
+        services: &[ServicePort],
+        wg_port: i32,
+    ) -> Result<Firewall> {
+        let req_body = Firewall::create_body(name, droplet_id, services, wg_port);
+
+        tracing::debug!("Creating firewall for droplet...");
+        let j = DoApiClient::new()?
+            .post("/firewalls", &req_body)
+            .await
+            .context("Failed to create firewall")?;
+        let firewall: Firewall = serde_json::from_value(j["firewall"].clone())?;
+        Ok(firewall)
+    }
+
+    /// Allows inbound traffic to the services, e.g. after
+    /// they're added to a running tunnel.
+    pub async fn add_rules(&self, services: &[ServicePort]) -> Result<()> {
+        DoApiClient::new()?
+            .post(
+                &format!("/firewalls/{}/rules", self.id),
+                &json!({ "inbound_rules": service_rules(services) }),
+            )
+            .await
+            .context("Failed to add firewall rules")?;
+        Ok(())
+    }
+
+    /// Stops allowing inbound traffic to the services, e.g. after
+    /// they're removed from a running tunnel.
+    pub async fn remove_rules(&self, services: &[ServicePort]) -> Result<()> {
+        DoApiClient::new()?
+            .delete_with_body(
+                &format!("/firewalls/{}/rules", self.id),
+                &json!({ "inbound_rules": service_rules(services) }),
+            )
+            .await
+            .context("Failed to remove firewall rules")
+    }
+
+    /// Delete the Firewall via the API.
+    pub async fn destroy(&self) -> Result<()> {
+        tracing::debug!("Deleting firewall from DigitalOcean...");
+        DoApiClient::new()?
+            .delete(&format!("/firewalls/{}", self.id))
+            .await
+            .context("Failed to delete firewall")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn firewall_rules_allow_only_services() -> Result<()> {
+        let services = ServicePort::from_str_multi("80/TCP,53/UDP")?;
+        let rules = inbound_rules(&services, 51820);
+        let allowed: Vec<(String, String)> = rules
+            .iter()
+            .map(|r| (r["protocol"].to_string(), r["ports"].to_string()))
+            .collect();
+        assert_eq!(
+            allowed,
+            vec![
+                ("\"tcp\"".to_string(), "\"22\"".to_string()),
+                ("\"udp\"".to_string(), "\"51820\"".to_string()),
+                ("\"tcp\"".to_string(), "\"80\"".to_string()),
+                ("\"udp\"".to_string(), "\"53\"".to_string()),
+            ]
+        );
+        assert_eq!(outbound_rules().len(), 3);
+        Ok(())
+    }
+}
@@ -1,12 +1,15 @@
 //! Logic to assign a pre-existing Floating IP resource in DigitalOcean
 //! to the Droplet used for managing the tunnel. Allows for DNS records
 //! to remain unchanged, but the tunnel to be rebuilt ad-hoc.
-use anyhow::{Context, Result};
+//! Floating IPs can also be reserved automatically, via [reserve],
+//! in which case they're recorded locally and reused across runs.
+use anyhow::{anyhow, Context, Result};
 use serde_json::json;
-use std::env;
 use std::net::IpAddr;
+use std::path::PathBuf;
 
-const DO_API_BASE_URL: &str = "https://api.digitalocean.com/v2/floating_ips";
+use crate::config::make_config_dir;
+use crate::server::digitalocean::client::{DoApiClient, DoApiError};
 
 /// Represents a DigitalOcean Reserved IP (FKA Floating IP).
 /// In order to use a Floating IP with DigitalOcean, first create it out of band,
@@ -25,24 +28,81 @@ impl FloatingIp {
     /// Attaches the Floating IP to the Droplet specified by [FloatingIp::droplet_id].
     /// Requires that the Floating IP already exists.
     pub async fn assign(&self) -> Result<()> {
-        let api_key =
-            env::var("DIGITALOCEAN_API_TOKEN").context("DIGITALOCEAN_API_TOKEN not set.")?;
         let req_body = json!({
             "type": "assign",
             "droplet_id": self.droplet_id,
         });
-        let request_url = DO_API_BASE_URL.to_owned() + "/" + &self.ip.to_string() + "/actions";
-
-        let client = reqwest::Client::new();
-        client
-            .post(request_url)
-            .json(&req_body)
-            .bearer_auth(api_key)
-            .send()
-            .await
-            .context("Network error, check connection")?;
-
         tracing::debug!("Assigning floating IP to droplet...");
+        DoApiClient::new()?
+            .post(&format!("/floating_ips/{}/actions", self.ip), &req_body)
+            .await
+            .context("Failed to assign floating IP, make sure it exists in the account")?;
         Ok(())
     }
 }
+
+/// Path to the file recording the reserved IP for the tunnel `name`.
+/// Stored outside the tunnel's config dir, which is removed on clean,
+/// so that the IP survives across runs.
+fn reserved_ip_path(name: &str) -> Result<PathBuf> {
+    Ok(make_config_dir(".reserved-ips")?.join(name))
+}
+
+/// Looks up the IP reserved for the tunnel `name`, if any.
+pub fn recorded_ip(name: &str) -> Result<Option<IpAddr>> {
+    let fpath = reserved_ip_path(name)?;
+    if !fpath.exists() {
+        return Ok(None);
+    }
+    let ip = std::fs::read_to_string(&fpath)
+        .with_context(|| format!("Failed to read {}", fpath.display()))?;
+    Ok(Some(ip.trim().parse()?))
+}
+
+/// Returns the IP reserved for the tunnel `name`, creating a new
+/// Floating IP in `region` via the API if none has been reserved yet.
+pub async fn reserve(name: &str, region: &str) -> Result<IpAddr> {
+    if let Some(ip) = recorded_ip(name)? {
+        tracing::debug!("Reusing reserved IP {}", ip);
+        return Ok(ip);
+    }
+    tracing::debug!("Reserving new floating IP in {}...", region);
+    let j = DoApiClient::new()?
+        .post("/floating_ips", &json!({ "region": region }))
+        .await
+        .context("Failed to reserve floating IP")?;
+    let ip: IpAddr = j["floating_ip"]["ip"]
+        .as_str()
+        .ok_or_else(|| anyhow!("No IP in API response"))?
+        .parse()?;
+    std::fs::write(reserved_ip_path(name)?, ip.to_string())
+        .context("Failed to record reserved IP")?;
+    Ok(ip)
+}
+
+/// Deletes the IP reserved for the tunnel `name` via the API,
+/// and forgets it locally. Returns the released IP, if there was one.
+pub async fn release(name: &str) -> Result<Option<IpAddr>> {
+    let ip = match recorded_ip(name)? {
+        Some(ip) => ip,
+        None => return Ok(None),
+    };
+    let client = DoApiClient::new()?;
+    let request_path = format!("/floating_ips/{}", ip);
+    // The IP may still be assigned to a droplet, so unassign it first.
+    // Ignore errors, since it's fine if it's already unassigned.
+    let _ = client
+        .post(
+            &(request_path.to_owned() + "/actions"),
+            &json!({ "type": "unassign" }),
+        )
+        .await;
+    match client.delete(&request_path).await {
+        Ok(()) => {}
+        // The IP was already deleted out of band.
+        Err(e) if matches!(e.downcast_ref(), Some(DoApiError::NotFound(_))) => {}
+        Err(e) => return Err(e.context("Failed to release floating IP")),
+    }
+    std::fs::remove_file(reserved_ip_path(name)?)?;
+    Ok(Some(ip))
+}
@@ -0,0 +1,102 @@
+//! Logic to find DigitalOcean resources created by innisfree that were
+//! never cleaned up, e.g. because the process was killed before it could
+//! tear down the tunnel. A resource is considered orphaned if it's tagged
+//! for a tunnel that has no local config dir on this machine.
+
+use anyhow::Result;
+
+use crate::config::config_dir_exists;
+use crate::pool;
+use crate::server::digitalocean::firewall::{get_all_firewalls, Firewall};
+use crate::server::digitalocean::server::destroy_droplet;
+use crate::server::digitalocean::ssh_key::{get_all_keys, DigitalOceanSshKey};
+use crate::server::digitalocean::tags::{get_managed_droplets, tunnel_name, TaggedDroplet};
+
+/// Collection of orphaned resources, as found by [Orphans::find].
+#[derive(Debug, Default)]
+pub struct Orphans {
+    /// Droplets tagged for a tunnel without local config.
+    pub droplets: Vec<TaggedDroplet>,
+    /// SSH keys labeled for a tunnel without local config.
+    pub ssh_keys: Vec<DigitalOceanSshKey>,
+    /// Firewalls applied only to orphaned droplets, or to none at all.
+    pub firewalls: Vec<Firewall>,
+}
+
+/// Checks whether a tunnel name belongs to a tunnel unknown on this machine,
+/// whose servers aren't parked or claimed by another tunnel either.
+fn is_orphaned(tunnel: Option<String>) -> Result<bool> {
+    match tunnel {
+        Some(t) => Ok(!config_dir_exists(&t)? && !pool::in_use(&t)?),
+        None => Ok(false),
+    }
+}
+
+impl Orphans {
+    /// Queries the API for all resources managed by innisfree,
+    /// and collects those without a corresponding local config dir.
+    pub async fn find() -> Result<Orphans> {
+        let mut orphans = Orphans::default();
+        for d in get_managed_droplets().await? {
+            if is_orphaned(tunnel_name(d.tags.iter().map(|t| t.as_str())))? {
+                orphans.droplets.push(d);
+            }
+        }
+        for k in get_all_keys().await? {
+            if is_orphaned(tunnel_name(k.name.split(' ')))? {
+                orphans.ssh_keys.push(k);
+            }
+        }
+        // Firewalls can't be tagged, so match them by name and droplets instead.
+        for f in get_all_firewalls().await? {
+            let name_is_tunnel = f.name == "innisfree" || f.name.starts_with("innisfree-");
+            let all_orphaned = f
+                .droplet_ids
+                .iter()
+                .all(|id| orphans.droplets.iter().any(|d| d.id == *id));
+            if name_is_tunnel
+                && all_orphaned
+                && !config_dir_exists(&f.name)?
+                && !pool::in_use(&f.name)?
+            {
+                orphans.firewalls.push(f);
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// Whether no orphaned resources were found.
+    pub fn is_empty(&self) -> bool {
+        self.droplets.is_empty() && self.ssh_keys.is_empty() && self.firewalls.is_empty()
+    }
+
+    /// Human-readable summary of the orphaned resources, one per line.
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec![];
+        for d in &self.droplets {
+            lines.push(format!("droplet  {}  {}", d.id, d.name));
+        }
+        for k in &self.ssh_keys {
+            lines.push(format!("ssh-key  {}  {}", k.id, k.name));
+        }
+        for f in &self.firewalls {
+            lines.push(format!("firewall  {}  {}", f.id, f.name));
+        }
+        lines
+    }
+
+    /// Destroys all orphaned resources. Firewalls are removed first,
+    /// then SSH keys, then droplets.
+    pub async fn destroy(&self) -> Result<()> {
+        for f in &self.firewalls {
+            f.destroy().await?;
+        }
+        for k in &self.ssh_keys {
+            k.destroy().await?;
+        }
+        for d in &self.droplets {
+            destroy_droplet(d.id).await?;
+        }
+        Ok(())
+    }
+}
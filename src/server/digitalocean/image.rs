@@ -0,0 +1,132 @@
+//! Logic to build a prebuilt "golden" image for DigitalOcean, so that
+//! tunnel servers can skip package installation on boot. A Droplet is
+//! provisioned once with the required packages, powered off, and snapshotted.
+//! The snapshot ID is recorded locally, and used by subsequent runs of `up`.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use std::path::PathBuf;
+use std::thread;
+use std::time;
+
+use crate::config::make_config_dir;
+use crate::server::cloudinit::generate_image_user_data;
+use crate::server::digitalocean::client::DoApiClient;
+use crate::server::digitalocean::server::{
+    destroy_droplet, DigitalOceanProvider, DropletConfig, DO_IMAGE,
+};
+use crate::server::digitalocean::ssh_key::DigitalOceanSshKey;
+use crate::server::digitalocean::tags::tags_for;
+use crate::ssh::SshKeypair;
+
+/// Name for the temporary Droplet, and prefix for the snapshot.
+const IMAGE_NAME: &str = "innisfree-image";
+
+/// Path to the file recording the prebuilt image ID for `region`.
+/// Snapshots are region-specific, so one is recorded per region.
+fn image_record_path(region: &str) -> Result<PathBuf> {
+    Ok(make_config_dir(".images")?.join(format!("digitalocean-{}", region)))
+}
+
+/// Looks up the prebuilt image ID for `region`, if one has been built
+/// via [build_image].
+pub fn recorded_image(region: &str) -> Result<Option<String>> {
+    let fpath = image_record_path(region)?;
+    if !fpath.exists() {
+        return Ok(None);
+    }
+    let image = std::fs::read_to_string(&fpath)
+        .with_context(|| format!("Failed to read {}", fpath.display()))?;
+    Ok(Some(image.trim().to_string()))
+}
+
+/// Provisions a Droplet with all packages required by innisfree installed,
+/// snapshots it, and records the snapshot ID for the provider's region.
+/// The temporary Droplet and SSH key are destroyed afterwards.
+/// Returns the snapshot ID.
+pub async fn build_image(provider: &DigitalOceanProvider) -> Result<String> {
+    tracing::info!("Creating temporary droplet for image build");
+    // An SSH key is required to suppress the root password email,
+    // but is otherwise unused: the droplet powers itself off when ready.
+    let ssh_keypair = SshKeypair::new("image")?;
+    let do_ssh_key = DigitalOceanSshKey::new(IMAGE_NAME, &ssh_keypair.public).await?;
+    let droplet_config = DropletConfig {
+        name: IMAGE_NAME.to_string(),
+        user_data: generate_image_user_data()?,
+        ssh_keys: vec![do_ssh_key.id],
+        tags: tags_for(IMAGE_NAME),
+        // Always start from a public image, not a previous snapshot.
+        image: DO_IMAGE.to_string(),
+        ..provider.droplet_config()
+    };
+    let j = DoApiClient::new()?
+        .post("/droplets", &droplet_config)
+        .await?;
+    let droplet_id = j["droplet"]["id"]
+        .as_u64()
+        .ok_or_else(|| anyhow!("No droplet ID in API response"))?;
+
+    let result = snapshot_droplet(droplet_id).await;
+    // Clean up regardless of outcome, since the droplet is no longer needed.
+    let _ = destroy_droplet(droplet_id as u32).await;
+    let _ = do_ssh_key.destroy().await;
+    let image = result?;
+
+    let fpath = image_record_path(&provider.region)?;
+    std::fs::write(&fpath, &image).context("Failed to record image ID")?;
+    tracing::debug!("Recorded image ID in {}", fpath.display());
+    Ok(image)
+}
+
+/// Waits for cloudinit to power off the Droplet, then snapshots it.
+/// Returns the snapshot ID.
+async fn snapshot_droplet(droplet_id: u64) -> Result<String> {
+    let client = DoApiClient::new()?;
+    // Package installation happens on first boot; cloudinit will power off
+    // the droplet once it's done.
+    loop {
+        thread::sleep(time::Duration::from_secs(10));
+        let j = client.get(&format!("/droplets/{}", droplet_id)).await?;
+        if j["droplet"]["status"] == "off" {
+            break;
+        }
+        tracing::info!("Image droplet still installing packages, waiting...");
+    }
+
+    let timestamp = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)?
+        .as_secs();
+    let snapshot_name = format!("{}-{}", IMAGE_NAME, timestamp);
+    tracing::info!("Creating snapshot '{}'", snapshot_name);
+    let j = client
+        .post(
+            &format!("/droplets/{}/actions", droplet_id),
+            &json!({ "type": "snapshot", "name": snapshot_name }),
+        )
+        .await?;
+    let action_id = j["action"]["id"]
+        .as_u64()
+        .ok_or_else(|| anyhow!("No action ID in API response"))?;
+    loop {
+        thread::sleep(time::Duration::from_secs(10));
+        let j = client.get(&format!("/actions/{}", action_id)).await?;
+        match j["action"]["status"].as_str() {
+            Some("completed") => break,
+            Some("errored") => return Err(anyhow!("Snapshot action failed")),
+            _ => tracing::info!("Snapshot still in progress, waiting..."),
+        }
+    }
+
+    let j = client
+        .get(&format!("/droplets/{}/snapshots", droplet_id))
+        .await?;
+    let snapshots = j["snapshots"]
+        .as_array()
+        .ok_or_else(|| anyhow!("No snapshots in API response"))?;
+    snapshots
+        .iter()
+        .find(|s| s["name"] == snapshot_name.as_str())
+        .and_then(|s| s["id"].as_u64())
+        .map(|id| id.to_string())
+        .ok_or_else(|| anyhow!("Snapshot '{}' not found", snapshot_name))
+}
@@ -2,6 +2,13 @@
 //! Right now, this is the only supported cloud provider,
 //! but more may be added in the future.
 
+pub mod client;
+pub mod firewall;
 pub mod floating_ip;
+pub mod gc;
+pub mod image;
+pub mod project;
 pub mod server;
 pub mod ssh_key;
+pub mod tags;
+pub mod vpc;
@@ -0,0 +1,78 @@
+//! Logic to assign the Droplet to a pre-existing DigitalOcean project.
+//! Keeps ephemeral innisfree servers separate from other infrastructure
+//! in the web console, rather than landing in the default project.
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::server::digitalocean::client::DoApiClient;
+
+#[derive(Clone, Debug, Deserialize)]
+/// Representation of a project, as defined by the DigitalOcean API.
+/// The project must be created out of band, e.g. via the web console,
+/// then passed in on the CLI with `--do-project`.
+/// For more information, see
+/// <https://docs.digitalocean.com/reference/api/api-reference/#tag/Projects>.
+pub struct Project {
+    /// UUID, created automatically by the DigitalOcean API, for this project.
+    pub id: String,
+    /// Human-readable name for the project, unique within the account.
+    pub name: String,
+}
+
+impl Project {
+    /// Returns the API path for the resources in the project `id`.
+    pub fn resources_path(id: &str) -> String {
+        format!("/projects/{}/resources", id)
+    }
+
+    /// Looks up the project named `name` via the API.
+    /// Fails if no such project exists.
+    pub async fn get(name: &str) -> Result<Project> {
+        let projects = DoApiClient::new()?
+            .get_all_pages("/projects", &[], "projects")
+            .await?;
+        let projects: Vec<Project> = serde_json::from_value(serde_json::Value::Array(projects))?;
+        find_project(projects, name)
+    }
+
+    /// Moves the Droplet specified by `droplet_id` into this project.
+    pub async fn assign_droplet(&self, droplet_id: u32) -> Result<()> {
+        tracing::debug!("Assigning droplet to project '{}'...", self.name);
+        DoApiClient::new()?
+            .post(&Project::resources_path(&self.id), &assign_body(droplet_id))
+            .await
+            .context("Failed to assign droplet to project")?;
+        Ok(())
+    }
+}
+
+/// Builds the request body to assign a Droplet to a project.
+pub fn assign_body<T: std::fmt::Display>(droplet_id: T) -> serde_json::Value {
+    json!({ "resources": [format!("do:droplet:{}", droplet_id)] })
+}
+
+/// Picks the project named `name` from a list of projects.
+fn find_project(projects: Vec<Project>, name: &str) -> Result<Project> {
+    projects
+        .into_iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| anyhow!("DigitalOcean project '{}' not found", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_found_by_name() -> Result<()> {
+        let projects: Vec<Project> = serde_json::from_value(json!([
+            { "id": "4e1bfbc3-dc3e-41f2-a18f-1b4d7ba71679", "name": "prod", "is_default": true },
+            { "id": "a1b2c3d4-0000-41f2-a18f-1b4d7ba71679", "name": "tunnels" },
+        ]))?;
+        let p = find_project(projects.clone(), "tunnels")?;
+        assert_eq!(p.id, "a1b2c3d4-0000-41f2-a18f-1b4d7ba71679");
+        assert!(find_project(projects, "staging").is_err());
+        Ok(())
+    }
+}
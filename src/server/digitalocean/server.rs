@@ -7,19 +7,22 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 use anyhow::{anyhow, Context, Result};
-use reqwest;
 use serde;
 use serde_json;
-use std::env;
 use std::net::IpAddr;
-use std::thread;
-use std::time;
+use tokio_util::sync::CancellationToken;
 
 use crate::config::ServicePort;
-use crate::server::cloudinit::generate_user_data;
+use crate::error;
+use crate::server::cloudinit::{generate_user_data, prebuilt_user_data, CloudConfigOptions};
+use crate::server::digitalocean::client::{api_url, DoApiClient};
+use crate::server::digitalocean::firewall::{get_all_firewalls, Firewall};
 use crate::server::digitalocean::floating_ip::FloatingIp;
-use crate::server::digitalocean::ssh_key::DigitalOceanSshKey;
-use crate::server::InnisfreeServer;
+use crate::server::digitalocean::project::{assign_body, Project};
+use crate::server::digitalocean::ssh_key::{get_tagged_keys, DigitalOceanSshKey};
+use crate::server::digitalocean::tags::{get_tagged_droplets, tags_for};
+use crate::server::digitalocean::vpc::Vpc;
+use crate::server::{poll_interval, ApiRequest, InnisfreeServer, ServerProvider};
 use crate::ssh::SshKeypair;
 use crate::wg::WireguardManager;
 
@@ -32,7 +35,6 @@ pub const DO_SIZE: &str = "s-1vcpu-1gb";
 /// The OS choice for to base the Droplet on. Defaults to most recent Debian Stable.
 /// See docs for more info: <https://docs.digitalocean.com/reference/api/api-reference/#tag/Images>.
 pub const DO_IMAGE: &str = "debian-11-x64";
-const DO_API_BASE_URL: &str = "https://api.digitalocean.com/v2/droplets";
 
 /// Representation of a DigitalOcean Droplet, i.e. cloud VM.
 /// See more documentation at
@@ -41,15 +43,15 @@ const DO_API_BASE_URL: &str = "https://api.digitalocean.com/v2/droplets";
 pub struct Droplet {
     /// Numeric ID, returned by API, to identify this Droplet.
     pub id: u32,
-    // Human-readable name for Droplet, also its hostname.
-    // name: String,
+    /// Human-readable name for Droplet, also its hostname.
+    pub name: String,
     /// Current state of server. Is `new` when booting, changes
     /// to `active` once host is booted and networking info is populated.
     pub status: String,
     /// Information about host networking, such as public and private
     /// interfaces and their corresponding IPv4/6 addresses. Use [Droplet::ipv4_address]
     /// to obtain an IP address easily.
-    networks: HashMap<String, Vec<HashMap<String, String>>>,
+    networks: HashMap<String, Vec<DropletNetwork>>,
     // The API takes a list, but we only care about 1 key,
     // the generated one, so use that.
     /// Optional dynamically generated SSH keypair, stored in cloud,
@@ -57,31 +59,56 @@ pub struct Droplet {
     /// passwords on instance creation).
     // TODO: Make this mandatory, since it's automatically created anyway.
     ssh_pubkey: Option<DigitalOceanSshKey>,
+    /// Cloud Firewall restricting inbound traffic to the exposed services,
+    /// SSH, and Wireguard. Created after the Droplet, and destroyed with it.
+    firewall: Option<Firewall>,
+}
+
+#[derive(Debug, Deserialize)]
+/// A network interface address on a Droplet, as listed under `networks`.
+struct DropletNetwork {
+    /// The IPv4 or IPv6 address on the interface.
+    ip_address: IpAddr,
+    /// Either `public` or `private`.
+    #[serde(rename = "type")]
+    network_type: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 /// Template for building a request to create a new Droplet.
 pub struct DropletConfig {
     /// The OS image used for creating the remote server. Defaults to [`DO_IMAGE`].
+    /// May be either a slug, or the numeric ID of a snapshot, as created via
+    /// [crate::server::digitalocean::image::build_image].
+    #[serde(serialize_with = "serialize_image")]
     pub image: String,
     /// Human-readable name for Droplet. Defaults to `innisfree`.
-    name: String,
+    pub name: String,
     /// The cloud region in which the server will be created. Defaults to [`DO_REGION`].
-    region: String,
+    pub region: String,
     /// The type of machine that will be created. Defaults to [`DO_SIZE`].
     /// See documentation for more options.
-    size: String,
+    pub size: String,
     /// Serialized content for a cloud-init YAML file.
     /// The [crate::manager::TunnelManager] will handle automatically generating
     /// cloud-init content with appropriate key material, via
     /// [crate::server::cloudinit::CloudConfig].
     /// See documentation for more information: <https://cloudinit.readthedocs.io/en/latest/>.
-    user_data: String,
+    pub user_data: String,
     /// List of SSH key IDs, as reported by the DigitalOcean API, for use
     /// during Droplet creation. Providing an SSH key ID during creation
     /// prevents emails from being sent to the account owner, providing
     /// a root password for the instance.
-    ssh_keys: Vec<u32>,
+    pub ssh_keys: Vec<u32>,
+    /// Tags applied to the Droplet, to identify it as managed by innisfree.
+    /// See [crate::server::digitalocean::tags].
+    pub tags: Vec<String>,
+    /// Whether to assign a public IPv6 address to the Droplet. Defaults to `true`.
+    pub ipv6: bool,
+    /// UUID of a pre-existing VPC in which to place the Droplet.
+    /// If unset, the Droplet is placed in the region's default VPC.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vpc_uuid: Option<String>,
 }
 
 impl DropletConfig {
@@ -89,6 +116,45 @@ impl DropletConfig {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Fills in the request for the tunnel `name`, booting with `user_data`,
+    /// which is trimmed down if the image is a prebuilt snapshot. The Droplet
+    /// is named `hostname`, if set, else after the tunnel. DigitalOcean sets
+    /// reverse DNS for its IPs to the name, if it's fully qualified.
+    pub fn for_tunnel(
+        self,
+        name: &str,
+        hostname: Option<&str>,
+        user_data: &str,
+        ssh_keys: Vec<u32>,
+    ) -> Result<Self> {
+        let user_data = if is_snapshot(&self.image) {
+            prebuilt_user_data(user_data)?
+        } else {
+            user_data.to_string()
+        };
+        Ok(DropletConfig {
+            name: hostname.unwrap_or(name).to_string(),
+            user_data,
+            ssh_keys,
+            tags: tags_for(name),
+            ..self
+        })
+    }
+}
+
+/// Checks whether an image refers to a snapshot, by numeric ID,
+/// rather than to a public image, by slug.
+pub fn is_snapshot(image: &str) -> bool {
+    image.parse::<u64>().is_ok()
+}
+
+/// The API expects snapshot IDs as integers, and slugs as strings.
+fn serialize_image<S: serde::Serializer>(image: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    match image.parse::<u64>() {
+        Ok(id) => serializer.serialize_u64(id),
+        Err(_) => serializer.serialize_str(image),
+    }
 }
 
 impl Default for DropletConfig {
@@ -100,21 +166,186 @@ impl Default for DropletConfig {
             size: DO_SIZE.to_string(),
             user_data: String::default(),
             ssh_keys: vec![],
+            tags: vec![],
+            ipv6: true,
+            vpc_uuid: None,
         }
     }
 }
 
 impl Droplet {
+    /// Make an API request and create a new DigitalOcean droplet.
+    /// The `provider` determines the region, size, image, VPC, and project;
+    /// the name, user data, and SSH keys are filled in automatically.
+    /// Blocks until the server is "ready", which usually takes about 60 seconds.
+    pub async fn new(
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        provider: &DigitalOceanProvider,
+    ) -> Result<Droplet> {
+        Droplet::create(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+            provider,
+            &CancellationToken::new(),
+        )
+        .await
+    }
+
+    /// Creates the droplet, as [Droplet::new] does, giving up and destroying
+    /// it if `cancel` fires before it has booted. Independent steps overlap:
+    /// the API lookups before creating anything, generating the user data
+    /// and uploading the SSH key, then attaching the firewall and project
+    /// while the droplet boots.
+    #[allow(clippy::too_many_arguments)]
+    async fn create(
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        provider: &DigitalOceanProvider,
+        cancel: &CancellationToken,
+    ) -> Result<Droplet> {
+        tracing::debug!("Creating new DigitalOcean Droplet");
+        let client = DoApiClient::new()?;
+        let droplet_config = provider.droplet_config();
+        // Look up the VPC and project before creating anything, so a typo fails fast.
+        let vpc = async {
+            match &droplet_config.vpc_uuid {
+                Some(vpc_uuid) => Vpc::get(vpc_uuid).await.map(Some),
+                None => Ok(None),
+            }
+        };
+        let project = async {
+            match &provider.project {
+                Some(p) => Project::get(p).await.map(Some),
+                None => Ok(None),
+            }
+        };
+        let (account, droplets, vpc, project) = tokio::try_join!(
+            client.get("/account"),
+            client.get_with_query("/droplets", &[("per_page", "1")]),
+            vpc,
+            project,
+        )?;
+        check_droplet_count(&account, &droplets, provider.max_droplets)?;
+        if let Some(vpc) = vpc {
+            vpc.validate_region(&droplet_config.region)?;
+            tracing::debug!("Placing droplet in VPC '{}' ({})", vpc.name, vpc.ip_range);
+        }
+        let (user_data, do_ssh_key) = tokio::join!(
+            generate_user_data(
+                ssh_client_keypair,
+                ssh_server_keypair,
+                &wg_mgr,
+                &services,
+                options,
+            ),
+            DigitalOceanSshKey::new(name, &ssh_client_keypair.public),
+        );
+        let (user_data, do_ssh_key) = match (user_data, do_ssh_key) {
+            (Ok(u), Ok(k)) => (u, k),
+            (Err(e), Ok(k)) => {
+                if let Err(e) = k.destroy().await {
+                    tracing::warn!("{:#}", e);
+                }
+                return Err(e);
+            }
+            (_, Err(e)) => return Err(e),
+        };
+        // Build JSON request body, for sending to DigitalOcean API
+        let droplet_config = droplet_config.for_tunnel(
+            name,
+            options.hostname.as_deref(),
+            &user_data,
+            vec![do_ssh_key.id],
+        )?;
+
+        let j = match client.post("/droplets", &droplet_config).await {
+            Ok(j) => j,
+            Err(e) => {
+                if let Err(e) = do_ssh_key.destroy().await {
+                    tracing::warn!("{:#}", e);
+                }
+                return Err(e);
+            }
+        };
+        let d: String = j["droplet"].to_string();
+        let mut droplet: Droplet = serde_json::from_str(&d)?;
+        // Add SSH key info after creation, since JSON response won't include it,
+        // even though JSON request did. We'll need it to clean up in `self.destroy`.
+        droplet.ssh_pubkey = Some(do_ssh_key);
+        tracing::debug!("Server created, waiting for networking");
+        let public_ports = options.public_ports(&services);
+        let (firewall, assigned, booted) = tokio::join!(
+            Firewall::new(
+                name,
+                droplet.id,
+                &public_ports,
+                wg_mgr.wg_remote_device.interface.listenport,
+            ),
+            async {
+                match &project {
+                    Some(p) => p.assign_droplet(droplet.id).await,
+                    None => Ok(()),
+                }
+            },
+            droplet.wait_for_boot(cancel),
+        );
+        if let Ok(f) = &firewall {
+            droplet.firewall = Some(f.clone());
+        }
+        match (firewall, assigned, booted) {
+            (Ok(f), Ok(()), Ok(mut booted)) => {
+                booted.firewall = Some(f);
+                Ok(booted)
+            }
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                if let Err(e) = droplet.destroy().await {
+                    tracing::warn!("Failed to destroy droplet {}: {:#}", droplet.id, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Finds the public address among the Droplet's `networks`
+    /// for the given family, either `v4` or `v6`.
+    fn public_address(&self, family: &str) -> Result<Option<IpAddr>> {
+        Ok(self
+            .networks
+            .get(family)
+            .and_then(|n| n.iter().find(|n| n.network_type == "public"))
+            .map(|n| n.ip_address))
+    }
+
     /// Block until a droplet is running. Upon creation, the API will
     /// return a result where `status="new"`. This method blocks until
-    /// the API reports `state="running"`.
-    async fn wait_for_boot(&self) -> Result<Droplet> {
+    /// the API reports `state="running"`, or `cancel` fires.
+    async fn wait_for_boot(&self, cancel: &CancellationToken) -> Result<Droplet> {
         // The JSON response for droplet creation won't include info like
         // public IPv4 address, because that hasn't been assigned yet. The 'status'
         // field will show as "new", so wait until it's "active", then network info
         // will be populated. Might be a good use of enums here.
+        let mut attempt = 0;
         loop {
-            thread::sleep(time::Duration::from_secs(10));
+            tokio::select! {
+                _ = cancel.cancelled() => {
+                    return Err(error::cancelled("Cancelled while waiting for droplet boot"));
+                }
+                _ = tokio::time::sleep(poll_interval(attempt)) => {}
+            }
+            attempt += 1;
             match get_droplet(self).await {
                 Ok(droplet) => {
                     if droplet.status == "active" {
@@ -134,65 +365,31 @@ impl Droplet {
 
 #[async_trait]
 impl InnisfreeServer for Droplet {
-    /// Make an API request and create a new DigitalOcean droplet.
-    /// Blocks until the server is "ready", which usually takes about 60 seconds.
-    async fn new(
-        name: &str,
-        services: Vec<ServicePort>,
-        wg_mgr: WireguardManager,
-        ssh_client_keypair: &SshKeypair,
-        ssh_server_keypair: &SshKeypair,
-    ) -> Result<Self> {
-        tracing::debug!("Creating new DigitalOcean Droplet");
-        let user_data =
-            generate_user_data(ssh_client_keypair, ssh_server_keypair, &wg_mgr, &services).await?;
-        let do_ssh_key =
-            DigitalOceanSshKey::new(name, &ssh_client_keypair.public.to_owned()).await?;
-        let ssh_keys: Vec<u32> = vec![do_ssh_key.id];
-        // Build JSON request body, for sending to DigitalOcean API
-        let droplet_config = DropletConfig {
-            name: name.to_string(),
-            user_data: user_data.to_string(),
-            ssh_keys,
-            ..DropletConfig::new()
-        };
+    /// Retrieves the public IPv4 address for the Droplet.
+    /// Technically can fail, if results are missing from the API response.
+    fn ipv4_address(&self) -> Result<IpAddr> {
+        self.public_address("v4")?
+            .ok_or_else(|| anyhow!("No public IPv4 address found for droplet {}", self.id))
+    }
 
-        // The API logic could be abstracted further, in a DigitalOcean Manager.
-        // Right now we only create Droplet resources, but an API Firewall would be nice.
-        let api_key =
-            env::var("DIGITALOCEAN_API_TOKEN").context("DIGITALOCEAN_API_TOKEN not set.")?;
-        let request_url = DO_API_BASE_URL;
-        let client = reqwest::Client::new();
-
-        let response = client
-            .post(request_url)
-            .json(&droplet_config)
-            .bearer_auth(api_key)
-            .send()
-            .await?
-            .error_for_status()?;
+    fn id(&self) -> String {
+        self.id.to_string()
+    }
 
-        let j: serde_json::Value = response.json().await?;
-        let d: String = j["droplet"].to_string();
-        let mut droplet: Droplet = serde_json::from_str(&d)?;
-        // Add SSH key info after creation, since JSON response won't include it,
-        // even though JSON request did. We'll need it to clean up in `self.destroy`.
-        droplet.ssh_pubkey = Some(do_ssh_key);
-        tracing::debug!("Server created, waiting for networking");
-        droplet.wait_for_boot().await
+    /// Retrieves the public IPv6 address for the Droplet, if IPv6 is enabled.
+    fn ipv6_address(&self) -> Result<Option<IpAddr>> {
+        self.public_address("v6")
     }
 
-    /// Retrieves the public IPv4 address for the Droplet.
-    /// Technically can fail, if results are missing from the API response.
-    fn ipv4_address(&self) -> Result<IpAddr> {
-        let mut s = String::new();
-        for v4_network in &self.networks["v4"] {
-            if v4_network["type"] == "public" {
-                s = v4_network["ip_address"].clone();
-                break;
-            }
-        }
-        Ok(s.parse()?)
+    /// DigitalOcean points the Droplet's reverse DNS at its name, if it's
+    /// fully qualified, e.g. via `--hostname`.
+    fn reverse_dns(&self) -> Option<String> {
+        self.name.contains('.').then(|| self.name.clone())
+    }
+
+    /// Polls the API for the Droplet's latest networking info.
+    async fn refresh_ipv4_address(&self) -> Result<IpAddr> {
+        get_droplet(self).await?.ipv4_address()
     }
 
     async fn assign_floating_ip(&self, floating_ip: IpAddr) -> Result<()> {
@@ -203,48 +400,442 @@ impl InnisfreeServer for Droplet {
         f.assign().await
     }
 
-    /// Calls the API to destroy a droplet.
+    /// Calls the API to destroy a droplet, along with its SSH key and firewall.
     async fn destroy(&self) -> Result<()> {
-        if let Some(k) = &self.ssh_pubkey {
-            k.destroy().await?;
-        } else {
-            tracing::warn!("No API pubkey associated with droplet, not destroying");
+        destroy_tunnel(
+            &self.name,
+            self.id,
+            self.firewall.iter().cloned().collect(),
+            self.ssh_pubkey.iter().cloned().collect(),
+        )
+        .await
+    }
+
+    /// Adds a rule for the service to the droplet's firewall.
+    async fn open_port(&self, service: &ServicePort) -> Result<()> {
+        match &self.firewall {
+            Some(f) => f.add_rules(std::slice::from_ref(service)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the service's rule from the droplet's firewall.
+    async fn close_port(&self, service: &ServicePort) -> Result<()> {
+        match &self.firewall {
+            Some(f) => f.remove_rules(std::slice::from_ref(service)).await,
+            None => Ok(()),
         }
+    }
+}
+
+/// Calls the API to destroy the droplet with the given ID.
+pub async fn destroy_droplet(id: u32) -> Result<()> {
+    DoApiClient::new()?
+        .delete(&format!("/droplets/{}", id))
+        .await
+        .context("Failed to destroy droplet")?;
+
+    tracing::debug!("Droplet {} destroyed", id);
+    Ok(())
+}
+
+/// Destroys the droplet `id`, along with the given firewalls and SSH keys.
+/// Any other droplets or SSH keys tagged for the tunnel `name`, e.g. leaked
+/// by a previous run that never cleaned up, are destroyed as well.
+async fn destroy_tunnel(
+    name: &str,
+    id: u32,
+    firewalls: Vec<Firewall>,
+    mut keys: Vec<DigitalOceanSshKey>,
+) -> Result<()> {
+    for f in firewalls {
+        f.destroy().await?;
+    }
+    match get_tagged_keys(name).await {
+        Ok(k) => keys.extend(k),
+        Err(e) => tracing::warn!("Failed to look up tagged SSH keys: {}", e),
+    }
+    keys.sort_unstable_by_key(|k| k.id);
+    keys.dedup_by_key(|k| k.id);
+    if keys.is_empty() {
+        tracing::warn!("No API pubkey associated with droplet, not destroying");
+    }
+    for k in keys {
+        k.destroy().await?;
+    }
 
-        let api_key =
-            env::var("DIGITALOCEAN_API_TOKEN").context("DIGITALOCEAN_API_TOKEN not set.")?;
-        let request_url = DO_API_BASE_URL.to_owned() + "/" + &self.id.to_string();
+    let mut droplet_ids = vec![id];
+    match get_tagged_droplets(name).await {
+        Ok(droplets) => droplet_ids.extend(droplets.iter().map(|d| d.id)),
+        Err(e) => tracing::warn!("Failed to look up tagged droplets: {}", e),
+    }
+    droplet_ids.sort_unstable();
+    droplet_ids.dedup();
+    for id in droplet_ids {
+        destroy_droplet(id).await?;
+    }
+    Ok(())
+}
 
-        let client = reqwest::Client::new();
-        client
-            .delete(request_url)
-            .bearer_auth(api_key)
-            .send()
+/// Checks that another droplet may be created, from the API's `/account` and
+/// `/droplets` responses: that the account is active, and has fewer droplets
+/// than both its limit and `max`, if any, so that e.g. a script retrying `up`
+/// in a loop can't run up costs.
+fn check_droplet_count(
+    account: &serde_json::Value,
+    droplets: &serde_json::Value,
+    max: Option<u64>,
+) -> Result<()> {
+    let account = &account["account"];
+    if let Some(status) = account["status"].as_str().filter(|s| *s != "active") {
+        return Err(anyhow!("DigitalOcean account is {}", status));
+    }
+    let used = droplets["meta"]["total"].as_u64().unwrap_or_default();
+    if let Some(limit) = account["droplet_limit"].as_u64() {
+        if used >= limit {
+            return Err(anyhow!(
+                "DigitalOcean account has {} of {} droplets, its limit",
+                used,
+                limit
+            ));
+        }
+    }
+    if let Some(max) = max {
+        if used >= max {
+            return Err(anyhow!(
+                "DigitalOcean account has {} droplets, refusing to create more than --max-droplets {}",
+                used,
+                max
+            ));
+        }
+    }
+    tracing::debug!("DigitalOcean account has {} droplets", used);
+    Ok(())
+}
+
+/// Factory for creating [Droplet] servers, registered as `digitalocean`.
+#[derive(Debug)]
+pub struct DigitalOceanProvider {
+    /// The cloud region in which droplets will be created. Defaults to [`DO_REGION`].
+    pub region: String,
+    /// The type of machine that will be created. Defaults to [`DO_SIZE`].
+    pub size: String,
+    /// The OS image used for creating droplets. Defaults to [`DO_IMAGE`].
+    pub image: String,
+    /// UUID of a pre-existing VPC in which to place droplets, if any.
+    pub vpc_uuid: Option<String>,
+    /// Name of a pre-existing project to which droplets are assigned, if any.
+    pub project: Option<String>,
+    /// Most droplets the account may have before creating another is refused,
+    /// if any, below the account's own droplet limit.
+    pub max_droplets: Option<u64>,
+}
+
+impl DigitalOceanProvider {
+    /// Creates a new [DigitalOceanProvider], overriding the default
+    /// region, size, and image where provided.
+    pub fn new(region: Option<String>, size: Option<String>, image: Option<String>) -> Self {
+        let defaults = DigitalOceanProvider::default();
+        DigitalOceanProvider {
+            region: region.unwrap_or(defaults.region),
+            size: size.unwrap_or(defaults.size),
+            image: image.unwrap_or(defaults.image),
+            ..defaults
+        }
+    }
+
+    /// Builds the [DropletConfig] template for droplets created by this provider.
+    pub fn droplet_config(&self) -> DropletConfig {
+        DropletConfig {
+            image: self.image.to_string(),
+            region: self.region.to_string(),
+            size: self.size.to_string(),
+            vpc_uuid: self.vpc_uuid.clone(),
+            ..DropletConfig::new()
+        }
+    }
+}
+
+impl Default for DigitalOceanProvider {
+    fn default() -> Self {
+        DigitalOceanProvider {
+            region: DO_REGION.to_string(),
+            size: DO_SIZE.to_string(),
+            image: DO_IMAGE.to_string(),
+            vpc_uuid: None,
+            project: None,
+            max_droplets: None,
+        }
+    }
+}
+
+#[async_trait]
+impl ServerProvider for DigitalOceanProvider {
+    fn name(&self) -> &'static str {
+        "digitalocean"
+    }
+
+    async fn create(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let server = Droplet::new(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+            self,
+        )
+        .await?;
+        Ok(Box::new(server))
+    }
+
+    /// Stops polling for the droplet's boot as soon as `cancel` fires.
+    async fn create_cancellable(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        cancel: &CancellationToken,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let droplet = Droplet::create(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+            self,
+            cancel,
+        )
+        .await?;
+        Ok(Box::new(droplet))
+    }
+
+    /// The VPC and project are only looked up on creation, so aren't validated.
+    async fn plan(
+        &self,
+        name: &str,
+        user_data: &str,
+        services: &[ServicePort],
+        wg_mgr: &WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<Vec<ApiRequest>> {
+        let mut droplet = serde_json::to_value(self.droplet_config().for_tunnel(
+            name,
+            options.hostname.as_deref(),
+            user_data,
+            vec![],
+        )?)?;
+        droplet["ssh_keys"] = serde_json::json!(["<ssh-key-id>"]);
+        let mut requests = vec![
+            ApiRequest::new(
+                "POST",
+                &api_url("/account/keys"),
+                &DigitalOceanSshKey::create_body(name, &ssh_client_keypair.public),
+            )?,
+            ApiRequest::new("POST", &api_url("/droplets"), &droplet)?,
+            ApiRequest::new(
+                "POST",
+                &api_url("/firewalls"),
+                &Firewall::create_body(
+                    name,
+                    "<droplet-id>",
+                    &options.public_ports(services),
+                    wg_mgr.wg_remote_device.interface.listenport,
+                ),
+            )?,
+        ];
+        if let Some(p) = &self.project {
+            requests.push(ApiRequest::new(
+                "POST",
+                &api_url(&Project::resources_path(&format!(
+                    "<id of project '{}'>",
+                    p
+                ))),
+                &assign_body("<droplet-id>"),
+            )?);
+        }
+        Ok(requests)
+    }
+
+    /// Destroys the droplet, looking up its firewall by name, since
+    /// firewalls can't be tagged.
+    async fn destroy(&self, name: &str, id: &str) -> Result<()> {
+        let id: u32 = id.parse().context("Invalid Droplet ID")?;
+        let firewalls = get_all_firewalls()
             .await?
-            .error_for_status()
-            .context("Failed to destroy droplet")?;
+            .into_iter()
+            .filter(|f| f.name == name && f.droplet_ids.contains(&id))
+            .collect();
+        destroy_tunnel(name, id, firewalls, vec![]).await
+    }
 
-        tracing::debug!("Droplet destroyed");
-        Ok(())
+    async fn server_exists(&self, name: &str, id: &str) -> Result<bool> {
+        let id: u32 = id.parse().context("Invalid Droplet ID")?;
+        Ok(get_tagged_droplets(name).await?.iter().any(|d| d.id == id))
+    }
+
+    async fn adopt(&self, name: &str, id: &str) -> Result<Box<dyn InnisfreeServer>> {
+        let id: u32 = id.parse().context("Invalid Droplet ID")?;
+        let j = DoApiClient::new()?
+            .get(&format!("/droplets/{}", id))
+            .await?;
+        let mut droplet: Droplet = serde_json::from_value(j["droplet"].clone())?;
+        if droplet.status != "active" {
+            return Err(anyhow!("Droplet {} is {}, not active", id, droplet.status));
+        }
+        droplet.firewall = get_all_firewalls()
+            .await?
+            .into_iter()
+            .find(|f| f.name == name && f.droplet_ids.contains(&id));
+        Ok(Box::new(droplet))
     }
 }
 
 /// Polls a droplet resource to get the latest data. Used during wait for boot,
 /// to capture networking info like PublicIPv4, which is assigned after creation.
 async fn get_droplet(droplet: &Droplet) -> Result<Droplet> {
-    let api_key = env::var("DIGITALOCEAN_API_TOKEN").context("DIGITALOCEAN_API_TOKEN not set.")?;
-    let request_url = DO_API_BASE_URL.to_owned() + "/" + &droplet.id.to_string();
-
-    let client = reqwest::Client::new();
-    let response = client
-        .get(request_url)
-        .bearer_auth(api_key)
-        .send()
-        .await?
-        .error_for_status()?;
-    let j: serde_json::Value = response.json().await?;
+    let j = DoApiClient::new()?
+        .get(&format!("/droplets/{}", droplet.id))
+        .await?;
     let d_s: String = j["droplet"].to_string();
     let mut d: Droplet = serde_json::from_str(&d_s)?;
     d.ssh_pubkey = droplet.ssh_pubkey.clone();
+    d.firewall = droplet.firewall.clone();
     Ok(d)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn provider_overrides_droplet_config() -> Result<()> {
+        let provider = DigitalOceanProvider::new(Some("ams3".to_string()), None, None);
+        let j = serde_json::to_value(provider.droplet_config())?;
+        assert_eq!(j["region"], "ams3");
+        assert_eq!(j["size"], DO_SIZE);
+        assert_eq!(j["image"], DO_IMAGE);
+        assert!(j.get("vpc_uuid").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn droplet_count_checked() {
+        let account = serde_json::json!({ "account": { "droplet_limit": 10, "status": "active" } });
+        let droplets = |n: u64| serde_json::json!({ "droplets": [], "meta": { "total": n } });
+        assert!(check_droplet_count(&account, &droplets(9), None).is_ok());
+        assert!(check_droplet_count(&account, &droplets(10), None).is_err());
+        assert!(check_droplet_count(&account, &droplets(2), Some(3)).is_ok());
+        let capped = check_droplet_count(&account, &droplets(3), Some(3));
+        assert!(capped.unwrap_err().to_string().contains("--max-droplets 3"));
+        let locked = serde_json::json!({ "account": { "droplet_limit": 10, "status": "locked" } });
+        assert!(check_droplet_count(&locked, &droplets(0), None).is_err());
+    }
+
+    #[test]
+    fn droplet_addresses_parsed() -> Result<()> {
+        let j = serde_json::json!({
+            "id": 3164494,
+            "name": "innisfree",
+            "status": "active",
+            "networks": {
+                "v4": [
+                    { "ip_address": "10.128.192.124", "netmask": "255.255.0.0", "gateway": "nil", "type": "private" },
+                    { "ip_address": "192.241.165.154", "netmask": "255.255.255.0", "gateway": "192.241.165.1", "type": "public" },
+                ],
+                "v6": [
+                    { "ip_address": "2604:a880:0:1010::18a:a001", "netmask": 64, "gateway": "2604:a880:0:1010::1", "type": "public" },
+                ],
+            },
+        });
+        let droplet: Droplet = serde_json::from_value(j)?;
+        let v4: IpAddr = "192.241.165.154".parse()?;
+        let v6: IpAddr = "2604:a880:0:1010::18a:a001".parse()?;
+        assert_eq!(droplet.ipv4_address()?, v4);
+        assert_eq!(droplet.ipv6_address()?, Some(v6));
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_image_serialized_as_id() -> Result<()> {
+        let provider = DigitalOceanProvider::new(None, None, Some("123456".to_string()));
+        let j = serde_json::to_value(provider.droplet_config())?;
+        assert_eq!(j["image"], 123456);
+        assert!(is_snapshot(&provider.image));
+        assert!(!is_snapshot(DO_IMAGE));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plan_uses_placeholders_for_ids() -> Result<()> {
+        let provider = DigitalOceanProvider {
+            project: Some("tunnels".to_string()),
+            ..DigitalOceanProvider::new(None, None, None)
+        };
+        let wg = WireguardManager::with_subnet("test", "10.50.0.0/30".parse()?)?;
+        let services = vec![ServicePort::try_from("443/TCP")?];
+        let requests = provider
+            .plan(
+                "test",
+                "#cloud-config\n",
+                &services,
+                &wg,
+                &SshKeypair::new("client")?,
+                &CloudConfigOptions::default(),
+            )
+            .await?;
+        let urls: Vec<&str> = requests.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://api.digitalocean.com/v2/account/keys",
+                "https://api.digitalocean.com/v2/droplets",
+                "https://api.digitalocean.com/v2/firewalls",
+                "https://api.digitalocean.com/v2/projects/<id of project 'tunnels'>/resources",
+            ]
+        );
+        assert_eq!(requests[1].body["ssh_keys"][0], "<ssh-key-id>");
+        assert_eq!(
+            requests[1].body["tags"],
+            serde_json::json!(tags_for("test"))
+        );
+        assert_eq!(requests[2].body["droplet_ids"][0], "<droplet-id>");
+        assert_eq!(requests[1].body["name"], "test");
+
+        // Named by hostname, but still tagged by tunnel
+        let options = CloudConfigOptions {
+            hostname: Some("mail.example.com".to_string()),
+            ..Default::default()
+        };
+        let requests = provider
+            .plan(
+                "test",
+                "#cloud-config\n",
+                &services,
+                &wg,
+                &SshKeypair::new("client")?,
+                &options,
+            )
+            .await?;
+        assert_eq!(requests[1].body["name"], "mail.example.com");
+        assert_eq!(
+            requests[1].body["tags"],
+            serde_json::json!(tags_for("test"))
+        );
+        Ok(())
+    }
+}
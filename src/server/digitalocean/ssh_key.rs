@@ -2,12 +2,13 @@
 //! Adding a new SSH key on instance creation prevents emails on
 //! instance creation, providing a root pw.
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use serde::Deserialize;
 use serde_json::json;
 use std::env;
 
-const DO_API_BASE_URL: &str = "https://api.digitalocean.com/v2";
+use crate::server::digitalocean::client::DoApiClient;
+use crate::server::digitalocean::tags::{label_for, MANAGED_BY_TAG};
 
 #[derive(Clone, Debug, Deserialize)]
 /// Representation of an SSH public key, as defined by the DigitalOcean API.
@@ -32,65 +33,137 @@ pub struct DigitalOceanSshKey {
 /// so that any user with access to the DigitalOcean account can log into
 /// the innisfree host.
 pub async fn get_all_keys() -> Result<Vec<DigitalOceanSshKey>> {
-    let api_key = env::var("DIGITALOCEAN_API_TOKEN")?;
-    let request_url = DO_API_BASE_URL.to_owned() + "/account/keys";
-    let client = reqwest::Client::new();
     tracing::debug!("Fetching SSH account public keys");
-    let response = client
-        .get(request_url)
-        .bearer_auth(api_key)
-        .send()
-        .await?
-        .error_for_status()?;
-    let j: serde_json::Value = response.json().await?;
-    let k: String = j["ssh_keys"].to_string();
-    let ssh_keys: Vec<DigitalOceanSshKey> = serde_json::from_str(&k)?;
+    let keys = DoApiClient::new()?
+        .get_all_pages("/account/keys", &[], "ssh_keys")
+        .await?;
+    let ssh_keys: Vec<DigitalOceanSshKey> = serde_json::from_value(serde_json::Value::Array(keys))?;
     Ok(ssh_keys)
 }
 
+/// Restricts which account SSH keys are added to the server, for accounts
+/// with many keys. Configured via env vars, since it applies to every tunnel:
+///
+///   * `INNISFREE_ACCOUNT_KEYS_FILTER`: only include keys whose name contains this string
+///   * `INNISFREE_ACCOUNT_KEYS_LIMIT`: include at most this many keys
+///
+/// Keys created by innisfree for other tunnels are always excluded.
+#[derive(Debug, Default)]
+pub struct KeyFilter {
+    /// Substring that key names must contain, if set.
+    pub name_contains: Option<String>,
+    /// Maximum number of keys to include, if set.
+    pub limit: Option<usize>,
+}
+
+impl KeyFilter {
+    /// Builds a [KeyFilter] from the `INNISFREE_ACCOUNT_KEYS_*` env vars.
+    pub fn from_env() -> Result<KeyFilter> {
+        let limit = match env::var("INNISFREE_ACCOUNT_KEYS_LIMIT") {
+            Ok(l) => Some(
+                l.parse()
+                    .context("INNISFREE_ACCOUNT_KEYS_LIMIT must be a number")?,
+            ),
+            Err(_) => None,
+        };
+        Ok(KeyFilter {
+            name_contains: env::var("INNISFREE_ACCOUNT_KEYS_FILTER").ok(),
+            limit,
+        })
+    }
+
+    /// Applies the filter to a list of account keys.
+    pub fn apply(&self, keys: Vec<DigitalOceanSshKey>) -> Vec<DigitalOceanSshKey> {
+        let keys = keys
+            .into_iter()
+            .filter(|k| !k.name.starts_with(MANAGED_BY_TAG))
+            .filter(|k| match &self.name_contains {
+                Some(f) => k.name.contains(f.as_str()),
+                None => true,
+            });
+        match self.limit {
+            Some(l) => keys.take(l).collect(),
+            None => keys.collect(),
+        }
+    }
+}
+
+/// Retrieves all SSH public keys created by innisfree for the tunnel `name`,
+/// as identified by their [label_for] name.
+pub async fn get_tagged_keys(name: &str) -> Result<Vec<DigitalOceanSshKey>> {
+    let label = label_for(name);
+    Ok(get_all_keys()
+        .await?
+        .into_iter()
+        .filter(|k| k.name == label)
+        .collect())
+}
+
 impl DigitalOceanSshKey {
+    /// Builds the request body to create the key for the tunnel `name`.
+    pub fn create_body(name: &str, public_key: &str) -> serde_json::Value {
+        json!({
+            "name": label_for(name),
+            "public_key": public_key,
+        })
+    }
+
     /// Creates a new DigitalOceanSshKey based on the public key material passed in.
     /// A new key will be created via the API, so that a subsequent Droplet creation
     /// request can reference the DigitalOceanSshKey by its numeric ID.
+    /// The API doesn't support tagging SSH keys, so the key is named
+    /// via [label_for] instead, to attribute it to the tunnel.
     pub async fn new(name: &str, public_key: &str) -> Result<DigitalOceanSshKey> {
-        let api_key = env::var("DIGITALOCEAN_API_TOKEN")?;
-        let req_body = json!({
-            "name": name,
-            "public_key": public_key,
-        });
-        let request_url = DO_API_BASE_URL.to_owned() + "/account/keys";
-
-        let client = reqwest::Client::new();
-        let response = client
-            .post(request_url)
-            .json(&req_body)
-            .bearer_auth(api_key)
-            .send()
-            .await?
-            .error_for_status()?;
-
+        let req_body = DigitalOceanSshKey::create_body(name, public_key);
         tracing::debug!("Syncing SSH keypair to DigitalOcean...");
-        let j: serde_json::Value = response.json().await?;
+        let j = DoApiClient::new()?.post("/account/keys", &req_body).await?;
         let k: String = j["ssh_key"].to_string();
         let do_ssh_key: DigitalOceanSshKey = serde_json::from_str(&k)?;
         Ok(do_ssh_key)
     }
     /// Delete the DigitalOceanSshKey via the API.
     pub async fn destroy(&self) -> Result<()> {
-        let api_key =
-            env::var("DIGITALOCEAN_API_TOKEN").context("DIGITALOCEAN_API_TOKEN not set.")?;
-        let request_url = DO_API_BASE_URL.to_owned() + "/account/keys/" + &self.id.to_string();
         tracing::debug!("Deleting SSH keypair from DigitalOcean...");
-        let client = reqwest::Client::new();
-        let response = client
-            .delete(request_url)
-            .bearer_auth(api_key)
-            .send()
-            .await?
-            .error_for_status();
-        match response {
-            Ok(_r) => Ok(()),
-            Err(e) => Err(anyhow!("Failed to delete ssh key: {:?}", e)),
+        DoApiClient::new()?
+            .delete(&format!("/account/keys/{}", self.id))
+            .await
+            .context("Failed to delete ssh key")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: u32, name: &str) -> DigitalOceanSshKey {
+        DigitalOceanSshKey {
+            public_key: "ssh-ed25519 AAAA".to_string(),
+            name: name.to_string(),
+            fingerprint: String::default(),
+            id,
         }
     }
+
+    #[test]
+    fn key_filter_excludes_managed_keys() {
+        let keys = vec![
+            key(1, "alice laptop"),
+            key(2, &label_for("innisfree-foo")),
+            key(3, "bob laptop"),
+            key(4, "ci"),
+        ];
+        let all: Vec<u32> = KeyFilter::default()
+            .apply(keys.clone())
+            .iter()
+            .map(|k| k.id)
+            .collect();
+        assert_eq!(all, vec![1, 3, 4]);
+
+        let filter = KeyFilter {
+            name_contains: Some("laptop".to_string()),
+            limit: Some(1),
+        };
+        let filtered: Vec<u32> = filter.apply(keys).iter().map(|k| k.id).collect();
+        assert_eq!(filtered, vec![1]);
+    }
 }
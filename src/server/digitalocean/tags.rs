@@ -0,0 +1,105 @@
+//! Tags applied to DigitalOcean resources created by innisfree,
+//! so that they can be found and attributed to a tunnel via the API,
+//! even if the local config dir for the tunnel has been lost.
+//! Tag names may only contain letters, numbers, colons, dashes, and underscores.
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::server::digitalocean::client::DoApiClient;
+
+/// Tag applied to every resource created by innisfree.
+pub const MANAGED_BY_TAG: &str = "managed-by:innisfree";
+
+/// Builds the tag identifying resources for the tunnel `name`.
+pub fn name_tag(name: &str) -> String {
+    format!("name:{}", name)
+}
+
+/// Builds the full set of tags for resources belonging to the tunnel `name`.
+pub fn tags_for(name: &str) -> Vec<String> {
+    vec![MANAGED_BY_TAG.to_string(), name_tag(name)]
+}
+
+/// Builds the label for resources that don't support tags, such as SSH keys,
+/// so they can still be attributed to the tunnel `name` by matching on it.
+pub fn label_for(name: &str) -> String {
+    tags_for(name).join(" ")
+}
+
+/// Finds the tunnel name among a set of tags, or the words of a label
+/// built via [label_for]. Returns `None` unless the resource
+/// is managed by innisfree.
+pub fn tunnel_name<'a, I: IntoIterator<Item = &'a str>>(tags: I) -> Option<String> {
+    let tags: Vec<&str> = tags.into_iter().collect();
+    if !tags.contains(&MANAGED_BY_TAG) {
+        return None;
+    }
+    tags.iter()
+        .find_map(|t| t.strip_prefix("name:"))
+        .map(|n| n.to_string())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+/// Minimal representation of a tagged Droplet, for cleanup purposes.
+pub struct TaggedDroplet {
+    /// Numeric ID, returned by API, to identify the Droplet.
+    pub id: u32,
+    /// Human-readable name for the Droplet.
+    pub name: String,
+    /// All tags applied to the Droplet.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Retrieves all Droplets carrying the tag `tag_name`.
+async fn get_droplets_by_tag(tag_name: &str) -> Result<Vec<TaggedDroplet>> {
+    let droplets = DoApiClient::new()?
+        .get_all_pages("/droplets", &[("tag_name", tag_name)], "droplets")
+        .await?;
+    let droplets: Vec<TaggedDroplet> = serde_json::from_value(serde_json::Value::Array(droplets))?;
+    Ok(droplets)
+}
+
+/// Retrieves all Droplets created by innisfree for the tunnel `name`,
+/// i.e. those tagged with both [MANAGED_BY_TAG] and [name_tag].
+pub async fn get_tagged_droplets(name: &str) -> Result<Vec<TaggedDroplet>> {
+    Ok(get_droplets_by_tag(&name_tag(name))
+        .await?
+        .into_iter()
+        .filter(|d| d.tags.iter().any(|t| t == MANAGED_BY_TAG))
+        .collect())
+}
+
+/// Retrieves all Droplets created by innisfree, for any tunnel.
+pub async fn get_managed_droplets() -> Result<Vec<TaggedDroplet>> {
+    get_droplets_by_tag(MANAGED_BY_TAG).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_identify_tunnel() {
+        assert_eq!(
+            tags_for("innisfree-foo"),
+            vec!["managed-by:innisfree", "name:innisfree-foo"]
+        );
+        assert_eq!(
+            label_for("innisfree"),
+            "managed-by:innisfree name:innisfree"
+        );
+    }
+
+    #[test]
+    fn tunnel_name_requires_managed_tag() {
+        let label = label_for("innisfree-foo");
+        assert_eq!(
+            tunnel_name(label.split(' ')),
+            Some("innisfree-foo".to_string())
+        );
+        assert_eq!(tunnel_name(["name:innisfree-foo"]), None);
+        assert_eq!(tunnel_name(["innisfree-foo"]), None);
+    }
+}
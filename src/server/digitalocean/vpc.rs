@@ -0,0 +1,71 @@
+//! Logic to look up a pre-existing VPC in DigitalOcean, so the Droplet
+//! can be placed inside it. Allows the tunnel host to reach other
+//! private resources in the account, such as databases.
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::server::digitalocean::client::DoApiClient;
+
+#[derive(Clone, Debug, Deserialize)]
+/// Representation of a VPC, as defined by the DigitalOcean API.
+/// The VPC must be created out of band, e.g. via the web console,
+/// then passed in on the CLI with `--vpc-uuid`.
+/// For more information, see
+/// <https://docs.digitalocean.com/reference/api/api-reference/#tag/VPCs>.
+pub struct Vpc {
+    /// UUID, created automatically by the DigitalOcean API, for this VPC.
+    pub id: String,
+    /// Human-readable name for the VPC.
+    pub name: String,
+    /// Slug of the region in which the VPC lives, e.g. `sfo2`.
+    /// Droplets can only be placed in a VPC within the same region.
+    pub region: String,
+    /// Private network range for the VPC, in CIDR notation.
+    pub ip_range: String,
+}
+
+impl Vpc {
+    /// Retrieves the VPC with the given UUID via the API.
+    /// Fails if the VPC does not exist.
+    pub async fn get(vpc_uuid: &str) -> Result<Vpc> {
+        let j = DoApiClient::new()?
+            .get(&format!("/vpcs/{}", vpc_uuid))
+            .await
+            .with_context(|| format!("VPC '{}' not found", vpc_uuid))?;
+        let vpc: Vpc = serde_json::from_value(j["vpc"].clone())?;
+        Ok(vpc)
+    }
+
+    /// Ensures the VPC is usable by a Droplet in `region`.
+    pub fn validate_region(&self, region: &str) -> Result<()> {
+        if self.region != region {
+            return Err(anyhow!(
+                "VPC '{}' is in region {}, but droplet region is {}",
+                self.name,
+                self.region,
+                region
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn vpc_region_must_match() -> Result<()> {
+        let vpc: Vpc = serde_json::from_value(json!({
+            "id": "5a4981aa-9653-4bd1-bef5-d6bff52042e4",
+            "name": "env.prod-vpc",
+            "region": "nyc1",
+            "ip_range": "10.10.10.0/24",
+            "default": false,
+        }))?;
+        assert!(vpc.validate_region("nyc1").is_ok());
+        assert!(vpc.validate_region("sfo2").is_err());
+        Ok(())
+    }
+}
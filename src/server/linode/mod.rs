@@ -0,0 +1,5 @@
+//! Support for the Linode (Akamai) cloud provider.
+//! Cloudinit content is injected via the Linode Metadata service,
+//! so the chosen region and image must both support it.
+
+pub mod server;
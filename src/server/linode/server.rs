@@ -0,0 +1,414 @@
+//! Logic for managing a remote server via the Linode cloud provider.
+//! The cloudinit user-data is passed through the Linode Metadata service,
+//! rather than a StackScript, so the same config as other providers is used.
+
+use async_trait::async_trait;
+use base64::Engine;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::net::IpAddr;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ServicePort;
+use crate::server::cloudinit::{generate_user_data, CloudConfigOptions};
+use crate::server::{wait_to_poll, ApiRequest, InnisfreeServer, ServerProvider, BOOT_TIMEOUT};
+use crate::ssh::SshKeypair;
+use crate::wg::WireguardManager;
+
+/// The region in which the Linode will be created, e.g. `us-sea`.
+/// Must be a region that supports the Metadata service.
+/// See docs for more info: <https://www.linode.com/docs/api/regions/>.
+pub const LINODE_REGION: &str = "us-sea";
+/// The type of VM instance to create, e.g. `g6-nanode-1`.
+/// See docs for more info: <https://www.linode.com/docs/api/linode-types/>.
+pub const LINODE_TYPE: &str = "g6-nanode-1";
+/// The OS choice for to base the Linode on. Must be a cloud-init compatible image.
+/// See docs for more info: <https://www.linode.com/docs/api/images/>.
+pub const LINODE_IMAGE: &str = "linode/debian11";
+const LINODE_API_BASE_URL: &str = "https://api.linode.com/v4/linode/instances";
+
+/// Representation of a Linode, i.e. cloud VM.
+/// See more documentation at
+/// <https://www.linode.com/docs/api/linode-instances/>.
+#[derive(Debug, Deserialize)]
+pub struct Linode {
+    /// Numeric ID, returned by API, to identify this Linode.
+    pub id: u64,
+    /// Current state of server. Is `provisioning` or `booting` at first,
+    /// and changes to `running` once the host is booted.
+    pub status: String,
+    /// List of IPv4 addresses assigned to the Linode. May include
+    /// private addresses, so use [Linode::ipv4_address] to find the public one.
+    ipv4: Vec<IpAddr>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+/// Template for building a request to create a new Linode.
+pub struct LinodeConfig {
+    /// The OS image used for creating the remote server. Defaults to [`LINODE_IMAGE`].
+    pub image: String,
+    /// Human-readable name for Linode. Defaults to `innisfree`.
+    label: String,
+    /// The cloud region in which the server will be created. Defaults to [`LINODE_REGION`].
+    region: String,
+    /// The type of machine that will be created. Defaults to [`LINODE_TYPE`].
+    #[serde(rename = "type")]
+    instance_type: String,
+    /// Root password for the instance. Required by the API, but never used,
+    /// since logins happen via the `innisfree` user over SSH.
+    root_pass: String,
+    /// Public SSH keys to install for the root user.
+    authorized_keys: Vec<String>,
+    /// Metadata service payload, containing the base64-encoded cloudinit config.
+    metadata: LinodeMetadata,
+    /// Whether the Linode should boot immediately after creation.
+    booted: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+/// Metadata payload for Linode creation, consumed by cloud-init on first boot.
+/// See documentation for more information: <https://www.linode.com/docs/products/compute/compute-instances/guides/metadata/>.
+pub struct LinodeMetadata {
+    /// Serialized cloud-init YAML file, base64-encoded as the API requires.
+    user_data: String,
+}
+
+impl LinodeConfig {
+    /// Creates a new [LinodeConfig] based on the default implementation.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Builds the request to create the Linode `name`, booting with `user_data`.
+    fn for_tunnel(name: &str, user_data: &str, ssh_client_keypair: &SshKeypair) -> Self {
+        LinodeConfig {
+            label: name.to_string(),
+            authorized_keys: vec![ssh_client_keypair.public.to_owned()],
+            metadata: LinodeMetadata {
+                user_data: base64::engine::general_purpose::STANDARD.encode(user_data),
+            },
+            ..LinodeConfig::new()
+        }
+    }
+}
+
+impl Default for LinodeConfig {
+    fn default() -> Self {
+        LinodeConfig {
+            image: LINODE_IMAGE.to_string(),
+            label: "innisfree".to_string(),
+            region: LINODE_REGION.to_string(),
+            instance_type: LINODE_TYPE.to_string(),
+            root_pass: generate_root_pass(),
+            authorized_keys: vec![],
+            metadata: LinodeMetadata {
+                user_data: String::default(),
+            },
+            booted: true,
+        }
+    }
+}
+
+/// Build a random throwaway password, to satisfy the API's requirement
+/// for a root password.
+fn generate_root_pass() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+impl Linode {
+    /// Make an API request and create a new Linode.
+    /// Blocks until the server is "running", which usually takes about 60 seconds.
+    pub async fn new(
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<Linode> {
+        Linode::create(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+            &CancellationToken::new(),
+        )
+        .await
+    }
+
+    /// Creates the Linode, as [Linode::new] does, giving up once `cancel`
+    /// fires. If it fails to boot, it's destroyed.
+    async fn create(
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        cancel: &CancellationToken,
+    ) -> Result<Linode> {
+        tracing::debug!("Creating new Linode");
+        let user_data = generate_user_data(
+            ssh_client_keypair,
+            ssh_server_keypair,
+            &wg_mgr,
+            &services,
+            options,
+        )
+        .await?;
+        let linode_config = LinodeConfig::for_tunnel(name, &user_data, ssh_client_keypair);
+
+        let api_key = env::var("LINODE_API_TOKEN").context("LINODE_API_TOKEN not set.")?;
+        let client = reqwest::Client::new();
+        let response = client
+            .post(LINODE_API_BASE_URL)
+            .json(&linode_config)
+            .bearer_auth(api_key)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let linode: Linode = response.json().await?;
+        tracing::debug!("Server created, waiting for boot");
+        match linode.wait_for_boot(cancel).await {
+            Ok(linode) => Ok(linode),
+            Err(e) => {
+                if let Err(e) = destroy_linode(linode.id).await {
+                    tracing::warn!("Failed to destroy linode {}: {:#}", linode.id, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Block until a Linode is running. Upon creation, the API will
+    /// return a result where `status="provisioning"`. This method blocks until
+    /// the API reports `status="running"`, for up to [BOOT_TIMEOUT],
+    /// or until `cancel` fires.
+    async fn wait_for_boot(&self, cancel: &CancellationToken) -> Result<Linode> {
+        let deadline = tokio::time::Instant::now() + BOOT_TIMEOUT;
+        let mut attempt = 0;
+        loop {
+            wait_to_poll(attempt, deadline, cancel, "linode boot").await?;
+            attempt += 1;
+            match get_linode(self.id).await {
+                Ok(linode) => {
+                    if linode.status == "running" {
+                        return Ok(linode);
+                    } else {
+                        tracing::info!("Server still booting, waiting...");
+                        continue;
+                    }
+                }
+                Err(_) => {
+                    return Err(anyhow!("Unknown error while waiting for linode boot"));
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl InnisfreeServer for Linode {
+    /// Retrieves the public IPv4 address for the Linode.
+    /// Private addresses, if any were assigned, are skipped.
+    fn ipv4_address(&self) -> Result<IpAddr> {
+        self.ipv4
+            .iter()
+            .find(|ip| match ip {
+                IpAddr::V4(v4) => !v4.is_private(),
+                IpAddr::V6(_) => false,
+            })
+            .copied()
+            .ok_or_else(|| anyhow!("No public IPv4 address found for linode {}", self.id))
+    }
+
+    fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    async fn assign_floating_ip(&self, _floating_ip: IpAddr) -> Result<()> {
+        Err(anyhow!("Floating IPs are not supported for Linode"))
+    }
+
+    /// Calls the API to destroy a Linode.
+    async fn destroy(&self) -> Result<()> {
+        destroy_linode(self.id).await
+    }
+}
+
+/// Factory for creating [Linode] servers, registered as `linode`.
+pub struct LinodeProvider;
+
+#[async_trait]
+impl ServerProvider for LinodeProvider {
+    fn name(&self) -> &'static str {
+        "linode"
+    }
+
+    async fn create(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let server = Linode::new(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+        )
+        .await?;
+        Ok(Box::new(server))
+    }
+
+    /// Stops polling for the Linode's boot as soon as `cancel` fires,
+    /// destroying it.
+    async fn create_cancellable(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        cancel: &CancellationToken,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let server = Linode::create(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+            cancel,
+        )
+        .await?;
+        Ok(Box::new(server))
+    }
+
+    async fn plan(
+        &self,
+        name: &str,
+        user_data: &str,
+        _services: &[ServicePort],
+        _wg_mgr: &WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        _options: &CloudConfigOptions,
+    ) -> Result<Vec<ApiRequest>> {
+        let linode_config = LinodeConfig {
+            // Generated afresh on creation, and never used.
+            root_pass: "<random>".to_string(),
+            ..LinodeConfig::for_tunnel(name, user_data, ssh_client_keypair)
+        };
+        Ok(vec![ApiRequest::new(
+            "POST",
+            LINODE_API_BASE_URL,
+            &linode_config,
+        )?])
+    }
+
+    async fn destroy(&self, _name: &str, id: &str) -> Result<()> {
+        destroy_linode(id.parse().context("Invalid Linode ID")?).await
+    }
+
+    async fn server_exists(&self, _name: &str, id: &str) -> Result<bool> {
+        let api_key = env::var("LINODE_API_TOKEN").context("LINODE_API_TOKEN not set.")?;
+        let request_url = LINODE_API_BASE_URL.to_owned() + "/" + id;
+        let response = reqwest::Client::new()
+            .get(request_url)
+            .bearer_auth(api_key)
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    async fn adopt(&self, _name: &str, id: &str) -> Result<Box<dyn InnisfreeServer>> {
+        let linode = get_linode(id.parse().context("Invalid Linode ID")?).await?;
+        if linode.status != "running" {
+            return Err(anyhow!("Linode {} is {}, not running", id, linode.status));
+        }
+        Ok(Box::new(linode))
+    }
+}
+
+/// Calls the API to destroy a Linode.
+async fn destroy_linode(id: u64) -> Result<()> {
+    let api_key = env::var("LINODE_API_TOKEN").context("LINODE_API_TOKEN not set.")?;
+    let request_url = LINODE_API_BASE_URL.to_owned() + "/" + &id.to_string();
+
+    let client = reqwest::Client::new();
+    client
+        .delete(request_url)
+        .bearer_auth(api_key)
+        .send()
+        .await?
+        .error_for_status()
+        .context("Failed to destroy linode")?;
+
+    tracing::debug!("Linode destroyed");
+    Ok(())
+}
+
+/// Polls a Linode resource to get the latest data. Used during wait for boot,
+/// to determine when the server is ready for SSH connections.
+async fn get_linode(id: u64) -> Result<Linode> {
+    let api_key = env::var("LINODE_API_TOKEN").context("LINODE_API_TOKEN not set.")?;
+    let request_url = LINODE_API_BASE_URL.to_owned() + "/" + &id.to_string();
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(request_url)
+        .bearer_auth(api_key)
+        .send()
+        .await?
+        .error_for_status()?;
+    let linode: Linode = response.json().await?;
+    Ok(linode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linode_config_serialization() -> Result<()> {
+        let config = LinodeConfig::new();
+        let j = serde_json::to_value(&config)?;
+        assert_eq!(j["type"], LINODE_TYPE);
+        assert_eq!(j["image"], LINODE_IMAGE);
+        assert!(j["root_pass"].as_str().unwrap_or_default().len() == 32);
+        Ok(())
+    }
+
+    #[test]
+    fn public_ip_is_preferred() -> Result<()> {
+        let linode = Linode {
+            id: 1,
+            status: "running".to_string(),
+            ipv4: vec!["192.168.128.4".parse()?, "172.105.1.2".parse()?],
+        };
+        let ip: IpAddr = "172.105.1.2".parse()?;
+        assert_eq!(linode.ipv4_address()?, ip);
+        Ok(())
+    }
+}
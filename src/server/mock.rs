@@ -0,0 +1,193 @@
+//! Fake cloud provider, selected via `--provider mock`, for developing
+//! and testing innisfree without a cloud account. "Creating" a server
+//! returns at once, with a fixed IP, and destroying it does nothing, so
+//! the rest of the tunnel's setup, e.g. SSH and Wireguard, runs against
+//! whatever listens on that IP, such as a local VM or container.
+//! To provision one as a real server would be, the cloud-init user-data
+//! is written to a NoCloud seed dir, if configured, for the VM or container
+//! to boot from, see `tools/e2e-runner`.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+use crate::config::ServicePort;
+use crate::server::cloudinit::{generate_user_data, CloudConfigOptions};
+use crate::server::{ApiRequest, InnisfreeServer, ServerProvider};
+use crate::ssh::SshKeypair;
+use crate::wg::WireguardManager;
+
+/// Env var holding the IP of mock servers, which defaults to loopback.
+pub const MOCK_IP_ENV: &str = "INNISFREE_MOCK_IP";
+/// Env var holding the dir to write mock servers' cloud-init seed to, if any.
+pub const MOCK_SEED_DIR_ENV: &str = "INNISFREE_MOCK_SEED_DIR";
+
+/// A server that exists only locally, at a fixed IP.
+pub struct MockServer {
+    name: String,
+    ip: IpAddr,
+}
+
+#[async_trait]
+impl InnisfreeServer for MockServer {
+    fn ipv4_address(&self) -> Result<IpAddr> {
+        Ok(self.ip)
+    }
+
+    fn id(&self) -> String {
+        format!("mock-{}", self.name)
+    }
+
+    async fn assign_floating_ip(&self, floating_ip: IpAddr) -> Result<()> {
+        tracing::debug!(
+            "Mock server '{}' took floating IP {}",
+            self.name,
+            floating_ip
+        );
+        Ok(())
+    }
+
+    async fn destroy(&self) -> Result<()> {
+        tracing::debug!("Mock server '{}' destroyed", self.name);
+        Ok(())
+    }
+}
+
+/// Factory for creating [MockServer]s, registered as `mock`.
+pub struct MockProvider {
+    ip: Option<IpAddr>,
+    seed_dir: Option<PathBuf>,
+}
+
+impl MockProvider {
+    /// Creates a provider whose servers are at `ip`, or at the IP in
+    /// [MOCK_IP_ENV], read on creation, if `None`. Their user-data is
+    /// written to `seed_dir`, or the dir in [MOCK_SEED_DIR_ENV], if any.
+    pub fn new(ip: Option<IpAddr>, seed_dir: Option<PathBuf>) -> MockProvider {
+        MockProvider { ip, seed_dir }
+    }
+
+    /// Returns the IP for a new server.
+    fn ip(&self) -> Result<IpAddr> {
+        if let Some(ip) = self.ip {
+            return Ok(ip);
+        }
+        match std::env::var(MOCK_IP_ENV) {
+            Ok(ip) => ip
+                .parse()
+                .with_context(|| format!("Invalid IP in {}: '{}'", MOCK_IP_ENV, ip)),
+            Err(_) => Ok(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        }
+    }
+
+    /// Returns the server `name`, as if it had been created.
+    fn server(&self, name: &str) -> Result<Box<dyn InnisfreeServer>> {
+        Ok(Box::new(MockServer {
+            name: name.to_string(),
+            ip: self.ip()?,
+        }))
+    }
+}
+
+impl Default for MockProvider {
+    fn default() -> Self {
+        MockProvider::new(None, None)
+    }
+}
+
+#[async_trait]
+impl ServerProvider for MockProvider {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn create(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let seed_dir = self
+            .seed_dir
+            .clone()
+            .or_else(|| std::env::var_os(MOCK_SEED_DIR_ENV).map(PathBuf::from));
+        if let Some(dir) = seed_dir {
+            let user_data = generate_user_data(
+                ssh_client_keypair,
+                ssh_server_keypair,
+                &wg_mgr,
+                &services,
+                options,
+            )
+            .await?;
+            // Meta-data first, since booting waits for the user-data.
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(
+                dir.join("meta-data"),
+                format!("instance-id: mock-{}\nlocal-hostname: {}\n", name, name),
+            )?;
+            std::fs::write(dir.join("user-data"), user_data)
+                .with_context(|| format!("Failed to write seed to {}", dir.display()))?;
+            tracing::info!("Wrote cloud-init seed for mock server to {}", dir.display());
+        }
+        let server = self.server(name)?;
+        tracing::info!("Mock server '{}' is up at {}", name, server.ipv4_address()?);
+        Ok(server)
+    }
+
+    /// No API, so no requests.
+    async fn plan(
+        &self,
+        _name: &str,
+        _user_data: &str,
+        _services: &[ServicePort],
+        _wg_mgr: &WireguardManager,
+        _ssh_client_keypair: &SshKeypair,
+        _options: &CloudConfigOptions,
+    ) -> Result<Vec<ApiRequest>> {
+        Ok(vec![])
+    }
+
+    async fn destroy(&self, _name: &str, _id: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn server_exists(&self, _name: &str, _id: &str) -> Result<bool> {
+        Ok(true)
+    }
+
+    async fn adopt(&self, name: &str, _id: &str) -> Result<Box<dyn InnisfreeServer>> {
+        self.server(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mock_servers_created_instantly() -> Result<()> {
+        let ip: IpAddr = "192.0.2.10".parse()?;
+        let dir = std::env::temp_dir().join(format!("innisfree-mock-{}", std::process::id()));
+        let provider = MockProvider::new(Some(ip), Some(dir.clone()));
+        let wg = WireguardManager::with_subnet("test", "10.50.0.0/30".parse()?)?;
+        let kp = SshKeypair::new("client")?;
+        let server = provider
+            .create("test", vec![], wg, &kp, &kp, &CloudConfigOptions::default())
+            .await?;
+        assert_eq!(server.ipv4_address()?, ip);
+        assert_eq!(server.id(), "mock-test");
+        let user_data = std::fs::read_to_string(dir.join("user-data"))?;
+        assert!(user_data.starts_with("#cloud-config"));
+        std::fs::remove_dir_all(&dir)?;
+        server.destroy().await?;
+        let adopted = provider.adopt("test", &server.id()).await?;
+        assert_eq!(adopted.ipv4_address()?, ip);
+        assert!(provider.server_exists("test", "mock-test").await?);
+        Ok(())
+    }
+}
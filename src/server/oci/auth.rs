@@ -0,0 +1,274 @@
+//! Request signing for the OCI API. OCI doesn't use bearer tokens;
+//! instead every request carries an RSA-SHA256 HTTP signature.
+//! Two identities are supported: a user's API key, configured via env vars,
+//! and instance principals, for when innisfree itself runs on an OCI instance.
+//! See documentation: <https://docs.oracle.com/en-us/iaas/Content/API/Concepts/signingrequests.htm>.
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+use openssl::x509::X509;
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::Method;
+use serde_json::json;
+use std::env;
+
+const OCI_METADATA_BASE_URL: &str = "http://169.254.169.254/opc/v2";
+
+/// Identity used to sign OCI API requests.
+pub struct OciCredentials {
+    /// Key ID placed in the signature header. For API keys, this is
+    /// `<tenancy>/<user>/<fingerprint>`; for instance principals, it's
+    /// the federated session token prefixed with `ST$`.
+    key_id: String,
+    /// RSA private key used to sign requests.
+    private_key: PKey<Private>,
+    /// Region identifier, e.g. `us-ashburn-1`.
+    pub region: String,
+}
+
+impl std::fmt::Debug for OciCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OciCredentials")
+            .field("region", &self.region)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Strips the PEM armor and line breaks, as the federation API expects.
+fn pem_body(pem: &str) -> String {
+    pem.lines()
+        .filter(|l| !l.starts_with("-----"))
+        .collect::<Vec<&str>>()
+        .join("")
+}
+
+impl OciCredentials {
+    /// Selects an identity based on the `OCI_AUTH` env var: either `api_key` (the default)
+    /// or `instance_principal`.
+    pub async fn from_env() -> Result<OciCredentials> {
+        match env::var("OCI_AUTH").as_deref() {
+            Ok("instance_principal") => OciCredentials::from_instance_principal().await,
+            Ok("api_key") | Err(_) => OciCredentials::from_api_key(),
+            Ok(other) => Err(anyhow!("Unsupported OCI_AUTH value: {}", other)),
+        }
+    }
+
+    /// Reads an API key identity from the `OCI_TENANCY_OCID`, `OCI_USER_OCID`,
+    /// `OCI_FINGERPRINT`, `OCI_PRIVATE_KEY_PATH`, and `OCI_REGION` env vars.
+    pub fn from_api_key() -> Result<OciCredentials> {
+        let tenancy = env::var("OCI_TENANCY_OCID").context("OCI_TENANCY_OCID not set.")?;
+        let user = env::var("OCI_USER_OCID").context("OCI_USER_OCID not set.")?;
+        let fingerprint = env::var("OCI_FINGERPRINT").context("OCI_FINGERPRINT not set.")?;
+        let key_path = env::var("OCI_PRIVATE_KEY_PATH").context("OCI_PRIVATE_KEY_PATH not set.")?;
+        let key_pem = std::fs::read(&key_path)
+            .with_context(|| format!("Failed to read OCI private key at {}", key_path))?;
+        let private_key =
+            PKey::private_key_from_pem(&key_pem).context("Failed to parse OCI private key")?;
+        Ok(OciCredentials {
+            key_id: format!("{}/{}/{}", tenancy, user, fingerprint),
+            private_key,
+            region: env::var("OCI_REGION").context("OCI_REGION not set.")?,
+        })
+    }
+
+    /// Obtains a session token via instance principal federation. The instance's
+    /// leaf certificate and key, served by the metadata service, are used to
+    /// sign a request exchanging a freshly generated session key for a token.
+    pub async fn from_instance_principal() -> Result<OciCredentials> {
+        let client = reqwest::Client::new();
+        let fetch = |path: &str| {
+            client
+                .get(format!("{}/{}", OCI_METADATA_BASE_URL, path))
+                .bearer_auth("Oracle")
+                .send()
+        };
+        let region = fetch("instance/canonicalRegionName")
+            .await
+            .context("Failed to reach OCI metadata service, not running on OCI?")?
+            .error_for_status()?
+            .text()
+            .await?;
+        let cert_pem = fetch("identity/cert.pem").await?.text().await?;
+        let key_pem = fetch("identity/key.pem").await?.text().await?;
+        let intermediate_pem = fetch("identity/intermediate.pem").await?.text().await?;
+
+        let cert = X509::from_pem(cert_pem.as_bytes())?;
+        let tenancy = cert
+            .subject_name()
+            .entries_by_nid(Nid::ORGANIZATIONALUNITNAME)
+            .filter_map(|e| e.data().as_utf8().ok())
+            .find_map(|ou| ou.strip_prefix("opc-tenant:").map(|t| t.to_string()))
+            .ok_or_else(|| anyhow!("No tenancy found in instance certificate"))?;
+        let fingerprint = cert
+            .digest(MessageDigest::sha1())?
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<String>>()
+            .join(":");
+        let instance_creds = OciCredentials {
+            key_id: format!("{}/fed-x509/{}", tenancy, fingerprint),
+            private_key: PKey::private_key_from_pem(key_pem.as_bytes())?,
+            region: region.trim().to_string(),
+        };
+
+        let session_key = PKey::from_rsa(Rsa::generate(2048)?)?;
+        let session_public = String::from_utf8(session_key.public_key_to_pem()?)?;
+        let body = json!({
+            "certificate": pem_body(&cert_pem),
+            "publicKey": pem_body(&session_public),
+            "intermediateCertificates": [pem_body(&intermediate_pem)],
+            "purpose": "DEFAULT",
+        });
+        let federation_url = format!(
+            "https://auth.{}.oraclecloud.com/v1/x509",
+            instance_creds.region
+        );
+        let response: serde_json::Value = instance_creds
+            .send(Method::POST, &federation_url, Some(&body))
+            .await
+            .context("Failed to federate instance principal")?
+            .json()
+            .await?;
+        let token = response["token"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No token in federation response"))?;
+        Ok(OciCredentials {
+            key_id: format!("ST${}", token),
+            private_key: session_key,
+            region: instance_creds.region,
+        })
+    }
+
+    /// Builds the signed headers for a request. Requests with a body
+    /// additionally sign the content headers, as OCI requires.
+    fn signed_headers(&self, method: &Method, url: &str, body: Option<&[u8]>) -> Result<HeaderMap> {
+        let parsed = reqwest::Url::parse(url)?;
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| anyhow!("No host in URL {}", url))?;
+        let target = match parsed.query() {
+            Some(q) => format!("{}?{}", parsed.path(), q),
+            None => parsed.path().to_string(),
+        };
+        let date = httpdate::fmt_http_date(std::time::SystemTime::now());
+
+        let mut headers = HeaderMap::new();
+        let mut signed: Vec<(&str, String)> = vec![
+            ("date", date),
+            (
+                "(request-target)",
+                format!("{} {}", method.as_str().to_lowercase(), target),
+            ),
+            ("host", host.to_string()),
+        ];
+        if let Some(b) = body {
+            let digest = openssl::sha::sha256(b);
+            signed.push(("content-length", b.len().to_string()));
+            signed.push(("content-type", "application/json".to_string()));
+            signed.push((
+                "x-content-sha256",
+                base64::engine::general_purpose::STANDARD.encode(digest),
+            ));
+        }
+
+        let signing_string = signed
+            .iter()
+            .map(|(k, v)| format!("{}: {}", k, v))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.private_key)?;
+        signer.update(signing_string.as_bytes())?;
+        let signature = base64::engine::general_purpose::STANDARD.encode(signer.sign_to_vec()?);
+
+        let header_names = signed
+            .iter()
+            .map(|(k, _)| *k)
+            .collect::<Vec<&str>>()
+            .join(" ");
+        for (k, v) in signed.iter().filter(|(k, _)| *k != "(request-target)") {
+            headers.insert(*k, HeaderValue::from_str(v)?);
+        }
+        let authorization = format!(
+            "Signature version=\"1\",keyId=\"{}\",algorithm=\"rsa-sha256\",{}",
+            self.key_id,
+            format_args!("headers=\"{}\",signature=\"{}\"", header_names, signature)
+        );
+        headers.insert("authorization", HeaderValue::from_str(&authorization)?);
+        Ok(headers)
+    }
+
+    /// Sends a signed request to the OCI API. Error responses are
+    /// surfaced with their body, since OCI reports useful messages
+    /// there, e.g. "Out of host capacity" for Free Tier shapes.
+    pub async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<reqwest::Response> {
+        let response = self.send_unchecked(method, url, body).await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("OCI API request failed ({}): {}", status, text));
+        }
+        Ok(response)
+    }
+
+    /// Sends a signed request to the OCI API, returning the response
+    /// whatever its status, e.g. so callers can handle 404s.
+    pub async fn send_unchecked(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<reqwest::Response> {
+        let body = body.map(serde_json::to_vec).transpose()?;
+        let headers = self.signed_headers(&method, url, body.as_deref())?;
+        let client = reqwest::Client::new();
+        let mut request = client.request(method, url).headers(headers);
+        if let Some(b) = body {
+            request = request.body(b);
+        }
+        request
+            .send()
+            .await
+            .context("Network error, check connection")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_covers_body_headers() -> Result<()> {
+        let creds = OciCredentials {
+            key_id: "tenancy/user/fingerprint".to_string(),
+            private_key: PKey::from_rsa(Rsa::generate(2048)?)?,
+            region: "us-ashburn-1".to_string(),
+        };
+        let url = "https://iaas.us-ashburn-1.oraclecloud.com/20160918/instances?limit=1";
+        let headers = creds.signed_headers(&Method::GET, url, None)?;
+        let auth = headers["authorization"].to_str()?;
+        assert!(auth.contains("headers=\"date (request-target) host\""));
+        assert!(auth.contains("keyId=\"tenancy/user/fingerprint\""));
+
+        let headers = creds.signed_headers(&Method::POST, url, Some(b"{}"))?;
+        let auth = headers["authorization"].to_str()?;
+        assert!(auth.contains("content-length content-type x-content-sha256"));
+        assert_eq!(headers["content-length"], "2");
+        Ok(())
+    }
+
+    #[test]
+    fn pem_armor_is_stripped() {
+        let pem = "-----BEGIN CERTIFICATE-----\nabc\ndef\n-----END CERTIFICATE-----\n";
+        assert_eq!(pem_body(pem), "abcdef");
+    }
+}
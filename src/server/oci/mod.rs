@@ -0,0 +1,6 @@
+//! Support for the Oracle Cloud Infrastructure (OCI) cloud provider.
+//! Defaults to the always-free Ampere A1 shape, so the ingress
+//! node costs nothing on a Free Tier account.
+
+pub mod auth;
+pub mod server;
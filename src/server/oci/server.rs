@@ -0,0 +1,434 @@
+//! Logic for managing a remote server via the OCI cloud provider.
+//! OCI instances take noticeably longer to provision than on other providers,
+//! and the public IP is only discoverable through the instance's VNIC,
+//! so boot polling allows longer and happens in two phases.
+//!
+//! The compartment, subnet, availability domain, and image must be
+//! created or looked up out of band, since they vary per tenancy and region.
+
+use async_trait::async_trait;
+use base64::Engine;
+use reqwest::Method;
+use serde::Deserialize;
+use serde_json::json;
+
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::net::IpAddr;
+use std::time;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ServicePort;
+use crate::server::cloudinit::{append_runcmd, generate_user_data, CloudConfigOptions};
+use crate::server::oci::auth::OciCredentials;
+use crate::server::{
+    env_or_placeholder, wait_to_poll, ApiRequest, InnisfreeServer, ServerProvider,
+};
+use crate::ssh::SshKeypair;
+use crate::wg::WireguardManager;
+
+/// The type of instance to create. Defaults to the always-free Ampere A1 shape.
+/// See docs for more info: <https://docs.oracle.com/en-us/iaas/Content/FreeTier/freetier_topic-Always_Free_Resources.htm>.
+pub const OCI_SHAPE: &str = "VM.Standard.A1.Flex";
+/// Number of OCPUs for the flexible shape. Free Tier allows up to 4 in total.
+pub const OCI_OCPUS: u32 = 1;
+/// Memory in GB for the flexible shape. Free Tier allows up to 24 in total.
+pub const OCI_MEMORY_GB: u32 = 6;
+const OCI_API_VERSION: &str = "20160918";
+/// Longest to wait for the instance to run and get a public IP.
+const OCI_BOOT_TIMEOUT: time::Duration = time::Duration::from_secs(1200);
+
+/// Representation of an OCI compute instance, i.e. cloud VM.
+/// See more documentation at
+/// <https://docs.oracle.com/en-us/iaas/api/#/en/iaas/20160918/Instance/>.
+#[derive(Debug)]
+pub struct OciInstance {
+    /// OCID, returned by API, to identify this instance.
+    pub id: String,
+    /// Compartment OCID in which the instance lives.
+    pub compartment_id: String,
+    /// Public IPv4 address of the instance's primary VNIC.
+    ip: Option<IpAddr>,
+    /// Credentials used to manage the instance, retained for teardown.
+    credentials: OciCredentials,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+/// Subset of the instance resource needed to poll for boot.
+struct InstanceStatus {
+    id: String,
+    lifecycle_state: String,
+}
+
+/// Builds the URL for a Core Services API path, e.g. `instances`.
+fn iaas_url(credentials: &OciCredentials, path: &str) -> String {
+    region_iaas_url(&credentials.region, path)
+}
+
+/// Builds the URL for a Core Services API path in the given region.
+fn region_iaas_url(region: &str, path: &str) -> String {
+    format!(
+        "https://iaas.{}.oraclecloud.com/{}/{}",
+        region, OCI_API_VERSION, path
+    )
+}
+
+/// Builds the request body to launch the instance `name`. Settings that
+/// vary per tenancy, e.g. the subnet, are read via `var`, given an env var name.
+fn launch_body<F>(
+    name: &str,
+    user_data: &str,
+    ssh_client_keypair: &SshKeypair,
+    var: F,
+) -> Result<serde_json::Value>
+where
+    F: Fn(&str) -> Result<String>,
+{
+    // OCI platform images ship with iptables rules rejecting all inbound
+    // traffic other than SSH, which would block WireGuard and the services,
+    // and rejecting all forwarded traffic, which would block DNAT mode.
+    let user_data = append_runcmd(user_data, &["iptables", "-F", "INPUT"])?;
+    let user_data = append_runcmd(&user_data, &["iptables", "-F", "FORWARD"])?;
+
+    Ok(json!({
+        "compartmentId": var("OCI_COMPARTMENT_OCID")?,
+        "availabilityDomain": var("OCI_AVAILABILITY_DOMAIN")?,
+        "displayName": name,
+        "shape": OCI_SHAPE,
+        "shapeConfig": { "ocpus": OCI_OCPUS, "memoryInGBs": OCI_MEMORY_GB },
+        "sourceDetails": {
+            "sourceType": "image",
+            "imageId": var("OCI_IMAGE_OCID")?,
+        },
+        "createVnicDetails": {
+            "subnetId": var("OCI_SUBNET_OCID")?,
+            "assignPublicIp": true,
+        },
+        "metadata": {
+            "ssh_authorized_keys": ssh_client_keypair.public,
+            "user_data": base64::engine::general_purpose::STANDARD.encode(user_data),
+        },
+    }))
+}
+
+/// Terminates the instance, including its boot volume.
+async fn terminate_instance(credentials: &OciCredentials, id: &str) -> Result<()> {
+    let request_url = format!(
+        "{}?preserveBootVolume=false",
+        iaas_url(credentials, &format!("instances/{}", id))
+    );
+    credentials
+        .send(Method::DELETE, &request_url, None)
+        .await
+        .context("Failed to destroy instance")?;
+    tracing::debug!("OCI instance terminated");
+    Ok(())
+}
+
+impl OciInstance {
+    /// Make an API request and launch a new OCI instance.
+    /// Blocks until the server is running and has a public IP,
+    /// which often takes several minutes.
+    pub async fn new(
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<OciInstance> {
+        OciInstance::create(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+            &CancellationToken::new(),
+        )
+        .await
+    }
+
+    /// Launches the instance, as [OciInstance::new] does, giving up once
+    /// `cancel` fires. If anything fails once it's launched, it's terminated.
+    async fn create(
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        cancel: &CancellationToken,
+    ) -> Result<OciInstance> {
+        tracing::debug!("Creating new OCI instance");
+        let credentials = OciCredentials::from_env().await?;
+        let compartment_id =
+            env::var("OCI_COMPARTMENT_OCID").context("OCI_COMPARTMENT_OCID not set.")?;
+        let user_data = generate_user_data(
+            ssh_client_keypair,
+            ssh_server_keypair,
+            &wg_mgr,
+            &services,
+            options,
+        )
+        .await?;
+        let body = launch_body(name, &user_data, ssh_client_keypair, |k| {
+            env::var(k).with_context(|| format!("{} not set.", k))
+        })?;
+        let status: InstanceStatus = credentials
+            .send(
+                Method::POST,
+                &iaas_url(&credentials, "instances"),
+                Some(&body),
+            )
+            .await
+            .context("Failed to launch OCI instance")?
+            .json()
+            .await?;
+        tracing::debug!("Server created, waiting for boot");
+        let mut instance = OciInstance {
+            id: status.id,
+            compartment_id,
+            ip: None,
+            credentials,
+        };
+        match instance.wait_for_boot(cancel).await {
+            Ok(()) => Ok(instance),
+            Err(e) => {
+                if let Err(e) = terminate_instance(&instance.credentials, &instance.id).await {
+                    tracing::warn!("Failed to terminate instance {}: {:#}", instance.id, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Block until an instance is running. Upon creation, the API will
+    /// return a result where `lifecycleState="PROVISIONING"`. This method blocks until
+    /// the API reports `lifecycleState="RUNNING"`, then looks up the public IP,
+    /// for up to [OCI_BOOT_TIMEOUT] in all, or until `cancel` fires.
+    async fn wait_for_boot(&mut self, cancel: &CancellationToken) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + OCI_BOOT_TIMEOUT;
+        let mut attempt = 0;
+        loop {
+            wait_to_poll(attempt, deadline, cancel, "OCI instance boot").await?;
+            attempt += 1;
+            let status: InstanceStatus = self
+                .credentials
+                .send(
+                    Method::GET,
+                    &iaas_url(&self.credentials, &format!("instances/{}", self.id)),
+                    None,
+                )
+                .await?
+                .json()
+                .await?;
+            match status.lifecycle_state.as_str() {
+                "RUNNING" => break,
+                "TERMINATING" | "TERMINATED" => {
+                    return Err(anyhow!("Instance {} terminated during boot", status.id));
+                }
+                _ => {
+                    tracing::info!("Server still booting, waiting...");
+                }
+            }
+        }
+        // The VNIC attachment may lag slightly behind the instance state.
+        loop {
+            if let Some(ip) = self.lookup_public_ip().await? {
+                self.ip = Some(ip);
+                return Ok(());
+            }
+            tracing::debug!("Waiting for public IP assignment...");
+            wait_to_poll(attempt, deadline, cancel, "OCI public IP").await?;
+            attempt += 1;
+        }
+    }
+
+    /// Finds the public IP on the instance's primary VNIC, if attached yet.
+    async fn lookup_public_ip(&self) -> Result<Option<IpAddr>> {
+        let attachments_url = format!(
+            "{}?compartmentId={}&instanceId={}",
+            iaas_url(&self.credentials, "vnicAttachments"),
+            self.compartment_id,
+            self.id
+        );
+        let attachments: serde_json::Value = self
+            .credentials
+            .send(Method::GET, &attachments_url, None)
+            .await?
+            .json()
+            .await?;
+        let vnic_id = match attachments[0]["vnicId"].as_str() {
+            Some(v) => v.to_string(),
+            None => return Ok(None),
+        };
+        let vnic: serde_json::Value = self
+            .credentials
+            .send(
+                Method::GET,
+                &iaas_url(&self.credentials, &format!("vnics/{}", vnic_id)),
+                None,
+            )
+            .await?
+            .json()
+            .await?;
+        match vnic["publicIp"].as_str() {
+            Some(ip) => Ok(Some(ip.parse()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl InnisfreeServer for OciInstance {
+    /// Returns the public IPv4 address of the instance's primary VNIC.
+    fn ipv4_address(&self) -> Result<IpAddr> {
+        self.ip
+            .ok_or_else(|| anyhow!("No public IP assigned to instance {}", self.id))
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    async fn assign_floating_ip(&self, _floating_ip: IpAddr) -> Result<()> {
+        Err(anyhow!("Floating IPs are not supported for OCI"))
+    }
+
+    /// Calls the API to terminate the instance, including its boot volume.
+    async fn destroy(&self) -> Result<()> {
+        terminate_instance(&self.credentials, &self.id).await
+    }
+}
+
+/// Factory for creating [OciInstance] servers, registered as `oci`.
+pub struct OciProvider;
+
+#[async_trait]
+impl ServerProvider for OciProvider {
+    fn name(&self) -> &'static str {
+        "oci"
+    }
+
+    async fn create(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let server = OciInstance::new(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+        )
+        .await?;
+        Ok(Box::new(server))
+    }
+
+    /// Stops polling for the instance's boot as soon as `cancel` fires,
+    /// terminating it.
+    async fn create_cancellable(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        cancel: &CancellationToken,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let server = OciInstance::create(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+            cancel,
+        )
+        .await?;
+        Ok(Box::new(server))
+    }
+
+    async fn plan(
+        &self,
+        name: &str,
+        user_data: &str,
+        _services: &[ServicePort],
+        _wg_mgr: &WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        _options: &CloudConfigOptions,
+    ) -> Result<Vec<ApiRequest>> {
+        let body = launch_body(name, user_data, ssh_client_keypair, |k| {
+            Ok(env_or_placeholder(k))
+        })?;
+        Ok(vec![ApiRequest::new(
+            "POST",
+            &region_iaas_url(&env_or_placeholder("OCI_REGION"), "instances"),
+            &body,
+        )?])
+    }
+
+    async fn destroy(&self, _name: &str, id: &str) -> Result<()> {
+        let credentials = OciCredentials::from_env().await?;
+        terminate_instance(&credentials, id).await
+    }
+
+    /// Terminated instances remain visible for a while, so
+    /// they're treated as gone, too.
+    async fn server_exists(&self, _name: &str, id: &str) -> Result<bool> {
+        let credentials = OciCredentials::from_env().await?;
+        let response = credentials
+            .send_unchecked(
+                Method::GET,
+                &iaas_url(&credentials, &format!("instances/{}", id)),
+                None,
+            )
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        let status: InstanceStatus = response.error_for_status()?.json().await?;
+        Ok(!matches!(
+            status.lifecycle_state.as_str(),
+            "TERMINATING" | "TERMINATED"
+        ))
+    }
+
+    async fn adopt(&self, _name: &str, id: &str) -> Result<Box<dyn InnisfreeServer>> {
+        let credentials = OciCredentials::from_env().await?;
+        let status: InstanceStatus = credentials
+            .send(
+                Method::GET,
+                &iaas_url(&credentials, &format!("instances/{}", id)),
+                None,
+            )
+            .await?
+            .json()
+            .await?;
+        if status.lifecycle_state != "RUNNING" {
+            return Err(anyhow!(
+                "Instance {} is {}, not running",
+                id,
+                status.lifecycle_state
+            ));
+        }
+        let mut instance = OciInstance {
+            id: status.id,
+            compartment_id: env::var("OCI_COMPARTMENT_OCID")
+                .context("OCI_COMPARTMENT_OCID not set.")?,
+            ip: None,
+            credentials,
+        };
+        instance.ip = instance.lookup_public_ip().await?;
+        Ok(Box::new(instance))
+    }
+}
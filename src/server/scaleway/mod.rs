@@ -0,0 +1,5 @@
+//! Support for the Scaleway cloud provider.
+//! Stardust instances are the default, since they're the cheapest
+//! option for a lightweight ingress node in the EU.
+
+pub mod server;
@@ -0,0 +1,471 @@
+//! Logic for managing a remote server via the Scaleway cloud provider.
+//! Unlike other providers, Scaleway requires the cloud-init user-data
+//! to be attached in a separate request after creation, and the
+//! instance to be powered on explicitly afterwards.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::net::IpAddr;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ServicePort;
+use crate::server::cloudinit::{generate_user_data, CloudConfigOptions};
+use crate::server::{
+    env_or_placeholder, wait_to_poll, ApiRequest, InnisfreeServer, ServerProvider, BOOT_TIMEOUT,
+};
+use crate::ssh::SshKeypair;
+use crate::wg::WireguardManager;
+
+/// The availability zone in which the instance will be created, e.g. `fr-par-1`.
+/// See docs for more info: <https://www.scaleway.com/en/docs/console/account/reference-content/products-availability/>.
+pub const SCW_ZONE: &str = "fr-par-1";
+/// The type of instance to create, e.g. `STARDUST1-S`.
+/// See docs for more info: <https://www.scaleway.com/en/stardust-instances/>.
+pub const SCW_COMMERCIAL_TYPE: &str = "STARDUST1-S";
+/// Marketplace label for the OS image. Defaults to Debian Stable.
+/// Resolved to a zone-specific image ID at creation time.
+pub const SCW_IMAGE_LABEL: &str = "debian_bullseye";
+const SCW_API_BASE_URL: &str = "https://api.scaleway.com";
+
+/// Representation of a Scaleway instance, i.e. cloud VM.
+/// See more documentation at
+/// <https://www.scaleway.com/en/developers/api/instance/>.
+#[derive(Debug, Deserialize)]
+pub struct ScalewayServer {
+    /// UUID, returned by API, to identify this instance.
+    pub id: String,
+    /// Current state of server. Is `stopped` after creation, then `starting`
+    /// once powered on, and `running` once booted.
+    pub state: String,
+    /// Public IP attached to the instance, if allocated yet.
+    public_ip: Option<ScalewayIp>,
+}
+
+#[derive(Debug, Deserialize)]
+/// Public IP attached to a Scaleway instance.
+struct ScalewayIp {
+    address: IpAddr,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+/// Template for building a request to create a new Scaleway instance.
+pub struct ScalewayServerConfig {
+    /// Human-readable name for the instance. Defaults to `innisfree`.
+    name: String,
+    /// The type of machine that will be created. Defaults to [`SCW_COMMERCIAL_TYPE`].
+    commercial_type: String,
+    /// The image ID used for creating the remote server, resolved from [`SCW_IMAGE_LABEL`].
+    pub image: String,
+    /// Project ID in which the instance is created.
+    project: String,
+    /// Request a public IPv4 address for the instance.
+    dynamic_ip_required: bool,
+}
+
+impl ScalewayServerConfig {
+    /// Builds the request to create the instance `name` from the given image.
+    fn new(name: &str, image: String, project: String) -> Self {
+        ScalewayServerConfig {
+            name: name.to_string(),
+            commercial_type: SCW_COMMERCIAL_TYPE.to_string(),
+            image,
+            project,
+            dynamic_ip_required: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+/// Entry in the marketplace's list of local images for a zone.
+struct LocalImage {
+    id: String,
+    compatible_commercial_types: Vec<String>,
+}
+
+/// Reads the Scaleway secret key from the `SCW_SECRET_KEY` env var.
+fn api_key() -> Result<String> {
+    env::var("SCW_SECRET_KEY").context("SCW_SECRET_KEY not set.")
+}
+
+/// Builds the URL for the collection of servers within [`SCW_ZONE`].
+fn servers_url() -> String {
+    format!(
+        "{}/instance/v1/zones/{}/servers",
+        SCW_API_BASE_URL, SCW_ZONE
+    )
+}
+
+/// Builds the URL for a server resource within [`SCW_ZONE`].
+fn server_url(id: &str) -> String {
+    format!("{}/{}", servers_url(), id)
+}
+
+/// Builds the URL for actions on a server, e.g. `poweron`.
+fn action_url(id: &str) -> String {
+    server_url(id) + "/action"
+}
+
+/// Builds the URL for a server's cloud-init user data.
+fn user_data_url(id: &str) -> String {
+    server_url(id) + "/user_data/cloud-init"
+}
+
+/// Looks up the zone-specific image ID for [`SCW_IMAGE_LABEL`], compatible
+/// with [`SCW_COMMERCIAL_TYPE`], via the marketplace API.
+async fn resolve_image_id() -> Result<String> {
+    let request_url = format!("{}/marketplace/v2/local-images", SCW_API_BASE_URL);
+    let client = reqwest::Client::new();
+    let response = client
+        .get(request_url)
+        .query(&[
+            ("zone", SCW_ZONE),
+            ("image_label", SCW_IMAGE_LABEL),
+            ("type", "instance_local"),
+        ])
+        .header("X-Auth-Token", api_key()?)
+        .send()
+        .await
+        .context("Network error, check connection")?
+        .error_for_status()?;
+    let j: serde_json::Value = response.json().await?;
+    let images: Vec<LocalImage> = serde_json::from_value(j["local_images"].clone())?;
+    images
+        .into_iter()
+        .find(|i| {
+            i.compatible_commercial_types
+                .iter()
+                .any(|t| t == SCW_COMMERCIAL_TYPE)
+        })
+        .map(|i| i.id)
+        .ok_or_else(|| {
+            anyhow!(
+                "No '{}' image found for {} in {}",
+                SCW_IMAGE_LABEL,
+                SCW_COMMERCIAL_TYPE,
+                SCW_ZONE
+            )
+        })
+}
+
+/// Sends a power action, e.g. `poweron` or `terminate`, to the instance.
+async fn server_action(id: &str, action: &str) -> Result<()> {
+    let client = reqwest::Client::new();
+    client
+        .post(action_url(id))
+        .json(&json!({ "action": action }))
+        .header("X-Auth-Token", api_key()?)
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("Failed to {} server", action))?;
+    Ok(())
+}
+
+/// Deletes an instance that was never powered on, which can't be terminated.
+async fn delete_server(id: &str) -> Result<()> {
+    reqwest::Client::new()
+        .delete(server_url(id))
+        .header("X-Auth-Token", api_key()?)
+        .send()
+        .await?
+        .error_for_status()
+        .context("Failed to delete server")?;
+    Ok(())
+}
+
+impl ScalewayServer {
+    /// Make API requests to create, configure, and power on a new Scaleway instance.
+    /// Blocks until the server is "running", which usually takes about 60 seconds.
+    pub async fn new(
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<ScalewayServer> {
+        ScalewayServer::create(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+            &CancellationToken::new(),
+        )
+        .await
+    }
+
+    /// Creates the instance, as [ScalewayServer::new] does, giving up once
+    /// `cancel` fires. If anything fails once the instance exists, it's
+    /// terminated, or deleted if it was never powered on.
+    async fn create(
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        cancel: &CancellationToken,
+    ) -> Result<ScalewayServer> {
+        tracing::debug!("Creating new Scaleway instance");
+        let user_data = generate_user_data(
+            ssh_client_keypair,
+            ssh_server_keypair,
+            &wg_mgr,
+            &services,
+            options,
+        )
+        .await?;
+        let server_config = ScalewayServerConfig::new(
+            name,
+            resolve_image_id().await?,
+            env::var("SCW_DEFAULT_PROJECT_ID").context("SCW_DEFAULT_PROJECT_ID not set.")?,
+        );
+
+        let client = reqwest::Client::new();
+        let response = client
+            .post(servers_url())
+            .json(&server_config)
+            .header("X-Auth-Token", api_key()?)
+            .send()
+            .await?
+            .error_for_status()?;
+        let j: serde_json::Value = response.json().await?;
+        let server: ScalewayServer = serde_json::from_value(j["server"].clone())?;
+
+        let mut powered_on = false;
+        let booted = async {
+            tracing::debug!("Attaching cloud-init user data");
+            client
+                .patch(user_data_url(&server.id))
+                .header("Content-Type", "text/plain")
+                .header("X-Auth-Token", api_key()?)
+                .body(user_data)
+                .send()
+                .await?
+                .error_for_status()
+                .context("Failed to attach cloud-init user data")?;
+
+            server_action(&server.id, "poweron").await?;
+            powered_on = true;
+            tracing::debug!("Server created, waiting for boot");
+            server.wait_for_boot(cancel).await
+        };
+        match booted.await {
+            Ok(server) => Ok(server),
+            Err(e) => {
+                let cleanup = if powered_on {
+                    server_action(&server.id, "terminate").await
+                } else {
+                    delete_server(&server.id).await
+                };
+                if let Err(e) = cleanup {
+                    tracing::warn!("Failed to destroy server {}: {:#}", server.id, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Block until an instance is running. After power on, the API will
+    /// report `state="starting"`. This method blocks until
+    /// the API reports `state="running"`, for up to [BOOT_TIMEOUT],
+    /// or until `cancel` fires.
+    async fn wait_for_boot(&self, cancel: &CancellationToken) -> Result<ScalewayServer> {
+        let deadline = tokio::time::Instant::now() + BOOT_TIMEOUT;
+        let mut attempt = 0;
+        loop {
+            wait_to_poll(attempt, deadline, cancel, "Scaleway server boot").await?;
+            attempt += 1;
+            match get_server(&self.id).await {
+                Ok(server) => {
+                    if server.state == "running" {
+                        return Ok(server);
+                    } else {
+                        tracing::info!("Server still booting, waiting...");
+                        continue;
+                    }
+                }
+                Err(_) => {
+                    return Err(anyhow!("Unknown error while waiting for server boot"));
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl InnisfreeServer for ScalewayServer {
+    /// Retrieves the public IPv4 address for the instance.
+    fn ipv4_address(&self) -> Result<IpAddr> {
+        self.public_ip
+            .as_ref()
+            .map(|ip| ip.address)
+            .ok_or_else(|| anyhow!("No public IP assigned to server {}", self.id))
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    async fn assign_floating_ip(&self, _floating_ip: IpAddr) -> Result<()> {
+        Err(anyhow!("Floating IPs are not supported for Scaleway"))
+    }
+
+    /// Calls the API to terminate the instance, which also
+    /// deletes its volumes and releases its public IP.
+    async fn destroy(&self) -> Result<()> {
+        server_action(&self.id, "terminate").await?;
+        tracing::debug!("Scaleway instance destroyed");
+        Ok(())
+    }
+}
+
+/// Factory for creating [ScalewayServer] servers, registered as `scaleway`.
+pub struct ScalewayProvider;
+
+#[async_trait]
+impl ServerProvider for ScalewayProvider {
+    fn name(&self) -> &'static str {
+        "scaleway"
+    }
+
+    async fn create(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let server = ScalewayServer::new(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+        )
+        .await?;
+        Ok(Box::new(server))
+    }
+
+    /// Stops polling for the instance's boot as soon as `cancel` fires,
+    /// destroying it.
+    async fn create_cancellable(
+        &self,
+        name: &str,
+        services: Vec<ServicePort>,
+        wg_mgr: WireguardManager,
+        ssh_client_keypair: &SshKeypair,
+        ssh_server_keypair: &SshKeypair,
+        options: &CloudConfigOptions,
+        cancel: &CancellationToken,
+    ) -> Result<Box<dyn InnisfreeServer>> {
+        let server = ScalewayServer::create(
+            name,
+            services,
+            wg_mgr,
+            ssh_client_keypair,
+            ssh_server_keypair,
+            options,
+            cancel,
+        )
+        .await?;
+        Ok(Box::new(server))
+    }
+
+    async fn plan(
+        &self,
+        name: &str,
+        user_data: &str,
+        _services: &[ServicePort],
+        _wg_mgr: &WireguardManager,
+        _ssh_client_keypair: &SshKeypair,
+        _options: &CloudConfigOptions,
+    ) -> Result<Vec<ApiRequest>> {
+        let server_config = ScalewayServerConfig::new(
+            name,
+            format!("<image-id for {}>", SCW_IMAGE_LABEL),
+            env_or_placeholder("SCW_DEFAULT_PROJECT_ID"),
+        );
+        Ok(vec![
+            ApiRequest::new("POST", &servers_url(), &server_config)?,
+            ApiRequest::new("PATCH", &user_data_url("<server-id>"), &user_data)?,
+            ApiRequest::new(
+                "POST",
+                &action_url("<server-id>"),
+                &json!({ "action": "poweron" }),
+            )?,
+        ])
+    }
+
+    async fn destroy(&self, _name: &str, id: &str) -> Result<()> {
+        server_action(id, "terminate").await
+    }
+
+    async fn server_exists(&self, _name: &str, id: &str) -> Result<bool> {
+        let response = reqwest::Client::new()
+            .get(server_url(id))
+            .header("X-Auth-Token", api_key()?)
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    async fn adopt(&self, _name: &str, id: &str) -> Result<Box<dyn InnisfreeServer>> {
+        let server = get_server(id).await?;
+        if server.state != "running" {
+            return Err(anyhow!("Server {} is {}, not running", id, server.state));
+        }
+        Ok(Box::new(server))
+    }
+}
+
+/// Polls an instance resource to get the latest data. Used during wait for boot,
+/// to capture networking info like the public IP, which is assigned on power on.
+async fn get_server(id: &str) -> Result<ScalewayServer> {
+    let client = reqwest::Client::new();
+    let response = client
+        .get(server_url(id))
+        .header("X-Auth-Token", api_key()?)
+        .send()
+        .await?
+        .error_for_status()?;
+    let j: serde_json::Value = response.json().await?;
+    let server: ScalewayServer = serde_json::from_value(j["server"].clone())?;
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_server_response() -> Result<()> {
+        let j = json!({
+            "id": "0d5a1b4e-0000-4a4a-9f3b-1d2b3c4d5e6f",
+            "state": "running",
+            "public_ip": { "address": "51.15.1.2", "dynamic": true },
+            "commercial_type": "STARDUST1-S",
+        });
+        let server: ScalewayServer = serde_json::from_value(j)?;
+        let ip: IpAddr = "51.15.1.2".parse()?;
+        assert_eq!(server.ipv4_address()?, ip);
+
+        let j = json!({ "id": "foo", "state": "stopped", "public_ip": null });
+        let server: ScalewayServer = serde_json::from_value(j)?;
+        assert!(server.ipv4_address().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+//! Health checks of the local services, see `innisfree up --health-check`.
+//! The local proxy for a service checks it before passing it any
+//! connections, and every few seconds afterwards. While the check fails,
+//! connections are turned away, rather than forwarded to a dead port: closed
+//! at once, or sent a fixed response, e.g. an HTTP 503, if configured.
+
+use anyhow::{anyhow, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::HealthCheck;
+
+/// How often each service is checked.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a check may take before it fails.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Checks the service at `addr` once.
+pub async fn check(check: &HealthCheck, addr: SocketAddr) -> Result<()> {
+    tokio::time::timeout(CHECK_TIMEOUT, async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let path = match check {
+            HealthCheck::Tcp => return Ok(()),
+            HealthCheck::Http { path } => path,
+        };
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: innisfree\r\nConnection: close\r\n\r\n",
+            path, addr
+        );
+        stream.write_all(request.as_bytes()).await?;
+        // Only the status line matters, e.g. `HTTP/1.1 200 OK`.
+        let mut head = [0; 16];
+        let mut read = 0;
+        while read < 12 {
+            match stream.read(&mut head[read..]).await? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        let line = String::from_utf8_lossy(&head[..read]);
+        let status = line
+            .strip_prefix("HTTP/")
+            .and_then(|l| l.split_once(' '))
+            .and_then(|(_, l)| l.get(..3))
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("not an HTTP response"))?;
+        if !(200..400).contains(&status) {
+            return Err(anyhow!("HTTP status {}", status));
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|_| anyhow!("timed out after {}s", CHECK_TIMEOUT.as_secs()))?
+}
+
+#[derive(Debug, Clone)]
+/// Whether a service passed its latest check, shared with its proxy.
+pub struct Health(Arc<AtomicBool>);
+
+impl Default for Health {
+    /// Healthy, as for services without checks.
+    fn default() -> Self {
+        Health(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+impl Health {
+    /// Whether the service passed its latest check.
+    pub fn is_healthy(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Checks the service at `addr` every [CHECK_INTERVAL] until `cancel`
+    /// fires, updating its health, which is unhealthy until the first check
+    /// passes. Changes are logged.
+    pub fn watch(&self, health_check: HealthCheck, addr: SocketAddr, cancel: CancellationToken) {
+        self.0.store(false, Ordering::Relaxed);
+        let health = self.clone();
+        tokio::spawn(async move {
+            tracing::info!("Waiting for service on {} to pass health checks", addr);
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            // Set once a check has run, so the first failure is logged too.
+            let mut checked = false;
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => return,
+                    _ = interval.tick() => {}
+                }
+                let result = check(&health_check, addr).await;
+                let healthy = result.is_ok();
+                if health.0.swap(healthy, Ordering::Relaxed) != healthy || !checked {
+                    match result {
+                        Ok(()) => tracing::info!("Service on {} is healthy", addr),
+                        Err(e) => tracing::warn!(
+                            "Service on {} failed its health check, turning connections away: {:#}",
+                            addr,
+                            e
+                        ),
+                    }
+                }
+                checked = true;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Serves `response` to each connection on a new local listener.
+    async fn serve(response: &'static [u8]) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = s.read(&mut request).await;
+                let _ = s.write_all(response).await;
+            }
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn services_checked() -> Result<()> {
+        let http = HealthCheck::Http {
+            path: "/healthz".to_string(),
+        };
+        let up = serve(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+        check(&HealthCheck::Tcp, up).await?;
+        check(&http, up).await?;
+        let failing = serve(b"HTTP/1.1 503 Service Unavailable\r\n\r\n").await?;
+        check(&HealthCheck::Tcp, failing).await?;
+        assert!(check(&http, failing).await.is_err());
+        let garbled = serve(b"SSH-2.0-OpenSSH\r\n").await?;
+        assert!(check(&http, garbled).await.is_err());
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        assert!(check(&HealthCheck::Tcp, closed).await.is_err());
+
+        // Unhealthy until the first check passes
+        let health = Health::default();
+        let cancel = CancellationToken::new();
+        health.watch(HealthCheck::Tcp, up, cancel.clone());
+        assert!(!health.is_healthy());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(health.is_healthy());
+        cancel.cancel();
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+//! Tunnels sharing another tunnel's server, see `innisfree up --share`, so
+//! several local tunnels, each with its own services, cost one server.
+//! A guest tunnel joins the host's Wireguard interface on the server as an
+//! additional peer, with its own subnet, and adds its own nginx streams
+//! alongside the host's. The server's firewall accepts the ports of the host
+//! and all its guests, as recorded in their local state.
+
+use anyhow::{anyhow, Context, Result};
+use std::net::IpAddr;
+
+use crate::config::{list_config_dirs, ServicePort};
+use crate::server::cloudinit::{firewall_config, RemoteProxy};
+use crate::server::InnisfreeServer;
+use crate::ssh::client::SshClient;
+use crate::state::TunnelState;
+use crate::wg::WireguardManager;
+
+/// Name of the Wireguard interface on the server.
+const REMOTE_INTERFACE: &str = "innisfree";
+
+/// Loads the state of the tunnel `host`, checking that its server can be
+/// shared: it's backed by `provider`, forwards via nginx without DNAT, and
+/// accepts SSH from the internet, which guests use to join it.
+pub fn host_state(host: &str, provider: &str) -> Result<TunnelState> {
+    let state = TunnelState::load(host)
+        .with_context(|| format!("Tunnel '{}' to share not found, start it first", host))?;
+    let options = &state.options;
+    if let Some(h) = &options.share {
+        return Err(anyhow!(
+            "Tunnel '{}' already shares the server of '{}', share that instead",
+            host,
+            h
+        ));
+    }
+    if state.provider != provider {
+        return Err(anyhow!(
+            "Tunnel '{}' uses provider '{}'",
+            host,
+            state.provider
+        ));
+    }
+    if options.remote_proxy != RemoteProxy::Nginx || options.dnat {
+        return Err(anyhow!(
+            "Only tunnels forwarding via nginx, without DNAT, can be shared"
+        ));
+    }
+    if state.ssh_over_tunnel {
+        return Err(anyhow!(
+            "Tunnel '{}' closed SSH to the internet via --private-ssh, so can't be shared",
+            host
+        ));
+    }
+    Ok(state)
+}
+
+/// Lists the local tunnels sharing the server of the tunnel `host`.
+pub fn guests(host: &str) -> Result<Vec<(String, TunnelState)>> {
+    let mut guests = vec![];
+    for name in list_config_dirs()? {
+        if let Ok(state) = TunnelState::load(&name) {
+            if state.options.share.as_deref() == Some(host) {
+                guests.push((name, state));
+            }
+        }
+    }
+    Ok(guests)
+}
+
+/// Returns the services forwarded by the server of the tunnel `host`: its
+/// own, and its guests'. Those of the tunnel `name`, either the host or a
+/// guest, are taken to be `services`, since they may be changing.
+pub fn server_services(
+    host: &str,
+    name: &str,
+    services: &[ServicePort],
+) -> Result<Vec<ServicePort>> {
+    let mut all = if host == name {
+        services.to_vec()
+    } else {
+        let mut all = TunnelState::load(host)?.services;
+        all.extend_from_slice(services);
+        all
+    };
+    for (guest, state) in guests(host)? {
+        if guest != name {
+            all.extend(state.services);
+        }
+    }
+    Ok(all)
+}
+
+/// Checks that the server of the tunnel `host` doesn't already forward any of
+/// the public ports of `services`, which the tunnel `name` is to forward, via
+/// the host itself or another of its guests.
+pub fn check_ports(host: &str, name: &str, services: &[ServicePort]) -> Result<()> {
+    let options = TunnelState::load(host)
+        .with_context(|| format!("Tunnel '{}' to share not found, start it first", host))?
+        .options;
+    let taken = options.public_ports(&server_services(host, name, &[])?);
+    match services
+        .iter()
+        .find(|s| taken.iter().any(|t| t.same_port(s)))
+    {
+        Some(s) => Err(anyhow!(
+            "Port {}/{} is already forwarded by the server of '{}'",
+            s.port,
+            s.protocol,
+            host
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Returns the path and contents of the firewall for the server of the
+/// tunnel `host`, accepting the ports of the host and its guests, where
+/// the tunnel `name` forwards `services`, see [server_services].
+pub fn server_firewall(
+    host: &str,
+    name: &str,
+    services: &[ServicePort],
+) -> Result<(&'static str, String)> {
+    let state = TunnelState::load(host)?;
+    let wg_port = u16::try_from(state.wg.wg_remote_device.interface.listenport)?;
+    let all = server_services(host, name, services)?;
+    firewall_config(&all, wg_port, &state.options, !state.ssh_over_tunnel)
+}
+
+/// Builds both ends of a guest's tunnel within `wg_subnet`. The remote end
+/// is the host's Wireguard interface, so takes its keys, port, and MTU, but
+/// has an address in the guest's subnet, see [join_cmds].
+pub fn guest_wg(
+    name: &str,
+    wg_subnet: ipnet::IpNet,
+    host: &WireguardManager,
+) -> Result<WireguardManager> {
+    let mut wg = WireguardManager::with_subnet(name, wg_subnet)?;
+    let remote = &host.wg_remote_device.interface;
+    wg.wg_remote_device.interface.keypair = remote.keypair.clone();
+    wg.wg_local_device.peer.keypair = remote.keypair.clone();
+    wg.set_listen_port(u16::try_from(remote.listenport)?);
+    wg.set_mtu(host.wg_remote_device.mtu);
+    Ok(wg)
+}
+
+/// Returns the address of `ip` within its tunnel's subnet, as `wg-quick`
+/// assigns it, and `ip` as the only address routed to a peer.
+fn prefixed(ip: IpAddr) -> (String, String) {
+    match ip {
+        IpAddr::V4(_) => (format!("{}/30", ip), format!("{}/32", ip)),
+        IpAddr::V6(_) => (format!("{}/127", ip), format!("{}/128", ip)),
+    }
+}
+
+/// Commands adding a guest's tunnel to the server's Wireguard interface:
+/// the guest's remote address, so the server answers on it, and the guest's
+/// local end as a peer. Safe to repeat, e.g. after the server rebooted.
+pub fn join_cmds(wg: &WireguardManager) -> Vec<Vec<String>> {
+    let (address, _) = prefixed(wg.wg_remote_ip);
+    let (_, allowed_ips) = prefixed(wg.wg_local_ip);
+    let peer = wg.wg_local_device.interface.keypair.public().to_string();
+    vec![
+        [
+            "sudo",
+            "ip",
+            "address",
+            "replace",
+            &address,
+            "dev",
+            REMOTE_INTERFACE,
+        ]
+        .map(String::from)
+        .to_vec(),
+        [
+            "sudo",
+            "wg",
+            "set",
+            REMOTE_INTERFACE,
+            "peer",
+            &peer,
+            "allowed-ips",
+            &allowed_ips,
+            "persistent-keepalive",
+            "25",
+        ]
+        .map(String::from)
+        .to_vec(),
+    ]
+}
+
+/// Commands removing a guest's tunnel from the server's Wireguard
+/// interface, undoing [join_cmds].
+fn leave_cmds(wg: &WireguardManager) -> Vec<Vec<String>> {
+    let (address, _) = prefixed(wg.wg_remote_ip);
+    let peer = wg.wg_local_device.interface.keypair.public().to_string();
+    vec![
+        [
+            "sudo",
+            "wg",
+            "set",
+            REMOTE_INTERFACE,
+            "peer",
+            &peer,
+            "remove",
+        ]
+        .map(String::from)
+        .to_vec(),
+        [
+            "sudo",
+            "ip",
+            "address",
+            "del",
+            &address,
+            "dev",
+            REMOTE_INTERFACE,
+        ]
+        .map(String::from)
+        .to_vec(),
+    ]
+}
+
+/// Returns the path on the server of the nginx streams for the guest `name`,
+/// next to the host's, see [crate::server::cloudinit::STREAM_CONFIG_PATH].
+pub fn stream_config_path(name: &str) -> String {
+    format!("/etc/nginx/conf.d/stream/guest-{}.conf", name)
+}
+
+/// Removes the guest tunnel `name` from its host's server, which keeps
+/// running: its peer, streams, and ports are removed, in the server's
+/// firewall and in `server`'s cloud firewall.
+pub async fn leave(name: &str, server: &dyn InnisfreeServer) -> Result<()> {
+    let state = TunnelState::load(name)?;
+    let host = state
+        .options
+        .share
+        .as_deref()
+        .ok_or_else(|| anyhow!("Tunnel '{}' doesn't share a server", name))?;
+    {
+        // Dropped before awaiting, since sessions can't be sent across threads.
+        let client = SshClient::for_tunnel(name)?;
+        let run = |cmd: &[&str], input: &str| client.exec(cmd, input.as_bytes(), &|| Ok(()));
+        let (path, ruleset) = server_firewall(host, name, &[])?;
+        run(&["sudo", "tee", path], &ruleset)?;
+        run(&["sudo", "nft", "-f", path], "")?;
+        run(&["sudo", "rm", "-f", &stream_config_path(name)], "")?;
+        run(&["sudo", "systemctl", "reload", "nginx"], "")?;
+        for cmd in leave_cmds(&state.wg) {
+            run(&cmd.iter().map(String::as_str).collect::<Vec<_>>(), "")?;
+        }
+    }
+    for s in &state.services {
+        server.close_port(s).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_joins_host_interface() -> Result<()> {
+        let host = WireguardManager::with_subnet("host", "10.50.0.0/30".parse()?)?;
+        let guest = guest_wg("guest", "10.50.0.4/30".parse()?, &host)?;
+        assert_eq!(guest.wg_remote_ip, "10.50.0.6".parse::<IpAddr>()?);
+        let local = guest.wg_local_device.config()?;
+        assert!(local.contains(&format!(
+            "PublicKey = {}",
+            host.wg_remote_device.interface.keypair.public()
+        )));
+        assert!(local.contains("AllowedIPs = 10.50.0.6/32"));
+
+        let join = join_cmds(&guest);
+        assert_eq!(
+            join[0].join(" "),
+            "sudo ip address replace 10.50.0.6/30 dev innisfree"
+        );
+        assert!(join[1].join(" ").contains(&format!(
+            "peer {} allowed-ips 10.50.0.5/32",
+            guest.wg_local_device.interface.keypair.public()
+        )));
+        assert!(leave_cmds(&guest)[0].join(" ").ends_with(" remove"));
+        assert_eq!(
+            stream_config_path("foo"),
+            "/etc/nginx/conf.d/stream/guest-foo.conf"
+        );
+        Ok(())
+    }
+}
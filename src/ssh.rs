@@ -3,45 +3,119 @@
 //! The client-side keys are written to a local config dir,
 //! by default `~/.config/innisfree/<service>`; the server
 //! keys are placed inside a cloudinit YAML file and passed in
-//! during instance creation.
+//! during instance creation. Commands are run on the server via
+//! the in-process [client::SshClient], see [SshOutput] for their results.
 
 use crate::config::make_config_dir;
-use anyhow::{Context, Result};
+use crate::secrets;
+use anyhow::{anyhow, Context, Result};
 use osshkeys::cipher::Cipher;
+use osshkeys::keys::rsa::{RsaKeyPair, RsaSignature};
 use osshkeys::keys::{KeyPair, KeyType};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-#[derive(Debug)]
-/// Representation of an ED25519 SSH keypair.
+pub mod agent;
+pub mod client;
+pub mod forward;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// Algorithm of a generated SSH keypair, see [SshKeypair::generate].
+pub enum SshKeyType {
+    /// ED25519, supported by any recent OpenSSH.
+    #[default]
+    Ed25519,
+    /// 4096-bit RSA, for appliances and older servers accepting nothing else.
+    Rsa4096,
+    /// ECDSA, on the NIST P-256 curve.
+    Ecdsa,
+}
+
+impl SshKeyType {
+    /// Every key type.
+    pub const ALL: [SshKeyType; 3] = [SshKeyType::Ed25519, SshKeyType::Rsa4096, SshKeyType::Ecdsa];
+
+    /// Name of the algorithm, as used in OpenSSH's key file names, e.g. `id_rsa`,
+    /// and cloud-init's `ssh_keys`, e.g. `rsa_private`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SshKeyType::Ed25519 => "ed25519",
+            SshKeyType::Rsa4096 => "rsa",
+            SshKeyType::Ecdsa => "ecdsa",
+        }
+    }
+}
+
+impl FromStr for SshKeyType {
+    type Err = anyhow::Error;
+
+    /// Parses `ed25519`, `rsa4096`, or `ecdsa`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "ed25519" => Ok(SshKeyType::Ed25519),
+            "rsa4096" => Ok(SshKeyType::Rsa4096),
+            "ecdsa" => Ok(SshKeyType::Ecdsa),
+            _ => Err(anyhow!(
+                "Invalid SSH key type '{}', expected 'ed25519', 'rsa4096', or 'ecdsa'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Representation of an SSH keypair, by default ED25519.
 pub struct SshKeypair {
     /// A human-readable prefix to distinguish it with a unique
     /// filepath if and when its written to disk.
     prefix: String,
-    /// The private ED25519 key material.
+    /// The private key material, in OpenSSH format.
     pub private: String,
-    /// The public ED25519 key material.
+    /// The public key material, in OpenSSH format.
     pub public: String,
+    /// Algorithm of the keypair.
+    #[serde(default)]
+    pub key_type: SshKeyType,
 }
 
 impl SshKeypair {
     /// Generates a new ED25519 SSH keypair.
     pub fn new(prefix: &str) -> Result<SshKeypair> {
-        let kp = KeyPair::generate(KeyType::ED25519, 0)?;
+        SshKeypair::generate(prefix, SshKeyType::Ed25519)
+    }
+
+    /// Generates a new SSH keypair of the given type.
+    pub fn generate(prefix: &str, key_type: SshKeyType) -> Result<SshKeypair> {
+        let kp = match key_type {
+            SshKeyType::Ed25519 => KeyPair::generate(KeyType::ED25519, 0)?,
+            SshKeyType::Rsa4096 => {
+                // Named `ssh-rsa`, rather than by signature algorithm, as
+                // authorized_keys and libssh2 expect. Signatures still use SHA-2.
+                let mut kp = RsaKeyPair::generate(4096)?;
+                kp.set_sign_type(RsaSignature::SHA1);
+                kp.into()
+            }
+            SshKeyType::Ecdsa => KeyPair::generate(KeyType::ECDSA, 256)?,
+        };
         let privkey = kp.serialize_openssh(None, Cipher::Null)?;
         let pubkey = kp.serialize_publickey()?;
         Ok(SshKeypair {
             prefix: prefix.to_string(),
             private: privkey,
             public: pubkey,
+            key_type,
         })
     }
 
     /// Builds predictable filename, based on the prefix,
     /// for use in writing to disk.
     fn filename(&self) -> String {
-        format!("{}_{}", &self.prefix, "id_ed25519")
+        key_filename(&self.prefix, self.key_type)
     }
 
     /// Store keypair on disk, in config dir.
@@ -50,8 +124,9 @@ impl SshKeypair {
         // Ensure service config dir is present
         let config_dir = make_config_dir(service_name).context("failed to create config dir")?;
 
-        // Write SSH privkey.
-        let privkey_filepath = Path::new(&config_dir).join(&self.filename());
+        // Write SSH privkey, encrypted if configured, so then unusable via `ssh -i`.
+        let privkey_filepath = Path::new(&config_dir).join(self.filename());
+        let privkey_data = secrets::encrypt(&self.private)?;
         let mut privkey = std::fs::OpenOptions::new()
             .write(true)
             .create(true)
@@ -60,7 +135,7 @@ impl SshKeypair {
             .open(&privkey_filepath)
             .context("failed to open privkey filepath for writing")?;
         privkey
-            .write_all(&self.private.as_bytes())
+            .write_all(&privkey_data)
             .context("Failed to write SSH privkey")?;
 
         // Write SSH pubkey.
@@ -73,12 +148,88 @@ impl SshKeypair {
             .open(&pubkey_filepath)
             .context("failed to open pubkey filepath for writing")?;
         pubkey
-            .write_all(&self.public.as_bytes())
+            .write_all(self.public.as_bytes())
             .context("failed to write SSH pubkey")?;
         Ok(privkey_filepath)
     }
 }
 
+/// Name of the file holding a private key of `key_type`, e.g. `client_id_ed25519`.
+pub(crate) fn key_filename(prefix: &str, key_type: SshKeyType) -> String {
+    format!("{}_id_{}", prefix, key_type.name())
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Output of a command run successfully on the server over SSH.
+pub struct SshOutput {
+    /// Everything the command wrote to stdout.
+    pub stdout: String,
+    /// Everything the command wrote to stderr, e.g. warnings.
+    pub stderr: String,
+}
+
+impl SshOutput {
+    /// Collects the output of `cmd`, which exited with `code`, or `None` if
+    /// killed, failing with an [SshCommandError] unless it exited successfully.
+    pub fn from_exit(
+        cmd: &str,
+        code: Option<i32>,
+        stdout: &[u8],
+        stderr: &[u8],
+    ) -> Result<SshOutput, SshCommandError> {
+        let stdout = String::from_utf8_lossy(stdout).to_string();
+        let stderr = String::from_utf8_lossy(stderr).to_string();
+        if code != Some(0) {
+            return Err(SshCommandError {
+                cmd: cmd.to_string(),
+                code,
+                stdout,
+                stderr,
+            });
+        }
+        Ok(SshOutput { stdout, stderr })
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A command run on the server over SSH failed.
+pub struct SshCommandError {
+    /// The command, as passed to the remote shell.
+    pub cmd: String,
+    /// Exit code, or `None` if killed by a signal.
+    pub code: Option<i32>,
+    /// Everything the command wrote to stdout.
+    pub stdout: String,
+    /// Everything the command wrote to stderr, usually explaining the failure.
+    pub stderr: String,
+}
+
+impl SshCommandError {
+    /// Last non-empty line of stderr, which usually holds the error message.
+    pub fn reason(&self) -> Option<&str> {
+        self.stderr
+            .lines()
+            .rev()
+            .map(str::trim)
+            .find(|l| !l.is_empty())
+    }
+}
+
+impl fmt::Display for SshCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "Remote command '{}' exited with {}", self.cmd, code)?,
+            None => write!(f, "Remote command '{}' was killed", self.cmd)?,
+        }
+        if let Some(reason) = self.reason() {
+            write!(f, ": {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SshCommandError {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,4 +246,32 @@ mod tests {
         assert!(kp.private.ends_with('\n'));
         Ok(())
     }
+
+    #[test]
+    fn key_types_generated() -> anyhow::Result<()> {
+        let kp = SshKeypair::generate("client", "ecdsa".parse()?)?;
+        assert!(kp.public.starts_with("ecdsa-sha2-nistp256 "));
+        assert_eq!(kp.filename(), "client_id_ecdsa");
+        assert_eq!(SshKeypair::new("client")?.filename(), "client_id_ed25519");
+        assert_eq!("rsa4096".parse::<SshKeyType>()?.name(), "rsa");
+        let kp = SshKeypair::generate("server", SshKeyType::Rsa4096)?;
+        assert!(kp.public.starts_with("ssh-rsa "));
+        assert!("dsa".parse::<SshKeyType>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_commands_report_stderr() {
+        let stderr =
+            "[#] ip link add innisfree type wireguard\nwg-quick: `innisfree' already exists\n";
+        let e = SshOutput::from_exit("wg-quick up", Some(1), b"", stderr.as_bytes()).unwrap_err();
+        assert_eq!(e.code, Some(1));
+        assert_eq!(
+            e.to_string(),
+            "Remote command 'wg-quick up' exited with 1: wg-quick: `innisfree' already exists"
+        );
+        let e = SshOutput::from_exit("sleep 60", None, b"", b"").unwrap_err();
+        assert_eq!(e.to_string(), "Remote command 'sleep 60' was killed");
+        assert!(SshOutput::from_exit("true", Some(0), b"", b"").is_ok());
+    }
 }
@@ -0,0 +1,236 @@
+//! Loads a tunnel's client key into the user's running ssh-agent, for
+//! `innisfree ssh --agent`, speaking the agent protocol on `SSH_AUTH_SOCK`.
+//! Logging in through the agent, rather than with the key in memory, lets
+//! agent-forwarding workflows reuse the key, e.g. `ssh -A` to the server from
+//! another terminal. The key is removed again once the session ends, or the
+//! tunnel is torn down.
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// Reply from the agent when a request succeeded.
+const SSH_AGENT_SUCCESS: u8 = 6;
+/// Request to add a private key to the agent.
+const SSH_AGENTC_ADD_IDENTITY: u8 = 17;
+/// Request to remove a key from the agent, by its public key.
+const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+
+/// Header of OpenSSH's private key format.
+const OPENSSH_KEY_MAGIC: &[u8] = b"openssh-key-v1\0";
+
+/// A client key loaded into an ssh-agent, removed from it again when dropped.
+pub struct AgentKey {
+    /// Socket of the agent holding the key.
+    socket: PathBuf,
+    /// The public key, in SSH wire format, by which the agent identifies it.
+    blob: Vec<u8>,
+}
+
+impl AgentKey {
+    /// Adds `private`, an unencrypted private key in OpenSSH format, to the
+    /// agent listening on `SSH_AUTH_SOCK`.
+    pub fn add(private: &str) -> Result<AgentKey> {
+        AgentKey::add_to(auth_sock()?, private)
+    }
+
+    fn add_to(socket: PathBuf, private: &str) -> Result<AgentKey> {
+        let key = PrivateKey::parse(private)?;
+        let mut msg = vec![SSH_AGENTC_ADD_IDENTITY];
+        msg.extend_from_slice(&key.fields);
+        request(&socket, &msg).context("Failed to add SSH key to ssh-agent")?;
+        tracing::debug!("Added SSH key to ssh-agent at {}", socket.display());
+        Ok(AgentKey {
+            socket,
+            blob: key.public,
+        })
+    }
+
+    /// The public key, in SSH wire format.
+    pub fn blob(&self) -> &[u8] {
+        &self.blob
+    }
+}
+
+impl Drop for AgentKey {
+    fn drop(&mut self) {
+        if let Err(e) = remove_from(&self.socket, &self.blob) {
+            tracing::warn!("{:#}", e);
+        }
+    }
+}
+
+/// Removes `private` from the agent listening on `SSH_AUTH_SOCK`, e.g. when
+/// tearing down a tunnel whose `innisfree ssh --agent` session was killed.
+/// Fails if the agent doesn't hold the key.
+pub fn remove(private: &str) -> Result<()> {
+    remove_from(&auth_sock()?, &PrivateKey::parse(private)?.public)
+}
+
+fn remove_from(socket: &Path, blob: &[u8]) -> Result<()> {
+    let mut msg = vec![SSH_AGENTC_REMOVE_IDENTITY];
+    put_string(&mut msg, blob);
+    request(socket, &msg).context("Failed to remove SSH key from ssh-agent")
+}
+
+/// Path of the running agent's socket.
+fn auth_sock() -> Result<PathBuf> {
+    std::env::var_os("SSH_AUTH_SOCK")
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("No ssh-agent running: SSH_AUTH_SOCK is not set"))
+}
+
+/// Sends the message `msg` to the agent on `socket`, failing unless it succeeds.
+fn request(socket: &Path, msg: &[u8]) -> Result<()> {
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("Failed to connect to ssh-agent at {}", socket.display()))?;
+    let mut framed = vec![];
+    put_string(&mut framed, msg);
+    stream.write_all(&framed)?;
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut reply = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut reply)?;
+    match reply.first() {
+        Some(&SSH_AGENT_SUCCESS) => Ok(()),
+        _ => Err(anyhow!("ssh-agent refused the request")),
+    }
+}
+
+/// Appends `data` to `buf` as an SSH string, prefixed by its length.
+fn put_string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// Reads an SSH string from the start of `buf`, advancing past it.
+fn take_string<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = take_u32(buf)? as usize;
+    if buf.len() < len {
+        return Err(anyhow!("Truncated SSH private key"));
+    }
+    let (s, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(s)
+}
+
+/// Reads a big-endian u32 from the start of `buf`, advancing past it.
+fn take_u32(buf: &mut &[u8]) -> Result<u32> {
+    if buf.len() < 4 {
+        return Err(anyhow!("Truncated SSH private key"));
+    }
+    let (n, rest) = buf.split_at(4);
+    *buf = rest;
+    Ok(u32::from_be_bytes([n[0], n[1], n[2], n[3]]))
+}
+
+/// A private key, decoded from OpenSSH format.
+struct PrivateKey {
+    /// The public key, in SSH wire format.
+    public: Vec<u8>,
+    /// The key type, private fields, and comment, as the agent expects them.
+    fields: Vec<u8>,
+}
+
+impl PrivateKey {
+    /// Decodes an unencrypted private key in OpenSSH format, holding one key.
+    fn parse(private: &str) -> Result<PrivateKey> {
+        let body: String = private
+            .lines()
+            .filter(|l| !l.starts_with("-----"))
+            .collect();
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(body)
+            .context("Invalid SSH private key")?;
+        let mut buf = data
+            .strip_prefix(OPENSSH_KEY_MAGIC)
+            .ok_or_else(|| anyhow!("SSH private key is not in OpenSSH format"))?;
+        if take_string(&mut buf)? != b"none" {
+            return Err(anyhow!("SSH private key is encrypted"));
+        }
+        let _kdf = take_string(&mut buf)?;
+        let _kdf_options = take_string(&mut buf)?;
+        if take_u32(&mut buf)? != 1 {
+            return Err(anyhow!("SSH private key file must hold exactly one key"));
+        }
+        let public = take_string(&mut buf)?.to_vec();
+        let mut section = take_string(&mut buf)?;
+        let _checks = (take_u32(&mut section)?, take_u32(&mut section)?);
+        let start = section;
+        // The key type's private fields, followed by the comment.
+        let count = match take_string(&mut section)? {
+            b"ssh-ed25519" => 3,
+            b"ecdsa-sha2-nistp256" => 4,
+            // RSA keys may be named by signature algorithm, which OpenSSH accepts.
+            b"ssh-rsa" | b"rsa-sha2-256" | b"rsa-sha2-512" => 7,
+            t => {
+                return Err(anyhow!(
+                    "Unsupported SSH key type '{}'",
+                    String::from_utf8_lossy(t)
+                ))
+            }
+        };
+        for _ in 0..count {
+            take_string(&mut section)?;
+        }
+        let fields = start[..start.len() - section.len()].to_vec();
+        Ok(PrivateKey { public, fields })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::{SshKeyType, SshKeypair};
+    use osshkeys::keys::{PublicKey, PublicParts};
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn keys_added_and_removed() -> Result<()> {
+        let socket = std::env::temp_dir().join(format!("innisfree-agent-{}", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket)?;
+        // Fake agent, accepting two requests
+        let agent = std::thread::spawn(move || -> Result<Vec<Vec<u8>>> {
+            let mut msgs = vec![];
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept()?;
+                let mut len = [0; 4];
+                stream.read_exact(&mut len)?;
+                let mut msg = vec![0; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut msg)?;
+                stream.write_all(&[0, 0, 0, 1, SSH_AGENT_SUCCESS])?;
+                msgs.push(msg);
+            }
+            Ok(msgs)
+        });
+
+        let kp = SshKeypair::generate("client", SshKeyType::Ecdsa)?;
+        let blob = PublicKey::from_keystr(&kp.public)?.blob()?;
+        let key = AgentKey::add_to(socket.clone(), &kp.private)?;
+        assert_eq!(key.blob(), blob);
+        drop(key);
+        let msgs = agent.join().unwrap()?;
+        std::fs::remove_file(&socket)?;
+
+        let mut add = &msgs[0][1..];
+        assert_eq!(msgs[0][0], SSH_AGENTC_ADD_IDENTITY);
+        assert_eq!(take_string(&mut add)?, b"ecdsa-sha2-nistp256");
+        let mut remove = &msgs[1][1..];
+        assert_eq!(msgs[1][0], SSH_AGENTC_REMOVE_IDENTITY);
+        assert_eq!(take_string(&mut remove)?, blob);
+
+        // Only the comment's padding follows the fields
+        let kp = SshKeypair::new("client")?;
+        let key = PrivateKey::parse(&kp.private)?;
+        let mut fields = &key.fields[..];
+        for _ in 0..4 {
+            take_string(&mut fields)?;
+        }
+        assert!(fields.is_empty());
+        assert!(PrivateKey::parse("garbage").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,506 @@
+//! In-process SSH client for the server, so that running commands, or an
+//! interactive shell, needs neither an `ssh` binary, nor is affected by
+//! the user's `~/.ssh/config`. Both ends authenticate with the tunnel's
+//! generated keypairs: the server's host key is pinned, rather than
+//! trusted on first use, and the client logs in with its own key, of the same type.
+
+use anyhow::{anyhow, Context, Result};
+use osshkeys::keys::{PublicKey, PublicParts};
+use ssh2::{Channel, MethodType, Session};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::make_config_dir;
+use crate::error;
+use crate::secrets;
+use crate::ssh::agent::AgentKey;
+use crate::ssh::{key_filename, SshKeyType, SshOutput};
+use crate::state::TunnelState;
+
+/// User created on the server by cloud-init.
+const SSH_USER: &str = "innisfree";
+
+/// How long to wait for the server to accept a connection, and to respond during setup.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for output before checking whether to stop waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Authenticated SSH connection to a tunnel's server.
+pub struct SshClient {
+    pub(super) session: Session,
+    /// Socket underlying the session, polled while waiting for output.
+    pub(super) fd: RawFd,
+    /// Client key loaded into ssh-agent for the session, see [SshClient::for_tunnel_via_agent].
+    /// Removed from the agent when the client is dropped.
+    agent_key: Option<AgentKey>,
+}
+
+impl SshClient {
+    /// Connects to the server at `ip`, verifying its host key is `host_key`,
+    /// in OpenSSH format, then logs in with the private key `client_key`.
+    pub fn connect(ip: IpAddr, client_key: &str, host_key: &str) -> Result<SshClient> {
+        let client = SshClient::handshake(ip, host_key)?;
+        client
+            .session
+            .userauth_pubkey_memory(SSH_USER, None, client_key, None)
+            .map_err(|e| error::ssh(format!("SSH login to {} failed: {}", ip, e)))?;
+        // Remote commands may run for a while, e.g. waiting on cloud-init.
+        client.session.set_timeout(0);
+        Ok(client)
+    }
+
+    /// Connects to the server at `ip`, as with [SshClient::connect], but logs
+    /// in via the running ssh-agent, with the key it holds as `agent_key`.
+    fn connect_via_agent(ip: IpAddr, agent_key: AgentKey, host_key: &str) -> Result<SshClient> {
+        let mut client = SshClient::handshake(ip, host_key)?;
+        let mut agent = client.session.agent()?;
+        agent
+            .connect()
+            .and_then(|_| agent.list_identities())
+            .map_err(|e| anyhow!("Failed to query ssh-agent: {}", e))?;
+        let identity = agent
+            .identities()?
+            .into_iter()
+            .find(|i| i.blob() == agent_key.blob())
+            .ok_or_else(|| anyhow!("ssh-agent doesn't list the added SSH key"))?;
+        agent
+            .userauth(SSH_USER, &identity)
+            .map_err(|e| error::ssh(format!("SSH login to {} via ssh-agent failed: {}", ip, e)))?;
+        let _ = agent.disconnect();
+        client.session.set_timeout(0);
+        client.agent_key = Some(agent_key);
+        Ok(client)
+    }
+
+    /// Opens a session with the server at `ip`, verifying its host key is
+    /// `host_key`, without logging in yet.
+    fn handshake(ip: IpAddr, host_key: &str) -> Result<SshClient> {
+        let addr = SocketAddr::new(ip, 22);
+        let tcp = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .map_err(|e| error::ssh(format!("Failed to connect to {}: {}", addr, e)))?;
+        let fd = tcp.as_raw_fd();
+        let mut session = Session::new()?;
+        session.set_timeout(CONNECT_TIMEOUT.as_millis() as u32);
+        // Only the generated host key is pinned, so don't negotiate any other.
+        session.method_pref(MethodType::HostKey, host_key_methods(host_key))?;
+        session.set_tcp_stream(tcp);
+        session
+            .handshake()
+            .map_err(|e| error::ssh(format!("SSH handshake with {} failed: {}", addr, e)))?;
+        let offered = session.host_key().map(|(k, _)| k).unwrap_or_default();
+        if !host_key_matches(offered, host_key)? {
+            return Err(error::ssh(format!(
+                "Host key for {} doesn't match the one generated for the server",
+                addr
+            )));
+        }
+        Ok(SshClient {
+            session,
+            fd,
+            agent_key: None,
+        })
+    }
+
+    /// Connects to the server of the tunnel `service_name`, running in a
+    /// separate process, with the keys from its saved state. Falls back to
+    /// the key and known_hosts files, for tunnels without saved state.
+    pub fn for_tunnel(service_name: &str) -> Result<SshClient> {
+        let (ip, client_key, host_key) = tunnel_keys(service_name)?;
+        SshClient::connect(ip, &client_key, &host_key)
+    }
+
+    /// Connects to the server of the tunnel `service_name`, as with
+    /// [SshClient::for_tunnel], but first adds the client key to the running
+    /// ssh-agent, logging in via the agent. The key stays in the agent,
+    /// e.g. for `ssh -A`, until the client is dropped.
+    pub fn for_tunnel_via_agent(service_name: &str) -> Result<SshClient> {
+        let (ip, client_key, host_key) = tunnel_keys(service_name)?;
+        let agent_key = AgentKey::add(&client_key)?;
+        SshClient::connect_via_agent(ip, agent_key, &host_key)
+    }
+
+    /// Runs `cmd` on the server, passing `input` on stdin, and returns its output.
+    /// Each argument is quoted for the remote shell, see [shell_join], so
+    /// it's passed as it is, as to a local command. Fails with an
+    /// [super::SshCommandError] if the command does. Stops waiting, closing the
+    /// channel, if `check` fails, e.g. because the tunnel is shutting down.
+    pub fn exec(
+        &self,
+        cmd: &[&str],
+        input: &[u8],
+        check: &dyn Fn() -> Result<()>,
+    ) -> Result<SshOutput> {
+        let cmd = shell_join(cmd);
+        let mut channel = self.open(&cmd)?;
+        channel.write_all(input)?;
+        channel.send_eof()?;
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        let pumped = self.pump(&mut channel, false, check, |stream, data| {
+            match stream {
+                Stream::Stdout => stdout.extend_from_slice(data),
+                Stream::Stderr => stderr.extend_from_slice(data),
+            }
+            Ok(())
+        });
+        if let Err(e) = pumped {
+            let _ = channel.close();
+            return Err(e);
+        }
+        let code = exit_code(&mut channel)?;
+        Ok(SshOutput::from_exit(&cmd, code, &stdout, &stderr)?)
+    }
+
+    /// Runs `cmd` on the server, for `innisfree exec` or `innisfree logs`,
+    /// passing through stdin, stdout, and stderr. Arguments are quoted as
+    /// for [SshClient::exec]. Returns its exit code, or `None` if it was killed.
+    pub fn exec_passthrough(&self, cmd: &[String]) -> Result<Option<i32>> {
+        let mut channel = self.open(&shell_join(cmd))?;
+        self.passthrough(&mut channel)
+    }
+
+    /// Opens an interactive shell on the server, for `innisfree ssh`,
+    /// with the local terminal in raw mode until it exits.
+    pub fn shell(&self) -> Result<Option<i32>> {
+        let mut channel = self.session.channel_session()?;
+        let term = std::env::var("TERM").unwrap_or_else(|_| "xterm".to_string());
+        channel.request_pty(&term, None, Some(terminal_size()))?;
+        channel.shell()?;
+        let _raw = RawMode::enable()?;
+        self.passthrough(&mut channel)
+    }
+
+    /// Copies the local file `local` to `remote` on the server, via SFTP,
+    /// returning the number of bytes copied. Paths on the server are
+    /// accessed as the `innisfree` user, so privileged ones need moving
+    /// into place afterwards, e.g. via `sudo mv`.
+    pub fn push_file(&self, local: &Path, remote: &str) -> Result<u64> {
+        let mut src =
+            File::open(local).with_context(|| format!("Failed to open {}", local.display()))?;
+        let mut dest = self
+            .session
+            .sftp()?
+            .create(Path::new(remote))
+            .with_context(|| format!("Failed to create {} on server", remote))?;
+        std::io::copy(&mut src, &mut dest)
+            .with_context(|| format!("Failed to copy {} to server", local.display()))
+    }
+
+    /// Copies the file `remote` on the server to `local`, via SFTP,
+    /// returning the number of bytes copied.
+    pub fn pull_file(&self, remote: &str, local: &Path) -> Result<u64> {
+        let mut src = self
+            .session
+            .sftp()?
+            .open(Path::new(remote))
+            .with_context(|| format!("Failed to open {} on server", remote))?;
+        let mut dest =
+            File::create(local).with_context(|| format!("Failed to create {}", local.display()))?;
+        std::io::copy(&mut src, &mut dest)
+            .with_context(|| format!("Failed to copy {} from server", remote))
+    }
+
+    /// Opens a channel running `cmd`.
+    fn open(&self, cmd: &str) -> Result<Channel> {
+        let mut channel = self.session.channel_session()?;
+        channel
+            .exec(cmd)
+            .with_context(|| format!("Failed to run '{}' on server", cmd))?;
+        Ok(channel)
+    }
+
+    /// Connects the channel to local stdin, stdout, and stderr,
+    /// until the remote end closes it, returning its exit code.
+    fn passthrough(&self, channel: &mut Channel) -> Result<Option<i32>> {
+        self.pump(channel, true, &|| Ok(()), |stream, data| {
+            let written = match stream {
+                Stream::Stdout => write_flushed(std::io::stdout(), data),
+                Stream::Stderr => write_flushed(std::io::stderr(), data),
+            };
+            Ok(written?)
+        })?;
+        exit_code(channel)
+    }
+
+    /// Reads from the channel until EOF, passing each chunk of output to
+    /// `sink`, and forwarding local stdin, if `stdin` is set. Checks `check`
+    /// whenever waiting, returning its error, if any.
+    fn pump(
+        &self,
+        channel: &mut Channel,
+        stdin: bool,
+        check: &dyn Fn() -> Result<()>,
+        sink: impl FnMut(Stream, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        // Reads don't block, so that stdout and stderr can be read in turn,
+        // without either filling the channel's window while we wait on the other.
+        self.session.set_blocking(false);
+        let pumped = self.pump_nonblocking(channel, stdin, check, sink);
+        self.session.set_blocking(true);
+        pumped
+    }
+
+    /// Loop of [SshClient::pump], with the session in non-blocking mode.
+    fn pump_nonblocking(
+        &self,
+        channel: &mut Channel,
+        mut stdin: bool,
+        check: &dyn Fn() -> Result<()>,
+        mut sink: impl FnMut(Stream, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let mut buf = [0; 16 * 1024];
+        loop {
+            let mut idle = true;
+            for stream in [Stream::Stdout, Stream::Stderr] {
+                let read = match stream {
+                    Stream::Stdout => channel.read(&mut buf),
+                    Stream::Stderr => channel.stderr().read(&mut buf),
+                };
+                match read {
+                    Ok(0) => {}
+                    Ok(n) => {
+                        idle = false;
+                        sink(stream, &buf[..n])?;
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if channel.eof() {
+                return Ok(());
+            }
+            if !idle {
+                continue;
+            }
+            check()?;
+            let mut fds = vec![self.fd];
+            if stdin {
+                fds.push(libc::STDIN_FILENO);
+            }
+            if !wait_readable(&fds, POLL_INTERVAL)?.contains(&libc::STDIN_FILENO) {
+                continue;
+            }
+            // SAFETY: buf is valid for writes of its length.
+            let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+            // Writes block, since there's nothing else to do until they're sent.
+            self.session.set_blocking(true);
+            let forwarded = match n {
+                n if n > 0 => channel.write_all(&buf[..n as usize]),
+                _ => {
+                    stdin = false;
+                    channel.send_eof().map_err(Into::into)
+                }
+            };
+            self.session.set_blocking(false);
+            forwarded?;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Output stream of a remote command.
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Quotes `arg` for a POSIX shell, in single quotes, unless it's plain.
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@,+%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Joins `cmd` into a command line for the remote shell, which SSH runs
+/// commands with, quoting each argument so it stays one argument.
+fn shell_join(cmd: &[impl AsRef<str>]) -> String {
+    cmd.iter()
+        .map(|a| shell_quote(a.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Server address, client private key, and server host key, of the tunnel
+/// `service_name`, from its saved state. Falls back to the key and
+/// known_hosts files, for tunnels without saved state.
+fn tunnel_keys(service_name: &str) -> Result<(IpAddr, String, String)> {
+    if let Ok(state) = TunnelState::load(service_name) {
+        return Ok((
+            state.ssh_ip(),
+            state.ssh_client_keypair.private,
+            state.ssh_server_keypair.public,
+        ));
+    }
+    let config_dir = make_config_dir(service_name)?;
+    let client_key = SshKeyType::ALL
+        .iter()
+        .find_map(|t| std::fs::read(config_dir.join(key_filename("client", *t))).ok())
+        .ok_or_else(|| anyhow!("Failed to read SSH client key"))?;
+    let client_key = secrets::decrypt(client_key).context("Failed to decrypt SSH client key")?;
+    let known_hosts = std::fs::read_to_string(config_dir.join("known_hosts"))
+        .context("Failed to read known_hosts")?;
+    let (ip, host_key) = known_hosts
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("Invalid known_hosts file"))?;
+    Ok((ip.parse()?, client_key, host_key.to_string()))
+}
+
+/// Host key algorithms to negotiate for `host_key`, an OpenSSH public key.
+/// RSA keys may sign with SHA-2 or, for older servers, SHA-1.
+fn host_key_methods(host_key: &str) -> &'static str {
+    match host_key.split(' ').next() {
+        Some("ssh-rsa") => "rsa-sha2-512,rsa-sha2-256,ssh-rsa",
+        Some("ecdsa-sha2-nistp256") => "ecdsa-sha2-nistp256",
+        _ => "ssh-ed25519",
+    }
+}
+
+/// Whether `offered`, the raw host key presented by the server, is `expected`,
+/// an OpenSSH public key, e.g. `ssh-ed25519 AAAA...`.
+fn host_key_matches(offered: &[u8], expected: &str) -> Result<bool> {
+    let expected = PublicKey::from_keystr(expected.trim())
+        .and_then(|k| k.blob())
+        .context("Invalid host key for server")?;
+    Ok(!offered.is_empty() && offered == expected.as_slice())
+}
+
+/// Waits for the remote command to exit, returning its exit code,
+/// or `None` if it was killed by a signal.
+fn exit_code(channel: &mut Channel) -> Result<Option<i32>> {
+    channel.wait_close()?;
+    if channel.exit_signal()?.exit_signal.is_some() {
+        return Ok(None);
+    }
+    Ok(Some(channel.exit_status()?))
+}
+
+/// Writes `data` to `out`, flushing it, so interactive output isn't held back.
+fn write_flushed(mut out: impl Write, data: &[u8]) -> std::io::Result<()> {
+    out.write_all(data)?;
+    out.flush()
+}
+
+/// Waits until any of `fds` is readable, or `timeout` elapses,
+/// returning those that are.
+pub(super) fn wait_readable(fds: &[RawFd], timeout: Duration) -> Result<Vec<RawFd>> {
+    let mut pollfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    // SAFETY: pollfds is a valid array of pollfd structs, of the given length.
+    let rc = unsafe {
+        libc::poll(
+            pollfds.as_mut_ptr(),
+            pollfds.len() as libc::nfds_t,
+            timeout.as_millis() as libc::c_int,
+        )
+    };
+    if rc < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e.into());
+        }
+    }
+    Ok(pollfds
+        .iter()
+        .filter(|p| p.revents & (libc::POLLIN | libc::POLLHUP) != 0)
+        .map(|p| p.fd)
+        .collect())
+}
+
+/// Size of the local terminal, as (columns, rows, width, height),
+/// defaulting to 80x24 if it's unknown, e.g. stdout isn't a terminal.
+fn terminal_size() -> (u32, u32, u32, u32) {
+    // SAFETY: winsize is plain data, filled in by the ioctl on success.
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    let rc = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) };
+    if rc != 0 || ws.ws_col == 0 {
+        return (80, 24, 0, 0);
+    }
+    (ws.ws_col.into(), ws.ws_row.into(), 0, 0)
+}
+
+/// Local terminal in raw mode, so keystrokes like ctrl+c reach the
+/// remote shell. The original mode is restored when dropped.
+struct RawMode(Option<libc::termios>);
+
+impl RawMode {
+    /// Puts stdin into raw mode, if it's a terminal.
+    fn enable() -> Result<RawMode> {
+        // SAFETY: termios is plain data, filled in by tcgetattr on success.
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Ok(RawMode(None));
+        }
+        let mut raw = original;
+        unsafe {
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .context("Failed to put terminal in raw mode");
+            }
+        }
+        Ok(RawMode(Some(original)))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(original) = &self.0 {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::SshKeypair;
+
+    #[test]
+    fn host_key_pinned() -> Result<()> {
+        let server = SshKeypair::new("server")?;
+        let other = SshKeypair::new("server")?;
+        let offered = PublicKey::from_keystr(&server.public)?.blob()?;
+        assert!(host_key_matches(&offered, &server.public)?);
+        assert!(!host_key_matches(&offered, &other.public)?);
+        assert!(!host_key_matches(&[], &server.public)?);
+        assert!(host_key_matches(&offered, "ssh-ed25519 garbage").is_err());
+        assert_eq!(host_key_methods(&server.public), "ssh-ed25519");
+        assert_eq!(
+            host_key_methods("ssh-rsa AAAA"),
+            "rsa-sha2-512,rsa-sha2-256,ssh-rsa"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn args_quoted_for_remote_shell() {
+        assert_eq!(shell_join(&["sudo", "wg", "show"]), "sudo wg show");
+        assert_eq!(
+            shell_join(&["sh", "-c", "ls /tmp && rm x"]),
+            "sh -c 'ls /tmp && rm x'"
+        );
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
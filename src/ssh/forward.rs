@@ -0,0 +1,216 @@
+//! Reverse port forwarding over SSH, as with `ssh -R`, carrying public traffic
+//! to local services when Wireguard can't, e.g. on networks blocking outbound
+//! UDP. The server's sshd listens on loopback ports, to which its forwarding
+//! config points, and each connection is relayed over the SSH session.
+
+use anyhow::{anyhow, Context, Result};
+use ssh2::{Channel, ErrorCode, Listener};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use super::client::SshClient;
+
+/// How long to wait for traffic before checking for cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long to wait for a local service to accept a forwarded connection.
+const LOCAL_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// libssh2's code for a non-blocking call that would have blocked.
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+/// Address on which the server's sshd listens for forwarded connections.
+const REMOTE_LISTEN_HOST: &str = "127.0.0.1";
+
+/// Ports on the server forwarded to local addresses, over one SSH session.
+pub struct ReverseForwards {
+    client: SshClient,
+    /// Listeners on the server, with the local address each forwards to.
+    listeners: Vec<(Listener, SocketAddr)>,
+    /// Ports on which the server listens, in the order of the targets.
+    remote_ports: Vec<u16>,
+}
+
+impl ReverseForwards {
+    /// Asks the server to listen on a free loopback port for each of `targets`,
+    /// forwarding connections to it. See [ReverseForwards::remote_ports].
+    pub fn listen(client: SshClient, targets: &[SocketAddr]) -> Result<ReverseForwards> {
+        let mut listeners = vec![];
+        let mut remote_ports = vec![];
+        for target in targets {
+            let (listener, port) = client
+                .session
+                .channel_forward_listen(0, Some(REMOTE_LISTEN_HOST), None)
+                .with_context(|| format!("Failed to forward a port on the server to {}", target))?;
+            tracing::debug!(
+                "Forwarding {}:{} on server to {}",
+                REMOTE_LISTEN_HOST,
+                port,
+                target
+            );
+            listeners.push((listener, *target));
+            remote_ports.push(port);
+        }
+        Ok(ReverseForwards {
+            client,
+            listeners,
+            remote_ports,
+        })
+    }
+
+    /// Loopback ports on which the server listens, one per target, in order.
+    pub fn remote_ports(&self) -> &[u16] {
+        &self.remote_ports
+    }
+
+    /// Relays connections until `cancel` fires, or the SSH session fails.
+    /// Blocks, so run it on a dedicated thread.
+    pub fn run(mut self, cancel: &CancellationToken) -> Result<()> {
+        // Everything shares the session, so nothing may block on it.
+        self.client.session.set_blocking(false);
+        let mut relays: Vec<Relay> = vec![];
+        let mut buf = [0; 16 * 1024];
+        while !cancel.is_cancelled() {
+            let mut idle = true;
+            for (listener, target) in self.listeners.iter_mut() {
+                match listener.accept() {
+                    Ok(channel) => {
+                        idle = false;
+                        match Relay::connect(channel, *target) {
+                            Ok(relay) => relays.push(relay),
+                            Err(e) => tracing::warn!("{:#}", e),
+                        }
+                    }
+                    Err(e) if session_would_block(&e) => {}
+                    Err(e) => return Err(anyhow!("Failed to accept forwarded connection: {}", e)),
+                }
+            }
+            for relay in relays.iter_mut() {
+                match relay.step(&mut buf) {
+                    Ok(progressed) => idle &= !progressed,
+                    Err(e) => {
+                        tracing::debug!("Forwarded connection to {} failed: {}", relay.target, e);
+                        relay.done = true;
+                    }
+                }
+            }
+            relays.retain(|r| !r.done);
+            if idle {
+                let mut fds = vec![self.client.fd];
+                fds.extend(relays.iter().map(|r| r.local.as_raw_fd()));
+                super::client::wait_readable(&fds, POLL_INTERVAL)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Connection forwarded from the server, relayed to a local address.
+struct Relay {
+    channel: Channel,
+    local: TcpStream,
+    target: SocketAddr,
+    /// Data read from the server, not yet written locally.
+    to_local: Vec<u8>,
+    /// Data read locally, not yet written to the server.
+    to_remote: Vec<u8>,
+    remote_eof: bool,
+    local_eof: bool,
+    done: bool,
+}
+
+impl Relay {
+    /// Connects the forwarded `channel` to the local address `target`.
+    fn connect(mut channel: Channel, target: SocketAddr) -> Result<Relay> {
+        let local = match TcpStream::connect_timeout(&target, LOCAL_CONNECT_TIMEOUT) {
+            Ok(local) => local,
+            Err(e) => {
+                let _ = channel.close();
+                return Err(anyhow!(
+                    "Failed to connect forwarded connection to {}: {}",
+                    target,
+                    e
+                ));
+            }
+        };
+        local.set_nonblocking(true)?;
+        Ok(Relay {
+            channel,
+            local,
+            target,
+            to_local: vec![],
+            to_remote: vec![],
+            remote_eof: false,
+            local_eof: false,
+            done: false,
+        })
+    }
+
+    /// Moves whatever data is ready in either direction, without blocking,
+    /// returning whether any was. Marks the relay done once both sides close.
+    fn step(&mut self, buf: &mut [u8]) -> std::io::Result<bool> {
+        let mut progressed = false;
+        if self.to_local.is_empty() && !self.remote_eof {
+            match self.channel.read(buf) {
+                Ok(0) if self.channel.eof() => {
+                    self.remote_eof = true;
+                    let _ = self.local.shutdown(Shutdown::Write);
+                }
+                Ok(n) => self.to_local.extend_from_slice(&buf[..n]),
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        progressed |= flush(&mut self.local, &mut self.to_local)?;
+        if self.to_remote.is_empty() && !self.local_eof {
+            match self.local.read(buf) {
+                Ok(0) => {
+                    self.local_eof = true;
+                    progressed = true;
+                    match self.channel.send_eof() {
+                        Err(e) if session_would_block(&e) => self.local_eof = false,
+                        r => r?,
+                    }
+                }
+                Ok(n) => self.to_remote.extend_from_slice(&buf[..n]),
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        progressed |= flush(&mut self.channel, &mut self.to_remote)?;
+        if self.remote_eof && self.local_eof && self.to_local.is_empty() {
+            let _ = self.channel.close();
+            self.done = true;
+        }
+        Ok(progressed)
+    }
+}
+
+/// Writes as much of `pending` to `w` as it accepts without blocking,
+/// returning whether anything was written.
+fn flush(w: &mut impl Write, pending: &mut Vec<u8>) -> std::io::Result<bool> {
+    if pending.is_empty() {
+        return Ok(false);
+    }
+    match w.write(pending) {
+        Ok(n) => {
+            pending.drain(..n);
+            Ok(n > 0)
+        }
+        Err(e) if would_block(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `e` only means the operation would have blocked.
+fn would_block(e: &std::io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock
+}
+
+/// Whether the SSH call failing with `e` only would have blocked.
+fn session_would_block(e: &ssh2::Error) -> bool {
+    e.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN)
+}
@@ -0,0 +1,224 @@
+//! Persisted details of a tunnel, written to `state.json` in its config dir,
+//! so that other processes can operate on a tunnel they didn't create,
+//! e.g. `innisfree down`, `innisfree ssh`, or `innisfree status`.
+//! Holds the tunnel's keys too, so `innisfree up` can re-attach to it,
+//! and is only readable by its owner, and optionally encrypted, see
+//! [crate::secrets].
+
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{make_config_dir, ServicePort};
+use crate::dns::DnsRecord;
+use crate::secrets;
+use crate::server::cloudinit::CloudConfigOptions;
+use crate::ssh::SshKeypair;
+use crate::wg::WireguardManager;
+
+#[derive(Debug, Serialize, Deserialize)]
+/// Details of the tunnel's remote server and configuration.
+pub struct TunnelState {
+    /// Name of the cloud provider backing the remote server, e.g. `digitalocean`.
+    pub provider: String,
+    /// Provider's identifier for the server, see [crate::server::InnisfreeServer::id].
+    pub server_id: String,
+    /// When the server was created, as seconds since the unix epoch.
+    pub created_at: u64,
+    /// Public IPv4 address of the server itself, for SSH and Wireguard.
+    pub server_ip: IpAddr,
+    /// Public IP on which services are published: the reserved IP,
+    /// if one was assigned, otherwise the same as `server_ip`.
+    pub public_ip: IpAddr,
+    /// Public IPv6 address of the server, if it has one.
+    pub public_ipv6: Option<IpAddr>,
+    /// Subnet holding the addresses of the tunnel's two Wireguard interfaces.
+    pub wg_subnet: IpNet,
+    /// Services currently forwarded, kept up to date as they're added or removed.
+    pub services: Vec<ServicePort>,
+    /// Customizations the server was configured with.
+    pub options: CloudConfigOptions,
+    /// Both ends of the Wireguard tunnel, including their keypairs.
+    pub wg: WireguardManager,
+    /// SSH keypair for connecting to the server.
+    pub ssh_client_keypair: SshKeypair,
+    /// SSH keypair identifying the server.
+    pub ssh_server_keypair: SshKeypair,
+    /// Whether the server's SSH is closed to the internet, and only reachable
+    /// over the tunnel, see [CloudConfigOptions::private_ssh].
+    #[serde(default)]
+    pub ssh_over_tunnel: bool,
+    /// Name of the tunnel the server was created for, if not this one, i.e.
+    /// if it was claimed from the pool, see [crate::pool]. Providers find the
+    /// server's resources by it, e.g. to destroy them.
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// DNS record pointed at the tunnel's public IP, if any, see [crate::dns].
+    #[serde(default)]
+    pub dns: Option<DnsRecord>,
+}
+
+/// Returns the path to the state file for the tunnel `service_name`.
+fn state_path(service_name: &str) -> Result<PathBuf> {
+    Ok(make_config_dir(service_name)?.join("state.json"))
+}
+
+/// Returns the current time, as seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl TunnelState {
+    /// Writes the state to the tunnel's config dir.
+    pub fn save(&self, service_name: &str) -> Result<()> {
+        let data = secrets::encrypt(&serde_json::to_string_pretty(self)?)?;
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(state_path(service_name)?)
+            .context("Failed to write tunnel state")?;
+        f.write_all(&data)?;
+        Ok(())
+    }
+
+    /// Reads the state from the tunnel's config dir.
+    pub fn load(service_name: &str) -> Result<TunnelState> {
+        let data = std::fs::read(state_path(service_name)?).context(
+            "Tunnel state not found. Try running 'innisfree up' first, or pass --name=<service>",
+        )?;
+        let s = secrets::decrypt(data).context("Failed to decrypt tunnel state")?;
+        serde_json::from_str(&s).context("Invalid tunnel state")
+    }
+
+    /// Applies `f` to the tunnel's saved state, e.g. after its services change.
+    pub fn update<F: FnOnce(&mut TunnelState)>(service_name: &str, f: F) -> Result<()> {
+        let mut state = TunnelState::load(service_name)?;
+        f(&mut state);
+        state.save(service_name)
+    }
+
+    /// Returns how long ago the server was created.
+    pub fn uptime(&self) -> Duration {
+        let created = UNIX_EPOCH + Duration::from_secs(self.created_at);
+        SystemTime::now()
+            .duration_since(created)
+            .unwrap_or_default()
+    }
+
+    /// Returns the address on which the server accepts SSH: its end of
+    /// the tunnel, if SSH is closed to the internet, else its public IP.
+    pub fn ssh_ip(&self) -> IpAddr {
+        if self.ssh_over_tunnel {
+            self.wg.wg_remote_ip
+        } else {
+            self.server_ip
+        }
+    }
+
+    /// Returns the name under which the provider knows the server: the
+    /// tunnel's own, `tunnel_name`, unless it was claimed from the pool.
+    pub fn server_name<'a>(&'a self, tunnel_name: &'a str) -> &'a str {
+        self.server_name.as_deref().unwrap_or(tunnel_name)
+    }
+
+    /// Returns why the saved tunnel can't be re-attached to as configured,
+    /// i.e. with the server on `provider`, created with `options`, and
+    /// published on `static_ip`, if any, so `up` must replace it.
+    pub fn mismatch(
+        &self,
+        provider: &str,
+        options: &CloudConfigOptions,
+        static_ip: Option<IpAddr>,
+    ) -> Option<String> {
+        if self.provider != provider {
+            return Some(format!("Saved tunnel uses provider '{}'", self.provider));
+        }
+        // Wireguard settings, e.g. a random port, are kept from the saved tunnel.
+        let saved = &self.options;
+        if saved.https_domain != options.https_domain
+            || saved.sni_routes != options.sni_routes
+            || saved.vhost_routes != options.vhost_routes
+            || saved.dnat != options.dnat
+            || saved.remote_proxy != options.remote_proxy
+            || saved.share != options.share
+        {
+            return Some("Saved tunnel was configured with different options".to_string());
+        }
+        if static_ip.is_some_and(|ip| ip != self.public_ip) {
+            return Some("Saved tunnel uses a different reserved IP".to_string());
+        }
+        None
+    }
+
+    /// Returns the public ports forwarded, e.g. `443/TCP`.
+    pub fn ports(&self) -> Vec<String> {
+        self.services
+            .iter()
+            .map(|s| format!("{}/{}", s.port, s.protocol))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn state_round_trips() -> Result<()> {
+        let state = TunnelState {
+            provider: "digitalocean".to_string(),
+            server_id: "12345".to_string(),
+            created_at: 1700000000,
+            server_ip: "203.0.113.5".parse()?,
+            public_ip: "198.51.100.7".parse()?,
+            public_ipv6: None,
+            wg_subnet: "10.50.0.0/30".parse()?,
+            services: vec![ServicePort::try_from("443:8443/TCP")?],
+            options: CloudConfigOptions::default(),
+            wg: WireguardManager::with_subnet("test", "10.50.0.0/30".parse()?)?,
+            ssh_client_keypair: SshKeypair::new("client")?,
+            ssh_server_keypair: SshKeypair::new("server")?,
+            ssh_over_tunnel: false,
+            server_name: None,
+            dns: None,
+        };
+        let mut j = serde_json::to_value(&state)?;
+        assert_eq!(j["wg_subnet"], "10.50.0.0/30");
+        assert_eq!(j["services"][0]["local_port"], 8443);
+        let parsed: TunnelState = serde_json::from_value(j.clone())?;
+        assert_eq!(parsed.server_ip, state.server_ip);
+        assert_eq!(parsed.wg_subnet, state.wg_subnet);
+        assert_eq!(parsed.ports(), vec!["443/TCP"]);
+        assert_eq!(parsed.options, state.options);
+        assert_eq!(parsed.wg.wg_remote_ip, state.wg.wg_remote_ip);
+        assert_eq!(
+            parsed.wg.wg_local_device.interface.keypair.public(),
+            state.wg.wg_local_device.interface.keypair.public()
+        );
+        assert_eq!(
+            parsed.ssh_server_keypair.public,
+            state.ssh_server_keypair.public
+        );
+        assert_eq!(parsed.ssh_ip(), state.server_ip);
+        assert_eq!(parsed.server_name("test"), "test");
+
+        // Saved before SSH could be closed
+        j.as_object_mut().unwrap().remove("ssh_over_tunnel");
+        let mut parsed: TunnelState = serde_json::from_value(j)?;
+        assert!(!parsed.ssh_over_tunnel);
+        parsed.ssh_over_tunnel = true;
+        assert_eq!(parsed.ssh_ip(), state.wg.wg_remote_ip);
+        Ok(())
+    }
+}
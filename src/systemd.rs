@@ -0,0 +1,247 @@
+//! Running a tunnel as a systemd service. `innisfree systemd install`
+//! writes a unit for a named tunnel, whose credentials go in a separate
+//! env file, and the `up` process notifies systemd once the public IP
+//! is serving traffic, so dependent units start only when it's usable.
+
+use anyhow::{anyhow, Context, Result};
+use std::fs::Permissions;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+/// Directory in which units are installed by default.
+pub const UNIT_DIR: &str = "/etc/systemd/system";
+/// Directory holding each service's env file, e.g. `/etc/innisfree/foo.env`.
+pub const ENV_DIR: &str = "/etc/innisfree";
+/// Env vars holding provider credentials and settings, copied into the env file.
+const PROVIDER_VARS: &[&str] = &[
+    "DIGITALOCEAN_API_TOKEN",
+    "INNISFREE_PROFILE",
+    "INNISFREE_TOKEN_FILE",
+    "INNISFREE_TOKEN_CMD",
+    "INNISFREE_TOKEN_KEYRING",
+    "INNISFREE_AGE",
+    "INNISFREE_AGE_RECIPIENT",
+    "INNISFREE_AGE_IDENTITY",
+    "LINODE_API_TOKEN",
+    "AZURE_CLIENT_ID",
+    "AZURE_CLIENT_SECRET",
+    "AZURE_SUBSCRIPTION_ID",
+    "AZURE_TENANT_ID",
+    "SCW_SECRET_KEY",
+    "SCW_DEFAULT_PROJECT_ID",
+    "OCI_AUTH",
+    "OCI_REGION",
+    "OCI_TENANCY_OCID",
+    "OCI_USER_OCID",
+    "OCI_FINGERPRINT",
+    "OCI_PRIVATE_KEY_PATH",
+    "OCI_COMPARTMENT_OCID",
+    "OCI_AVAILABILITY_DOMAIN",
+    "OCI_SUBNET_OCID",
+    "OCI_IMAGE_OCID",
+];
+
+/// Returns the name of the unit for the tunnel `name`, e.g. `innisfree-foo.service`.
+pub fn unit_name(name: &str) -> String {
+    format!("innisfree-{}.service", name)
+}
+
+/// Quotes an argument for a unit's `Exec` lines, escaping the `%`
+/// specifiers and `$` variables that systemd would otherwise expand.
+fn quote_arg(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    let plain = !escaped.is_empty()
+        && !escaped.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c));
+    if plain {
+        escaped
+    } else {
+        format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Renders the unit for the tunnel `name`, running `exe up` with `up_args`.
+pub fn unit_file(name: &str, exe: &Path, env_file: &Path, up_args: &[String]) -> Result<String> {
+    if up_args
+        .iter()
+        .any(|a| a == "-n" || a == "--name" || a.starts_with("--name="))
+    {
+        return Err(anyhow!("Pass the tunnel's name via --name, not after '--'"));
+    }
+    let exe = quote_arg(&exe.display().to_string());
+    let mut start = vec![exe.clone(), "up".to_string(), "--name".to_string()];
+    start.push(quote_arg(name));
+    start.extend(up_args.iter().map(|a| quote_arg(a)));
+    let stop = [
+        exe,
+        "down".to_string(),
+        "--name".to_string(),
+        quote_arg(name),
+    ];
+
+    let mut context = tera::Context::new();
+    context.insert("name", name);
+    context.insert("env_file", &env_file.display().to_string());
+    context.insert("exec_start", &start.join(" "));
+    context.insert("exec_stop", &stop.join(" "));
+    tera::Tera::one_off(
+        include_str!("../files/innisfree.service.j2"),
+        &context,
+        false,
+    )
+    .context("Failed to render systemd unit")
+}
+
+/// Renders an env file for the given variables, as read by `EnvironmentFile=`.
+fn env_file(vars: &[(String, String)]) -> String {
+    vars.iter()
+        .map(|(k, v)| {
+            format!(
+                "{}=\"{}\"\n",
+                k,
+                v.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect()
+}
+
+/// Writes `contents` to `path`, creating its parent dir, with the given mode.
+/// The mode is applied before writing, even if the file already exists,
+/// e.g. with wider permissions, so secrets are never left readable.
+fn write_file(path: &Path, contents: &str, mode: u32) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    f.set_permissions(Permissions::from_mode(mode))?;
+    f.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// Installs a unit for the tunnel `name` into `unit_dir`, running the
+/// current executable with `up_args`. Provider credentials set in the
+/// current env are saved to the unit's env file, under `env_dir`,
+/// rather than the unit itself, which is world-readable.
+/// Returns the path to the unit.
+pub fn install(name: &str, up_args: &[String], unit_dir: &Path, env_dir: &Path) -> Result<PathBuf> {
+    let exe = std::env::current_exe().context("Failed to find innisfree executable")?;
+    let env_path = env_dir.join(format!("{}.env", name));
+    let unit = unit_file(name, &exe, &env_path, up_args)?;
+
+    let vars: Vec<(String, String)> = PROVIDER_VARS
+        .iter()
+        .filter_map(|k| std::env::var(k).ok().map(|v| (k.to_string(), v)))
+        .collect();
+    if vars.is_empty() {
+        tracing::warn!(
+            "No provider credentials found in env, add them to {}",
+            env_path.display()
+        );
+    } else {
+        write_file(&env_path, &env_file(&vars), 0o600)?;
+        tracing::info!(
+            "Saved {} provider settings to {}",
+            vars.len(),
+            env_path.display()
+        );
+    }
+    let unit_path = unit_dir.join(unit_name(name));
+    write_file(&unit_path, &unit, 0o644)?;
+    Ok(unit_path)
+}
+
+/// Whether the process was started by systemd, as a `Type=notify` service.
+pub fn notify_enabled() -> bool {
+    std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+/// Sends a status update to systemd, e.g. `READY=1`, as `sd_notify(3)` does.
+/// Does nothing unless running as a `Type=notify` service.
+pub fn notify(state: &str) -> Result<()> {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(p) => p,
+        Err(_) => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        // Abstract sockets are only supported on Linux.
+        #[cfg(target_os = "linux")]
+        Some(abstract_name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(abstract_name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    }
+    .with_context(|| format!("Failed to notify systemd via {}", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_args_are_escaped() {
+        assert_eq!(quote_arg("443/TCP"), "443/TCP");
+        assert_eq!(quote_arg("100%"), "100%%");
+        assert_eq!(quote_arg("$HOME"), "$$HOME");
+        assert_eq!(quote_arg("a b"), "\"a b\"");
+        assert_eq!(quote_arg("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(quote_arg(""), "\"\"");
+    }
+
+    #[test]
+    fn unit_stops_via_down() -> Result<()> {
+        let unit = unit_file(
+            "foo",
+            Path::new("/usr/bin/innisfree"),
+            Path::new("/etc/innisfree/foo.env"),
+            &["--ports".to_string(), "443/TCP".to_string()],
+        )?;
+        assert!(unit.contains("Type=notify\n"));
+        assert!(unit.contains("EnvironmentFile=-/etc/innisfree/foo.env\n"));
+        assert!(unit.contains("ExecStart=/usr/bin/innisfree up --name foo --ports 443/TCP\n"));
+        assert!(unit.contains("ExecStop=/usr/bin/innisfree down --name foo\n"));
+        assert!(unit.contains("ExecReload=/bin/kill -HUP $MAINPID\n"));
+        assert!(unit_file(
+            "foo",
+            Path::new("/usr/bin/innisfree"),
+            Path::new("/etc/innisfree/foo.env"),
+            &["--name=bar".to_string()],
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn env_file_values_are_quoted() {
+        let vars = vec![
+            ("DIGITALOCEAN_API_TOKEN".to_string(), "abc123".to_string()),
+            ("OCI_AUTH".to_string(), "a\"b".to_string()),
+        ];
+        assert_eq!(
+            env_file(&vars),
+            "DIGITALOCEAN_API_TOKEN=\"abc123\"\nOCI_AUTH=\"a\\\"b\"\n"
+        );
+    }
+
+    #[test]
+    fn existing_files_get_mode() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("innisfree-env-{}", std::process::id()));
+        std::fs::write(&path, "")?;
+        std::fs::set_permissions(&path, Permissions::from_mode(0o644))?;
+        write_file(&path, "DIGITALOCEAN_API_TOKEN=\"abc123\"\n", 0o600)?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        std::fs::remove_file(&path)?;
+        assert_eq!(mode & 0o777, 0o600);
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+//! Where the DigitalOcean API token is read from. By default it's the
+//! `DIGITALOCEAN_API_TOKEN` env var, but env vars show up in process
+//! listings, e.g. `/proc/<pid>/environ`, and in systemd unit files, so the
+//! token may instead be read from a file, the OS keyring, or the output of
+//! a command, e.g. a password manager's, or come from a credentials profile,
+//! see [crate::credentials]. The source is chosen once, at startup, see
+//! [set_source], and the token read when first needed.
+
+use anyhow::{anyhow, Context, Result};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+/// Env var holding the token, read unless another source is chosen.
+pub const TOKEN_ENV: &str = "DIGITALOCEAN_API_TOKEN";
+/// Service under which the token is stored in the OS keyring.
+pub const KEYRING_SERVICE: &str = "innisfree";
+/// Account under which the token is stored in the OS keyring, unless
+/// chosen by a credentials profile.
+pub const KEYRING_ACCOUNT: &str = "digitalocean";
+
+/// Where to read the API token from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TokenSource {
+    /// The [TOKEN_ENV] env var.
+    #[default]
+    Env,
+    /// A file holding only the token, e.g. a systemd credential.
+    File(PathBuf),
+    /// The output of a shell command, e.g. `pass show do/token`.
+    Command(String),
+    /// The OS keyring, under [KEYRING_SERVICE] and the given account: the
+    /// Secret Service, via `secret-tool`, on Linux, or the login keychain,
+    /// via `security`, on macOS.
+    Keyring(String),
+    /// The token itself, e.g. from a credentials profile.
+    Value(String),
+}
+
+static SOURCE: OnceLock<TokenSource> = OnceLock::new();
+static TOKEN: OnceLock<String> = OnceLock::new();
+
+/// Chooses where the token is read from, for the rest of the process.
+/// Fails if already chosen.
+pub fn set_source(source: TokenSource) -> Result<()> {
+    SOURCE
+        .set(source)
+        .map_err(|_| anyhow!("API token source already set"))
+}
+
+/// Runs `cmd`, returning its output, or failing with its errors.
+fn output(cmd: &mut Command) -> Result<String> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let output = cmd
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .with_context(|| format!("Failed to run {}, is it installed?", program))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+impl TokenSource {
+    /// Describes where the token is read from, without revealing it.
+    fn describe(&self) -> String {
+        match self {
+            TokenSource::Env => TOKEN_ENV.to_string(),
+            TokenSource::File(path) => path.display().to_string(),
+            TokenSource::Command(cmd) => format!("'{}'", cmd),
+            TokenSource::Keyring(account) => format!("keyring account '{}'", account),
+            TokenSource::Value(_) => "credentials profile".to_string(),
+        }
+    }
+
+    /// Reads the token, without surrounding whitespace, e.g. a trailing
+    /// newline. Fails if it's missing or empty.
+    pub fn read(&self) -> Result<String> {
+        let token = match self {
+            TokenSource::Env => std::env::var(TOKEN_ENV).with_context(|| {
+                format!(
+                    "{} not set, nor --token-file, --token-cmd, or --token-keyring",
+                    TOKEN_ENV
+                )
+            })?,
+            TokenSource::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read API token from {}", path.display()))?,
+            TokenSource::Command(cmd) => output(Command::new("sh").arg("-c").arg(cmd))
+                .with_context(|| format!("Failed to get API token from '{}'", cmd))?,
+            TokenSource::Keyring(account) => {
+                let mut cmd = match cfg!(target_os = "macos") {
+                    true => {
+                        let mut c = Command::new("security");
+                        c.args(["find-generic-password", "-s", KEYRING_SERVICE])
+                            .args(["-a", account, "-w"]);
+                        c
+                    }
+                    false => {
+                        let mut c = Command::new("secret-tool");
+                        c.args(["lookup", "service", KEYRING_SERVICE])
+                            .args(["account", account]);
+                        c
+                    }
+                };
+                output(&mut cmd).context("Failed to get API token from the keyring")?
+            }
+            TokenSource::Value(token) => token.clone(),
+        };
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(anyhow!("API token from {} is empty", self.describe()));
+        }
+        Ok(token.to_string())
+    }
+}
+
+/// Returns the DigitalOcean API token, read from the chosen source,
+/// see [set_source], the first time, and remembered thereafter.
+pub fn digitalocean() -> Result<String> {
+    if let Some(token) = TOKEN.get() {
+        return Ok(token.clone());
+    }
+    let token = SOURCE.get().cloned().unwrap_or_default().read()?;
+    Ok(TOKEN.get_or_init(|| token).clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_read_from_sources() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("innisfree-token-{}", std::process::id()));
+        std::fs::write(&path, "dop_v1_abc123\n")?;
+        assert_eq!(TokenSource::File(path.clone()).read()?, "dop_v1_abc123");
+        std::fs::write(&path, "\n")?;
+        assert!(TokenSource::File(path.clone()).read().is_err());
+        std::fs::remove_file(&path)?;
+        assert!(TokenSource::File(path).read().is_err());
+
+        let cmd = TokenSource::Command("echo dop_v1_def456".to_string());
+        assert_eq!(cmd.read()?, "dop_v1_def456");
+        let failing = TokenSource::Command("echo locked >&2; exit 1".to_string());
+        let e = failing.read().unwrap_err();
+        assert!(format!("{:#}", e).ends_with("failed: locked"));
+        Ok(())
+    }
+}
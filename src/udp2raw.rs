@@ -0,0 +1,119 @@
+//! Encapsulation of Wireguard's UDP traffic in TCP, via `udp2raw`, for
+//! networks that throttle or drop UDP, see `innisfree up --transport tcp`.
+//! The server runs `udp2raw` as a service, set up by cloud-init, and the
+//! local end runs it as a child process, which Wireguard uses as its endpoint.
+
+use anyhow::{anyhow, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::process::{Child, Command, Stdio};
+
+/// Where the server downloads `udp2raw` from, since distros don't package it.
+pub const UDP2RAW_RELEASE_URL: &str =
+    "https://github.com/wangyu-/udp2raw/releases/download/20230206.0/udp2raw_binaries.tar.gz";
+/// Path to `udp2raw` on the server.
+const SERVER_BINARY: &str = "/usr/local/bin/udp2raw";
+/// Path to the `udp2raw` service's unit on the server.
+pub const SERVER_UNIT_PATH: &str = "/etc/systemd/system/innisfree-udp2raw.service";
+
+/// Builds the arguments shared by both ends: the shared `key`, and TCP
+/// framing, with `udp2raw` managing iptables rules, so the kernel doesn't
+/// reset its raw connections. Wireguard authenticates the traffic itself,
+/// so the key only has to match, not stay secret.
+fn common_args(key: &str) -> Vec<String> {
+    vec![
+        "-k".to_string(),
+        key.to_string(),
+        "--raw-mode".to_string(),
+        "faketcp".to_string(),
+        "-a".to_string(),
+    ]
+}
+
+/// Builds the command line for the server end, accepting TCP on `port`,
+/// and passing the packets on to Wireguard, listening on the same UDP port.
+fn server_cmd(port: u16, key: &str) -> Vec<String> {
+    let mut cmd = vec![
+        SERVER_BINARY.to_string(),
+        "-s".to_string(),
+        format!("-l{}", SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)),
+        format!("-r{}", SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)),
+    ];
+    cmd.extend(common_args(key));
+    cmd
+}
+
+/// Renders the systemd unit running the server end, see [server_cmd].
+pub fn server_unit(port: u16, key: &str) -> Result<String> {
+    let mut context = tera::Context::new();
+    context.insert("exec_start", &server_cmd(port, key).join(" "));
+    tera::Tera::one_off(include_str!("../files/udp2raw.service.j2"), &context, false)
+        .context("Failed to render udp2raw unit")
+}
+
+/// Commands for cloud-init, installing `udp2raw` on the server, then starting it.
+pub fn server_runcmd() -> Vec<Vec<String>> {
+    let install = format!(
+        "curl -fsSL {} | tar -xzO udp2raw_amd64 > {} && chmod 755 {}",
+        UDP2RAW_RELEASE_URL, SERVER_BINARY, SERVER_BINARY
+    );
+    vec![
+        vec!["sh".to_string(), "-c".to_string(), install],
+        vec![
+            "systemctl".to_string(),
+            "enable".to_string(),
+            "--now".to_string(),
+            "innisfree-udp2raw".to_string(),
+        ],
+    ]
+}
+
+/// Address on which the local end listens for Wireguard's packets.
+pub const LOCAL_IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// Local address on which the local end listens for Wireguard's packets,
+/// i.e. the endpoint of the local interface's peer.
+pub fn local_endpoint(port: u16) -> SocketAddr {
+    SocketAddr::new(LOCAL_IP, port)
+}
+
+/// Builds the arguments for the local end, carrying packets sent to
+/// [local_endpoint] to the server at `server`.
+fn client_args(server: SocketAddr, key: &str) -> Vec<String> {
+    let mut args = vec![
+        "-c".to_string(),
+        format!("-l{}", local_endpoint(server.port())),
+        format!("-r{}", server),
+    ];
+    args.extend(common_args(key));
+    args
+}
+
+/// Starts the local end, connecting to the server at `server`.
+/// Kill the returned process to stop it.
+pub fn spawn_client(server: SocketAddr, key: &str) -> Result<Child> {
+    Command::new("udp2raw")
+        .args(client_args(server, key))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run udp2raw, is it installed? {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_ends_share_port_and_key() -> Result<()> {
+        let unit = server_unit(51820, "abc=")?;
+        assert!(unit.contains(
+            "ExecStart=/usr/local/bin/udp2raw -s -l0.0.0.0:51820 -r127.0.0.1:51820 -k abc= --raw-mode faketcp -a"
+        ));
+        let args = client_args("203.0.113.5:51820".parse()?, "abc=");
+        assert_eq!(
+            args.join(" "),
+            "-c -l127.0.0.1:51820 -r203.0.113.5:51820 -k abc= --raw-mode faketcp -a"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! Webhook notifications for a tunnel's lifecycle, e.g. so on-call gets
+//! notified when the public ingress changes IP. Each webhook receives
+//! either a generic JSON object, or a Slack-compatible message, via POST.
+//! Delivery is best-effort: failures are logged, but never stop the tunnel.
+
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::event::TunnelEvent;
+use crate::state;
+
+/// How long to wait for a webhook endpoint to respond.
+#[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Shape of the payload posted to a webhook.
+pub enum WebhookFormat {
+    /// Object describing the event, see [TunnelEvent].
+    Json,
+    /// Message for a Slack incoming webhook, or compatible services,
+    /// e.g. Mattermost or Discord's `/slack` endpoints.
+    Slack,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Endpoint notified of tunnel events.
+pub struct Webhook {
+    /// URL to which events are posted.
+    pub url: String,
+    /// Shape of the payload posted to `url`.
+    pub format: WebhookFormat,
+}
+
+impl FromStr for Webhook {
+    type Err = anyhow::Error;
+
+    /// Parses `[slack=]<URL>`, e.g. `slack=https://hooks.slack.com/services/...`.
+    /// Without the prefix, the generic JSON payload is sent.
+    fn from_str(s: &str) -> Result<Self> {
+        let (format, url) = match s.strip_prefix("slack=") {
+            Some(url) => (WebhookFormat::Slack, url),
+            None => (WebhookFormat::Json, s),
+        };
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err(anyhow!(
+                "Invalid webhook '{}', expected [slack=]<URL>, e.g. https://example.com/hook",
+                s
+            ));
+        }
+        Ok(Webhook {
+            url: url.to_string(),
+            format,
+        })
+    }
+}
+
+/// Whether webhooks are sent `event`. Only changes on-call cares about are,
+/// rather than each step of provisioning.
+fn delivered(event: &TunnelEvent) -> bool {
+    matches!(
+        event,
+        TunnelEvent::Up { .. }
+            | TunnelEvent::Degraded { .. }
+            | TunnelEvent::Recreated { .. }
+            | TunnelEvent::Destroyed
+    )
+}
+
+/// Builds the payload posted to a webhook of the given format.
+fn payload(event: &TunnelEvent, service_name: &str, format: WebhookFormat) -> serde_json::Value {
+    let message = event.message(service_name);
+    match format {
+        WebhookFormat::Slack => json!({ "text": message }),
+        WebhookFormat::Json => {
+            let public_ip = match event {
+                TunnelEvent::Up { public_ip } | TunnelEvent::Recreated { public_ip } => {
+                    Some(public_ip)
+                }
+                _ => None,
+            };
+            json!({
+                "event": event.name(),
+                "name": service_name,
+                "public_ip": public_ip,
+                "message": message,
+                "timestamp": state::now(),
+            })
+        }
+    }
+}
+
+/// Posts `body` to `url`, failing on error responses.
+#[cfg(feature = "webhooks")]
+async fn post(url: &str, body: &serde_json::Value) -> Result<()> {
+    reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?
+        .post(url)
+        .json(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Without an HTTP client, webhooks can't be delivered.
+#[cfg(not(feature = "webhooks"))]
+async fn post(_url: &str, _body: &serde_json::Value) -> Result<()> {
+    Err(anyhow!(
+        "innisfree was built without the 'webhooks' feature"
+    ))
+}
+
+/// Notifies each webhook of `event` on the tunnel `service_name`,
+/// concurrently. Failures are logged, rather than returned.
+pub async fn notify(webhooks: &[Webhook], service_name: &str, event: &TunnelEvent) {
+    if !delivered(event) {
+        return;
+    }
+    let deliveries = webhooks.iter().map(|w| async move {
+        let body = payload(event, service_name, w.format);
+        if let Err(e) = post(&w.url, &body).await {
+            tracing::warn!(
+                "Failed to send '{}' event to webhook {}: {:#}",
+                event.name(),
+                w.url,
+                e
+            );
+        }
+    });
+    futures::future::join_all(deliveries).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhooks_parsed_with_format() -> Result<()> {
+        let w: Webhook = "https://example.com/hook".parse()?;
+        assert_eq!(w.format, WebhookFormat::Json);
+        let w: Webhook = "slack=https://hooks.slack.com/services/T0/B0/x".parse()?;
+        assert_eq!(w.format, WebhookFormat::Slack);
+        assert_eq!(w.url, "https://hooks.slack.com/services/T0/B0/x");
+        assert!("slack=hooks.slack.com".parse::<Webhook>().is_err());
+
+        let event = TunnelEvent::Recreated {
+            public_ip: "203.0.113.5".parse()?,
+        };
+        let j = payload(&event, "foo", WebhookFormat::Json);
+        assert_eq!(j["event"], "recreated");
+        assert_eq!(j["public_ip"], "203.0.113.5");
+        let j = payload(&event, "foo", WebhookFormat::Slack);
+        assert_eq!(
+            j["text"],
+            "Tunnel 'foo' moved to a new server, serving on 203.0.113.5"
+        );
+        assert!(
+            payload(&TunnelEvent::Destroyed, "foo", WebhookFormat::Json)["public_ip"].is_null()
+        );
+        assert!(!delivered(&TunnelEvent::WireguardUp));
+        Ok(())
+    }
+}
@@ -2,19 +2,32 @@
 //! Includes methods for generating keypairs ([`WireguardKeypair::new`]),
 //! for configuring interfaces ([WireguardHost]),
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
 use std::io::prelude::*;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::process::{Command, Stdio};
 use std::str;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::config::{make_config_dir, ServicePort};
-use crate::net::generate_unused_subnet;
-use serde::Serialize;
-
-const WIREGUARD_LISTEN_PORT: i32 = 51820;
-
-#[derive(Debug, Serialize, Clone)]
+use crate::net::{generate_unused_subnet, probe_path_mtu};
+use ipnet::IpNet;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// UDP port on which the server listens for Wireguard, unless overridden.
+pub(crate) const WIREGUARD_LISTEN_PORT: i32 = 51820;
+/// Range for [WireguardPort::Random], i.e. the dynamic ports.
+const WIREGUARD_RANDOM_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+/// Bytes added to each packet by Wireguard: 40 for the outer IPv6 header,
+/// 8 for UDP, and 32 for Wireguard's own header and authentication tag.
+const WIREGUARD_OVERHEAD: u16 = 80;
+/// Well-known host used to probe the path MTU, see [WireguardMtu::Auto].
+const PMTU_PROBE_TARGET: &str = "1.1.1.1";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 /// Contains the public and private key material
 /// for a Wireguard ED25519 keypair.
 pub struct WireguardKeypair {
@@ -34,9 +47,23 @@ impl WireguardKeypair {
             public: pubkey,
         })
     }
+
+    /// Represents a remote peer's identity, known only by its public key.
+    /// The private key is left empty, since it's never rendered for a peer.
+    pub fn from_public(public: &str) -> WireguardKeypair {
+        WireguardKeypair {
+            private: String::new(),
+            public: public.to_string(),
+        }
+    }
+
+    /// Returns the public key, by which peers refer to this identity.
+    pub fn public(&self) -> &str {
+        &self.public
+    }
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 /// Represents a Wireguard that can be peered with.
 pub struct WireguardHost {
     /// Human-readable name for peer on the Wireguard network.
@@ -55,7 +82,7 @@ pub struct WireguardHost {
     pub keypair: WireguardKeypair,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 /// Represents a network device for handling Wireguard traffic.
 /// Must include remote and local identities in the form of `WireguardHost`.
 pub struct WireguardDevice {
@@ -65,6 +92,14 @@ pub struct WireguardDevice {
     pub interface: WireguardHost,
     /// Representation of remote peer as a [WireguardHost].
     pub peer: WireguardHost,
+    /// Whether public traffic reaches this device via DNAT on the peer,
+    /// with the clients' addresses intact. If so, replies to those
+    /// addresses are routed back over the tunnel, too.
+    pub dnat: bool,
+    /// MTU for the interface. If unset, `wg-quick` derives it from the
+    /// default route, which is too large on links with extra encapsulation,
+    /// e.g. PPPoE or some LTE networks, stalling large transfers.
+    pub mtu: Option<u16>,
 }
 
 impl WireguardDevice {
@@ -104,12 +139,139 @@ impl WireguardDevice {
     pub fn write_locally(&self, service_name: &str, services: &[ServicePort]) -> Result<()> {
         let wg_config_path = make_config_dir(service_name)?.join(format!("{}.conf", service_name));
         let mut f = std::fs::File::create(&wg_config_path)?;
-        f.write_all(&self.config_with_services(services)?.as_bytes())?;
+        f.write_all(self.config_with_services(services)?.as_bytes())?;
         Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
+/// Returns a Wireguard config with its `PrivateKey` removed, for keeping on
+/// disk once the interface is up, since `wg-quick down` doesn't need it.
+pub fn without_private_key(config: &str) -> String {
+    config
+        .lines()
+        .filter(|l| !l.trim_start().starts_with("PrivateKey"))
+        .map(|l| format!("{}\n", l))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How to choose the MTU for both ends of the tunnel, see [WireguardDevice::mtu].
+pub enum WireguardMtu {
+    /// Probe the path MTU at startup, and subtract the Wireguard overhead.
+    Auto,
+    /// Use a fixed value.
+    Fixed(u16),
+}
+
+impl WireguardMtu {
+    /// Returns the MTU to use, probing the path for [WireguardMtu::Auto].
+    pub fn resolve(self) -> Result<u16> {
+        match self {
+            WireguardMtu::Fixed(mtu) => Ok(mtu),
+            WireguardMtu::Auto => {
+                let path_mtu = probe_path_mtu(PMTU_PROBE_TARGET.parse()?)?;
+                let mtu = mtu_for_path(path_mtu);
+                tracing::info!("Path MTU is {}, using Wireguard MTU {}", path_mtu, mtu);
+                Ok(mtu)
+            }
+        }
+    }
+}
+
+impl FromStr for WireguardMtu {
+    type Err = anyhow::Error;
+
+    /// Parses `auto`, or an MTU no smaller than the IPv6 minimum of 1280.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(WireguardMtu::Auto);
+        }
+        let mtu: u16 = s
+            .parse()
+            .map_err(|_| anyhow!("Invalid MTU '{}', expected a number or 'auto'", s))?;
+        if !(1280..=9000).contains(&mtu) {
+            return Err(anyhow!("MTU {} out of range, must be 1280-9000", mtu));
+        }
+        Ok(WireguardMtu::Fixed(mtu))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How to choose the UDP port on which the server listens for Wireguard.
+pub enum WireguardPort {
+    /// Pick a random high port, e.g. to dodge networks blocking the default.
+    Random,
+    /// Use a fixed port.
+    Fixed(u16),
+}
+
+impl WireguardPort {
+    /// Returns the port to use, picking one for [WireguardPort::Random].
+    pub fn resolve(self) -> u16 {
+        match self {
+            WireguardPort::Fixed(port) => port,
+            WireguardPort::Random => {
+                let port = rand::thread_rng().gen_range(WIREGUARD_RANDOM_PORTS);
+                tracing::info!("Using random Wireguard port {}", port);
+                port
+            }
+        }
+    }
+}
+
+impl FromStr for WireguardPort {
+    type Err = anyhow::Error;
+
+    /// Parses `random`, or a port number. Port 22 is reserved for SSH.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("random") {
+            return Ok(WireguardPort::Random);
+        }
+        let port: u16 = s
+            .parse()
+            .map_err(|_| anyhow!("Invalid port '{}', expected a number or 'random'", s))?;
+        if port == 0 || port == 22 {
+            return Err(anyhow!("Port {} can't be used for Wireguard", port));
+        }
+        Ok(WireguardPort::Fixed(port))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// How Wireguard's packets travel between the two ends of the tunnel.
+pub enum WireguardTransport {
+    /// Plain UDP, as Wireguard sends them.
+    #[default]
+    Udp,
+    /// Encapsulated in TCP via `udp2raw`, for networks that throttle or
+    /// drop UDP. Slower, so only worth it where UDP doesn't work well.
+    Tcp,
+}
+
+impl FromStr for WireguardTransport {
+    type Err = anyhow::Error;
+
+    /// Parses `udp` or `tcp`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "udp" => Ok(WireguardTransport::Udp),
+            "tcp" => Ok(WireguardTransport::Tcp),
+            _ => Err(anyhow!(
+                "Invalid transport '{}', expected 'udp' or 'tcp'",
+                s
+            )),
+        }
+    }
+}
+
+/// Returns the largest safe Wireguard MTU for a path MTU. Subtracts the
+/// worst-case overhead, for an IPv6 outer header, as `wg-quick` does.
+pub fn mtu_for_path(path_mtu: u16) -> u16 {
+    path_mtu.saturating_sub(WIREGUARD_OVERHEAD)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 /// Controller class for creating both ends of a Wireguard tunnel.
 /// Generates keypairs for local and remote interfaces.
 /// Generates configuration files for both interfaces.
@@ -130,10 +292,23 @@ pub struct WireguardManager {
 }
 
 impl WireguardManager {
-    /// Create a new controller class, based on `service_name`.
+    /// Create a new controller class, based on `service_name`,
+    /// using the first unused subnet within [crate::net::INNISFREE_SUBNET].
     pub fn new(service_name: &str) -> Result<WireguardManager> {
-        let wg_subnet = generate_unused_subnet()?;
+        WireguardManager::with_subnet(service_name, generate_unused_subnet()?)
+    }
+
+    /// Create a new controller class, based on `service_name`, whose
+    /// interfaces take the two addresses in `wg_subnet`, e.g. as chosen
+    /// by [crate::net::choose_subnet].
+    pub fn with_subnet(service_name: &str, wg_subnet: IpNet) -> Result<WireguardManager> {
         let s = wg_subnet.hosts().collect::<Vec<IpAddr>>();
+        if s.len() != 2 {
+            return Err(anyhow!(
+                "Tunnel subnet {} must hold exactly two addresses",
+                wg_subnet
+            ));
+        }
 
         let wg_local_ip = s[0];
         let wg_local_name = format!("innisfree-{}-local", service_name);
@@ -161,11 +336,15 @@ impl WireguardManager {
             name: wg_local_name,
             interface: wg_local_host.clone(),
             peer: wg_remote_host.clone(),
+            dnat: false,
+            mtu: None,
         };
         let wg_remote_device = WireguardDevice {
             name: wg_remote_name,
             interface: wg_remote_host,
             peer: wg_local_host,
+            dnat: false,
+            mtu: None,
         };
 
         Ok(WireguardManager {
@@ -180,6 +359,156 @@ impl WireguardManager {
             wg_remote_device,
         })
     }
+
+    /// Sets the MTU for both ends of the tunnel, which must match.
+    pub fn set_mtu(&mut self, mtu: Option<u16>) {
+        self.wg_local_device.mtu = mtu;
+        self.wg_remote_device.mtu = mtu;
+    }
+
+    /// Sets the UDP port on which the remote end listens, which the
+    /// local end connects to. Defaults to 51820.
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.wg_remote_device.interface.listenport = i32::from(port);
+        self.wg_local_device.peer.listenport = i32::from(port);
+    }
+}
+
+/// Returns the time of the most recent handshake on the interface `iface`,
+/// via `wg show`, or `None` if there hasn't been one. Fails if the interface
+/// doesn't exist.
+pub fn latest_handshake(iface: &str) -> Result<Option<SystemTime>> {
+    let output = Command::new("wg")
+        .args(["show", iface, "latest-handshakes"])
+        .stderr(Stdio::null())
+        .output()
+        .context("Failed to run wg show")?;
+    if !output.status.success() {
+        return Err(anyhow!("Wireguard interface {} not found", iface));
+    }
+    Ok(parse_latest_handshake(str::from_utf8(&output.stdout)?))
+}
+
+/// Parses the output of `wg show <iface> latest-handshakes`, i.e. a line
+/// per peer of `<pubkey>\t<unix timestamp>`, returning the latest.
+/// A timestamp of 0 means no handshake yet.
+fn parse_latest_handshake(output: &str) -> Option<SystemTime> {
+    output
+        .lines()
+        .filter_map(|l| l.split_whitespace().nth(1)?.parse::<u64>().ok())
+        .filter(|t| *t > 0)
+        .max()
+        .map(|t| UNIX_EPOCH + Duration::from_secs(t))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Traffic and handshake stats for one peer of a Wireguard interface,
+/// as reported by `wg show`.
+pub struct PeerStats {
+    /// Public key identifying the peer.
+    pub public_key: String,
+    /// Address from which the peer last sent traffic, if any.
+    pub endpoint: Option<SocketAddr>,
+    /// Time of the most recent handshake with the peer, if any.
+    pub latest_handshake: Option<SystemTime>,
+    /// Bytes received from the peer.
+    pub rx_bytes: u64,
+    /// Bytes sent to the peer.
+    pub tx_bytes: u64,
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "peer: {}", self.public_key)?;
+        if let Some(endpoint) = self.endpoint {
+            writeln!(f, "  endpoint: {}", endpoint)?;
+        }
+        let handshake = match self.latest_handshake.map(|t| t.elapsed()) {
+            Some(Ok(e)) => format!("{} seconds ago", e.as_secs()),
+            Some(Err(_)) => "just now".to_string(),
+            None => "never".to_string(),
+        };
+        writeln!(f, "  latest handshake: {}", handshake)?;
+        write!(
+            f,
+            "  transfer: {} received, {} sent",
+            human_bytes(self.rx_bytes),
+            human_bytes(self.tx_bytes)
+        )
+    }
+}
+
+/// Formats a byte count with a binary unit, as `wg show` does, e.g. `1.50 MiB`.
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for u in UNITS {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = u;
+    }
+    format!("{:.2} {}", value, unit)
+}
+
+/// Returns stats for each peer of the interface `iface`, via `wg show`.
+/// Fails if the interface doesn't exist.
+pub fn peer_stats(iface: &str) -> Result<Vec<PeerStats>> {
+    let output = Command::new("wg")
+        .args(["show", iface, "dump"])
+        .stderr(Stdio::null())
+        .output()
+        .context("Failed to run wg show")?;
+    if !output.status.success() {
+        return Err(anyhow!("Wireguard interface {} not found", iface));
+    }
+    parse_dump(str::from_utf8(&output.stdout)?)
+}
+
+/// Parses the output of `wg show <iface> dump`. The first line describes
+/// the interface itself, and each following line a peer, with tab-separated
+/// fields: public key, preshared key, endpoint, allowed IPs, latest handshake,
+/// bytes received, bytes sent, and keepalive interval.
+fn parse_dump(output: &str) -> Result<Vec<PeerStats>> {
+    output
+        .lines()
+        .skip(1)
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let fields: Vec<&str> = l.split('\t').collect();
+            if fields.len() < 7 {
+                return Err(anyhow!("Unexpected line in wg show output: {}", l));
+            }
+            let handshake: u64 = fields[4].parse()?;
+            Ok(PeerStats {
+                public_key: fields[0].to_string(),
+                endpoint: fields[2].parse().ok(),
+                latest_handshake: (handshake > 0)
+                    .then(|| UNIX_EPOCH + Duration::from_secs(handshake)),
+                rx_bytes: fields[5].parse()?,
+                tx_bytes: fields[6].parse()?,
+            })
+        })
+        .collect()
+}
+
+/// Points the peer identified by `pubkey` on the interface `iface` at a new
+/// endpoint, via `wg set`, without bringing the interface down.
+pub fn set_peer_endpoint(iface: &str, pubkey: &str, endpoint: SocketAddr) -> Result<()> {
+    let status = Command::new("wg")
+        .args(["set", iface, "peer", pubkey, "endpoint"])
+        .arg(endpoint.to_string())
+        .status()
+        .context("Failed to run wg set")?;
+    if !status.success() {
+        return Err(anyhow!("Failed to update endpoint on {}", iface));
+    }
+    Ok(())
 }
 
 /// Create a new ED25519 private key via ``wg genkey``.
@@ -234,6 +563,8 @@ mod tests {
             name: "foo1".to_string(),
             interface: wg_hosts[0].clone(),
             peer: wg_hosts[1].clone(),
+            dnat: false,
+            mtu: None,
         };
         let wg_config = wg_device.config()?;
         assert!(wg_config.contains("Interface"));
@@ -245,6 +576,10 @@ mod tests {
         assert!(wg_config.contains(&wg_hosts[1].keypair.public));
         assert!(!wg_config.contains(&wg_hosts[1].keypair.private));
 
+        let redacted = without_private_key(&wg_config);
+        assert!(!redacted.contains(&wg_hosts[0].keypair.private));
+        assert!(redacted.contains(&wg_hosts[1].keypair.public));
+
         // Slashes '/' will be rendered as hex value &#x2F if formatting is broken
         assert!(!wg_config.contains("&#x2F"));
         assert!(!wg_config.contains(r"&#x2F"));
@@ -252,6 +587,124 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn config_generation_ipv6() -> anyhow::Result<()> {
+        let mut wg_hosts = _generate_hosts()?;
+        wg_hosts[0].address = "fd50::".parse()?;
+        wg_hosts[1].address = "fd50::1".parse()?;
+        wg_hosts[1].endpoint = Some("2604:a880::1".parse()?);
+        let wg_device = WireguardDevice {
+            name: "foo1".to_string(),
+            interface: wg_hosts[0].clone(),
+            peer: wg_hosts[1].clone(),
+            dnat: false,
+            mtu: None,
+        };
+        let wg_config = wg_device.config()?;
+        assert!(wg_config.contains("Address = fd50::/127"));
+        assert!(wg_config.contains("Endpoint = [2604:a880::1]:80"));
+        assert!(wg_config.contains("AllowedIPs = fd50::1/128"));
+        Ok(())
+    }
+
+    #[test]
+    fn config_generation_dnat() -> anyhow::Result<()> {
+        let wg_hosts = _generate_hosts()?;
+        let mut wg_device = WireguardDevice {
+            name: "foo1".to_string(),
+            interface: wg_hosts[0].clone(),
+            peer: wg_hosts[1].clone(),
+            dnat: false,
+            mtu: None,
+        };
+        assert!(!wg_device.config()?.contains("Table = off"));
+        wg_device.dnat = true;
+        let wg_config = wg_device.config()?;
+        assert!(wg_config.contains("Table = off"));
+        assert!(wg_config.contains("AllowedIPs = 0.0.0.0/0"));
+        Ok(())
+    }
+
+    #[test]
+    fn config_generation_mtu() -> anyhow::Result<()> {
+        let mut mgr = WireguardManager::new("foo-mtu")?;
+        assert!(!mgr.wg_local_device.config()?.contains("MTU"));
+        mgr.set_mtu(Some(1380));
+        assert!(mgr.wg_local_device.config()?.contains("MTU = 1380"));
+        assert!(mgr.wg_remote_device.config()?.contains("MTU = 1380"));
+        Ok(())
+    }
+
+    #[test]
+    fn listen_port_applies_to_both_ends() -> anyhow::Result<()> {
+        let mut mgr = WireguardManager::new("foo-port")?;
+        mgr.set_listen_port(41641);
+        assert!(mgr
+            .wg_remote_device
+            .config()?
+            .contains("ListenPort = 41641"));
+        let mut local = mgr.wg_local_device.clone();
+        local.peer.endpoint = Some("203.0.113.5".parse()?);
+        assert!(local.config()?.contains("Endpoint = 203.0.113.5:41641"));
+
+        assert_eq!("random".parse::<WireguardPort>()?, WireguardPort::Random);
+        assert!(WIREGUARD_RANDOM_PORTS.contains(&WireguardPort::Random.resolve()));
+        assert_eq!("41641".parse::<WireguardPort>()?.resolve(), 41641);
+        assert!("22".parse::<WireguardPort>().is_err());
+        assert!("70000".parse::<WireguardPort>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn latest_handshake_parsed() {
+        assert_eq!(parse_latest_handshake(""), None);
+        assert_eq!(
+            parse_latest_handshake("ISRq2SHZQDnSfV0VlmMEP4MbwfExE/iNHzthMQ7eNmY=\t0\n"),
+            None
+        );
+        let output = "ISRq2SHZQDnSfV0VlmMEP4MbwfExE/iNHzthMQ7eNmY=\t1700000000\n\
+                      yPgz26A4S6RcniNaikFZrc0C0SyCW1moXmDP7AMeimE=\t1700000100\n";
+        assert_eq!(
+            parse_latest_handshake(output),
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_100))
+        );
+    }
+
+    #[test]
+    fn peer_stats_parsed_from_dump() -> anyhow::Result<()> {
+        let output = "yPgz26A4S6RcniNaikFZrc0C0SyCW1moXmDP7AMeimE=\tISRq2SHZQDnSfV0VlmMEP4MbwfExE/iNHzthMQ7eNmY=\t0\toff\n\
+                      ISRq2SHZQDnSfV0VlmMEP4MbwfExE/iNHzthMQ7eNmY=\t(none)\t203.0.113.5:51820\t10.50.0.2/32\t1700000000\t1572864\t2048\t25\n";
+        let stats = parse_dump(output)?;
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].endpoint, Some("203.0.113.5:51820".parse()?));
+        assert_eq!(
+            stats[0].latest_handshake,
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        let display = stats[0].to_string();
+        assert!(display.contains("1.50 MiB received, 2.00 KiB sent"));
+
+        // Peers that never connected have no endpoint or handshake
+        let output = "a\tb\t0\toff\nc\t(none)\t(none)\t10.50.0.2/32\t0\t0\t0\toff\n";
+        let stats = parse_dump(output)?;
+        assert_eq!(stats[0].endpoint, None);
+        assert!(stats[0].to_string().contains("latest handshake: never"));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_mtu() -> anyhow::Result<()> {
+        assert_eq!("auto".parse::<WireguardMtu>()?, WireguardMtu::Auto);
+        assert_eq!("1380".parse::<WireguardMtu>()?, WireguardMtu::Fixed(1380));
+        assert!("576".parse::<WireguardMtu>().is_err());
+        assert!("big".parse::<WireguardMtu>().is_err());
+        // A standard Ethernet path yields wg-quick's default
+        assert_eq!(mtu_for_path(1500), 1420);
+        // PPPoE adds 8 bytes of its own
+        assert_eq!(mtu_for_path(1492), 1412);
+        Ok(())
+    }
+
     // Helper function for reusable structs
     fn _generate_hosts() -> Result<Vec<WireguardHost>> {
         let kp1 = WireguardKeypair::new()?;
@@ -289,6 +742,8 @@ mod tests {
             name: "foo".to_string(),
             interface: wg_hosts[0].clone(),
             peer: wg_hosts[1].clone(),
+            dnat: false,
+            mtu: None,
         };
         assert_eq!(wg_device.name, "foo");
         assert_eq!(wg_hosts[0].name, "foo1");
@@ -304,6 +759,8 @@ mod tests {
             name: "foo".to_string(),
             interface: wg_h1.clone(),
             peer: wg_h2.clone(),
+            dnat: false,
+            mtu: None,
         };
         assert_eq!(wg_device.name, "foo");
         assert_eq!(wg_hosts[0].name, "foo1");
@@ -321,6 +778,8 @@ mod tests {
             name: "foo".to_string(),
             interface: wg_h1.clone(),
             peer: wg_h2.clone(),
+            dnat: false,
+            mtu: None,
         };
 
         let wg_device2 = wg_device.clone();
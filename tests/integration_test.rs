@@ -6,3 +6,12 @@ fn simple_http_server_is_remotely_accessible() {
     let status = Command::new("./tools/test-runner").status();
     assert!(status.is_ok());
 }
+
+/// Runs the full up/proxy/down cycle against a local container standing in
+/// for the server, via `--provider mock`, so it needs no cloud account.
+#[ignore]
+#[test]
+fn tunnel_to_container_server_is_accessible() {
+    let status = Command::new("./tools/e2e-runner").status();
+    assert!(status.is_ok_and(|s| s.success()));
+}